@@ -2,13 +2,38 @@
 extern crate criterion;
 
 use criterion::Criterion;
+use std::fs::File;
+use std::io::Write;
+use std::process::Command;
 
 fn bench_parse_query() {
     let _ = 1 + 1;
 }
 
+//Every record read and projected, where the cost of carrying the field names shows the most
+fn bench_select_star(c: &mut Criterion) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("elb.log");
+    let mut file = File::create(&path).unwrap();
+    for i in 0..10000 {
+        writeln!(file, r#"2015-11-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.{}:80 0.000036 0.001035 0.000025 200 200 0 {} "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#, i % 4, i).unwrap();
+    }
+    drop(file);
+
+    let path = path.to_str().unwrap().to_string();
+    c.bench_function("select star over 10k elb lines", move |b| {
+        b.iter(|| {
+            Command::new(env!("CARGO_BIN_EXE_logq"))
+                .args(["query", "--output", "csv", "select * from elb", &path])
+                .output()
+                .unwrap()
+        })
+    });
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("parse query", |b| b.iter(bench_parse_query));
+    bench_select_star(c);
 }
 
 criterion_group!(benches, criterion_benchmark);
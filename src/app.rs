@@ -1,8 +1,17 @@
 use csv::Writer;
+use flate2::read::MultiGzDecoder;
+use hashbrown::{HashMap, HashSet};
 use nom::error::VerboseError;
 use prettytable::{Row, Table};
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::result;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use crate::common;
 use crate::execution;
@@ -14,43 +23,113 @@ pub(crate) type AppResult<T> = result::Result<T, AppError>;
 #[derive(Fail, Debug)]
 pub(crate) enum AppError {
     #[fail(display = "Syntax Error: {}", _0)]
-    Syntax(String),
+    Syntax(#[cause] SyntaxError),
+    #[fail(display = "Plan Error: {}", _0)]
+    Plan(#[cause] PlanError),
+    #[fail(display = "Io Error: {}", _0)]
+    Io(#[cause] io::Error),
+    #[fail(display = "Execution Error: {}", _0)]
+    Execution(#[cause] execution::types::StreamError),
+    #[fail(display = "Output Error: {}", _0)]
+    Output(#[cause] OutputError),
+    #[fail(display = "Merge Error: {}", _0)]
+    Merge(#[cause] MergeError),
+    #[fail(display = "Warnings treated as errors:\n{}", _0)]
+    Warnings(String),
+    #[fail(display = "Can't read the query file {}: {}", _0, _1)]
+    QueryFile(String, #[cause] io::Error),
+}
+
+impl PartialEq for AppError {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (AppError::Syntax(a), AppError::Syntax(b)) => a == b,
+            (AppError::Plan(a), AppError::Plan(b)) => a == b,
+            (AppError::Io(_), AppError::Io(_)) => true,
+            (AppError::Execution(a), AppError::Execution(b)) => a == b,
+            (AppError::Output(_), AppError::Output(_)) => true,
+            (AppError::Merge(a), AppError::Merge(b)) => a == b,
+            (AppError::Warnings(a), AppError::Warnings(b)) => a == b,
+            (AppError::QueryFile(a, _), AppError::QueryFile(b, _)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for AppError {}
+
+#[derive(Fail, Debug, PartialEq, Eq)]
+pub(crate) enum SyntaxError {
+    #[fail(display = "{}", _0)]
+    Invalid(String),
     #[fail(display = "Input is fully consumed, the leftover are \"{}\"", _0)]
     InputNotAllConsumed(String),
+    #[fail(display = "Invalid Log File Format")]
+    InvalidLogFileFormat,
+    #[fail(display = "Unknown input format \"{}\", the supported formats are {}", _0, _1)]
+    UnknownInputFormat(String, String),
+    #[fail(display = "The query reads the rows of its VALUES list, it can't read a log file too")]
+    ValuesWithLogFile,
+}
+
+#[derive(Fail, Debug, PartialEq, Eq)]
+pub(crate) enum PlanError {
     #[fail(display = "{}", _0)]
-    Parse(#[cause] logical::parser::ParseError),
+    Logical(#[cause] logical::parser::ParseError),
     #[fail(display = "{}", _0)]
-    PhysicalPlan(#[cause] logical::types::PhysicalPlanError),
+    Physical(#[cause] logical::types::PhysicalPlanError),
+    #[fail(display = "Nondeterministic Query: {}", _0)]
+    Nondeterministic(String),
+}
+
+#[derive(Fail, Debug)]
+pub(crate) enum OutputError {
     #[fail(display = "{}", _0)]
-    CreateStream(#[cause] execution::types::CreateStreamError),
+    Csv(#[cause] csv::Error),
     #[fail(display = "{}", _0)]
-    Stream(#[cause] execution::types::StreamError),
-    #[fail(display = "Invalid Log File Format")]
-    InvalidLogFileFormat,
+    Json(#[cause] json::Error),
+    #[fail(display = "{} can't be the value of a metric, {}", _0, _1)]
+    MetricValue(String, String),
+    #[fail(display = "A column without a name can't be a label of {}", _0)]
+    EmptyLabelName(String),
+    #[fail(display = "More than one column becomes the label {} of {}", _0, _1)]
+    DuplicateLabelName(String, String),
+}
+
+#[derive(Fail, Debug)]
+pub(crate) enum MergeError {
     #[fail(display = "{}", _0)]
-    WriteCsv(#[cause] csv::Error),
+    Json(#[cause] json::Error),
+    #[fail(display = "Invalid Partial Record")]
+    InvalidRecord,
     #[fail(display = "{}", _0)]
-    WriteJson(#[cause] json::Error),
+    Aggregate(#[cause] execution::types::AggregateError),
 }
 
-impl PartialEq for AppError {
+impl PartialEq for MergeError {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
-            (AppError::Syntax(_), AppError::Syntax(_)) => true,
-            (AppError::InputNotAllConsumed(_), AppError::InputNotAllConsumed(_)) => true,
-            (AppError::Parse(_), AppError::Parse(_)) => true,
-            (AppError::PhysicalPlan(_), AppError::PhysicalPlan(_)) => true,
-            (AppError::CreateStream(_), AppError::CreateStream(_)) => true,
-            (AppError::Stream(_), AppError::Stream(_)) => true,
-            (AppError::InvalidLogFileFormat, AppError::InvalidLogFileFormat) => true,
-            (AppError::WriteCsv(_), AppError::WriteCsv(_)) => true,
-            (AppError::WriteJson(_), AppError::WriteJson(_)) => true,
+            (MergeError::Json(_), MergeError::Json(_)) => true,
+            (MergeError::InvalidRecord, MergeError::InvalidRecord) => true,
+            (MergeError::Aggregate(a), MergeError::Aggregate(b)) => a == b,
             _ => false,
         }
     }
 }
 
-impl Eq for AppError {}
+impl Eq for MergeError {}
+
+impl From<MergeError> for AppError {
+    fn from(err: MergeError) -> AppError {
+        AppError::Merge(err)
+    }
+}
+
+impl From<execution::types::AggregateError> for MergeError {
+    fn from(err: execution::types::AggregateError) -> MergeError {
+        MergeError::Aggregate(err)
+    }
+}
 
 impl From<nom::Err<VerboseError<&str>>> for AppError {
     fn from(e: nom::Err<VerboseError<&str>>) -> AppError {
@@ -62,7 +141,7 @@ impl From<nom::Err<VerboseError<&str>>> for AppError {
                     errors.push('\n');
                 }
 
-                AppError::Syntax(errors)
+                AppError::Syntax(SyntaxError::Invalid(errors))
             }
             nom::Err::Error(v) => {
                 let mut errors: String = String::new();
@@ -71,53 +150,79 @@ impl From<nom::Err<VerboseError<&str>>> for AppError {
                     errors.push('\n');
                 }
 
-                AppError::Syntax(errors)
+                AppError::Syntax(SyntaxError::Invalid(errors))
             }
-            _ => AppError::Syntax(String::new()),
+            _ => AppError::Syntax(SyntaxError::Invalid(String::new())),
         }
     }
 }
 
+impl From<SyntaxError> for AppError {
+    fn from(err: SyntaxError) -> AppError {
+        AppError::Syntax(err)
+    }
+}
+
 impl From<logical::parser::ParseError> for AppError {
-    fn from(e: logical::parser::ParseError) -> AppError {
-        AppError::Parse(e)
+    fn from(err: logical::parser::ParseError) -> AppError {
+        AppError::Plan(PlanError::Logical(err))
     }
 }
 
 impl From<logical::types::PhysicalPlanError> for AppError {
     fn from(err: logical::types::PhysicalPlanError) -> AppError {
-        AppError::PhysicalPlan(err)
+        AppError::Plan(PlanError::Physical(err))
     }
 }
 
 impl From<execution::types::CreateStreamError> for AppError {
     fn from(err: execution::types::CreateStreamError) -> AppError {
-        AppError::CreateStream(err)
+        match err {
+            execution::types::CreateStreamError::Io(e) => AppError::Io(e),
+            _ => AppError::Execution(execution::types::StreamError::Get(err)),
+        }
+    }
+}
+
+impl From<io::Error> for AppError {
+    fn from(err: io::Error) -> AppError {
+        AppError::Io(err)
+    }
+}
+
+impl From<execution::datasource::ReaderError> for AppError {
+    fn from(err: execution::datasource::ReaderError) -> AppError {
+        match err {
+            execution::datasource::ReaderError::Io(e) => AppError::Io(e),
+            _ => AppError::Execution(execution::types::StreamError::Reader(err)),
+        }
     }
 }
 
 impl From<execution::types::StreamError> for AppError {
     fn from(err: execution::types::StreamError) -> AppError {
-        AppError::Stream(err)
+        AppError::Execution(err)
     }
 }
 
 impl From<csv::Error> for AppError {
     fn from(err: csv::Error) -> AppError {
-        AppError::WriteCsv(err)
+        AppError::Output(OutputError::Csv(err))
     }
 }
 
 impl From<json::Error> for AppError {
     fn from(err: json::Error) -> AppError {
-        AppError::WriteJson(err)
+        AppError::Output(OutputError::Json(err))
     }
 }
 
+#[derive(Debug)]
 pub(crate) enum OutputMode {
     Table,
     Csv,
     Json,
+    Prometheus(PrometheusMetric),
 }
 
 impl FromStr for OutputMode {
@@ -133,131 +238,2993 @@ impl FromStr for OutputMode {
     }
 }
 
+//The Prometheus text exposition of the rows, one sample per row: the value column is the sample and every
+//other column is a label. Without a value column the last one is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PrometheusMetric {
+    name: String,
+    value_column: Option<common::types::VariableName>,
+}
+
+impl PrometheusMetric {
+    pub(crate) fn new(name: &str, value_column: Option<common::types::VariableName>) -> Result<Self, String> {
+        let valid_first = |c: char| c.is_ascii_alphabetic() || c == '_' || c == ':';
+        let valid = name.starts_with(valid_first) && name.chars().all(|c| valid_first(c) || c.is_ascii_digit());
+        if !valid {
+            return Err(format!("{} is not a valid metric name", name));
+        }
+
+        Ok(PrometheusMetric {
+            name: name.to_string(),
+            value_column,
+        })
+    }
+
+    fn render(&self, record: &execution::stream::Record) -> AppResult<String> {
+        let mut tuples = record.to_tuples();
+        let value_idx = match &self.value_column {
+            Some(column_name) => tuples.iter().position(|(name, _)| name == column_name).ok_or_else(|| {
+                AppError::Output(OutputError::MetricValue(
+                    column_name.clone(),
+                    "there is no such column".to_string(),
+                ))
+            })?,
+            None if !tuples.is_empty() => tuples.len() - 1,
+            None => {
+                return Err(AppError::Output(OutputError::MetricValue(
+                    self.name.clone(),
+                    "the row has no column".to_string(),
+                )));
+            }
+        };
+        let (value_name, value) = tuples.remove(value_idx);
+
+        //A missing sample reads as NaN in Prometheus
+        let sample = match value {
+            common::types::Value::Int(i) => i.to_string(),
+            common::types::Value::Float(f) if f.is_infinite() => {
+                if f.is_sign_positive() {
+                    "+Inf".to_string()
+                } else {
+                    "-Inf".to_string()
+                }
+            }
+            common::types::Value::Float(f) => f.to_string(),
+            common::types::Value::Null => "NaN".to_string(),
+            value => {
+                let value_type = execution::types::ValueType::of(&value)
+                    .map(|t| t.to_string())
+                    .unwrap_or_default();
+                return Err(AppError::Output(OutputError::MetricValue(
+                    value_name,
+                    format!("it is a {}", value_type),
+                )));
+            }
+        };
+
+        let mut labels = Vec::new();
+        let mut label_names = HashSet::new();
+        for (name, value) in tuples.iter() {
+            let label_name =
+                label_name(name).ok_or_else(|| AppError::Output(OutputError::EmptyLabelName(self.name.clone())))?;
+            //Two columns made into the same label would write a sample Prometheus rejects
+            if !label_names.insert(label_name.clone()) {
+                return Err(AppError::Output(OutputError::DuplicateLabelName(
+                    label_name,
+                    self.name.clone(),
+                )));
+            }
+            labels.push(format!(
+                "{}=\"{}\"",
+                label_name,
+                escape_label_value(&common::types::value_text(value))
+            ));
+        }
+
+        if labels.is_empty() {
+            Ok(format!("{} {}", self.name, sample))
+        } else {
+            Ok(format!("{}{{{}}} {}", self.name, labels.join(","), sample))
+        }
+    }
+}
+
+//A label name is [a-zA-Z_][a-zA-Z0-9_]*, the other characters of the column name become underscores and a
+//leading digit is prefixed with one. An empty name has no label.
+fn label_name(column_name: &str) -> Option<String> {
+    if column_name.is_empty() {
+        return None;
+    }
+
+    let mut label_name: String = column_name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if label_name.starts_with(|c: char| c.is_ascii_digit()) {
+        label_name.insert(0, '_');
+    }
+    Some(label_name)
+}
+
+fn escape_label_value(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum ExplainFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for ExplainFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ExplainFormat::Text),
+            "json" => Ok(ExplainFormat::Json),
+            _ => Err("unknown explain format".to_string()),
+        }
+    }
+}
+
+//The separator is written between two records and the newline after the last one. The table
+//output has no per record lines, only the trailing newline applies to it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RecordLayout {
+    pub(crate) separator: String,
+    pub(crate) trailing_newline: bool,
+}
+
+impl Default for RecordLayout {
+    fn default() -> Self {
+        RecordLayout {
+            separator: String::new(),
+            trailing_newline: true,
+        }
+    }
+}
+
+//The comma separated name:type pairs of --expect-schema, e.g. "host:string,requests:int"
+pub(crate) fn parse_schema_contract(s: &str) -> Result<Vec<(String, execution::types::ValueType)>, String> {
+    let mut contract = Vec::new();
+    for pair in s.split(',') {
+        let mut parts = pair.splitn(2, ':');
+        match (parts.next().map(str::trim), parts.next().map(str::trim)) {
+            (Some(column_name), Some(value_type)) if !column_name.is_empty() => {
+                let value_type = execution::types::ValueType::from_str(&value_type.to_ascii_lowercase())?;
+                contract.push((column_name.to_ascii_lowercase(), value_type));
+            }
+            _ => return Err(format!("{} is not a name:type pair", pair.trim())),
+        }
+    }
+
+    Ok(contract)
+}
+
+//Turns the \n, \t, \r and \\ escapes typed on the command line into the characters
+pub(crate) fn unescape_separator(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('r') => unescaped.push('\r'),
+            Some('\\') => unescaped.push('\\'),
+            Some(other) => {
+                unescaped.push('\\');
+                unescaped.push(other);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+
+    unescaped
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct QueryOptions {
+    pub(crate) raw_line: bool,
+    pub(crate) partial: bool,
+    pub(crate) signed_limit: bool,
+    pub(crate) nan_policy: execution::types::NanPolicy,
+    pub(crate) explain_format: ExplainFormat,
+    pub(crate) changed_by: Option<String>,
+    pub(crate) number_format: execution::datasource::NumberFormat,
+    pub(crate) summary: Option<String>,
+    pub(crate) flush_interval: Option<std::time::Duration>,
+    pub(crate) flush_emit: execution::types::FlushEmit,
+    pub(crate) quiet: bool,
+    pub(crate) partition_by: Option<String>,
+    pub(crate) partition_template: Option<String>,
+    pub(crate) stringify: bool,
+    pub(crate) input_format: Option<String>,
+    pub(crate) record_layout: RecordLayout,
+    pub(crate) no_header: bool,
+    //The directory of --cache, none without it
+    pub(crate) cache_dir: Option<PathBuf>,
+    pub(crate) columns: Option<Vec<String>>,
+    pub(crate) source_column: Option<String>,
+    pub(crate) skip_empty_aggregate: bool,
+    pub(crate) strict_deterministic: bool,
+    pub(crate) fields_directive: Option<String>,
+    pub(crate) explain_cost: bool,
+    pub(crate) skip_bad_lines: bool,
+    pub(crate) warnings_as_errors: bool,
+    pub(crate) multiline: Option<execution::datasource::RecordStart>,
+    pub(crate) cross_join_limit: Option<u64>,
+    pub(crate) snapshot: Option<std::path::PathBuf>,
+    pub(crate) max_memory: Option<u64>,
+    pub(crate) order_by_hash: Option<Vec<String>>,
+    pub(crate) expect_schema: Option<Vec<(String, execution::types::ValueType)>>,
+    pub(crate) check_every_row: bool,
+    pub(crate) seed: Option<u64>,
+}
+
+const SUPPORTED_FORMATS: &[&str] = &["elb", "alb", "squid", "s3", "nginx", "w3c", "json"];
+
+//The query kept in a file, - reads it from stdin. The lines are joined as the parser reads a single one,
+//and the semicolon ending a statement in a .sql file is dropped.
+pub(crate) fn read_query_file(path: &str) -> AppResult<String> {
+    let content = if path == "-" {
+        let mut content = String::new();
+        io::Read::read_to_string(&mut io::stdin(), &mut content)
+            .map_err(|e| AppError::QueryFile(path.to_string(), e))?;
+        content
+    } else {
+        fs::read_to_string(path).map_err(|e| AppError::QueryFile(path.to_string(), e))?
+    };
+
+    let query = content.lines().map(str::trim).collect::<Vec<_>>().join(" ");
+    Ok(query.trim().trim_end_matches(';').trim_end().to_string())
+}
+
 pub(crate) fn run(
     query_str: &str,
     data_source: common::types::DataSource,
     explain_mode: bool,
     output_mode: OutputMode,
+    options: &QueryOptions,
 ) -> AppResult<()> {
-    let (rest_of_str, select_stmt) = syntax::parser::select_query(&query_str)?;
+    execution::warnings::take();
+    run_query(query_str, data_source, explain_mode, output_mode, options)?;
+    report_warnings(execution::warnings::take(), options)
+}
+
+//The warnings of the query are printed together once it's done, or fail it under --warnings-as-errors
+fn report_warnings(warnings: execution::warnings::Warnings, options: &QueryOptions) -> AppResult<()> {
+    if warnings.is_empty() {
+        return Ok(());
+    }
+
+    if options.warnings_as_errors {
+        return Err(AppError::Warnings(warnings.to_string().trim_end().to_string()));
+    }
+    if !options.quiet {
+        eprint!("{}", warnings);
+    }
+
+    Ok(())
+}
+
+//Whether the query has any row at all, the scan stops at the first one. A group by or an order by still
+//reads the whole input before its first row.
+pub(crate) fn exists(
+    query_str: &str,
+    data_source: common::types::DataSource,
+    options: &QueryOptions,
+) -> AppResult<bool> {
+    execution::warnings::take();
+    let (physical_plan, variables, _) = plan_query(query_str, data_source, options)?;
+    let mut stream = physical_plan.get(variables)?;
+    let found = stream.next()?.is_some();
+    stream.close();
+    report_warnings(execution::warnings::take(), options)?;
+    Ok(found)
+}
+
+fn plan_query(
+    query_str: &str,
+    data_source: common::types::DataSource,
+    options: &QueryOptions,
+) -> AppResult<(
+    execution::types::Node,
+    common::types::Variables,
+    common::types::DataSource,
+)> {
+    let (rest_of_str, mut select_stmt) = syntax::parser::select_query(&query_str)?;
     if !rest_of_str.is_empty() {
-        return Err(AppError::InputNotAllConsumed(rest_of_str.to_string()));
+        return Err(AppError::Syntax(SyntaxError::InputNotAllConsumed(
+            rest_of_str.to_string(),
+        )));
+    }
+
+    //A VALUES list replaces the input of the query, and its alias is not a log format. It is taken out of
+    //the statement so the rows are only parsed here.
+    let data_source = match select_stmt.values_opt.take() {
+        Some(_) if data_source != common::types::DataSource::Stdin => {
+            return Err(AppError::Syntax(SyntaxError::ValuesWithLogFile));
+        }
+        Some(values) => logical::parser::parse_values(&values)?,
+        None => {
+            select_stmt.table_name = resolve_table_name(&select_stmt.table_name, &data_source, options)?;
+            data_source
+        }
+    };
+
+    let mut node = logical::parser::parse_query(select_stmt, data_source.clone())?;
+    if let Some(column_name) = &options.changed_by {
+        node = logical::types::Node::ChangedBy(column_name.clone(), Box::new(node));
     }
+    if let Some(column_names) = &options.order_by_hash {
+        node = logical::types::Node::HashOrder(column_names.clone(), Box::new(node));
+    }
+    if let Some(contract) = &options.expect_schema {
+        node = logical::types::Node::EnforceSchema(contract.clone(), options.check_every_row, Box::new(node));
+    }
+    if let Some(summary_str) = &options.summary {
+        let (rest_of_str, mut summary_stmt) = syntax::parser::select_query(summary_str)?;
+        if !rest_of_str.is_empty() {
+            return Err(AppError::Syntax(SyntaxError::InputNotAllConsumed(
+                rest_of_str.to_string(),
+            )));
+        }
+        if let Some(input_format) = &options.input_format {
+            summary_stmt.table_name = input_format.clone();
+        }
 
-    if !["elb", "alb", "squid", "s3"].contains(&&*select_stmt.table_name) {
-        return Err(AppError::InvalidLogFileFormat);
+        let summary_node = logical::parser::parse_query(summary_stmt, data_source.clone())?;
+        node = logical::types::Node::Tee(Box::new(node), Box::new(summary_node));
     }
+    let node = logical::optimizer::reorder_filters(node);
+    check_deterministic(&node, options)?;
 
-    let node = logical::parser::parse_query(select_stmt, data_source.clone())?;
-    let mut physical_plan_creator = logical::types::PhysicalPlanCreator::new(data_source);
+    let mut physical_plan_creator = logical::types::PhysicalPlanCreator::new(data_source.clone())
+        .with_raw_line(options.raw_line)
+        .with_source_column(options.source_column.clone())
+        .with_fields_directive(options.fields_directive.clone())
+        .with_partial(options.partial)
+        .with_skip_empty_aggregate(options.skip_empty_aggregate)
+        .with_signed_limit(options.signed_limit)
+        .with_nan_policy(options.nan_policy)
+        .with_number_format(options.number_format)
+        .with_flush_interval(options.flush_interval)
+        .with_flush_emit(options.flush_emit)
+        .with_skip_bad_lines(options.skip_bad_lines)
+        .with_multiline(options.multiline.clone())
+        .with_snapshot(options.snapshot.clone())
+        .with_max_memory(options.max_memory)
+        .with_seed(options.seed)
+        .with_json_fields(logical::parser::referenced_columns(&node))
+        .with_join_row_limit(
+            options
+                .cross_join_limit
+                .unwrap_or(execution::types::DEFAULT_JOIN_ROW_LIMIT),
+        );
     let (physical_plan, variables) = node.physical(&mut physical_plan_creator)?;
+    physical_plan_creator.check_snapshot()?;
+
+    Ok((*physical_plan, variables, data_source))
+}
+
+fn run_query(
+    query_str: &str,
+    data_source: common::types::DataSource,
+    explain_mode: bool,
+    output_mode: OutputMode,
+    options: &QueryOptions,
+) -> AppResult<()> {
+    let (physical_plan, variables, data_source) = plan_query(query_str, data_source, options)?;
 
     if explain_mode {
-        println!("Query Plan:");
-        println!("{:?}", physical_plan);
+        if let (true, common::types::DataSource::File(path)) = (options.explain_cost, &data_source) {
+            const COST_SAMPLE_BYTES: usize = 1 << 20;
+            let estimate = execution::cost::estimate_cost(&physical_plan, &variables, path, COST_SAMPLE_BYTES)?;
+            println!("Estimated Cost (extrapolated from the first MiB of the file, not measured):");
+            print!("{}", estimate);
+            return Ok(());
+        }
+
+        match options.explain_format {
+            ExplainFormat::Text => {
+                println!("Query Plan:");
+                println!("{:?}", physical_plan);
+            }
+            ExplainFormat::Json => {
+                println!("{}", physical_plan.to_json().pretty(2));
+            }
+        }
         Ok(())
     } else {
-        let mut stream = physical_plan.get(variables)?;
+        let get_stream = || -> AppResult<Box<dyn execution::stream::RecordStream>> {
+            let mut stream = physical_plan.get(variables.clone())?;
+            if let Some(column_names) = &options.columns {
+                stream = Box::new(execution::stream::SelectColumnsStream::new(
+                    column_names.clone(),
+                    stream,
+                ));
+            }
+            if options.stringify {
+                stream = Box::new(execution::stream::StringifyStream::new(stream));
+            }
+            Ok(stream)
+        };
 
-        match output_mode {
-            OutputMode::Table => {
-                let mut table = Table::new();
-                while let Some(record) = stream.next()? {
-                    table.add_row(Row::new(record.to_row()));
-                }
-                table.printstd();
+        //The columns come from the plan so the header is there without a row, an empty list leaves them
+        //to the first record
+        let header = if !options.no_header {
+            options
+                .columns
+                .clone()
+                .or_else(|| physical_plan.output_names())
+                .or_else(|| Some(Vec::new()))
+        } else {
+            None
+        };
+        let header = header.as_deref();
+
+        match (
+            &options.partition_by,
+            &options.partition_template,
+            &options.cache_dir,
+            &data_source,
+        ) {
+            (Some(column_name), Some(template), _, _) => {
+                write_partitioned(&mut *get_stream()?, output_mode, column_name, template, header)
             }
-            OutputMode::Csv => {
-                let mut wtr = Writer::from_writer(std::io::stdout());
-                while let Some(record) = stream.next()? {
-                    let csv_record = record.to_csv_record();
-                    wtr.write_record(csv_record)?;
-                }
+            //Only a complete scan of a file has a result worth keeping, stdin, named pipes and windowed output
+            //change as they go, and a snapshot moves on with every run
+            (_, _, Some(cache_dir), common::types::DataSource::File(path))
+                if options.flush_interval.is_none()
+                    && options.snapshot.is_none()
+                    && !execution::datasource::is_fifo(path) =>
+            {
+                let key = format!("{:?} {:?} {}", output_mode, options, query_str);
+                let run_query = || {
+                    let mut output = Vec::new();
+                    write_records(
+                        &mut *get_stream()?,
+                        output_mode,
+                        &options.record_layout,
+                        header,
+                        false,
+                        &mut output,
+                    )?;
+                    Ok(output)
+                };
+                let output = ResultCache::new(cache_dir.clone()).fetch_or_run(&key, path, run_query)?;
+                io::stdout().write_all(&output)?;
+                Ok(())
             }
-            OutputMode::Json => {
-                let mut data = json::JsonValue::new_array();
-                while let Some(record) = stream.next()? {
-                    let mut obj = json::JsonValue::new_object();
-                    for (key, val) in record.to_tuples() {
-                        match val {
-                            common::types::Value::Boolean(b) => {
-                                obj[key] = b.into();
-                            }
-                            common::types::Value::DateTime(dt) => {
-                                obj[key] = dt.to_string().into();
-                            }
-                            common::types::Value::Float(f) => {
-                                obj[key] = f.into_inner().into();
-                            }
-                            common::types::Value::Host(h) => {
-                                obj[key] = h.to_string().into();
-                            }
-                            common::types::Value::HttpRequest(h) => {
-                                obj[key] = h.to_string().into();
-                            }
-                            common::types::Value::Int(i) => {
-                                obj[key] = i.into();
-                            }
-                            common::types::Value::Null => {
-                                obj[key] = json::Null;
-                            }
-                            common::types::Value::String(s) => {
-                                obj[key] = s.into();
-                            }
-                        }
-                    }
+            //A flushed window is printed right away rather than once the followed source ends
+            _ => write_stream(
+                &mut *get_stream()?,
+                output_mode,
+                &options.record_layout,
+                header,
+                options.flush_interval.is_some(),
+            ),
+        }
+    }
+}
 
-                    data.push(obj)?;
+//The rendered output of earlier queries, one file per query and source file. An entry records the
+//modification time of the file it was computed from and is recomputed once the file changes. The
+//outputs are as private as the logs they come from, so the directory is only used when it belongs to
+//the user and nobody else can read it.
+pub(crate) struct ResultCache {
+    dir: PathBuf,
+}
+
+impl ResultCache {
+    pub(crate) fn new(dir: PathBuf) -> Self {
+        ResultCache { dir }
+    }
+
+    //$XDG_CACHE_HOME/logq, or ~/.cache/logq without it, none without a home either
+    pub(crate) fn user_cache_dir() -> Option<PathBuf> {
+        let cache_home = std::env::var_os("XDG_CACHE_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+        Some(cache_home.join("logq"))
+    }
+
+    pub(crate) fn fetch_or_run<F>(&self, key: &str, path: &Path, run: F) -> AppResult<Vec<u8>>
+    where
+        F: FnOnce() -> AppResult<Vec<u8>>,
+    {
+        let modified = fs::metadata(path)?.modified()?;
+        let mtime = match modified.duration_since(std::time::UNIX_EPOCH) {
+            Ok(duration) => duration.as_nanos().to_string(),
+            Err(_) => String::new(),
+        };
+
+        let mut header = json::JsonValue::new_object();
+        header["key"] = key.into();
+        header["path"] = path.to_string_lossy().to_string().into();
+        header["mtime"] = mtime.into();
+        let header = header.dump();
+
+        //Without a private directory the query runs uncached
+        if !is_private_dir(&self.dir) {
+            return run();
+        }
+
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        std::hash::Hash::hash(&(key, path), &mut hasher);
+        let entry_path = self.dir.join(format!("{:016x}", std::hash::Hasher::finish(&hasher)));
+
+        //The header repeats the whole key, so a hash collision or a stale mtime is a miss
+        if let Ok(entry) = fs::read(&entry_path) {
+            if let Some(newline) = entry.iter().position(|b| *b == b'\n') {
+                if entry[..newline] == *header.as_bytes() {
+                    return Ok(entry[newline + 1..].to_vec());
                 }
-                let s = data.dump();
-                println!("{}", s);
             }
         }
 
-        Ok(())
+        let output = run()?;
+
+        //The cache is best effort, failing to write it doesn't fail the query. The entry is written next to
+        //its place and renamed into it, so a concurrent run reads either the whole entry or none.
+        let mut entry = header.into_bytes();
+        entry.push(b'\n');
+        entry.extend_from_slice(&output);
+        let _ = tempfile::NamedTempFile::new_in(&self.dir).and_then(|mut file| {
+            file.write_all(&entry)?;
+            file.persist(&entry_path).map_err(|e| e.error)?;
+            Ok(())
+        });
+
+        Ok(output)
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::fs::File;
-    use std::io::Write;
-    use tempfile::tempdir;
+//Creates the directory readable by the user alone, and checks an existing one is owned by the user
+//and closed to everybody else
+#[cfg(unix)]
+fn is_private_dir(dir: &Path) -> bool {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
 
-    #[test]
-    fn test_run_explain_mode() {
-        let query_str = "select * from squid";
-        let dir = tempdir().unwrap();
-        let file_path = dir.path().join("log_for_test.log");
-        let mut file = File::create(file_path.clone()).unwrap();
-        writeln!(file, r#"1515734740.494      1 [MASKEDIPADDRESS] TCP_DENIED/407 3922 CONNECT d.dropbox.com:443 - HIER_NONE/- text/html"#).unwrap();
-        file.sync_all().unwrap();
-        drop(file);
+    let _ = fs::DirBuilder::new().recursive(true).mode(0o700).create(dir);
+    match fs::symlink_metadata(dir) {
+        Ok(metadata) => {
+            metadata.is_dir() && metadata.uid() == unsafe { libc::geteuid() } && metadata.mode() & 0o077 == 0
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_private_dir(dir: &Path) -> bool {
+    fs::create_dir_all(dir).is_ok()
+}
 
-        let data_source = common::types::DataSource::File(file_path);
-        let result = run(&*query_str, data_source, true, OutputMode::Csv);
+//Warn about the parts of a query that may give different results between runs, or refuse to run it
+fn check_deterministic(node: &logical::types::Node, options: &QueryOptions) -> AppResult<()> {
+    let mut found = logical::analyzer::find_nondeterminism(node);
+    //A seed given on the command line pins the estimate of approx_count_distinct
+    if options.seed.is_some() {
+        found.retain(|nondeterminism| {
+            *nondeterminism
+                != logical::analyzer::Nondeterminism::ApproximateAggregate("approx_count_distinct".to_string())
+        });
+    }
+    if options.strict_deterministic && !found.is_empty() {
+        let reasons: Vec<String> = found.iter().map(|n| n.to_string()).collect();
+        return Err(AppError::Plan(PlanError::Nondeterministic(reasons.join("; "))));
+    }
 
-        assert_eq!(result, Ok(()));
+    for nondeterminism in found.iter() {
+        execution::warnings::warn(execution::warnings::WarningKind::Nondeterministic, || {
+            nondeterminism.to_string()
+        });
+    }
 
-        dir.close().unwrap();
+    Ok(())
+}
+
+//An --input-format overrides the table of the query without sniffing, otherwise "auto" is detected from the file
+fn resolve_table_name(
+    table_name: &str,
+    data_source: &common::types::DataSource,
+    options: &QueryOptions,
+) -> AppResult<String> {
+    if let Some(input_format) = &options.input_format {
+        if !SUPPORTED_FORMATS.contains(&input_format.as_str()) {
+            return Err(AppError::Syntax(SyntaxError::UnknownInputFormat(
+                input_format.clone(),
+                SUPPORTED_FORMATS.join(", "),
+            )));
+        }
+
+        return Ok(input_format.clone());
     }
 
-    #[test]
-    fn test_run_real_mode() {
-        let dir = tempdir().unwrap();
-        let file_path = dir.path().join("log_for_test.log");
-        let mut file = File::create(file_path.clone()).unwrap();
-        writeln!(file, r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 42355 "GET https://example.com:443/ HTTP/1.1" "Mozilla/5.0 (Windows NT 5.1) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/46.0.2490.80 Safari/537.36" ECDHE-RSA-AES128-GCM-SHA256 TLSv1.2"#).unwrap();
-        file.sync_all().unwrap();
-        drop(file);
+    if table_name == "auto" {
+        let table_name = detect_table_name(data_source)?;
+        if !options.quiet {
+            eprintln!("Detected log format: {}", table_name);
+        }
+        return Ok(table_name);
+    }
+
+    if !SUPPORTED_FORMATS.contains(&table_name) {
+        return Err(AppError::Syntax(SyntaxError::InvalidLogFileFormat));
+    }
+
+    Ok(table_name.to_string())
+}
+
+//The "auto" table sniffs the format from the first lines, which can only be read twice from a file. A
+//named pipe, like stdin, would hand the sampled lines to the sniffing alone.
+fn detect_table_name(data_source: &common::types::DataSource) -> AppResult<String> {
+    const SAMPLE_SIZE: usize = 5;
+    match data_source {
+        common::types::DataSource::File(path) if execution::datasource::is_fifo(path) => {
+            Err(AppError::Syntax(SyntaxError::Invalid(
+                "The log format can't be detected from a named pipe, please specify the table".to_string(),
+            )))
+        }
+        common::types::DataSource::File(path) => {
+            let file = fs::File::open(path)?;
+            let table_name = if path.extension() == Some(std::ffi::OsStr::new("gz")) {
+                execution::datasource::detect_format(io::BufReader::new(MultiGzDecoder::new(file)), SAMPLE_SIZE)?
+            } else {
+                execution::datasource::detect_format(io::BufReader::new(file), SAMPLE_SIZE)?
+            };
+            Ok(table_name)
+        }
+        //Merged files are all of the same format, the first one tells it
+        common::types::DataSource::SortedFiles(paths, _) => match paths.first() {
+            Some(path) => detect_table_name(&common::types::DataSource::File(path.clone())),
+            None => Err(AppError::Syntax(SyntaxError::Invalid(
+                "The log format can't be detected without a file".to_string(),
+            ))),
+        },
+        common::types::DataSource::Stdin => Err(AppError::Syntax(SyntaxError::Invalid(
+            "The log format can't be detected from stdin, please specify the table".to_string(),
+        ))),
+        common::types::DataSource::Values(_, _) => Err(AppError::Syntax(SyntaxError::Invalid(
+            "The log format can't be detected from a VALUES list".to_string(),
+        ))),
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct BenchReport {
+    pub(crate) records: usize,
+    pub(crate) bytes: u64,
+    pub(crate) durations: Vec<Duration>,
+}
+
+impl BenchReport {
+    pub(crate) fn best(&self) -> Duration {
+        self.durations.iter().min().cloned().unwrap_or_default()
+    }
+
+    pub(crate) fn median(&self) -> Duration {
+        let mut durations = self.durations.clone();
+        durations.sort();
+        durations.get(durations.len() / 2).cloned().unwrap_or_default()
+    }
+
+    //Records and megabytes per second over the given run time
+    pub(crate) fn throughput(&self, duration: Duration) -> (f64, f64) {
+        let seconds = duration.as_secs_f64().max(f64::EPSILON);
+        (
+            self.records as f64 / seconds,
+            self.bytes as f64 / (1024.0 * 1024.0) / seconds,
+        )
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} records, {} bytes, {} iterations",
+            self.records,
+            self.bytes,
+            self.durations.len()
+        )?;
+        for (label, duration) in [("best", self.best()), ("median", self.median())].iter() {
+            let (records_per_sec, mb_per_sec) = self.throughput(*duration);
+            writeln!(
+                f,
+                "{}: {:?}, {:.0} records/sec, {:.2} MB/sec",
+                label, duration, records_per_sec, mb_per_sec
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+//Time the reader and parser alone: every record of the file is parsed and dropped, no query runs
+pub(crate) fn bench(path: &Path, table_name: Option<&str>, iterations: usize) -> AppResult<BenchReport> {
+    let data_source = common::types::DataSource::File(path.to_path_buf());
+    let table_name = match table_name {
+        Some(table_name) => table_name.to_string(),
+        None => detect_table_name(&data_source)?,
+    };
+    let node = execution::types::Node::DataSource(data_source, execution::datasource::ReaderBuilder::new(table_name));
+
+    let bytes = fs::metadata(path)?.len();
+    let mut records = 0;
+    let mut durations = Vec::new();
+    for _ in 0..iterations.max(1) {
+        let started = Instant::now();
+        let mut stream = node.get(common::types::Variables::default())?;
+        records = 0;
+        while stream.next()?.is_some() {
+            records += 1;
+        }
+        durations.push(started.elapsed());
+    }
+
+    Ok(BenchReport {
+        records,
+        bytes,
+        durations,
+    })
+}
+
+//Merge the partial aggregate records emitted by "--partial --output json" runs into the final results
+pub(crate) fn merge(paths: &[PathBuf], output_mode: OutputMode) -> AppResult<()> {
+    let records = merge_partial_records(paths)?;
+    let mut stream = execution::stream::InMemoryStream::new(records);
+    write_stream(&mut stream, output_mode, &RecordLayout::default(), None, false)
+}
+
+fn merge_partial_records(paths: &[PathBuf]) -> AppResult<VecDeque<execution::stream::Record>> {
+    let mut key_fields: Vec<String> = Vec::new();
+    let mut aggregates: Vec<(String, execution::types::Aggregate)> = Vec::new();
+    let mut groups: Vec<Option<common::types::Tuple>> = Vec::new();
+    let mut seen_groups: HashSet<Option<common::types::Tuple>> = HashSet::new();
+    let mut initialized = false;
+
+    for path in paths.iter() {
+        let content = fs::read_to_string(path)?;
+        let data = json::parse(&content).map_err(MergeError::Json)?;
+        if !data.is_array() {
+            return Err(AppError::Merge(MergeError::InvalidRecord));
+        }
+
+        for obj in data.members() {
+            if !obj.is_object() {
+                return Err(AppError::Merge(MergeError::InvalidRecord));
+            }
+
+            //The record lists its aggregate columns, every other column is a group by key
+            if !initialized {
+                let aggregate_fields = &obj[execution::types::PARTIAL_AGGREGATES_COLUMN];
+                if !aggregate_fields.is_array() {
+                    return Err(AppError::Merge(MergeError::InvalidRecord));
+                }
+                for field in aggregate_fields.members() {
+                    let field = field.as_str().ok_or(MergeError::InvalidRecord)?;
+                    let s = obj[field].as_str().ok_or(MergeError::InvalidRecord)?;
+                    let state = execution::types::PartialState::from_str(s).map_err(MergeError::Aggregate)?;
+                    aggregates.push((field.to_string(), new_merge_aggregate(&state)));
+                }
+                for (field, _) in obj.entries() {
+                    let is_aggregate = aggregates.iter().any(|(name, _)| name == field);
+                    if !is_aggregate && field != execution::types::PARTIAL_AGGREGATES_COLUMN {
+                        key_fields.push(field.to_string());
+                    }
+                }
+                initialized = true;
+            }
+
+            let key = if key_fields.is_empty() {
+                None
+            } else {
+                let mut values = Vec::new();
+                for field in key_fields.iter() {
+                    values.push(json_to_value(&obj[field.as_str()]));
+                }
+                Some(values)
+            };
 
-        let data_source = common::types::DataSource::File(file_path);
+            for (field, aggregate) in aggregates.iter_mut() {
+                let s = obj[field.as_str()].as_str().ok_or(MergeError::InvalidRecord)?;
+                let state = execution::types::PartialState::from_str(s).map_err(MergeError::Aggregate)?;
+                aggregate
+                    .merge_partial(key.clone(), state)
+                    .map_err(MergeError::Aggregate)?;
+            }
+
+            if seen_groups.insert(key.clone()) {
+                groups.push(key);
+            }
+        }
+    }
+
+    let mut records = VecDeque::new();
+    for key in groups.into_iter() {
+        let mut fields = key_fields.clone();
+        let mut values = key.clone().unwrap_or_default();
+        for (field, aggregate) in aggregates.iter_mut() {
+            fields.push(field.clone());
+            values.push(aggregate.get_aggregated(&key).map_err(MergeError::Aggregate)?);
+        }
+        records.push_back(execution::stream::Record::new(fields, values));
+    }
+
+    Ok(records)
+}
+
+fn new_merge_aggregate(state: &execution::types::PartialState) -> execution::types::Aggregate {
+    let named = execution::types::Named::Star;
+    match state {
+        execution::types::PartialState::Sum(_) => {
+            execution::types::Aggregate::Sum(execution::types::SumAggregate::new(), named)
+        }
+        execution::types::PartialState::Count(_) => {
+            execution::types::Aggregate::Count(execution::types::CountAggregate::new(), named)
+        }
+        execution::types::PartialState::Avg(_, _) => {
+            execution::types::Aggregate::Avg(execution::types::AvgAggregate::new(), named)
+        }
+    }
+}
+
+fn json_to_value(val: &json::JsonValue) -> common::types::Value {
+    if let Some(s) = val.as_str() {
+        common::types::Value::String(s.to_string())
+    } else if let Some(b) = val.as_bool() {
+        common::types::Value::Boolean(b)
+    } else if let Some(i) = val.as_i32() {
+        common::types::Value::Int(i)
+    } else if let Some(f) = val.as_f32() {
+        common::types::Value::Float(ordered_float::OrderedFloat::from(f))
+    } else {
+        common::types::Value::Null
+    }
+}
+
+fn record_to_json(record: &execution::stream::Record) -> json::JsonValue {
+    let mut obj = json::JsonValue::new_object();
+    for (key, val) in record.to_tuples() {
+        obj[key] = value_to_json(val);
+    }
+
+    obj
+}
+
+fn value_to_json(val: common::types::Value) -> json::JsonValue {
+    match val {
+        common::types::Value::Boolean(b) => b.into(),
+        common::types::Value::DateTime(dt) => dt.to_string().into(),
+        common::types::Value::Float(f) => f.into_inner().into(),
+        common::types::Value::Host(h) => h.to_string().into(),
+        common::types::Value::HttpRequest(h) => h.to_string().into(),
+        common::types::Value::Int(i) => i.into(),
+        common::types::Value::Null => json::Null,
+        common::types::Value::String(s) => s.into(),
+        common::types::Value::Array(values) => json::JsonValue::Array(values.into_iter().map(value_to_json).collect()),
+    }
+}
+
+//The csv and prometheus partitions are written as their records come, with at most this many of their files
+//open at once. The others are built in memory and written out at the end.
+const MAX_OPEN_PARTITIONS: usize = 64;
+
+enum PartitionWriter {
+    Table(Table),
+    Csv(Option<Box<Writer<fs::File>>>),
+    Json(json::JsonValue),
+    Prometheus(PrometheusMetric, Option<fs::File>),
+}
+
+impl PartitionWriter {
+    fn new(output_mode: &OutputMode) -> PartitionWriter {
+        match output_mode {
+            OutputMode::Table => PartitionWriter::Table(Table::new()),
+            OutputMode::Csv => PartitionWriter::Csv(None),
+            OutputMode::Json => PartitionWriter::Json(json::JsonValue::new_array()),
+            OutputMode::Prometheus(metric) => PartitionWriter::Prometheus(metric.clone(), None),
+        }
+    }
+
+    fn needs_file(&self) -> bool {
+        match self {
+            PartitionWriter::Csv(None) | PartitionWriter::Prometheus(_, None) => true,
+            _ => false,
+        }
+    }
+
+    //A partition file is created on its first record, and appended to when it is opened again
+    fn open(&mut self, path: &str, first: bool) -> AppResult<()> {
+        let file = if first {
+            fs::File::create(path)?
+        } else {
+            fs::OpenOptions::new().append(true).open(path)?
+        };
+        match self {
+            PartitionWriter::Csv(wtr) => *wtr = Some(Box::new(Writer::from_writer(file))),
+            PartitionWriter::Prometheus(_, opened) => *opened = Some(file),
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn close(&mut self) -> AppResult<()> {
+        match self {
+            PartitionWriter::Csv(wtr) => {
+                if let Some(mut wtr) = wtr.take() {
+                    wtr.flush()?;
+                }
+            }
+            PartitionWriter::Prometheus(_, file) => {
+                if let Some(mut file) = file.take() {
+                    file.flush()?;
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    //The header is the one of the whole output, written at the top of every csv partition
+    fn write(
+        &mut self,
+        record: &execution::stream::Record,
+        first: bool,
+        header: Option<&[common::types::VariableName]>,
+    ) -> AppResult<()> {
+        match self {
+            PartitionWriter::Table(table) => {
+                table.add_row(Row::new(record.to_row()));
+            }
+            PartitionWriter::Csv(Some(wtr)) => {
+                if first {
+                    match header {
+                        Some(field_names) if !field_names.is_empty() => wtr.write_record(field_names)?,
+                        Some(_) => wtr.write_record(record.field_names())?,
+                        None => {}
+                    }
+                }
+                wtr.write_record(record.to_csv_record())?;
+            }
+            PartitionWriter::Json(data) => {
+                data.push(record_to_json(record))?;
+            }
+            PartitionWriter::Prometheus(metric, Some(file)) => {
+                writeln!(file, "{}", metric.render(record)?)?;
+            }
+            PartitionWriter::Csv(None) | PartitionWriter::Prometheus(_, None) => {
+                unreachable!("the partition file is opened before its records are written")
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(mut self, path: &str) -> AppResult<()> {
+        match &mut self {
+            PartitionWriter::Table(table) => {
+                table.print(&mut fs::File::create(path)?)?;
+            }
+            PartitionWriter::Json(data) => {
+                writeln!(fs::File::create(path)?, "{}", data.dump())?;
+            }
+            _ => {}
+        }
+
+        self.close()
+    }
+}
+
+//The file name part of a partition value, a null value goes to the "__null__" partition
+fn partition_key(value: &common::types::Value) -> String {
+    let key = match value {
+        common::types::Value::Null => "__null__".to_string(),
+        value => common::types::value_text(value),
+    };
+
+    //Keep every partition next to each other whatever the value holds
+    key.replace(&['/', '\\'][..], "_")
+}
+
+//Route each record to the file named by substituting its partition value for "{key}" in the template,
+//every file is created on its first record
+fn write_partitioned(
+    stream: &mut dyn execution::stream::RecordStream,
+    output_mode: OutputMode,
+    column_name: &str,
+    template: &str,
+    header: Option<&[common::types::VariableName]>,
+) -> AppResult<()> {
+    let mut writers: HashMap<String, PartitionWriter> = HashMap::new();
+    //The partitions with an open file, the first opened is closed first
+    let mut opened: VecDeque<String> = VecDeque::new();
+    while let Some(record) = stream.next()? {
+        let variables = record.to_variables();
+        let value = variables
+            .get(column_name)
+            .ok_or_else(|| execution::types::StreamError::UnknownColumn(column_name.to_string()))?;
+        let path = template.replace("{key}", &partition_key(value));
+
+        let first = !writers.contains_key(&path);
+        if first {
+            writers.insert(path.clone(), PartitionWriter::new(&output_mode));
+        }
+        if writers[&path].needs_file() {
+            if opened.len() == MAX_OPEN_PARTITIONS {
+                let oldest = opened.pop_front().unwrap();
+                writers.get_mut(&oldest).unwrap().close()?;
+            }
+            writers.get_mut(&path).unwrap().open(&path, first)?;
+            opened.push_back(path.clone());
+        }
+        writers.get_mut(&path).unwrap().write(&record, first, header)?;
+    }
+
+    for (path, writer) in writers.into_iter() {
+        writer.finish(&path)?;
+    }
+
+    Ok(())
+}
+
+fn write_stream(
+    stream: &mut dyn execution::stream::RecordStream,
+    output_mode: OutputMode,
+    layout: &RecordLayout,
+    header: Option<&[common::types::VariableName]>,
+    live: bool,
+) -> AppResult<()> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    write_records(stream, output_mode, layout, header, live, &mut out)
+}
+
+//A csv line without its newline
+fn csv_line<I, T>(fields: I) -> AppResult<Vec<u8>>
+where
+    I: IntoIterator<Item = T>,
+    T: AsRef<[u8]>,
+{
+    let mut line = Vec::new();
+    {
+        let mut wtr = Writer::from_writer(&mut line);
+        wtr.write_record(fields)?;
+        wtr.flush()?;
+    }
+    if line.ends_with(b"\n") {
+        line.pop();
+    }
+
+    Ok(line)
+}
+
+//The header only applies to the csv output, the other formats name the columns on their own or not at all.
+//Live output, for a source that is followed, is flushed after every record.
+fn write_records<W: Write>(
+    stream: &mut dyn execution::stream::RecordStream,
+    output_mode: OutputMode,
+    layout: &RecordLayout,
+    header: Option<&[common::types::VariableName]>,
+    live: bool,
+    out: &mut W,
+) -> AppResult<()> {
+    match output_mode {
+        //The columns of a table are sized from all of its rows, so live each record is a table of its own
+        OutputMode::Table if live => {
+            while let Some(record) = stream.next()? {
+                Table::init(vec![Row::new(record.to_row())]).print(out)?;
+                out.flush()?;
+            }
+        }
+        OutputMode::Table => {
+            let mut table = Table::new();
+            while let Some(record) = stream.next()? {
+                table.add_row(Row::new(record.to_row()));
+            }
+
+            let mut rendered = Vec::new();
+            table.print(&mut rendered)?;
+            if !layout.trailing_newline && rendered.ends_with(b"\n") {
+                rendered.pop();
+            }
+            out.write_all(&rendered)?;
+        }
+        OutputMode::Csv => {
+            //Each line is rendered on its own so the separator and the last newline can be controlled. A line
+            //is written with its newline, only without a trailing newline is it held back until the next line.
+            let write_line = |out: &mut W, line: &[u8], open: &mut bool| -> io::Result<()> {
+                if *open {
+                    out.write_all(b"\n")?;
+                }
+                out.write_all(line)?;
+                if layout.trailing_newline {
+                    out.write_all(b"\n")?;
+                }
+                *open = !layout.trailing_newline;
+                Ok(())
+            };
+
+            let mut open = false;
+            let mut header_from_record = false;
+            match header {
+                Some(field_names) if !field_names.is_empty() => write_line(out, &csv_line(field_names)?, &mut open)?,
+                Some(_) => header_from_record = true,
+                None => {}
+            }
+
+            //The separator goes between two records, not after the header
+            let write_record =
+                |out: &mut W, record: &execution::stream::Record, first: bool, open: &mut bool| -> AppResult<()> {
+                    let mut line = if first {
+                        Vec::new()
+                    } else {
+                        layout.separator.as_bytes().to_vec()
+                    };
+                    line.extend(csv_line(record.to_csv_record())?);
+                    write_line(out, &line, open)?;
+                    if live {
+                        out.flush()?;
+                    }
+                    Ok(())
+                };
+
+            //A record of a json log has the keys seen so far, so the header is the columns of all the records
+            //and a record is null in the ones it doesn't have. Live output can't wait, the first record heads it.
+            if header_from_record && !live {
+                let mut records = Vec::new();
+                let mut field_names: Vec<common::types::VariableName> = Vec::new();
+                while let Some(record) = stream.next()? {
+                    for field_name in record.field_names().iter() {
+                        if !field_names.contains(field_name) {
+                            field_names.push(field_name.clone());
+                        }
+                    }
+                    records.push(record);
+                }
+                if records.is_empty() {
+                    return Ok(());
+                }
+
+                write_line(out, &csv_line(&field_names)?, &mut open)?;
+                for (i, record) in records.iter().enumerate() {
+                    let variables = record.to_variables();
+                    let values = field_names
+                        .iter()
+                        .map(|field_name| variables.get(field_name).cloned().unwrap_or(common::types::Value::Null))
+                        .collect();
+                    let record = execution::stream::Record::new(field_names.clone(), values);
+                    write_record(out, &record, i == 0, &mut open)?;
+                }
+                return Ok(());
+            }
+
+            let mut first = true;
+            while let Some(record) = stream.next()? {
+                if header_from_record {
+                    write_line(out, &csv_line(record.field_names())?, &mut open)?;
+                    header_from_record = false;
+                }
+
+                write_record(out, &record, first, &mut open)?;
+                first = false;
+            }
+        }
+        OutputMode::Json => {
+            let separator = format!(",{}", layout.separator);
+            out.write_all(b"[")?;
+            let mut first = true;
+            while let Some(record) = stream.next()? {
+                if !first {
+                    out.write_all(separator.as_bytes())?;
+                }
+                out.write_all(record_to_json(&record).dump().as_bytes())?;
+                if live {
+                    out.flush()?;
+                }
+                first = false;
+            }
+
+            out.write_all(b"]")?;
+            if layout.trailing_newline {
+                out.write_all(b"\n")?;
+            }
+        }
+        //The exposition format is a line per sample, the layout doesn't apply
+        OutputMode::Prometheus(metric) => {
+            while let Some(record) = stream.next()? {
+                writeln!(out, "{}", metric.render(&record)?)?;
+                if live {
+                    out.flush()?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::fs::File;
+    use std::io::Write;
+    use std::rc::Rc;
+    use tempfile::tempdir;
+
+    fn records_for_layout() -> execution::stream::InMemoryStream {
+        let mut records = VecDeque::new();
+        for (host, count) in [("a.com", 1), ("b.com", 2)].iter() {
+            records.push_back(execution::stream::Record::new(
+                vec!["host".to_string(), "count".to_string()],
+                vec![
+                    common::types::Value::String(host.to_string()),
+                    common::types::Value::Int(*count),
+                ],
+            ));
+        }
+        execution::stream::InMemoryStream::new(records)
+    }
+
+    //Writes the lines to a log in a temporary directory, which goes away with the directory
+    fn elb_log<S: AsRef<str>>(lines: &[S]) -> (tempfile::TempDir, common::types::DataSource) {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("log_for_test.log");
+        let mut file = File::create(file_path.clone()).unwrap();
+        for line in lines.iter() {
+            writeln!(file, "{}", line.as_ref()).unwrap();
+        }
+        file.sync_all().unwrap();
+        (dir, common::types::DataSource::File(file_path))
+    }
+
+    //Runs the query over a log made of the lines and collects the records it outputs
+    fn query_elb_lines<S: AsRef<str>>(lines: &[S], query_str: &str) -> Vec<execution::stream::Record> {
+        let (dir, data_source) = elb_log(lines);
+        let (physical_plan, variables, _) = plan_query(query_str, data_source, &QueryOptions::default()).unwrap();
+        let mut stream = physical_plan.get(variables).unwrap();
+
+        let mut records = Vec::new();
+        while let Some(record) = stream.next().unwrap() {
+            records.push(record);
+        }
+        dir.close().unwrap();
+        records
+    }
+
+    #[test]
+    fn test_write_records_with_layout() {
+        let default_layout = RecordLayout::default();
+        let blank_line = RecordLayout {
+            separator: unescape_separator("\\n"),
+            trailing_newline: true,
+        };
+        let no_trailing_newline = RecordLayout {
+            separator: String::new(),
+            trailing_newline: false,
+        };
+
+        let cases = vec![
+            (OutputMode::Csv, &default_layout, "a.com,1\nb.com,2\n"),
+            (OutputMode::Csv, &blank_line, "a.com,1\n\nb.com,2\n"),
+            (OutputMode::Csv, &no_trailing_newline, "a.com,1\nb.com,2"),
+            (
+                OutputMode::Json,
+                &default_layout,
+                "[{\"host\":\"a.com\",\"count\":1},{\"host\":\"b.com\",\"count\":2}]\n",
+            ),
+            (
+                OutputMode::Json,
+                &blank_line,
+                "[{\"host\":\"a.com\",\"count\":1},\n{\"host\":\"b.com\",\"count\":2}]\n",
+            ),
+            (
+                OutputMode::Json,
+                &no_trailing_newline,
+                "[{\"host\":\"a.com\",\"count\":1},{\"host\":\"b.com\",\"count\":2}]",
+            ),
+        ];
+
+        for (output_mode, layout, expected) in cases {
+            let mut out = Vec::new();
+            write_records(&mut records_for_layout(), output_mode, layout, None, false, &mut out).unwrap();
+            assert_eq!(expected, String::from_utf8(out).unwrap());
+        }
+    }
+
+    //Keeps what was written until it is flushed, the way stdout holds back a line without its end
+    struct FlushedWriter {
+        pending: Vec<u8>,
+        flushed: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Write for FlushedWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.pending.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushed.borrow_mut().append(&mut self.pending);
+            Ok(())
+        }
+    }
+
+    //Hands out its records, then stands for a followed source with nothing new to read. It notes what
+    //had been flushed by then and ends the stream, as the source would otherwise block there.
+    struct BlockingStream {
+        records: execution::stream::InMemoryStream,
+        flushed: Rc<RefCell<Vec<u8>>>,
+        flushed_when_blocked: Rc<RefCell<Option<String>>>,
+    }
+
+    impl execution::stream::RecordStream for BlockingStream {
+        fn next(&mut self) -> execution::types::StreamResult<Option<execution::stream::Record>> {
+            let record = self.records.next()?;
+            if record.is_none() {
+                let flushed = String::from_utf8(self.flushed.borrow().clone()).unwrap();
+                *self.flushed_when_blocked.borrow_mut() = Some(flushed);
+            }
+            Ok(record)
+        }
+
+        fn close(&self) {}
+    }
+
+    #[test]
+    fn test_write_live_records() {
+        let flushed_when_blocked = |output_mode: OutputMode| {
+            let flushed = Rc::new(RefCell::new(Vec::new()));
+            let flushed_when_blocked = Rc::new(RefCell::new(None));
+            let mut stream = BlockingStream {
+                records: records_for_layout(),
+                flushed: flushed.clone(),
+                flushed_when_blocked: flushed_when_blocked.clone(),
+            };
+            let mut out = FlushedWriter {
+                pending: Vec::new(),
+                flushed,
+            };
+            write_records(&mut stream, output_mode, &RecordLayout::default(), None, true, &mut out).unwrap();
+            let blocked = flushed_when_blocked.borrow_mut().take();
+            blocked.unwrap()
+        };
+
+        assert_eq!("a.com,1\nb.com,2\n", flushed_when_blocked(OutputMode::Csv));
+        assert_eq!(
+            "[{\"host\":\"a.com\",\"count\":1},{\"host\":\"b.com\",\"count\":2}",
+            flushed_when_blocked(OutputMode::Json)
+        );
+        let metric = PrometheusMetric::new("logq_requests_total", None).unwrap();
+        assert_eq!(
+            "logq_requests_total{host=\"a.com\"} 1\nlogq_requests_total{host=\"b.com\"} 2\n",
+            flushed_when_blocked(OutputMode::Prometheus(metric))
+        );
+        let table = flushed_when_blocked(OutputMode::Table);
+        assert!(table.contains("a.com") && table.contains("b.com"));
+    }
+
+    #[test]
+    fn test_csv_header() {
+        let (dir, data_source) = elb_log(&[
+            r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 512 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#,
+        ]);
+
+        let write = |query_str: &str| {
+            let (physical_plan, variables, _) =
+                plan_query(query_str, data_source.clone(), &QueryOptions::default()).unwrap();
+            let header = physical_plan.output_names().unwrap();
+            let mut out = Vec::new();
+            write_records(
+                &mut *physical_plan.get(variables).unwrap(),
+                OutputMode::Csv,
+                &RecordLayout::default(),
+                Some(header.as_slice()),
+                false,
+                &mut out,
+            )
+            .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        //No row matches, the header is still written
+        assert_eq!(
+            "h,n\n",
+            write("select host_name(backend_and_port) as h, count(*) as n from elb where sent_bytes > 1000 group by h")
+        );
+        assert_eq!(
+            "h,n\n10.0.0.215,1\n",
+            write("select host_name(backend_and_port) as h, count(*) as n from elb group by h")
+        );
+        assert_eq!(
+            "elb_status_code,sent_bytes\n",
+            write("select elb_status_code, sent_bytes from elb where sent_bytes > 1000")
+        );
+
+        //A plan that doesn't know its columns takes them from its records
+        let blank_line = RecordLayout {
+            separator: unescape_separator("\\n"),
+            trailing_newline: true,
+        };
+        let mut out = Vec::new();
+        write_records(
+            &mut records_for_layout(),
+            OutputMode::Csv,
+            &blank_line,
+            Some(&[][..]),
+            false,
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!("host,count\na.com,1\n\nb.com,2\n", String::from_utf8(out).unwrap());
+
+        //The header has the columns of every record, not only the first one's
+        let mut records = VecDeque::new();
+        records.push_back(execution::stream::Record::new(
+            vec!["a".to_string()],
+            vec![common::types::Value::Int(1)],
+        ));
+        records.push_back(execution::stream::Record::new(
+            vec!["a".to_string(), "b".to_string()],
+            vec![
+                common::types::Value::Int(2),
+                common::types::Value::String("x".to_string()),
+            ],
+        ));
+        let mut out = Vec::new();
+        write_records(
+            &mut execution::stream::InMemoryStream::new(records),
+            OutputMode::Csv,
+            &RecordLayout::default(),
+            Some(&[][..]),
+            false,
+            &mut out,
+        )
+        .unwrap();
+        assert_eq!("a,b\n1,<null>\n2,x\n", String::from_utf8(out).unwrap());
+
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_write_records_as_prometheus() {
+        let write = |stream: &mut dyn execution::stream::RecordStream, metric: PrometheusMetric| {
+            let mut out = Vec::new();
+            write_records(
+                stream,
+                OutputMode::Prometheus(metric),
+                &RecordLayout::default(),
+                None,
+                false,
+                &mut out,
+            )
+            .map(|_| String::from_utf8(out).unwrap())
+        };
+
+        let metric = PrometheusMetric::new("logq_requests_total", None).unwrap();
+        assert_eq!(
+            "logq_requests_total{host=\"a.com\"} 1\nlogq_requests_total{host=\"b.com\"} 2\n",
+            write(&mut records_for_layout(), metric).unwrap()
+        );
+
+        //The host isn't a number to sample
+        let metric = PrometheusMetric::new("logq_requests_total", Some("host".to_string())).unwrap();
+        assert!(write(&mut records_for_layout(), metric).is_err());
+
+        let mut records = VecDeque::new();
+        records.push_back(execution::stream::Record::new(
+            vec!["path".to_string(), "latency".to_string()],
+            vec![
+                common::types::Value::String("/a\"b\\c".to_string()),
+                common::types::Value::Null,
+            ],
+        ));
+        let metric = PrometheusMetric::new("latency:avg", None).unwrap();
+        assert_eq!(
+            "latency:avg{path=\"/a\\\"b\\\\c\"} NaN\n",
+            write(&mut execution::stream::InMemoryStream::new(records), metric).unwrap()
+        );
+
+        //Label names are made valid and infinities are spelled as Prometheus does
+        let mut records = VecDeque::new();
+        records.push_back(execution::stream::Record::new(
+            vec!["status code".to_string(), "5xx".to_string(), "ratio".to_string()],
+            vec![
+                common::types::Value::Int(200),
+                common::types::Value::Int(0),
+                common::types::Value::Float(ordered_float::OrderedFloat::from(std::f32::INFINITY)),
+            ],
+        ));
+        records.push_back(execution::stream::Record::new(
+            vec!["status code".to_string(), "5xx".to_string(), "ratio".to_string()],
+            vec![
+                common::types::Value::Int(500),
+                common::types::Value::Int(1),
+                common::types::Value::Float(ordered_float::OrderedFloat::from(std::f32::NEG_INFINITY)),
+            ],
+        ));
+        let metric = PrometheusMetric::new("ratio", None).unwrap();
+        assert_eq!(
+            "ratio{status_code=\"200\",_5xx=\"0\"} +Inf\nratio{status_code=\"500\",_5xx=\"1\"} -Inf\n",
+            write(&mut execution::stream::InMemoryStream::new(records), metric).unwrap()
+        );
+
+        let mut records = VecDeque::new();
+        records.push_back(execution::stream::Record::new(
+            vec!["".to_string(), "n".to_string()],
+            vec![common::types::Value::Int(1), common::types::Value::Int(2)],
+        ));
+        let metric = PrometheusMetric::new("n", None).unwrap();
+        assert!(write(&mut execution::stream::InMemoryStream::new(records), metric).is_err());
+
+        let mut records = VecDeque::new();
+        records.push_back(execution::stream::Record::new(
+            vec!["status code".to_string(), "status-code".to_string(), "n".to_string()],
+            vec![
+                common::types::Value::Int(200),
+                common::types::Value::Int(200),
+                common::types::Value::Int(1),
+            ],
+        ));
+        let metric = PrometheusMetric::new("n", None).unwrap();
+        assert!(write(&mut execution::stream::InMemoryStream::new(records), metric).is_err());
+
+        assert!(PrometheusMetric::new("2xx_total", None).is_err());
+        assert!(PrometheusMetric::new("requests-total", None).is_err());
+    }
+
+    #[test]
+    fn test_result_cache() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("log_for_test.log");
+        let mut file = File::create(file_path.clone()).unwrap();
+        writeln!(file, r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 100 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#).unwrap();
+        file.sync_all().unwrap();
+
+        let cache = ResultCache::new(dir.path().join("cache"));
+        let reads = std::cell::Cell::new(0);
+        let read = || {
+            reads.set(reads.get() + 1);
+            Ok(b"100\n".to_vec())
+        };
+
+        let query_str = "select sent_bytes from elb";
+        assert_eq!(
+            b"100\n".to_vec(),
+            cache.fetch_or_run(query_str, &file_path, read).unwrap()
+        );
+        assert_eq!(
+            b"100\n".to_vec(),
+            cache.fetch_or_run(query_str, &file_path, read).unwrap()
+        );
+        assert_eq!(1, reads.get());
+
+        cache.fetch_or_run("select request from elb", &file_path, read).unwrap();
+        assert_eq!(2, reads.get());
+
+        file.set_modified(std::time::SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        drop(file);
+        cache.fetch_or_run(query_str, &file_path, read).unwrap();
+        assert_eq!(3, reads.get());
+
+        //A directory others can read is left alone
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let shared_dir = dir.path().join("shared");
+            fs::create_dir(&shared_dir).unwrap();
+            fs::set_permissions(&shared_dir, fs::Permissions::from_mode(0o755)).unwrap();
+            let shared_cache = ResultCache::new(shared_dir.clone());
+            shared_cache.fetch_or_run(query_str, &file_path, read).unwrap();
+            shared_cache.fetch_or_run(query_str, &file_path, read).unwrap();
+            assert_eq!(5, reads.get());
+            assert_eq!(0, fs::read_dir(&shared_dir).unwrap().count());
+
+            let mode = fs::metadata(dir.path().join("cache")).unwrap().permissions().mode();
+            assert_eq!(0o700, mode & 0o777);
+        }
+
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_run_with_cache() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("log_for_test.log");
+        let mut file = File::create(file_path.clone()).unwrap();
+        writeln!(file, r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 100 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#).unwrap();
+        file.sync_all().unwrap();
+        drop(file);
+
+        let cache_dir = dir.path().join("cache");
+        let options = QueryOptions {
+            cache_dir: Some(cache_dir.clone()),
+            ..QueryOptions::default()
+        };
+        let run_query = || {
+            run(
+                "select sent_bytes from elb",
+                common::types::DataSource::File(file_path.clone()),
+                false,
+                OutputMode::Csv,
+                &options,
+            )
+        };
+
+        assert_eq!(Ok(()), run_query());
+        let entries: Vec<PathBuf> = fs::read_dir(&cache_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(1, entries.len());
+        let entry = fs::read_to_string(&entries[0]).unwrap();
+        let (header, output) = entry.split_at(entry.find('\n').unwrap() + 1);
+        assert_eq!("sent_bytes\n100\n", output);
+
+        //A hit prints the entry as it is, a miss would have written the output back
+        fs::write(&entries[0], format!("{}cached\n", header)).unwrap();
+        assert_eq!(Ok(()), run_query());
+        assert_eq!(format!("{}cached\n", header), fs::read_to_string(&entries[0]).unwrap());
+
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_run_explain_mode() {
+        let query_str = "select * from squid";
+        let (dir, data_source) = elb_log(&[
+            r#"1515734740.494      1 [MASKEDIPADDRESS] TCP_DENIED/407 3922 CONNECT d.dropbox.com:443 - HIER_NONE/- text/html"#,
+        ]);
+        let result = run(
+            &*query_str,
+            data_source,
+            true,
+            OutputMode::Csv,
+            &QueryOptions::default(),
+        );
+
+        assert_eq!(result, Ok(()));
+
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_run_error_categories() {
+        let (dir, data_source) = elb_log(&[
+            r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 42355 "GET https://example.com:443/ HTTP/1.1" "Mozilla/5.0 (Windows NT 5.1) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/46.0.2490.80 Safari/537.36" ECDHE-RSA-AES128-GCM-SHA256 TLSv1.2"#,
+        ]);
+
+        let result = run(
+            "select * from elb where",
+            data_source.clone(),
+            false,
+            OutputMode::Csv,
+            &QueryOptions::default(),
+        );
+        match result {
+            Err(AppError::Syntax(SyntaxError::InputNotAllConsumed(_))) => {}
+            _ => panic!("expected a syntax error, got {:?}", result),
+        }
+
+        let result = run(
+            "select * from nginx",
+            data_source.clone(),
+            false,
+            OutputMode::Csv,
+            &QueryOptions::default(),
+        );
+        assert_eq!(result, Err(AppError::Syntax(SyntaxError::InvalidLogFileFormat)));
+
+        let result = run(
+            "select request, count(sent_bytes) from elb group by timestamp",
+            data_source.clone(),
+            false,
+            OutputMode::Csv,
+            &QueryOptions::default(),
+        );
+        assert_eq!(
+            result,
+            Err(AppError::Plan(PlanError::Logical(
+                logical::parser::ParseError::GroupByFieldsMismatch
+            )))
+        );
+
+        let missing_source = common::types::DataSource::File(dir.path().join("missing.log"));
+        let result = run(
+            "select * from elb",
+            missing_source,
+            false,
+            OutputMode::Csv,
+            &QueryOptions::default(),
+        );
+        match result {
+            Err(AppError::Io(_)) => {}
+            _ => panic!("expected an io error, got {:?}", result),
+        }
+
+        let result = run(
+            r#"select * from elb where sent_bytes > "a""#,
+            data_source,
+            false,
+            OutputMode::Csv,
+            &QueryOptions::default(),
+        );
+        match result {
+            Err(AppError::Execution(execution::types::StreamError::Evaluate(_))) => {}
+            _ => panic!("expected an execution error, got {:?}", result),
+        }
+
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_run_with_raw_line() {
+        let (dir, data_source) = elb_log(&[
+            r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 42355 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#,
+            r#"2019-06-07T18:45:34.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 512 "GET https://example.com:443/ HTTP/1.1" "mozilla/5.0" - -"#,
+        ]);
+
+        let query_str = r#"select * from elb where line contains "curl""#;
+        let options = QueryOptions {
+            raw_line: true,
+            ..QueryOptions::default()
+        };
+        let (physical_plan, variables, _) = plan_query(query_str, data_source.clone(), &options).unwrap();
+        let mut stream = physical_plan.get(variables).unwrap();
+
+        let record = stream.next().unwrap().unwrap();
+        assert_eq!(
+            Some(&common::types::Value::Int(42355)),
+            record.to_variables().get("sent_bytes")
+        );
+        assert_eq!(None, stream.next().unwrap());
+
+        let result = run(query_str, data_source, false, OutputMode::Csv, &options);
+        assert_eq!(result, Ok(()));
+
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_merge_sorted_files() {
+        let dir = tempdir().unwrap();
+        let mut file_paths = Vec::new();
+        for (name, seconds) in [("first.log", [30, 32, 35]), ("second.log", [31, 33, 34])].iter() {
+            let file_path = dir.path().join(name);
+            let mut file = File::create(file_path.clone()).unwrap();
+            for second in seconds.iter() {
+                writeln!(file, r#"2019-06-07T18:45:{}.000000Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 {} "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#, second, second).unwrap();
+            }
+            file.sync_all().unwrap();
+            file_paths.push(file_path);
+        }
+
+        let data_source = common::types::DataSource::SortedFiles(file_paths, "timestamp".to_string());
+        let query_str = "select sent_bytes from elb";
+        let (_, select_stmt) = syntax::parser::select_query(query_str).unwrap();
+        let node = logical::parser::parse_query(select_stmt, data_source.clone()).unwrap();
+        let mut physical_plan_creator = logical::types::PhysicalPlanCreator::new(data_source);
+        let (physical_plan, variables) = node.physical(&mut physical_plan_creator).unwrap();
+        let mut stream = physical_plan.get(variables).unwrap();
+
+        let mut sent_bytes = Vec::new();
+        while let Some(record) = stream.next().unwrap() {
+            sent_bytes.push(record.to_variables().get("sent_bytes").cloned().unwrap());
+        }
+        let expected: Vec<common::types::Value> = (30..36).map(common::types::Value::Int).collect();
+        assert_eq!(expected, sent_bytes);
+
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_source_column() {
+        let dir = tempdir().unwrap();
+        let mut file_paths = Vec::new();
+        for (name, sent_bytes) in [("first.log", 100), ("second.log", 200)].iter() {
+            let file_path = dir.path().join(name);
+            let mut file = File::create(file_path.clone()).unwrap();
+            writeln!(file, r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 {} "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#, sent_bytes).unwrap();
+            file.sync_all().unwrap();
+            file_paths.push(file_path);
+        }
+
+        for file_path in file_paths.iter() {
+            let data_source = common::types::DataSource::File(file_path.clone());
+            let query_str = "select __source, count(*) as c from elb group by __source";
+            let (_, select_stmt) = syntax::parser::select_query(query_str).unwrap();
+            let node = logical::parser::parse_query(select_stmt, data_source.clone()).unwrap();
+            let mut physical_plan_creator =
+                logical::types::PhysicalPlanCreator::new(data_source).with_source_column(Some("__source".to_string()));
+            let (physical_plan, variables) = node.physical(&mut physical_plan_creator).unwrap();
+            let mut stream = physical_plan.get(variables).unwrap();
+
+            let record = stream.next().unwrap().unwrap();
+            let expected = execution::stream::Record::new(
+                vec!["__source".to_string(), "c".to_string()],
+                vec![
+                    common::types::Value::String(file_path.to_string_lossy().to_string()),
+                    common::types::Value::Int(1),
+                ],
+            );
+            assert_eq!(expected, record);
+            assert_eq!(None, stream.next().unwrap());
+        }
+
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_percentage_over_aggregates() {
+        let mut lines: Vec<String> = [100, 2000, 300, 4000]
+            .iter()
+            .map(|sent_bytes| format!(r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 {} "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#, sent_bytes))
+            .collect();
+        lines.push(r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.216:80 0.000036 0.001035 0.000025 200 200 0 100 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#.to_string());
+
+        let records = query_elb_lines(&lines, "select backend_and_port, count(*) as total, count_buckets(sent_bytes > 1000) as large, large_0 * 100.0 / total as large_pct from elb group by backend_and_port order by total desc");
+
+        let fields = ["total".to_string(), "large_0".to_string(), "large_pct".to_string()];
+        let rows: Vec<Vec<common::types::Value>> = records.iter().map(|record| record.get(&fields)).collect();
+        assert_eq!(
+            vec![
+                vec![
+                    common::types::Value::Int(4),
+                    common::types::Value::Int(2),
+                    common::types::Value::Float(ordered_float::OrderedFloat::from(50.0))
+                ],
+                vec![
+                    common::types::Value::Int(1),
+                    common::types::Value::Int(0),
+                    common::types::Value::Float(ordered_float::OrderedFloat::from(0.0))
+                ],
+            ],
+            rows
+        );
+    }
+
+    #[test]
+    fn test_aggregate_over_no_matching_rows() {
+        let (dir, data_source) = elb_log(&[
+            r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 512 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#,
+        ]);
+
+        let collect = |skip_empty_aggregate: bool| {
+            let options = QueryOptions {
+                skip_empty_aggregate,
+                ..QueryOptions::default()
+            };
+            let query_str = "select count(*) as c, sum(sent_bytes) as s from elb where sent_bytes > 1000";
+            let (physical_plan, variables, _) = plan_query(query_str, data_source.clone(), &options).unwrap();
+            let mut stream = physical_plan.get(variables).unwrap();
+
+            let mut records = Vec::new();
+            while let Some(record) = stream.next().unwrap() {
+                records.push(record);
+            }
+            records
+        };
+
+        let records = collect(false);
+        assert_eq!(1, records.len());
+        assert_eq!(
+            vec![common::types::Value::Int(0), common::types::Value::Null],
+            records[0].get(&["c".to_string(), "s".to_string()])
+        );
+
+        assert!(collect(true).is_empty());
+
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_unit_literals_in_where() {
+        let lines: Vec<String> = [(0.000036, 100), (0.5, 2048), (0.000036, 4096)]
+            .iter()
+            .map(|(backend_processing_time, sent_bytes)| format!(r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 {} 0.000025 200 200 0 {} "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#, backend_processing_time, sent_bytes))
+            .collect();
+
+        let records = query_elb_lines(
+            &lines,
+            "select * from elb where sent_bytes > 1kib and backend_processing_time > 100ms",
+        );
+        assert_eq!(1, records.len());
+        assert_eq!(
+            vec![common::types::Value::Int(2048)],
+            records[0].get(&["sent_bytes".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_chained_comparison_in_where() {
+        let lines: Vec<String> = [100, 2048, 400]
+            .iter()
+            .map(|sent_bytes| format!(r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 {} "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#, sent_bytes))
+            .collect();
+
+        let records = query_elb_lines(&lines, "select * from elb where 100 < sent_bytes / 2 < 1000");
+        assert_eq!(1, records.len());
+        assert_eq!(
+            vec![common::types::Value::Int(400)],
+            records[0].get(&["sent_bytes".to_string()])
+        );
+        assert!(!records[0].field_names().contains(&"__chain_0".to_string()));
+
+        let records = query_elb_lines(&lines, "select *, 100 < sent_bytes / 2 < 1000 as mid from elb");
+        let mid: Vec<common::types::Value> = records
+            .iter()
+            .map(|record| record.get(&["mid".to_string()]).remove(0))
+            .collect();
+        assert_eq!(
+            vec![
+                common::types::Value::Boolean(false),
+                common::types::Value::Boolean(false),
+                common::types::Value::Boolean(true)
+            ],
+            mid
+        );
+        assert!(!records[0].field_names().contains(&"__chain_0".to_string()));
+    }
+
+    #[test]
+    fn test_prev_delta_between_rows() {
+        let lines: Vec<String> = [
+            ("18:45:33.000000", 100),
+            ("18:45:35.500000", 250),
+            ("18:45:40.000000", 600),
+        ]
+        .iter()
+        .map(|(timestamp, sent_bytes)| format!(r#"2019-06-07T{}Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 {} "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#, timestamp, sent_bytes))
+        .collect();
+
+        let records = query_elb_lines(
+            &lines,
+            "select sent_bytes - prev(sent_bytes) as bytes_delta, timestamp - prev(timestamp) as gap from elb",
+        );
+        let result: Vec<Vec<common::types::Value>> = records
+            .iter()
+            .map(|record| record.get(&["bytes_delta".to_string(), "gap".to_string()]))
+            .collect();
+        //Nothing before the first row
+        assert_eq!(
+            vec![
+                vec![common::types::Value::Null, common::types::Value::Null],
+                vec![
+                    common::types::Value::Int(150),
+                    common::types::Value::Float(ordered_float::OrderedFloat(2.5))
+                ],
+                vec![
+                    common::types::Value::Int(350),
+                    common::types::Value::Float(ordered_float::OrderedFloat(4.5))
+                ],
+            ],
+            result
+        );
+    }
+
+    #[test]
+    fn test_hop_windows() {
+        let lines: Vec<String> = ["2019-06-07T18:45:33.559871Z", "2019-06-07T18:47:10.000000Z"]
+            .iter()
+            .map(|timestamp| format!(r#"{} elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 512 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#, timestamp))
+            .collect();
+
+        let records = query_elb_lines(
+            &lines,
+            "select hop(timestamp, 5m, 1m) as w, count(*) as c from elb group by w order by w asc",
+        );
+
+        //18:45:33 is in the windows from 18:41 to 18:45 and 18:47:10 in those from 18:43 to 18:47
+        let rows: Vec<Vec<common::types::Value>> = records
+            .iter()
+            .map(|record| record.get(&["w".to_string(), "c".to_string()]))
+            .collect();
+        let expected: Vec<Vec<common::types::Value>> = [(41, 1), (42, 1), (43, 2), (44, 2), (45, 2), (46, 1), (47, 1)]
+            .iter()
+            .map(|(minute, count)| {
+                let window = format!("2019-06-07T18:{}:00Z", minute);
+                vec![
+                    common::types::Value::DateTime(chrono::DateTime::parse_from_rfc3339(&window).unwrap()),
+                    common::types::Value::Int(*count),
+                ]
+            })
+            .collect();
+        assert_eq!(expected, rows);
+    }
+
+    #[test]
+    fn test_count_buckets() {
+        let lines: Vec<String> = [(200, 100), (302, 350), (404, 450), (503, 600)]
+            .iter()
+            .map(|(status, sent_bytes)| format!(r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 {} {} 0 {} "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#, status, status, sent_bytes))
+            .collect();
+
+        let records = query_elb_lines(
+            &lines,
+            r#"select count_buckets([sent_bytes < 300, sent_bytes < 400, sent_bytes < 500]) as b from elb"#,
+        );
+        let expected = execution::stream::Record::new(
+            vec!["b_0".to_string(), "b_1".to_string(), "b_2".to_string()],
+            vec![
+                common::types::Value::Int(1),
+                common::types::Value::Int(2),
+                common::types::Value::Int(3),
+            ],
+        );
+        assert_eq!(vec![expected], records);
+    }
+
+    #[test]
+    fn test_reordered_filter_keeps_results() {
+        let lines: Vec<String> = [(200, 100), (302, 350), (404, 450), (503, 600)]
+            .iter()
+            .map(|(status, sent_bytes)| format!(r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 {} {} 0 {} "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#, status, status, sent_bytes))
+            .collect();
+        let (dir, data_source) = elb_log(&lines);
+
+        let query_str =
+            r#"select * from elb where user_agent contains "curl" and sent_bytes > 300 and sent_bytes < 500"#;
+        let (rest, select_stmt) = syntax::parser::select_query(query_str).unwrap();
+        assert_eq!("", rest);
+        let node = logical::parser::parse_query(select_stmt, data_source.clone()).unwrap();
+
+        let collect = |node: logical::types::Node| {
+            let mut physical_plan_creator = logical::types::PhysicalPlanCreator::new(data_source.clone());
+            let (physical_plan, variables) = node.physical(&mut physical_plan_creator).unwrap();
+            let mut stream = physical_plan.get(variables).unwrap();
+            let mut records = Vec::new();
+            while let Some(record) = stream.next().unwrap() {
+                records.push(record);
+            }
+            records
+        };
+
+        let expected = collect(node.clone());
+        let reordered = logical::optimizer::reorder_filters(node);
+        assert_eq!(expected, collect(reordered));
+        assert_eq!(2, expected.len());
+
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_detail_and_summary_in_one_scan() {
+        let lines: Vec<String> = [(200, 100), (302, 350), (404, 450), (503, 600)]
+            .iter()
+            .map(|(status, sent_bytes)| format!(r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 {} {} 0 {} "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#, status, status, sent_bytes))
+            .collect();
+        let (dir, data_source) = elb_log(&lines);
+
+        let (_, detail_stmt) =
+            syntax::parser::select_query(r#"select sent_bytes from elb where sent_bytes > 400"#).unwrap();
+        let (_, summary_stmt) =
+            syntax::parser::select_query(r#"select count(*) as n, sum(sent_bytes) as total from elb"#).unwrap();
+        let detail = logical::parser::parse_query(detail_stmt, data_source.clone()).unwrap();
+        let summary = logical::parser::parse_query(summary_stmt, data_source.clone()).unwrap();
+        let node = logical::types::Node::Tee(Box::new(detail), Box::new(summary));
+
+        let mut physical_plan_creator = logical::types::PhysicalPlanCreator::new(data_source);
+        let (physical_plan, variables) = node.physical(&mut physical_plan_creator).unwrap();
+        let mut stream = physical_plan.get(variables).unwrap();
+
+        let mut records = Vec::new();
+        while let Some(record) = stream.next().unwrap() {
+            records.push(record);
+        }
+        let expected = vec![
+            execution::stream::Record::new(vec!["sent_bytes".to_string()], vec![common::types::Value::Int(450)]),
+            execution::stream::Record::new(vec!["sent_bytes".to_string()], vec![common::types::Value::Int(600)]),
+            execution::stream::Record::new(
+                vec!["n".to_string(), "total".to_string()],
+                vec![
+                    common::types::Value::Int(4),
+                    common::types::Value::Float(ordered_float::OrderedFloat(1500.0)),
+                ],
+            ),
+        ];
+        assert_eq!(expected, records);
+
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_order_by_unselected_aggregate() {
+        let lines: Vec<String> = ["10.0.0.215:80", "10.0.0.216:80", "10.0.0.216:80"]
+            .iter()
+            .map(|target| format!(r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 {} 0.000036 0.001035 0.000025 200 200 0 100 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#, target))
+            .collect();
+
+        let records = query_elb_lines(
+            &lines,
+            "select backend_and_port from elb group by backend_and_port order by count(*) desc",
+        );
+        let mut result = Vec::new();
+        for record in records.iter() {
+            assert_eq!(&["backend_and_port".to_string()], record.field_names());
+            result.push(record.to_csv_record());
+        }
+        assert_eq!(
+            vec![vec!["10.0.0.216:80".to_string()], vec!["10.0.0.215:80".to_string()]],
+            result
+        );
+    }
+
+    #[test]
+    fn test_quoted_alias() {
+        let records = query_elb_lines(
+            &[
+                r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 100 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#,
+            ],
+            r#"select count(*) as "count (all)", sum(sent_bytes) as "sent bytes" from elb"#,
+        );
+        let expected = execution::stream::Record::new(
+            vec!["count (all)".to_string(), "sent bytes".to_string()],
+            vec![
+                common::types::Value::Int(1),
+                common::types::Value::Float(ordered_float::OrderedFloat(100.0)),
+            ],
+        );
+        assert_eq!(vec![expected], records);
+    }
+
+    #[test]
+    fn test_conditional_ratio() {
+        let lines: Vec<String> = [(200, 100), (302, 350), (500, 450), (503, 600)]
+            .iter()
+            .map(|(status, sent_bytes)| format!(r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 {} {} 0 {} "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#, status, status, sent_bytes))
+            .collect();
+
+        let records = query_elb_lines(
+            &lines,
+            r#"select conditional_ratio(sent_bytes > 400, sent_bytes > 0) as rate from elb"#,
+        );
+        let expected = execution::stream::Record::new(
+            vec!["rate".to_string()],
+            vec![common::types::Value::Float(ordered_float::OrderedFloat(0.5))],
+        );
+        assert_eq!(vec![expected], records);
+    }
+
+    #[test]
+    fn test_backend_failure_rate() {
+        let lines: Vec<String> = [("0.5", "200"), ("-1", "-"), ("1.5", "200"), ("-1", "-")]
+            .iter()
+            .map(|(backend_processing_time, status)| format!(r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 -1 {} -1 504 {} 0 0 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#, backend_processing_time, status))
+            .collect();
+
+        let records = query_elb_lines(
+            &lines,
+            "select avg(backend_processing_time) as avg_time, backend_failure_rate() as rate from elb",
+        );
+
+        //The failed requests are left out of the average
+        let expected = execution::stream::Record::new(
+            vec!["avg_time".to_string(), "rate".to_string()],
+            vec![
+                common::types::Value::Float(ordered_float::OrderedFloat(1.0)),
+                common::types::Value::Float(ordered_float::OrderedFloat(0.5)),
+            ],
+        );
+        assert_eq!(vec![expected], records);
+    }
+
+    #[test]
+    fn test_skip_bad_lines_collects_warnings() {
+        let (dir, data_source) = elb_log(&[
+            r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 -1 0.5 -1 200 200 0 0 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#,
+            "not a log line",
+            r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 -1 -1 -1 504 - 0 0 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#,
+        ]);
+
+        execution::warnings::take();
+        let options = QueryOptions {
+            skip_bad_lines: true,
+            ..QueryOptions::default()
+        };
+        let (physical_plan, variables, _) =
+            plan_query("select backend_processing_time from elb", data_source, &options).unwrap();
+        let mut stream = physical_plan.get(variables).unwrap();
+
+        let mut values = Vec::new();
+        while let Some(record) = stream.next().unwrap() {
+            values.extend(record.get(&["backend_processing_time".to_string()]));
+        }
+        assert_eq!(
+            vec![
+                common::types::Value::Float(ordered_float::OrderedFloat(0.5)),
+                common::types::Value::Null
+            ],
+            values
+        );
+
+        let warnings = execution::warnings::take();
+        assert_eq!(1, warnings.count(execution::warnings::WarningKind::SkippedLine));
+        assert_eq!(1, warnings.count(execution::warnings::WarningKind::NullSentinel));
+
+        let options = QueryOptions {
+            warnings_as_errors: true,
+            ..QueryOptions::default()
+        };
+        assert!(matches!(
+            report_warnings(warnings, &options),
+            Err(AppError::Warnings(_))
+        ));
+
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_exists() {
+        let dir = tempdir().unwrap();
+        let write_log = |name: &str, lines: &[&str]| {
+            let file_path = dir.path().join(name);
+            let mut file = File::create(file_path.clone()).unwrap();
+            for line in lines.iter() {
+                writeln!(file, "{}", line).unwrap();
+            }
+            file.sync_all().unwrap();
+            common::types::DataSource::File(file_path)
+        };
+        let ok = r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 42355 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#;
+        let failed = r#"2019-06-07T18:45:34.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 504 504 0 0 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#;
+        let query_str = r#"select backend_status_code from elb where backend_status_code = "504""#;
+
+        let without_match = write_log("ok.log", &[ok, ok]);
+        assert_eq!(Ok(false), exists(query_str, without_match, &QueryOptions::default()));
+
+        //The scan stops at the match and never reaches the line that doesn't parse
+        let with_match = write_log("failed.log", &[ok, failed, "not a log line"]);
+        assert_eq!(Ok(true), exists(query_str, with_match, &QueryOptions::default()));
+
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_query_keeps_the_case_of_literals() {
+        let (dir, data_source) = elb_log(&[
+            r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 42355 "GET https://example.com:443/API/Users HTTP/1.1" "curl/7.38.0" - -"#,
+        ]);
+
+        //As the command line reads it, the keywords and columns are lowercased but not the patterns
+        let found = |query_str: &str| {
+            let query_str = syntax::parser::lowercase_query(query_str);
+            exists(&query_str, data_source.clone(), &QueryOptions::default())
+        };
+        assert_eq!(
+            Ok(true),
+            found(r#"SELECT Request FROM elb WHERE url_path(Request) = "/API/Users""#)
+        );
+        assert_eq!(
+            Ok(true),
+            found(r#"SELECT Request FROM elb WHERE url_path(Request) ~ "^/API/""#)
+        );
+        assert_eq!(
+            Ok(false),
+            found(r#"SELECT Request FROM elb WHERE url_path(Request) ~ "^/api/""#)
+        );
+
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_parse_schema_contract() {
+        assert_eq!(
+            Ok(vec![
+                ("host".to_string(), execution::types::ValueType::String),
+                ("requests".to_string(), execution::types::ValueType::Int),
+            ]),
+            parse_schema_contract("host:string, Requests:INT")
+        );
+        assert!(parse_schema_contract("host:str").is_err());
+        assert!(parse_schema_contract("host").is_err());
+    }
+
+    #[test]
+    fn test_filter_on_null_fields() {
+        let lines: Vec<String> = [("0.5", r#""curl/7.38.0""#), ("-1", "-"), ("1.5", r#""-""#)]
+            .iter()
+            .map(|(backend_processing_time, user_agent)| format!(r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.1 {} 0.1 200 200 0 0 "GET https://example.com:443/ HTTP/1.1" {} - -"#, backend_processing_time, user_agent))
+            .collect();
+
+        let collect = |query_str: &str| {
+            query_elb_lines(&lines, query_str)
+                .iter()
+                .map(|record| record.get(&["backend_processing_time".to_string()]).remove(0))
+                .collect::<Vec<_>>()
+        };
+        let float = |f: f32| common::types::Value::Float(ordered_float::OrderedFloat(f));
+
+        assert_eq!(
+            vec![common::types::Value::Null, float(1.5)],
+            collect("select backend_processing_time, user_agent from elb where user_agent is null")
+        );
+        assert_eq!(
+            vec![float(0.5)],
+            collect("select backend_processing_time, user_agent from elb where user_agent is not null")
+        );
+        //The null time is neither above nor below 1, the row drops out of both filters
+        assert_eq!(
+            vec![float(1.5)],
+            collect("select backend_processing_time, user_agent from elb where backend_processing_time > 1")
+        );
+        assert_eq!(
+            vec![float(0.5)],
+            collect("select backend_processing_time, user_agent from elb where not (backend_processing_time > 1)")
+        );
+    }
+
+    #[test]
+    fn test_filter_json_lines() {
+        let records = query_elb_lines(
+            &[
+                r#"{"level": "error", "status": 502, "http": {"path": "/a"}}"#,
+                r#"{"level": "info", "status": 200, "http": {"path": "/b"}}"#,
+            ],
+            "select level, http_path from json where status >= 500",
+        );
+        assert_eq!(1, records.len());
+        assert_eq!(
+            vec![
+                common::types::Value::String("error".to_string()),
+                common::types::Value::String("/a".to_string())
+            ],
+            records[0].get(&["level".to_string(), "http_path".to_string()])
+        );
+
+        //The key only shows up on the second line, the first one is null there
+        let lines = [r#"{"a": 1}"#, r#"{"a": 2, "b": "x"}"#];
+        let records = query_elb_lines(&lines, "select b from json");
+        assert_eq!(
+            vec![
+                vec![common::types::Value::Null],
+                vec![common::types::Value::String("x".to_string())]
+            ],
+            records
+                .iter()
+                .map(|record| record.get(&["b".to_string()]))
+                .collect::<Vec<_>>()
+        );
+        let records = query_elb_lines(&lines, "select a from json where b is null");
+        assert_eq!(1, records.len());
+        assert_eq!(vec![common::types::Value::Int(1)], records[0].get(&["a".to_string()]));
+    }
+
+    #[test]
+    fn test_filter_with_like() {
+        let lines: Vec<String> = [
+            (1, "GET https://example.com:443/ HTTP/1.1"),
+            (2, "POST https://example.com:443/upload HTTP/1.1"),
+            (3, "GET https://example.com:443/100%25 HTTP/1.1"),
+            (4, "PUT https://example.com:443/ HTTP/1.1"),
+        ]
+        .iter()
+        .map(|(sent_bytes, request)| format!(r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.1 0.1 0.1 200 200 0 {} "{}" "curl/7.38.0" - -"#, sent_bytes, request))
+        .collect();
+
+        let collect = |query_str: &str| {
+            query_elb_lines(&lines, query_str)
+                .iter()
+                .map(|record| record.get(&["sent_bytes".to_string()]).remove(0))
+                .collect::<Vec<_>>()
+        };
+        let ints = |ints: &[i32]| ints.iter().map(|&i| common::types::Value::Int(i)).collect::<Vec<_>>();
+
+        assert_eq!(
+            ints(&[1, 3]),
+            collect(r#"select sent_bytes, request from elb where request like "GET %""#)
+        );
+        assert_eq!(
+            ints(&[2, 4]),
+            collect(r#"select sent_bytes, request from elb where request not like "GET %""#)
+        );
+        assert_eq!(
+            ints(&[4]),
+            collect(r#"select sent_bytes, request from elb where request like "P_T %""#)
+        );
+        assert_eq!(
+            ints(&[3]),
+            collect(r#"select sent_bytes, request from elb where request like "%/100\%25 %""#)
+        );
+    }
+
+    #[test]
+    fn test_filter_with_regex_match() {
+        let lines: Vec<String> = [
+            (1, "Googlebot/2.1"),
+            (2, "curl/7.38.0"),
+            (3, "some-crawler/1.0"),
+            (4, "Mozilla/5.0"),
+        ]
+        .iter()
+        .map(|(sent_bytes, user_agent)| format!(r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.1 0.1 0.1 200 200 0 {} "GET https://example.com:443/ HTTP/1.1" "{}" - -"#, sent_bytes, user_agent))
+        .collect();
+
+        let sent_bytes: Vec<common::types::Value> = query_elb_lines(
+            &lines,
+            r#"select sent_bytes, user_agent from elb where user_agent ~ "bot|crawler""#,
+        )
+        .iter()
+        .map(|record| record.get(&["sent_bytes".to_string()]).remove(0))
+        .collect();
+        assert_eq!(
+            vec![common::types::Value::Int(1), common::types::Value::Int(3)],
+            sent_bytes
+        );
+
+        let (dir, data_source) = elb_log(&lines);
+        let result = plan_query(
+            r#"select sent_bytes, user_agent from elb where user_agent ~ "(bot""#,
+            data_source,
+            &QueryOptions::default(),
+        );
+        assert!(matches!(
+            result,
+            Err(AppError::Plan(PlanError::Physical(
+                logical::types::PhysicalPlanError::InvalidRegex(_)
+            )))
+        ));
+
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_requires_group_by() {
+        let dir = tempdir().unwrap();
+        let options = QueryOptions {
+            snapshot: Some(dir.path().join("counts.json")),
+            ..QueryOptions::default()
+        };
+        //The plan is checked before anything is read, explained it doesn't read stdin at all
+        let plan = |query_str: &str| {
+            run(
+                query_str,
+                common::types::DataSource::Stdin,
+                true,
+                OutputMode::Csv,
+                &options,
+            )
+        };
+
+        assert_eq!(
+            Err(AppError::Plan(PlanError::Physical(
+                logical::types::PhysicalPlanError::SnapshotWithoutGroupBy
+            ))),
+            plan("select elb_status_code from elb")
+        );
+        assert_eq!(
+            Ok(()),
+            plan("select elb_status_code, count(*) as n from elb group by elb_status_code")
+        );
+
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_values_source_through_filter() {
+        let query_str = r#"select id, name from (values (1, "a"), (2, "b"), (3, "c")) as t(id, name) where id > 1"#;
+        let (rest, select_stmt) = syntax::parser::select_query(query_str).unwrap();
+        assert_eq!("", rest);
+        let node = logical::parser::parse_query(select_stmt, common::types::DataSource::Stdin).unwrap();
+        let mut physical_plan_creator = logical::types::PhysicalPlanCreator::new(common::types::DataSource::Stdin);
+        let (physical_plan, variables) = node.physical(&mut physical_plan_creator).unwrap();
+        let mut stream = physical_plan.get(variables).unwrap();
+
+        for (id, name) in [(2, "b"), (3, "c")].iter() {
+            let expected = execution::stream::Record::new(
+                vec!["id".to_string(), "name".to_string()],
+                vec![
+                    common::types::Value::Int(*id),
+                    common::types::Value::String(name.to_string()),
+                ],
+            );
+            assert_eq!(expected, stream.next().unwrap().unwrap());
+        }
+        assert_eq!(None, stream.next().unwrap());
+
+        //Every row has to fill each of the named columns
+        let query_str = r#"select * from (values (1, "a"), (2)) as t(id, name)"#;
+        let (_, select_stmt) = syntax::parser::select_query(query_str).unwrap();
+        let result = logical::parser::parse_query(select_stmt, common::types::DataSource::Stdin);
+        assert_eq!(Err(logical::parser::ParseError::ValuesColumnCount(2, 1, 2)), result);
+    }
+
+    #[test]
+    fn test_cross_join_values() {
+        let query_str =
+            r#"select id, tier from (values (1), (2)) as t(id) cross join (values ("a"), ("b")) as d(tier)"#;
+        let (rest, select_stmt) = syntax::parser::select_query(query_str).unwrap();
+        assert_eq!("", rest);
+        let node = logical::parser::parse_query(select_stmt, common::types::DataSource::Stdin).unwrap();
+
+        let mut physical_plan_creator = logical::types::PhysicalPlanCreator::new(common::types::DataSource::Stdin);
+        let (physical_plan, variables) = node.physical(&mut physical_plan_creator).unwrap();
+        let mut stream = physical_plan.get(variables).unwrap();
+        for (id, tier) in [(1, "a"), (1, "b"), (2, "a"), (2, "b")].iter() {
+            let expected = execution::stream::Record::new(
+                vec!["id".to_string(), "tier".to_string()],
+                vec![
+                    common::types::Value::Int(*id),
+                    common::types::Value::String(tier.to_string()),
+                ],
+            );
+            assert_eq!(expected, stream.next().unwrap().unwrap());
+        }
+        assert_eq!(None, stream.next().unwrap());
+
+        //The fourth row trips a limit of 3 once the first 3 are emitted
+        let mut physical_plan_creator =
+            logical::types::PhysicalPlanCreator::new(common::types::DataSource::Stdin).with_join_row_limit(3);
+        let (physical_plan, variables) = node.physical(&mut physical_plan_creator).unwrap();
+        let mut stream = physical_plan.get(variables).unwrap();
+        for _ in 0..3 {
+            assert!(stream.next().unwrap().is_some());
+        }
+        assert_eq!(Err(execution::types::StreamError::JoinLimit(3)), stream.next());
+
+        //The columns of both sides are side by side, a name on both can't be told apart
+        let query_str = r#"select * from (values (1), (2)) as t(id) cross join (values (3)) as d(id)"#;
+        let (_, select_stmt) = syntax::parser::select_query(query_str).unwrap();
+        let node = logical::parser::parse_query(select_stmt, common::types::DataSource::Stdin).unwrap();
+        let mut physical_plan_creator = logical::types::PhysicalPlanCreator::new(common::types::DataSource::Stdin);
+        assert_eq!(
+            Err(logical::types::PhysicalPlanError::JoinColumnClash("id".to_string())),
+            node.physical(&mut physical_plan_creator).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_run_values_query() {
+        let query_str = r#"select id from (values (1), (2)) as t(id)"#;
+        let run_query = |data_source| run(query_str, data_source, false, OutputMode::Csv, &QueryOptions::default());
+        assert_eq!(Ok(()), run_query(common::types::DataSource::Stdin));
+
+        //The rows come from the query, a log file given with it would go unread
+        assert_eq!(
+            Err(AppError::Syntax(SyntaxError::ValuesWithLogFile)),
+            run_query(common::types::DataSource::File(PathBuf::from("access.log")))
+        );
+    }
+
+    #[test]
+    fn test_write_partitioned() {
+        let dir = tempdir().unwrap();
+        let mut records = VecDeque::new();
+        for (family, path) in [("2xx", "/a"), ("5xx", "/b"), ("2xx", "/c")].iter() {
+            records.push_back(execution::stream::Record::new(
+                vec!["status_family".to_string(), "path".to_string()],
+                vec![
+                    common::types::Value::String(family.to_string()),
+                    common::types::Value::String(path.to_string()),
+                ],
+            ));
+        }
+        records.push_back(execution::stream::Record::new(
+            vec!["status_family".to_string(), "path".to_string()],
+            vec![
+                common::types::Value::Null,
+                common::types::Value::String("/d".to_string()),
+            ],
+        ));
+        let mut stream = execution::stream::InMemoryStream::new(records);
+
+        let template = dir.path().join("out_{key}.csv");
+        write_partitioned(
+            &mut stream,
+            OutputMode::Csv,
+            "status_family",
+            template.to_str().unwrap(),
+            Some(&[][..]),
+        )
+        .unwrap();
+
+        let content = fs::read_to_string(dir.path().join("out_2xx.csv")).unwrap();
+        assert_eq!("status_family,path\n2xx,/a\n2xx,/c\n", content);
+        let content = fs::read_to_string(dir.path().join("out_5xx.csv")).unwrap();
+        assert_eq!("status_family,path\n5xx,/b\n", content);
+        let content = fs::read_to_string(dir.path().join("out___null__.csv")).unwrap();
+        assert_eq!("status_family,path\n<null>,/d\n", content);
+
+        let mut stream = execution::stream::InMemoryStream::new(VecDeque::new());
+        assert!(write_partitioned(&mut stream, OutputMode::Csv, "host", template.to_str().unwrap(), None).is_ok());
+        let mut stream = records_for_layout();
+        assert_eq!(
+            Err(AppError::Execution(execution::types::StreamError::UnknownColumn(
+                "status_family".to_string()
+            ))),
+            write_partitioned(
+                &mut stream,
+                OutputMode::Csv,
+                "status_family",
+                template.to_str().unwrap(),
+                None
+            )
+        );
+
+        //More partitions than open files, each is reopened to append its next rows
+        let mut records = VecDeque::new();
+        for round in 0..2 {
+            for key in 0..MAX_OPEN_PARTITIONS + 1 {
+                records.push_back(execution::stream::Record::new(
+                    vec!["key".to_string(), "round".to_string()],
+                    vec![common::types::Value::Int(key as i32), common::types::Value::Int(round)],
+                ));
+            }
+        }
+        let mut stream = execution::stream::InMemoryStream::new(records);
+        let template = dir.path().join("many_{key}.csv");
+        write_partitioned(
+            &mut stream,
+            OutputMode::Csv,
+            "key",
+            template.to_str().unwrap(),
+            Some(&[][..]),
+        )
+        .unwrap();
+        for key in 0..MAX_OPEN_PARTITIONS + 1 {
+            let content = fs::read_to_string(dir.path().join(format!("many_{}.csv", key))).unwrap();
+            assert_eq!(format!("key,round\n{},0\n{},1\n", key, key), content);
+        }
+
+        //The header of the plan heads every partition, --no-header leaves it out
+        let query_partitions = |header: Option<&[common::types::VariableName]>| {
+            let record = |key: &str| {
+                execution::stream::Record::new(
+                    vec!["key".to_string()],
+                    vec![common::types::Value::String(key.to_string())],
+                )
+            };
+            let mut stream = execution::stream::InMemoryStream::new(vec![record("a"), record("b")].into());
+            let template = dir.path().join("header_{key}.csv");
+            write_partitioned(&mut stream, OutputMode::Csv, "key", template.to_str().unwrap(), header).unwrap();
+            fs::read_to_string(dir.path().join("header_b.csv")).unwrap()
+        };
+        assert_eq!("k\nb\n", query_partitions(Some(&["k".to_string()][..])));
+        assert_eq!("b\n", query_partitions(None));
+
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_bench() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("log_for_test.log");
+        let mut file = File::create(file_path.clone()).unwrap();
+        for _ in 0..10 {
+            writeln!(file, r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 100 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#).unwrap();
+        }
+        file.sync_all().unwrap();
+        drop(file);
+
+        let report = bench(&file_path, None, 3).unwrap();
+        assert_eq!(10, report.records);
+        assert_eq!(3, report.durations.len());
+        let (records_per_sec, mb_per_sec) = report.throughput(report.best());
+        assert!(records_per_sec > 0.0);
+        assert!(mb_per_sec > 0.0);
+        assert!(report.best() <= report.median());
+
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_resolve_table_name_with_input_format() {
+        let (dir, data_source) = elb_log(&[
+            "# exported from the load balancer",
+            r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 100 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#,
+        ]);
+        let mut options = QueryOptions {
+            quiet: true,
+            ..QueryOptions::default()
+        };
+        assert!(resolve_table_name("auto", &data_source, &options).is_err());
+
+        options.input_format = Some("elb".to_string());
+        assert_eq!(
+            Ok("elb".to_string()),
+            resolve_table_name("auto", &data_source, &options)
+        );
+        assert_eq!(Ok("elb".to_string()), resolve_table_name("alb", &data_source, &options));
+
+        options.input_format = Some("apache".to_string());
+        let expected = AppError::Syntax(SyntaxError::UnknownInputFormat(
+            "apache".to_string(),
+            "elb, alb, squid, s3, nginx, w3c, json".to_string(),
+        ));
+        assert_eq!(Err(expected), resolve_table_name("auto", &data_source, &options));
+
+        //Sniffing would take the first lines away from the query
+        #[cfg(unix)]
+        {
+            let fifo_path = dir.path().join("log.fifo");
+            assert!(std::process::Command::new("mkfifo")
+                .arg(&fifo_path)
+                .status()
+                .unwrap()
+                .success());
+            let fifo = common::types::DataSource::File(fifo_path);
+            options.input_format = None;
+            assert!(matches!(
+                resolve_table_name("auto", &fifo, &options),
+                Err(AppError::Syntax(SyntaxError::Invalid(_)))
+            ));
+        }
+
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_group_duration_with_count() {
+        let lines: Vec<String> = [
+            ("2019-06-07T18:45:33.000000Z", "10.0.0.215:80"),
+            ("2019-06-07T18:46:03.000000Z", "10.0.0.216:80"),
+            ("2019-06-07T18:47:33.000000Z", "10.0.0.215:80"),
+        ]
+        .iter()
+        .map(|(timestamp, target)| format!(r#"{} elb1 78.168.134.92:4586 {} 0.000036 0.001035 0.000025 200 200 0 100 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#, timestamp, target))
+        .collect();
+
+        let records = query_elb_lines(&lines, "select backend_and_port, count(*) as c, group_duration() as span from elb group by backend_and_port order by c desc");
+        assert_eq!(2, records.len());
+        let expected = execution::stream::Record::new(
+            vec!["backend_and_port".to_string(), "c".to_string(), "span".to_string()],
+            vec![
+                common::types::Value::Host(common::types::parse_host("10.0.0.215:80").unwrap()),
+                common::types::Value::Int(2),
+                common::types::Value::Float(ordered_float::OrderedFloat(120.0)),
+            ],
+        );
+        assert_eq!(expected, records[0]);
+        assert_eq!(vec![common::types::Value::Null], records[1].get(&["span".to_string()]));
+    }
+
+    #[test]
+    fn test_merge_partial_outputs() {
+        let dir = tempdir().unwrap();
+        let first_path = dir.path().join("partial_0.json");
+        let mut file = File::create(first_path.clone()).unwrap();
+        writeln!(
+            file,
+            r#"[{{"host":"a","c":"count:2","s":"sum:10","m":"avg:5:2","_partial_aggregates":["c","s","m"]}},{{"host":"b","c":"count:1","s":"sum:1","m":"avg:1:1","_partial_aggregates":["c","s","m"]}}]"#
+        )
+        .unwrap();
+        drop(file);
+
+        let second_path = dir.path().join("partial_1.json");
+        let mut file = File::create(second_path.clone()).unwrap();
+        writeln!(
+            file,
+            r#"[{{"host":"a","c":"count:2","s":"sum:2","m":"avg:1:2","_partial_aggregates":["c","s","m"]}}]"#
+        )
+        .unwrap();
+        drop(file);
+
+        let records = merge_partial_records(&[first_path.clone(), second_path.clone()]).unwrap();
+        let fields = vec!["host".to_string(), "c".to_string(), "s".to_string(), "m".to_string()];
+        let expected = vec![
+            execution::stream::Record::new(
+                fields.clone(),
+                vec![
+                    common::types::Value::String("a".to_string()),
+                    common::types::Value::Int(4),
+                    common::types::Value::Float(ordered_float::OrderedFloat::from(12.0)),
+                    common::types::Value::Float(ordered_float::OrderedFloat::from(3.0)),
+                ],
+            ),
+            execution::stream::Record::new(
+                fields,
+                vec![
+                    common::types::Value::String("b".to_string()),
+                    common::types::Value::Int(1),
+                    common::types::Value::Float(ordered_float::OrderedFloat::from(1.0)),
+                    common::types::Value::Float(ordered_float::OrderedFloat::from(1.0)),
+                ],
+            ),
+        ];
+        assert_eq!(expected, records.into_iter().collect::<Vec<_>>());
+
+        let result = merge(&[first_path.clone(), second_path], OutputMode::Csv);
+        assert_eq!(result, Ok(()));
+
+        let broken_path = dir.path().join("partial_2.json");
+        let mut file = File::create(broken_path.clone()).unwrap();
+        writeln!(
+            file,
+            r#"[{{"host":"a","c":"count:2","s":"oops","m":"avg:1:2","_partial_aggregates":["c","s","m"]}}]"#
+        )
+        .unwrap();
+        drop(file);
+
+        let result = merge(&[first_path, broken_path], OutputMode::Csv);
+        assert_eq!(
+            result,
+            Err(AppError::Merge(MergeError::Aggregate(
+                execution::types::AggregateError::InvalidType
+            )))
+        );
+
+        //A key that reads like a state is still a key
+        let key_path = dir.path().join("partial_3.json");
+        let mut file = File::create(key_path.clone()).unwrap();
+        writeln!(
+            file,
+            r#"[{{"tag":"count:1","c":"count:2","_partial_aggregates":["c"]}},{{"tag":"count:1","c":"count:3","_partial_aggregates":["c"]}}]"#
+        )
+        .unwrap();
+        drop(file);
+        let records = merge_partial_records(&[key_path]).unwrap();
+        assert_eq!(
+            vec![execution::stream::Record::new(
+                vec!["tag".to_string(), "c".to_string()],
+                vec![
+                    common::types::Value::String("count:1".to_string()),
+                    common::types::Value::Int(5),
+                ],
+            )],
+            records.into_iter().collect::<Vec<_>>()
+        );
+
+        //Without the list of aggregate columns the record isn't a partial one
+        let unlisted_path = dir.path().join("partial_4.json");
+        let mut file = File::create(unlisted_path.clone()).unwrap();
+        writeln!(file, r#"[{{"host":"a","c":"count:2"}}]"#).unwrap();
+        drop(file);
+        assert_eq!(
+            Err(AppError::Merge(MergeError::InvalidRecord)),
+            merge_partial_records(&[unlisted_path]).map(|_| ())
+        );
+
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_partial_requires_partial_states() {
+        let plan = |query_str: &str| {
+            let (_, select_stmt) = syntax::parser::select_query(query_str).unwrap();
+            let node = logical::parser::parse_query(select_stmt, common::types::DataSource::Stdin).unwrap();
+            let mut physical_plan_creator =
+                logical::types::PhysicalPlanCreator::new(common::types::DataSource::Stdin).with_partial(true);
+            node.physical(&mut physical_plan_creator).map(|_| ())
+        };
+
+        assert_eq!(
+            Err(logical::types::PhysicalPlanError::NoPartialState("max".to_string())),
+            plan("select elb_status_code, max(sent_bytes) as m from elb group by elb_status_code")
+        );
+        assert_eq!(
+            Ok(()),
+            plan("select elb_status_code, sum(sent_bytes) as s from elb group by elb_status_code")
+        );
+    }
+
+    #[test]
+    fn test_plan_with_seed() {
+        let elb_line = |sent_bytes: usize| {
+            format!(
+                r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 {} "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#,
+                sent_bytes
+            )
+        };
+        let estimate = |data_source: &common::types::DataSource, seed: Option<u64>| {
+            let (_, select_stmt) =
+                syntax::parser::select_query("select approx_count_distinct(sent_bytes) as n from elb").unwrap();
+            let node = logical::parser::parse_query(select_stmt, data_source.clone()).unwrap();
+            let mut physical_plan_creator =
+                logical::types::PhysicalPlanCreator::new(data_source.clone()).with_seed(seed);
+            let (physical_plan, variables) = node.physical(&mut physical_plan_creator).unwrap();
+            let mut stream = physical_plan.get(variables).unwrap();
+            let record = stream.next().unwrap().unwrap();
+            assert_eq!(None, stream.next().unwrap());
+            record.get(&["n".to_string()]).remove(0)
+        };
+
+        let lines: Vec<String> = (0..2).map(elb_line).collect();
+        let (dir, data_source) = elb_log(&lines);
+        assert_eq!(common::types::Value::Int(2), estimate(&data_source, Some(42)));
+        dir.close().unwrap();
+
+        let lines: Vec<String> = (0..40).map(elb_line).collect();
+        let (dir, data_source) = elb_log(&lines);
+        assert_eq!(estimate(&data_source, Some(42)), estimate(&data_source, Some(42)));
+        //A run without a seed uses the fixed default one
+        assert_eq!(
+            estimate(&data_source, Some(logical::types::DEFAULT_SEED)),
+            estimate(&data_source, None)
+        );
+        //Another seed lands the values in other registers and so on another estimate
+        let estimates: HashSet<common::types::Value> = (0..16).map(|seed| estimate(&data_source, Some(seed))).collect();
+        assert!(estimates.len() > 1);
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_check_deterministic_with_seed() {
+        let (_, select_stmt) =
+            syntax::parser::select_query("select approx_count_distinct(sent_bytes) as n from elb").unwrap();
+        let node = logical::parser::parse_query(select_stmt, common::types::DataSource::Stdin).unwrap();
+
+        let options = QueryOptions {
+            strict_deterministic: true,
+            ..QueryOptions::default()
+        };
+        assert!(check_deterministic(&node, &options).is_err());
+
+        let options = QueryOptions {
+            strict_deterministic: true,
+            seed: Some(42),
+            ..QueryOptions::default()
+        };
+        assert!(check_deterministic(&node, &options).is_ok());
+    }
+
+    #[test]
+    fn test_run_query_file() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("log_for_test.log");
+        let mut file = File::create(file_path.clone()).unwrap();
+        writeln!(file, r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 42355 "GET https://example.com:443/ HTTP/1.1" "curl/7.46.0" ECDHE-RSA-AES128-GCM-SHA256 TLSv1.2"#).unwrap();
+        file.sync_all().unwrap();
+        drop(file);
+
+        let query_path = dir.path().join("report.sql");
+        let mut file = File::create(query_path.clone()).unwrap();
+        writeln!(file, "select host_name(backend_and_port) as h,").unwrap();
+        writeln!(file, "    sum(sent_bytes) as s").unwrap();
+        writeln!(file, "from elb").unwrap();
+        writeln!(file, "group by h;").unwrap();
+        drop(file);
+
+        let query = read_query_file(query_path.to_str().unwrap()).unwrap();
+        assert_eq!(
+            "select host_name(backend_and_port) as h, sum(sent_bytes) as s from elb group by h",
+            query
+        );
+        let result = run(
+            &query,
+            common::types::DataSource::File(file_path),
+            false,
+            OutputMode::Csv,
+            &QueryOptions::default(),
+        );
+        assert_eq!(Ok(()), result);
+
+        let missing = dir.path().join("missing.sql");
+        let missing = missing.to_str().unwrap();
+        assert_eq!(
+            Err(AppError::QueryFile(
+                missing.to_string(),
+                io::Error::from(io::ErrorKind::NotFound)
+            )),
+            read_query_file(missing)
+        );
+
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_run_real_mode() {
+        let (dir, data_source) = elb_log(&[
+            r#"2019-06-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 42355 "GET https://example.com:443/ HTTP/1.1" "Mozilla/5.0 (Windows NT 5.1) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/46.0.2490.80 Safari/537.36" ECDHE-RSA-AES128-GCM-SHA256 TLSv1.2"#,
+        ]);
         let result = run(
             r#"select time_bucket("5 seconds", timestamp) as t, sum(sent_bytes) as s from elb group by t order by t asc limit 1"#,
             data_source.clone(),
             false,
             OutputMode::Csv,
+            &QueryOptions::default(),
         );
         assert_eq!(result, Ok(()));
 
@@ -266,6 +3233,7 @@ mod tests {
             data_source.clone(),
             false,
             OutputMode::Csv,
+            &QueryOptions::default(),
         );
         assert_eq!(result, Ok(()));
 
@@ -274,6 +3242,7 @@ mod tests {
             data_source.clone(),
             false,
             OutputMode::Csv,
+            &QueryOptions::default(),
         );
         assert_eq!(result, Ok(()));
 
@@ -18,6 +18,35 @@ pub(crate) enum Value {
     DateTime(chrono::DateTime<chrono::offset::FixedOffset>),
     HttpRequest(common::types::HttpRequest),
     Host(common::types::Host),
+    Array(Vec<Value>),
+}
+
+//The plain text of a value as the output prints it, empty for a null
+pub(crate) fn value_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        Value::Int(i) => i.to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::Boolean(b) => b.to_string(),
+        Value::DateTime(dt) => dt.to_string(),
+        Value::Host(host) => host.to_string(),
+        Value::HttpRequest(request) => request.to_string(),
+        Value::Array(values) => format_array(values),
+        Value::Null => String::new(),
+    }
+}
+
+//The text of an array value, its elements separated by commas within brackets
+pub(crate) fn format_array(values: &[Value]) -> String {
+    let elements: Vec<String> = values
+        .iter()
+        .map(|value| match value {
+            Value::Null => "null".to_string(),
+            value => value_text(value),
+        })
+        .collect();
+
+    format!("[{}]", elements.join(", "))
 }
 
 pub(crate) type ParseHostResult<T> = result::Result<T, ParseHostError>;
@@ -221,7 +250,7 @@ pub(crate) enum DatePartUnit {
 }
 
 pub(crate) fn parse_date_part_unit(s: &str) -> ParseDatePartResult<DatePartUnit> {
-    match s {
+    match s.to_ascii_lowercase().as_str() {
         "second" => Ok(DatePartUnit::Second),
         "minute" => Ok(DatePartUnit::Minute),
         "hour" => Ok(DatePartUnit::Hour),
@@ -233,8 +262,9 @@ pub(crate) fn parse_date_part_unit(s: &str) -> ParseDatePartResult<DatePartUnit>
 }
 
 pub(crate) fn parse_time_interval_unit(s: &str, plural: bool) -> ParseTimeIntervalResult<TimeIntervalUnit> {
+    let s = s.to_ascii_lowercase();
     if plural {
-        match s {
+        match s.as_str() {
             "seconds" => Ok(TimeIntervalUnit::Second),
             "minutes" => Ok(TimeIntervalUnit::Minute),
             "hours" => Ok(TimeIntervalUnit::Hour),
@@ -244,7 +274,7 @@ pub(crate) fn parse_time_interval_unit(s: &str, plural: bool) -> ParseTimeInterv
             _ => Err(ParseTimeIntervalError::UnknownTimeUnit),
         }
     } else {
-        match s {
+        match s.as_str() {
             "second" => Ok(TimeIntervalUnit::Second),
             "minute" => Ok(TimeIntervalUnit::Minute),
             "hour" => Ok(TimeIntervalUnit::Hour),
@@ -303,6 +333,10 @@ pub(crate) fn merge(left: Variables, right: Variables) -> Variables {
 pub(crate) enum DataSource {
     File(PathBuf),
     Stdin,
+    //The literal rows of a VALUES list and the names of their columns
+    Values(Vec<Vec<Value>>, Vec<VariableName>),
+    //Files each already sorted in ascending order of the column, read as a single stream in that order
+    SortedFiles(Vec<PathBuf>, VariableName),
 }
 
 #[cfg(test)]
@@ -0,0 +1,233 @@
+use super::datasource::RecordRead;
+use super::stream::{InMemoryStream, Record};
+use super::types::{CreateStreamError, Node, StreamResult};
+use crate::common::types::Variables;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, Read};
+use std::path::Path;
+
+//The rows and bytes an operator is expected to emit over the whole file, extrapolated from running
+//the plan over a sample taken from the start of the file. Only a rough guide: a sample from the start
+//misses whatever changes later in the file, and the groups of a group by are assumed to keep growing
+//with the input.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct CostEstimate {
+    pub(crate) operator: String,
+    pub(crate) rows: f64,
+    pub(crate) bytes: f64,
+    pub(crate) children: Vec<CostEstimate>,
+}
+
+impl CostEstimate {
+    fn write_tree(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        writeln!(
+            f,
+            "{}{} (estimated rows: {:.0}, estimated bytes: {:.0})",
+            "  ".repeat(depth),
+            self.operator,
+            self.rows,
+            self.bytes
+        )?;
+        for child in self.children.iter() {
+            child.write_tree(f, depth + 1)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for CostEstimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_tree(f, 0)
+    }
+}
+
+struct Sample {
+    records: Vec<Record>,
+    //The rows of the whole file per row of the sample
+    scale: f64,
+    bytes_per_row: f64,
+    fields_per_row: f64,
+}
+
+pub(crate) fn estimate_cost(
+    plan: &Node,
+    variables: &Variables,
+    path: &Path,
+    sample_bytes: usize,
+) -> StreamResult<CostEstimate> {
+    let (source, plan) = plan.clone().detach_source();
+    let reader_builder = match source {
+        Node::DataSource(_, reader_builder) => reader_builder,
+        _ => {
+            return Err(CreateStreamError::Stream.into());
+        }
+    };
+
+    //Whole lines only, up to the sample size
+    let file_size = std::fs::metadata(path).map_err(CreateStreamError::from)?.len();
+    let file = File::open(path).map_err(CreateStreamError::from)?;
+    let mut chunk = String::new();
+    let mut lines = io::BufReader::new(file.take(sample_bytes as u64));
+    loop {
+        let mut line = String::new();
+        if lines.read_line(&mut line).map_err(CreateStreamError::from)? == 0 || !line.ends_with('\n') {
+            break;
+        }
+        chunk.push_str(&line);
+    }
+
+    let mut reader = reader_builder.with_reader(chunk.as_bytes());
+    let mut records = Vec::new();
+    while let Some(record) = reader.read_record()? {
+        records.push(record);
+    }
+    if records.is_empty() {
+        return Ok(CostEstimate {
+            operator: operator_name(&plan),
+            rows: 0.0,
+            bytes: 0.0,
+            children: Vec::new(),
+        });
+    }
+
+    let sample_rows = records.len() as f64;
+    let sample = Sample {
+        scale: (file_size as f64 / chunk.len() as f64).max(1.0),
+        bytes_per_row: chunk.len() as f64 / sample_rows,
+        fields_per_row: records.iter().map(|r| r.field_names().len()).sum::<usize>() as f64 / sample_rows,
+        records,
+    };
+
+    estimate_node(&plan, variables, &sample)
+}
+
+fn operator_name(node: &Node) -> String {
+    match node {
+        Node::TeeInput => "DataSource".to_string(),
+        _ => node.to_json()["operator"].as_str().unwrap_or_default().to_string(),
+    }
+}
+
+fn children(node: &Node) -> Vec<&Node> {
+    match node {
+        Node::Filter(source, _)
+        | Node::Map(_, source)
+        | Node::GroupBy { source, .. }
+        | Node::Limit(_, source)
+        | Node::OrderBy { source, .. }
+        | Node::ChangedBy(_, source)
+        | Node::HashOrder(_, source, _)
+        | Node::EnforceSchema(_, _, source)
+        | Node::Distinct { source, .. }
+        | Node::DropColumns(_, source)
+        | Node::Unnest(_, source) => vec![source],
+        Node::Tee(source, detail, summary, _) => vec![source, detail, summary],
+        Node::Join { left, right, .. } => vec![left, right],
+        Node::MergeSorted(_, sources) => sources.iter().collect(),
+        Node::DataSource(_, _) | Node::TeeInput => Vec::new(),
+    }
+}
+
+fn estimate_node(node: &Node, variables: &Variables, sample: &Sample) -> StreamResult<CostEstimate> {
+    let mut child_estimates = Vec::new();
+    for child in children(node) {
+        child_estimates.push(estimate_node(child, variables, sample)?);
+    }
+
+    let input = InMemoryStream::new(sample.records.iter().cloned().collect());
+    let mut stream = node.get_with_input(variables.clone(), Some(Box::new(input)))?;
+    let mut sample_rows = 0.0;
+    let mut sample_fields = 0.0;
+    while let Some(record) = stream.next()? {
+        sample_rows += 1.0;
+        sample_fields += record.field_names().len() as f64;
+    }
+
+    let rows = match node {
+        //A single group whatever the input
+        Node::GroupBy { fields, .. } if fields.is_empty() => sample_rows,
+        Node::Limit(row_count, _) if *row_count >= 0 => {
+            let input_rows = child_estimates.first().map(|c| c.rows).unwrap_or(0.0);
+            input_rows.min(f64::from(*row_count))
+        }
+        _ => sample_rows * sample.scale,
+    };
+    //The bytes of a row are taken to shrink and grow with the number of its fields
+    let bytes = if sample_rows > 0.0 {
+        rows * sample.bytes_per_row * (sample_fields / sample_rows) / sample.fields_per_row
+    } else {
+        0.0
+    };
+
+    Ok(CostEstimate {
+        operator: operator_name(node),
+        rows,
+        bytes,
+        children: child_estimates,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::types::DataSource;
+    use crate::logical;
+    use crate::syntax;
+    use std::io::Write;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_estimate_cost() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("elb.log");
+        let mut file = File::create(&path).unwrap();
+        for i in 0..1000 {
+            writeln!(file, r#"2015-11-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.{}:80 0.000036 0.001035 0.000025 200 200 0 {} "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#, i % 4, i * 7919 % 1000).unwrap();
+        }
+        drop(file);
+
+        let query_str =
+            "select backend_and_port, count(*) as c from elb where sent_bytes > 499 group by backend_and_port limit 2";
+        let (_, select_stmt) = syntax::parser::select_query(query_str).unwrap();
+        let data_source = DataSource::File(path.clone());
+        let node = logical::parser::parse_query(select_stmt, data_source.clone()).unwrap();
+        let mut physical_plan_creator = logical::types::PhysicalPlanCreator::new(data_source);
+        let (plan, variables) = node.physical(&mut physical_plan_creator).unwrap();
+
+        //A sample of a tenth of the file
+        let file_size = std::fs::metadata(&path).unwrap().len() as usize;
+        let estimate = estimate_cost(&plan, &variables, &path, file_size / 10).unwrap();
+
+        let mut chain = Vec::new();
+        let mut current = &estimate;
+        loop {
+            chain.push(current);
+            match current.children.first() {
+                Some(child) => current = child,
+                None => break,
+            }
+        }
+        let operators: Vec<&str> = chain.iter().map(|c| c.operator.as_str()).collect();
+        assert_eq!(vec!["Limit", "GroupBy", "Filter", "Map", "DataSource"], operators);
+
+        //Roughly the 1000 rows of the file, half of them pass the filter, then 4 groups and the limit
+        let source = chain[4];
+        assert!((source.rows - 1000.0).abs() < 50.0, "{}", estimate);
+        assert!(
+            (source.bytes - file_size as f64).abs() < file_size as f64 * 0.05,
+            "{}",
+            estimate
+        );
+        assert_eq!(2.0, chain[0].rows);
+        for pair in chain.windows(2) {
+            assert!(pair[0].rows <= pair[1].rows, "{}", estimate);
+        }
+        //The filter keeps the columns of the projection, only the rows drop
+        assert!(chain[2].rows > 300.0 && chain[2].rows < 700.0, "{}", estimate);
+        assert!(chain[2].bytes < chain[3].bytes, "{}", estimate);
+
+        dir.close().unwrap();
+    }
+}
@@ -1,17 +1,23 @@
-use super::stream::Record;
+use super::stream::{Record, Schema};
+use super::warnings::{self, WarningKind};
 use crate::common;
 use crate::common::types::Value;
+use flate2::read::MultiGzDecoder;
 use ordered_float::OrderedFloat;
 use regex::Regex;
 use url;
 
+use std::borrow::Cow;
 use std::fmt;
 use std::fs::File;
 use std::io;
 use std::io::BufRead;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::result;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub(crate) enum DataType {
@@ -143,7 +149,7 @@ lazy_static! {
             "chosen_cert_arn".to_string(),
             "matched_rule_priority".to_string(),
             "request_creation_time".to_string(),
-            "action_executed".to_string(),
+            "actions_executed".to_string(),
             "redirect_url".to_string(),
             "error_reason".to_string(),
         ]
@@ -246,6 +252,38 @@ lazy_static! {
     };
 }
 
+lazy_static! {
+    static ref NGINX_FIELD_NAMES: Vec<String> = {
+        vec![
+            "remote_addr".to_string(),
+            "ident".to_string(),
+            "remote_user".to_string(),
+            "time_local".to_string(),
+            "request".to_string(),
+            "status".to_string(),
+            "body_bytes_sent".to_string(),
+            "referer".to_string(),
+            "user_agent".to_string(),
+        ]
+    };
+}
+
+lazy_static! {
+    static ref NGINX_DATATYPES: Vec<DataType> = {
+        vec![
+            DataType::String,
+            DataType::String,
+            DataType::String,
+            DataType::DateTime,
+            DataType::String,
+            DataType::String,
+            DataType::Integral,
+            DataType::String,
+            DataType::String,
+        ]
+    };
+}
+
 //Reference: https://docs.aws.amazon.com/elasticloadbalancing/latest/classic/access-log-collection.html
 pub(crate) enum ClassicLoadBalancerLogField {
     Timestamp = 0,
@@ -380,14 +418,14 @@ impl FromStr for ApplicationLoadBalancerLogField {
         match s {
             "type" => Ok(ApplicationLoadBalancerLogField::Type),
             "timestamp" => Ok(ApplicationLoadBalancerLogField::Timestamp),
-            "elbname" => Ok(ApplicationLoadBalancerLogField::Elbname),
+            "elb" => Ok(ApplicationLoadBalancerLogField::Elbname),
             "client_and_port" => Ok(ApplicationLoadBalancerLogField::ClientAndPort),
-            "backend_and_port" => Ok(ApplicationLoadBalancerLogField::TargetAndPort),
+            "target_and_port" => Ok(ApplicationLoadBalancerLogField::TargetAndPort),
             "request_processing_time" => Ok(ApplicationLoadBalancerLogField::RequestProcessingTime),
-            "backend_processing_time" => Ok(ApplicationLoadBalancerLogField::TargetProcessingTime),
+            "target_processing_time" => Ok(ApplicationLoadBalancerLogField::TargetProcessingTime),
             "response_processing_time" => Ok(ApplicationLoadBalancerLogField::ResponseProcessingTime),
             "elb_status_code" => Ok(ApplicationLoadBalancerLogField::ELBStatusCode),
-            "backend_status_code" => Ok(ApplicationLoadBalancerLogField::TargetStatusCode),
+            "target_status_code" => Ok(ApplicationLoadBalancerLogField::TargetStatusCode),
             "received_bytes" => Ok(ApplicationLoadBalancerLogField::ReceivedBytes),
             "sent_bytes" => Ok(ApplicationLoadBalancerLogField::SentBytes),
             "request" => Ok(ApplicationLoadBalancerLogField::Request),
@@ -400,7 +438,7 @@ impl FromStr for ApplicationLoadBalancerLogField {
             "chosen_cert_arn" => Ok(ApplicationLoadBalancerLogField::ChosenCertArn),
             "matched_rule_priority" => Ok(ApplicationLoadBalancerLogField::MatchedRulePriority),
             "request_creation_time" => Ok(ApplicationLoadBalancerLogField::RequestCreationTime),
-            "action_executed" => Ok(ApplicationLoadBalancerLogField::ActionExecuted),
+            "actions_executed" => Ok(ApplicationLoadBalancerLogField::ActionExecuted),
             "redirect_url" => Ok(ApplicationLoadBalancerLogField::RedirectUrl),
             "error_reason" => Ok(ApplicationLoadBalancerLogField::ErrorReason),
             _ => Err("unknown column name".to_string()),
@@ -576,6 +614,64 @@ impl SquidLogField {
     }
 }
 
+//The combined format, the default of nginx
+//Reference: http://nginx.org/en/docs/http/ngx_http_log_module.html#log_format
+pub(crate) enum NginxLogField {
+    RemoteAddr = 0,
+    //The identd user of the common log format, nginx always writes "-"
+    Ident = 1,
+    RemoteUser = 2,
+    TimeLocal = 3,
+    Request = 4,
+    Status = 5,
+    BodyBytesSent = 6,
+    Referer = 7,
+    UserAgent = 8,
+}
+
+impl FromStr for NginxLogField {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "remote_addr" => Ok(NginxLogField::RemoteAddr),
+            "ident" => Ok(NginxLogField::Ident),
+            "remote_user" => Ok(NginxLogField::RemoteUser),
+            "time_local" => Ok(NginxLogField::TimeLocal),
+            "request" => Ok(NginxLogField::Request),
+            "status" => Ok(NginxLogField::Status),
+            "body_bytes_sent" => Ok(NginxLogField::BodyBytesSent),
+            "referer" => Ok(NginxLogField::Referer),
+            "user_agent" => Ok(NginxLogField::UserAgent),
+            _ => Err("unknown column name".to_string()),
+        }
+    }
+}
+
+impl NginxLogField {
+    pub(crate) fn len() -> usize {
+        9
+    }
+
+    pub(crate) fn field_names() -> Vec<String> {
+        NGINX_FIELD_NAMES.clone()
+    }
+
+    pub(crate) fn datatypes() -> Vec<DataType> {
+        NGINX_DATATYPES.clone()
+    }
+
+    pub(crate) fn datatype(idx: usize) -> DataType {
+        NGINX_DATATYPES[idx].clone()
+    }
+
+    pub(crate) fn schema() -> Vec<(String, DataType)> {
+        let fields = Self::field_names();
+        let datatypes = Self::datatypes();
+        fields.into_iter().zip(datatypes.into_iter()).collect()
+    }
+}
+
 pub(crate) type ReaderResult<T> = result::Result<T, ReaderError>;
 
 #[derive(Fail, Debug)]
@@ -594,6 +690,33 @@ pub(crate) enum ReaderError {
     ParseHost(#[cause] common::types::ParseHostError),
     #[fail(display = "{}", _0)]
     ParseHttpRequest(#[cause] common::types::ParseHttpRequestError),
+    #[fail(display = "Unknown log format, please specify the table")]
+    UnknownFormat,
+    #[fail(display = "Ambiguous log format, please specify one of: {}", _0)]
+    AmbiguousFormat(String),
+    #[fail(display = "No \"{}\" line names the fields before the first record", _0)]
+    MissingFieldsDirective(String),
+    #[fail(display = "{}", _0)]
+    ParseJson(#[cause] json::Error),
+    #[fail(display = "The line is not a JSON object")]
+    NotJsonObject,
+}
+
+impl ReaderError {
+    //A line that doesn't parse, as opposed to an input that can't be read at all
+    fn is_line_error(&self) -> bool {
+        matches!(
+            self,
+            ReaderError::ParseDateTime(_)
+                | ReaderError::ParseIntegral(_)
+                | ReaderError::ParseFloat(_)
+                | ReaderError::ParseUrl(_)
+                | ReaderError::ParseHost(_)
+                | ReaderError::ParseHttpRequest(_)
+                | ReaderError::ParseJson(_)
+                | ReaderError::NotJsonObject
+        )
+    }
 }
 
 impl From<io::Error> for ReaderError {
@@ -602,6 +725,12 @@ impl From<io::Error> for ReaderError {
     }
 }
 
+impl From<json::Error> for ReaderError {
+    fn from(err: json::Error) -> ReaderError {
+        ReaderError::ParseJson(err)
+    }
+}
+
 impl From<chrono::format::ParseError> for ReaderError {
     fn from(err: chrono::format::ParseError) -> ReaderError {
         ReaderError::ParseDateTime(err)
@@ -638,10 +767,234 @@ impl From<url::ParseError> for ReaderError {
     }
 }
 
-#[derive(Debug)]
+//How numeric tokens are written in the log. Grouped numbers like "1,536,000" are opt-in because
+//the separators would otherwise be rejected by the numeric parsing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum NumberFormat {
+    #[default]
+    Plain,
+    //"1,536,000" and "1,234.56"
+    CommaGrouped,
+    //"1.536.000" and "1.234,56"
+    DecimalComma,
+}
+
+impl FromStr for NumberFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(NumberFormat::Plain),
+            "comma-grouped" => Ok(NumberFormat::CommaGrouped),
+            "decimal-comma" => Ok(NumberFormat::DecimalComma),
+            _ => Err("unknown number format".to_string()),
+        }
+    }
+}
+
+//The pattern the first line of a record matches, the lines that don't are continuations of the record
+//before like the lines of a stack trace
+#[derive(Debug, Clone)]
+pub(crate) struct RecordStart(Regex);
+
+impl PartialEq for RecordStart {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for RecordStart {}
+
+impl FromStr for RecordStart {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        Regex::new(s)
+            .map(RecordStart)
+            .map_err(|e| format!("invalid multiline pattern: {}", e))
+    }
+}
+
+impl NumberFormat {
+    fn normalize<'a>(self, s: &'a str) -> Cow<'a, str> {
+        match self {
+            NumberFormat::Plain => Cow::Borrowed(s),
+            NumberFormat::CommaGrouped => Cow::Owned(s.replace(',', "")),
+            NumberFormat::DecimalComma => Cow::Owned(s.replace('.', "").replace(',', ".")),
+        }
+    }
+}
+
+lazy_static! {
+    static ref SPLIT_THE_LINE_REGEX: Regex = Regex::new(r#"[^\s"'\[\]]+|"([^"]*)"|'([^']*)'|\[([^\[\]]*)\]"#).unwrap();
+}
+
+fn parse_value(datatype: &DataType, s: &str, number_format: NumberFormat) -> ReaderResult<Value> {
+    match datatype {
+        DataType::DateTime => {
+            //The common log format of nginx writes the time as [10/Oct/2000:13:55:36 -0700]
+            let dt = if s.starts_with('[') {
+                chrono::DateTime::parse_from_str(
+                    s.trim_start_matches('[').trim_end_matches(']'),
+                    "%d/%b/%Y:%H:%M:%S %z",
+                )?
+            } else {
+                chrono::DateTime::parse_from_rfc3339(s)?
+            };
+            Ok(Value::DateTime(dt))
+        }
+        DataType::String => Ok(Value::String(s.to_string())),
+        DataType::Integral => {
+            let i = number_format.normalize(s).parse::<i32>()?;
+            Ok(Value::Int(i))
+        }
+        DataType::Float => {
+            let f = number_format.normalize(s).parse::<f32>()?;
+            Ok(Value::Float(OrderedFloat::from(f)))
+        }
+        DataType::Host => {
+            if s == "-" {
+                Ok(Value::Null)
+            } else {
+                let host = common::types::parse_host(s)?;
+                Ok(Value::Host(host))
+            }
+        }
+        DataType::HttpRequest => {
+            let s = s.trim_matches('"');
+            let request = common::types::parse_http_request(s)?;
+            Ok(Value::HttpRequest(request))
+        }
+    }
+}
+
+//Whether a line fits the table: no more tokens than the table has fields, every typed field present
+//and parsable. A table of plain strings can't be told apart by its types, so it needs every field.
+fn matches_table(line: &str, datatypes: &[DataType]) -> bool {
+    let tokens: Vec<&str> = SPLIT_THE_LINE_REGEX.find_iter(line).map(|m| m.as_str()).collect();
+    let required = datatypes
+        .iter()
+        .rposition(|datatype| *datatype != DataType::String)
+        .map_or(datatypes.len(), |last_typed| last_typed + 1);
+    if tokens.len() < required || tokens.len() > datatypes.len() {
+        return false;
+    }
+
+    tokens
+        .iter()
+        .zip(datatypes.iter())
+        .all(|(token, datatype)| parse_value(datatype, token, NumberFormat::default()).is_ok())
+}
+
+//A JSON value of a line, a number is integral when it is a whole one within range. An object in an
+//array has no column of its own and is kept as its JSON text.
+fn json_value(value: &json::JsonValue) -> Value {
+    if let Some(s) = value.as_str() {
+        Value::String(s.to_string())
+    } else if let Some(b) = value.as_bool() {
+        Value::Boolean(b)
+    } else if let Some(i) = value.as_i32() {
+        Value::Int(i)
+    } else if let Some(f) = value.as_f32() {
+        Value::Float(OrderedFloat::from(f))
+    } else if value.is_array() {
+        Value::Array(value.members().map(json_value).collect())
+    } else if value.is_object() {
+        Value::String(value.dump())
+    } else {
+        Value::Null
+    }
+}
+
+//The keys of an object as fields, a nested object is flattened so {"a": {"b": 1}} is the field "a_b"
+fn flatten_json_object(prefix: Option<&str>, object: &json::JsonValue, fields: &mut Vec<(String, Value)>) {
+    for (key, value) in object.entries() {
+        let name = match prefix {
+            Some(prefix) => format!("{}.{}", prefix, key),
+            None => key.to_string(),
+        };
+        if value.is_object() {
+            flatten_json_object(Some(&name), value, fields);
+        } else {
+            fields.push((normalize_field_name(&name), json_value(value)));
+        }
+    }
+}
+
+//Pick the table whose format fits the first non-empty lines of the log
+pub(crate) fn detect_format<R: BufRead>(rdr: R, sample_size: usize) -> ReaderResult<String> {
+    let tables = [
+        ("elb", ClassicLoadBalancerLogField::datatypes()),
+        ("alb", ApplicationLoadBalancerLogField::datatypes()),
+        ("s3", S3Field::datatypes()),
+        ("squid", SquidLogField::datatypes()),
+        ("nginx", NginxLogField::datatypes()),
+    ];
+
+    let mut lines = Vec::new();
+    for line in rdr.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        lines.push(line);
+        if lines.len() >= sample_size {
+            break;
+        }
+    }
+    if lines.is_empty() {
+        return Err(ReaderError::UnknownFormat);
+    }
+    if lines
+        .iter()
+        .all(|line| json::parse(line).map(|value| value.is_object()).unwrap_or(false))
+    {
+        return Ok("json".to_string());
+    }
+
+    let candidates: Vec<&str> = tables
+        .iter()
+        .filter(|(_, datatypes)| lines.iter().all(|line| matches_table(line, datatypes)))
+        .map(|(table_name, _)| *table_name)
+        .collect();
+
+    match candidates.len() {
+        0 => Err(ReaderError::UnknownFormat),
+        1 => Ok(candidates[0].to_string()),
+        _ => Err(ReaderError::AmbiguousFormat(candidates.join(", "))),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub(crate) struct ReaderBuilder {
     capacity: usize,
     table_name: String,
+    raw_line: bool,
+    number_format: NumberFormat,
+    source_column: Option<String>,
+    follow: bool,
+    fields_directive: String,
+    skip_bad_lines: bool,
+    multiline: Option<RecordStart>,
+    json_fields: Vec<String>,
+}
+
+pub(crate) const DEFAULT_FIELDS_DIRECTIVE: &str = "#Fields:";
+
+//"cs-uri-stem" or "cs(User-Agent)" can't be written as a column in a query, "cs_uri_stem" and
+//"cs_user_agent" can
+fn normalize_field_name(name: &str) -> String {
+    let normalized: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    normalized.trim_matches('_').to_string()
 }
 
 pub(crate) trait RecordRead {
@@ -653,16 +1006,190 @@ impl ReaderBuilder {
         ReaderBuilder {
             capacity: 8 * (1 << 10),
             table_name,
+            raw_line: false,
+            number_format: NumberFormat::default(),
+            source_column: None,
+            follow: false,
+            fields_directive: DEFAULT_FIELDS_DIRECTIVE.to_string(),
+            skip_bad_lines: false,
+            multiline: None,
+            json_fields: Vec::new(),
         }
     }
 
+    pub(crate) fn table_name(&self) -> &str {
+        &self.table_name
+    }
+
+    //The fields of the records before any is read, a log declaring its own fields only has them in the file
+    pub(crate) fn field_names(&self) -> Option<Vec<String>> {
+        let mut field_names = match self.table_name.as_str() {
+            "elb" => ClassicLoadBalancerLogField::field_names(),
+            "alb" => ApplicationLoadBalancerLogField::field_names(),
+            "s3" => S3Field::field_names(),
+            "nginx" => NginxLogField::field_names(),
+            "w3c" | "json" => return None,
+            _ => SquidLogField::field_names(),
+        };
+        if self.raw_line {
+            field_names.push("line".to_string());
+        }
+        if let Some(column_name) = &self.source_column {
+            field_names.push(column_name.clone());
+        }
+
+        Some(field_names)
+    }
+
+    pub(crate) fn number_format(&mut self, number_format: NumberFormat) -> &mut ReaderBuilder {
+        self.number_format = number_format;
+        self
+    }
+
+    //Append the unparsed line as the "line" field of every record
+    pub(crate) fn raw_line(&mut self, yes: bool) -> &mut ReaderBuilder {
+        self.raw_line = yes;
+        self
+    }
+
+    //Append the file every record was read from as a field with the given name
+    pub(crate) fn source_column(&mut self, column_name: Option<String>) -> &mut ReaderBuilder {
+        self.source_column = column_name;
+        self
+    }
+
+    //The prefix of the line naming the fields of a "w3c" log
+    pub(crate) fn fields_directive(&mut self, fields_directive: String) -> &mut ReaderBuilder {
+        self.fields_directive = fields_directive;
+        self
+    }
+
+    //Skip the lines that don't parse with a warning instead of failing the query on the first one
+    pub(crate) fn skip_bad_lines(&mut self, yes: bool) -> &mut ReaderBuilder {
+        self.skip_bad_lines = yes;
+        self
+    }
+
+    //Join the lines not matching the start of a record to the record before, its "line" field holds them all
+    pub(crate) fn multiline(&mut self, record_start: Option<RecordStart>) -> &mut ReaderBuilder {
+        self.multiline = record_start;
+        self
+    }
+
+    //The keys every record of a "json" log has from the first line on, null on a line without them, so that a
+    //query naming a key that only shows up on a later line doesn't fail on the lines before
+    pub(crate) fn json_fields(&mut self, field_names: Vec<String>) -> &mut ReaderBuilder {
+        self.json_fields = field_names;
+        self
+    }
+
+    //Keep reading a named pipe across its writers instead of stopping when the last one closes
+    pub(crate) fn follow(&mut self, yes: bool) -> &mut ReaderBuilder {
+        self.follow = yes;
+        self
+    }
+
+    pub(crate) fn with_fifo_path<P: AsRef<Path>>(&self, path: P) -> ReaderResult<Reader<FifoRead>> {
+        let source = path.as_ref().to_string_lossy().to_string();
+        let fifo = FifoRead::open(path.as_ref(), self.follow)?;
+        Ok(Reader::new(self, fifo, self.table_name.clone()).with_source(self, source))
+    }
+
     pub(crate) fn with_path<P: AsRef<Path>>(&self, path: P) -> ReaderResult<Reader<File>> {
-        Ok(Reader::new(self, File::open(path)?, self.table_name.clone()))
+        let source = path.as_ref().to_string_lossy().to_string();
+        Ok(Reader::new(self, File::open(path)?, self.table_name.clone()).with_source(self, source))
+    }
+
+    //Exports may concatenate several gzip members into one file, so keep decoding past the first member
+    pub(crate) fn with_gzip_path<P: AsRef<Path>>(&self, path: P) -> ReaderResult<Reader<MultiGzDecoder<File>>> {
+        let source = path.as_ref().to_string_lossy().to_string();
+        let decoder = MultiGzDecoder::new(File::open(path)?);
+        Ok(Reader::new(self, decoder, self.table_name.clone()).with_source(self, source))
     }
 
     #[allow(dead_code)]
     pub(crate) fn with_reader<R: io::Read>(&self, rdr: R) -> Reader<R> {
-        Reader::new(self, rdr, self.table_name.clone())
+        Reader::new(self, rdr, self.table_name.clone()).with_source(self, "stdin".to_string())
+    }
+
+    //A stream has no file extension to tell, so `cat access.log.gz | logq ...` is recognized by the
+    //gzip magic of its first bytes instead
+    pub(crate) fn with_sniffed_reader<R: io::Read + 'static>(&self, rdr: R) -> ReaderResult<Reader<Box<dyn io::Read>>> {
+        Ok(self.with_reader(sniff_gzip(rdr)?))
+    }
+}
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+//The first bytes are read ahead to look for the gzip magic and put back in front of the rest, so a plain
+//stream reads exactly as before. A short read of a pipe is retried until the magic is complete or the
+//stream ends.
+pub(crate) fn sniff_gzip<R: io::Read + 'static>(mut rdr: R) -> io::Result<Box<dyn io::Read>> {
+    use std::io::Read;
+
+    let mut head = Vec::with_capacity(GZIP_MAGIC.len());
+    rdr.by_ref().take(GZIP_MAGIC.len() as u64).read_to_end(&mut head)?;
+
+    let is_gzip = head == GZIP_MAGIC;
+    let rdr = io::Cursor::new(head).chain(rdr);
+    if is_gzip {
+        Ok(Box::new(MultiGzDecoder::new(rdr)))
+    } else {
+        Ok(Box::new(rdr))
+    }
+}
+
+#[cfg(unix)]
+pub(crate) fn is_fifo<P: AsRef<Path>>(path: P) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    std::fs::metadata(path)
+        .map(|metadata| metadata.file_type().is_fifo())
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_fifo<P: AsRef<Path>>(_path: P) -> bool {
+    false
+}
+
+const FIFO_RETRY_DELAY: Duration = Duration::from_millis(10);
+
+//A named pipe reads zero bytes once its last writer has closed, although the next writer of a pipeline
+//like `cat a.log > pipe; cat b.log > pipe` may be about to open it. Without following, that empty read
+//is the end of the input as for a file. When following, the pipe is reopened, which blocks until the
+//next writer shows up, so the reader only ever sees an end when it is dropped.
+#[derive(Debug)]
+pub(crate) struct FifoRead {
+    path: PathBuf,
+    file: File,
+    follow: bool,
+}
+
+impl FifoRead {
+    //Blocks until a writer opens the other end
+    pub(crate) fn open(path: &Path, follow: bool) -> io::Result<FifoRead> {
+        Ok(FifoRead {
+            path: path.to_path_buf(),
+            file: File::open(path)?,
+            follow,
+        })
+    }
+}
+
+impl io::Read for FifoRead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.file.read(buf) {
+                Ok(0) if self.follow && !buf.is_empty() => {
+                    self.file = File::open(&self.path)?;
+                }
+                Ok(n) => return Ok(n),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(FIFO_RETRY_DELAY),
+                Err(e) => return Err(e),
+            }
+        }
     }
 }
 
@@ -670,6 +1197,34 @@ impl ReaderBuilder {
 pub(crate) struct Reader<R> {
     rdr: io::BufReader<R>,
     table_name: String,
+    raw_line: bool,
+    number_format: NumberFormat,
+    source: Option<(String, Value)>,
+    fields_directive: String,
+    declared_fields: Option<Vec<String>>,
+    schema: Option<Arc<Schema>>,
+    skip_bad_lines: bool,
+    line_number: u64,
+    multiline: Option<RecordStart>,
+    //The line read past the end of a multiline record, the first line of the next one
+    pending_line: Option<String>,
+    continued_lines: u64,
+}
+
+//The "line" and source fields are appended after the keys, a key of the same name is left to the line
+fn json_declared_fields(builder: &ReaderBuilder, table_name: &str) -> Option<Vec<String>> {
+    if table_name != "json" || builder.json_fields.is_empty() {
+        return None;
+    }
+
+    let field_names = builder
+        .json_fields
+        .iter()
+        .filter(|field_name| !(builder.raw_line && field_name.as_str() == "line"))
+        .filter(|field_name| builder.source_column.as_deref() != Some(field_name.as_str()))
+        .cloned()
+        .collect();
+    Some(field_names)
 }
 
 impl<R: io::Read> Reader<R> {
@@ -677,34 +1232,216 @@ impl<R: io::Read> Reader<R> {
         Reader {
             rdr: io::BufReader::with_capacity(builder.capacity, rdr),
             table_name,
+            raw_line: builder.raw_line,
+            number_format: builder.number_format,
+            source: None,
+            fields_directive: builder.fields_directive.clone(),
+            declared_fields: json_declared_fields(builder, &table_name),
+            schema: None,
+            skip_bad_lines: builder.skip_bad_lines,
+            line_number: 0,
+            multiline: builder.multiline.clone(),
+            pending_line: None,
+            continued_lines: 0,
         }
     }
 
+    fn with_source(mut self, builder: &ReaderBuilder, source: String) -> Reader<R> {
+        self.source = builder
+            .source_column
+            .as_ref()
+            .map(|column_name| (column_name.clone(), Value::String(source)));
+        self
+    }
+
     #[allow(dead_code)]
     fn close(&self) {}
+
+    fn push_extra_values(&self, line: &str, values: &mut Vec<Value>) {
+        if self.raw_line {
+            values.push(Value::String(line.to_string()));
+        }
+
+        if let Some((_, source)) = &self.source {
+            values.push(source.clone());
+        }
+    }
+
+    //The fields of the log followed by the extra ones, shared by every record until a new declaration
+    fn schema(&mut self) -> Arc<Schema> {
+        if let Some(schema) = &self.schema {
+            return schema.clone();
+        }
+
+        let mut field_names = if self.table_name == "elb" {
+            ClassicLoadBalancerLogField::field_names()
+        } else if self.table_name == "alb" {
+            ApplicationLoadBalancerLogField::field_names()
+        } else if self.table_name == "s3" {
+            S3Field::field_names()
+        } else if self.table_name == "nginx" {
+            NginxLogField::field_names()
+        } else if self.table_name == "w3c" || self.table_name == "json" {
+            self.declared_fields.clone().unwrap_or_default()
+        } else {
+            SquidLogField::field_names()
+        };
+        if self.raw_line {
+            field_names.push("line".to_string());
+        }
+        if let Some((column_name, _)) = &self.source {
+            field_names.push(column_name.clone());
+        }
+
+        let schema = Arc::new(Schema::new(field_names));
+        self.schema = Some(schema.clone());
+        schema
+    }
+
+    //A log declaring its own fields in the W3C extended style: the "#Fields: a b c" line names the
+    //fields of the lines after it, any other line starting with "#" is a comment. The declaration has
+    //no types so every value is a string.
+    fn read_declared_record(&mut self) -> ReaderResult<Option<Record>> {
+        loop {
+            let mut buf = String::new();
+            if self.rdr.read_line(&mut buf)? == 0 {
+                return Ok(None);
+            }
+
+            let line = buf.trim_end_matches(&['\r', '\n'][..]);
+            if line.starts_with(&self.fields_directive) {
+                let declaration = &line[self.fields_directive.len()..];
+                self.declared_fields = Some(declaration.split_whitespace().map(normalize_field_name).collect());
+                self.schema = None;
+                continue;
+            }
+            if line.starts_with('#') || line.trim().is_empty() {
+                continue;
+            }
+
+            let field_count = match &self.declared_fields {
+                Some(field_names) => field_names.len(),
+                None => {
+                    return Err(ReaderError::MissingFieldsDirective(self.fields_directive.clone()));
+                }
+            };
+            let mut values: Vec<Value> = SPLIT_THE_LINE_REGEX
+                .find_iter(line)
+                .take(field_count)
+                .map(|m| Value::String(m.as_str().to_string()))
+                .collect();
+            while values.len() < field_count {
+                values.push(Value::Null);
+            }
+            self.push_extra_values(line, &mut values);
+
+            return Ok(Some(Record::with_schema(self.schema(), values)));
+        }
+    }
 }
 
-impl<R: io::Read> RecordRead for Reader<R> {
-    fn read_record(&mut self) -> ReaderResult<Option<Record>> {
+impl<R: io::Read> Reader<R> {
+    //The next record's lines, a single one unless the lines after it continue it under multiline
+    fn read_lines(&mut self, buf: &mut String) -> io::Result<usize> {
+        let mut read = match self.pending_line.take() {
+            Some(line) => {
+                buf.push_str(&line);
+                line.len()
+            }
+            None => self.rdr.read_line(buf)?,
+        };
+        if read == 0 {
+            return Ok(0);
+        }
+        self.line_number += 1 + self.continued_lines;
+        self.continued_lines = 0;
+
+        if let Some(RecordStart(record_start)) = &self.multiline {
+            loop {
+                let mut line = String::new();
+                if self.rdr.read_line(&mut line)? == 0 {
+                    break;
+                }
+                if record_start.is_match(&line) {
+                    self.pending_line = Some(line);
+                    break;
+                }
+
+                //The last line may come without a newline
+                if !buf.ends_with('\n') {
+                    buf.push('\n');
+                }
+                read += line.len();
+                buf.push_str(&line);
+                self.continued_lines += 1;
+            }
+        }
+
+        Ok(read)
+    }
+
+    //A JSON object per line, each key a field. The fields are the keys seen so far in the order they first
+    //appeared, a key a line doesn't have is null on it.
+    fn read_json_record(&mut self) -> ReaderResult<Option<Record>> {
+        loop {
+            let mut buf = String::new();
+            if self.read_lines(&mut buf)? == 0 {
+                return Ok(None);
+            }
+
+            let line = buf.trim_end_matches(&['\r', '\n'][..]);
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let object = json::parse(line)?;
+            if !object.is_object() {
+                return Err(ReaderError::NotJsonObject);
+            }
+            let mut fields = Vec::new();
+            flatten_json_object(None, &object, &mut fields);
+
+            let field_names = self.declared_fields.get_or_insert_with(Vec::new);
+            let mut values = vec![Value::Null; field_names.len()];
+            for (name, value) in fields {
+                match field_names.iter().position(|field_name| *field_name == name) {
+                    Some(idx) => values[idx] = value,
+                    None => {
+                        field_names.push(name);
+                        values.push(value);
+                        self.schema = None;
+                    }
+                }
+            }
+            self.push_extra_values(line, &mut values);
+
+            return Ok(Some(Record::with_schema(self.schema(), values)));
+        }
+    }
+
+    fn read_parsed_record(&mut self) -> ReaderResult<Option<Record>> {
         let mut buf = String::new();
-        let more_data = self.rdr.read_line(&mut buf)?;
+        let more_data = self.read_lines(&mut buf)?;
 
         if more_data > 0 {
-            let field_names = if self.table_name == "elb" {
-                ClassicLoadBalancerLogField::field_names()
+            let line_number = self.line_number;
+            //The fields are on the first line, the continuation lines only show in the "line" field
+            let first_line = buf.lines().next().unwrap_or("");
+            let field_count = if self.table_name == "elb" {
+                ClassicLoadBalancerLogField::len()
             } else if self.table_name == "alb" {
-                ApplicationLoadBalancerLogField::field_names()
+                ApplicationLoadBalancerLogField::len()
             } else if self.table_name == "s3" {
-                S3Field::field_names()
+                S3Field::len()
+            } else if self.table_name == "nginx" {
+                NginxLogField::len()
             } else {
-                SquidLogField::field_names()
+                SquidLogField::len()
             };
 
-            let regex_literal = r#"[^\s"'\[\]]+|"([^"]*)"|'([^']*)'|\[([^\[\]]*)\]"#;
-            let split_the_line_regex: Regex = Regex::new(regex_literal).unwrap();
             //FIXME: parse to the more specific
             let mut values: Vec<Value> = Vec::new();
-            for (i, m) in split_the_line_regex.find_iter(&buf).enumerate() {
+            for (i, m) in SPLIT_THE_LINE_REGEX.find_iter(first_line).enumerate() {
                 if self.table_name == "elb" {
                     if i >= ClassicLoadBalancerLogField::len() {
                         break;
@@ -721,6 +1458,10 @@ impl<R: io::Read> RecordRead for Reader<R> {
                     if i >= S3Field::len() {
                         break;
                     }
+                } else if self.table_name == "nginx" {
+                    if i >= NginxLogField::len() {
+                        break;
+                    }
                 } else {
                     unreachable!();
                 }
@@ -732,48 +1473,64 @@ impl<R: io::Read> RecordRead for Reader<R> {
                     ApplicationLoadBalancerLogField::datatype(i)
                 } else if self.table_name == "s3" {
                     S3Field::datatype(i)
+                } else if self.table_name == "nginx" {
+                    NginxLogField::datatype(i)
                 } else {
                     SquidLogField::datatype(i)
                 };
 
-                match datatype {
-                    DataType::DateTime => {
-                        let dt = chrono::DateTime::parse_from_rfc3339(s)?;
-                        values.push(Value::DateTime(dt));
+                //The load balancers log a processing time of -1 when the backend never responded
+                //Such lines are common, so the query is warned once rather than on every one of them
+                if datatype == DataType::Float && s == "-1" && (self.table_name == "elb" || self.table_name == "alb") {
+                    warnings::warn_once(WarningKind::NullSentinel, || format!("line {}", line_number));
+                    values.push(Value::Null);
+                    continue;
+                }
+
+                //The tokenizer keeps a quoted user agent whole, the quotes aren't part of it
+                let is_user_agent = (self.table_name == "elb" && i == ClassicLoadBalancerLogField::UserAgent as usize)
+                    || (self.table_name == "alb" && i == ApplicationLoadBalancerLogField::UserAgent as usize);
+                if is_user_agent {
+                    let user_agent = s.trim_matches('"');
+                    if user_agent.is_empty() || user_agent == "-" {
+                        values.push(Value::Null);
+                    } else {
+                        values.push(Value::String(user_agent.to_string()));
                     }
-                    DataType::String => {
+                    continue;
+                }
+
+                //The load balancers write "- - - " for the request of a connection that never sent one
+                if datatype == DataType::HttpRequest
+                    && (self.table_name == "elb" || self.table_name == "alb")
+                    && s.trim_matches('"').split_whitespace().all(|part| part == "-")
+                {
+                    values.push(Value::Null);
+                    continue;
+                }
+
+                //The request, referer and user agent are quoted, and nginx writes "-" for a missing value
+                if self.table_name == "nginx" && datatype == DataType::String {
+                    let s = s.trim_matches('"');
+                    if s == "-" {
+                        values.push(Value::Null);
+                    } else {
                         values.push(Value::String(s.to_string()));
                     }
-                    DataType::Integral => {
-                        let i = s.parse::<i32>()?;
-                        values.push(Value::Int(i));
-                    }
-                    DataType::Float => {
-                        let f = s.parse::<f32>()?;
-                        values.push(Value::Float(OrderedFloat::from(f)));
-                    }
-                    DataType::Host => {
-                        if s == "-" {
-                            values.push(Value::Null)
-                        } else {
-                            let host = common::types::parse_host(s)?;
-                            values.push(Value::Host(host));
-                        }
-                    }
-                    DataType::HttpRequest => {
-                        let s = s.trim_matches('"');
-                        let request = common::types::parse_http_request(s)?;
-                        values.push(Value::HttpRequest(request));
-                    }
+                    continue;
                 }
+
+                values.push(parse_value(&datatype, s, self.number_format)?);
             }
 
             //Adjust the width to be the same
-            while values.len() < field_names.len() {
+            while values.len() < field_count {
                 values.push(Value::Null);
             }
 
-            let record = Record::new(field_names, values);
+            self.push_extra_values(buf.trim_end_matches(&['\r', '\n'][..]), &mut values);
+
+            let record = Record::with_schema(self.schema(), values);
 
             Ok(Some(record))
         } else {
@@ -782,6 +1539,29 @@ impl<R: io::Read> RecordRead for Reader<R> {
     }
 }
 
+impl<R: io::Read> RecordRead for Reader<R> {
+    fn read_record(&mut self) -> ReaderResult<Option<Record>> {
+        if self.table_name == "w3c" {
+            return self.read_declared_record();
+        }
+
+        loop {
+            let result = if self.table_name == "json" {
+                self.read_json_record()
+            } else {
+                self.read_parsed_record()
+            };
+            match result {
+                Err(ref e) if self.skip_bad_lines && e.is_line_error() => {
+                    let line_number = self.line_number;
+                    warnings::warn(WarningKind::SkippedLine, || format!("line {}: {}", line_number, e));
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -809,11 +1589,14 @@ mod tests {
             Value::Int(0),
             Value::Int(42355),
             Value::HttpRequest(common::types::parse_http_request("GET https://example.com:443/ HTTP/1.1").unwrap()),
-            Value::String("\"Mozilla/5.0 (Windows NT 5.1) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/46.0.2490.80 Safari/537.36\"".to_string()),
+            Value::String(
+                "Mozilla/5.0 (Windows NT 5.1) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/46.0.2490.80 Safari/537.36"
+                    .to_string(),
+            ),
             Value::String("ECDHE-RSA-AES128-GCM-SHA256".to_string()),
             Value::String("TLSv1.2".to_string()),
             Value::Null,
-            Value::Null
+            Value::Null,
         ];
         let expected: Option<Record> = Some(Record::new(fields, data));
 
@@ -836,7 +1619,7 @@ mod tests {
             Value::Int(0),
             Value::Int(41690),
             Value::HttpRequest(common::types::parse_http_request("GET http://example.com:80/?mode=json&after=&iteration=1 HTTP/1.1").unwrap()),
-            Value::String("\"Mozilla/5.0 (Linux; Android 5.1.1; Nexus 5 Build/LMY48I; wv) AppleWebKit/537.36 (KHTML, like Gecko) Version/4.0 Chrome/46.0.2490.76 Mobile Safari/537.36 [FB_IAB/FB4A;FBAV/52.0.0.12.18;]\"".to_string()),
+            Value::String("Mozilla/5.0 (Linux; Android 5.1.1; Nexus 5 Build/LMY48I; wv) AppleWebKit/537.36 (KHTML, like Gecko) Version/4.0 Chrome/46.0.2490.76 Mobile Safari/537.36 [FB_IAB/FB4A;FBAV/52.0.0.12.18;]".to_string()),
             Value::String("-".to_string()),
             Value::String("-".to_string()),
             Value::String("arn:aws:elasticloadbalancing:us-west-2:123456789012:targetgroup/my-targets/73e2d6bc24d8a067".to_string()),
@@ -847,6 +1630,31 @@ mod tests {
         assert_eq!(expected, record)
     }
 
+    #[test]
+    fn test_aws_elb_reader_with_user_agent() {
+        let content = r#"2015-11-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 42355 "GET https://example.com:443/ HTTP/1.1" "curl/7.46.0 (x86_64-pc-linux-gnu) libcurl/7.46.0" ECDHE-RSA-AES128-GCM-SHA256 TLSv1.2
+2015-11-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 42355 "GET https://example.com:443/ HTTP/1.1" "-" ECDHE-RSA-AES128-GCM-SHA256 TLSv1.2
+2015-11-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 42355 "GET https://example.com:443/ HTTP/1.1" - ECDHE-RSA-AES128-GCM-SHA256 TLSv1.2"#;
+        let mut reader = ReaderBuilder::new("elb".to_string()).with_reader(BufReader::new(content.as_bytes()));
+        let fields = vec!["user_agent".to_string(), "ssl_cipher".to_string()];
+
+        let record = reader.read_record().unwrap().unwrap();
+        assert_eq!(
+            vec![
+                Value::String("curl/7.46.0 (x86_64-pc-linux-gnu) libcurl/7.46.0".to_string()),
+                Value::String("ECDHE-RSA-AES128-GCM-SHA256".to_string())
+            ],
+            record.get(&fields)
+        );
+        for _ in 0..2 {
+            let record = reader.read_record().unwrap().unwrap();
+            assert_eq!(
+                vec![Value::Null, Value::String("ECDHE-RSA-AES128-GCM-SHA256".to_string())],
+                record.get(&fields)
+            );
+        }
+    }
+
     #[test]
     fn test_aws_alb_reader() {
         let content = r#"http 2018-07-02T22:23:00.186641Z app/my-loadbalancer/50dc6c495c0c9188 192.168.131.39:2817 10.0.0.1:80 0.000 0.001 0.000 200 200 34 366 "GET http://www.example.com:80/ HTTP/1.1" "curl/7.46.0" - - arn:aws:elasticloadbalancing:us-east-2:123456789012:targetgroup/my-targets/73e2d6bc24d8a067 "Root=1-58337262-36d228ad5d99923122bbe354" "-" "-" 0 2018-07-02T22:22:48.364000Z "forward" "-" "-""#;
@@ -867,7 +1675,7 @@ mod tests {
             Value::Int(34),
             Value::Int(366),
             Value::HttpRequest(common::types::parse_http_request("GET http://www.example.com:80/ HTTP/1.1").unwrap()),
-            Value::String("\"curl/7.46.0\"".to_string()),
+            Value::String("curl/7.46.0".to_string()),
             Value::String("-".to_string()),
             Value::String("-".to_string()),
             Value::String(
@@ -888,6 +1696,31 @@ mod tests {
         assert_eq!(expected, record);
     }
 
+    #[test]
+    fn test_aws_alb_reader_missing_request() {
+        let content = r#"http 2018-07-02T22:23:00.186641Z app/my-loadbalancer/50dc6c495c0c9188 192.168.131.39:2817 10.0.0.1:80 0.000 0.001 0.000 200 200 34 366 "- - - " "-" - - arn:aws:elasticloadbalancing:us-east-2:123456789012:targetgroup/my-targets/73e2d6bc24d8a067 "Root=1-58337262-36d228ad5d99923122bbe354" "-" "-" 0 2018-07-02T22:22:48.364000Z "forward" "-" "-""#;
+        let mut reader = ReaderBuilder::new("alb".to_string()).with_reader(BufReader::new(content.as_bytes()));
+        let record = reader.read_record().unwrap().unwrap();
+        assert_eq!(
+            vec![Value::Null, Value::Null],
+            record.get(&["request".to_string(), "user_agent".to_string()])
+        );
+    }
+
+    #[test]
+    fn test_aws_alb_field_names() {
+        let fields = ApplicationLoadBalancerLogField::field_names();
+        assert_eq!(ApplicationLoadBalancerLogField::len(), fields.len());
+        for (idx, name) in fields.iter().enumerate() {
+            let field = ApplicationLoadBalancerLogField::from_str(name).unwrap();
+            assert_eq!(idx, field as usize);
+        }
+        assert_eq!(
+            "actions_executed",
+            fields[ApplicationLoadBalancerLogField::ActionExecuted as usize]
+        );
+    }
+
     #[test]
     fn test_aws_s3_reader() {
         let content = r#"79a59df900b949e55d96a1e698fbacedfd6e09d98eacf8f8d5218e7cd47ef2be awsexamplebucket [06/Feb/2019:00:00:38 +0000] 192.0.2.3 79a59df900b949e55d96a1e698fbacedfd6e09d98eacf8f8d5218e7cd47ef2be 3E57427F3EXAMPLE REST.GET.VERSIONING - "GET /awsexamplebucket?versioning HTTP/1.1" 200 - 113 - 7 - "-" "S3Console/0.4" - s9lzHYrFp76ZVxRcpX9+5cjAnEH2ROuNkd2BHfIa6UkFVdtjf5mKR3/eTPFvsiP/XV/VLi31234= SigV2 ECDHE-RSA-AES128-GCM-SHA256 AuthHeader awsexamplebucket.s3.amazonaws.com TLSV1.1"#;
@@ -948,6 +1781,374 @@ mod tests {
         assert_eq!(expected, record);
     }
 
+    #[test]
+    fn test_nginx_reader() {
+        let content = r#"127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] "GET /apache_pb.gif HTTP/1.0" 200 2326 "http://www.example.com/start.html" "Mozilla/4.08 [en] (Win98; I ;Nav)"
+10.0.0.2 - - [10/Oct/2000:13:56:01 -0700] "POST /login HTTP/1.1" 302 0 "-" "curl/7.38.0""#;
+        let mut reader = ReaderBuilder::new("nginx".to_string()).with_reader(BufReader::new(content.as_bytes()));
+        let fields = NginxLogField::field_names();
+
+        let record = reader.read_record().unwrap();
+        let data = vec![
+            Value::String("127.0.0.1".to_string()),
+            Value::Null,
+            Value::String("frank".to_string()),
+            Value::DateTime(chrono::DateTime::parse_from_rfc3339("2000-10-10T13:55:36-07:00").unwrap()),
+            Value::String("GET /apache_pb.gif HTTP/1.0".to_string()),
+            Value::String("200".to_string()),
+            Value::Int(2326),
+            Value::String("http://www.example.com/start.html".to_string()),
+            Value::String("Mozilla/4.08 [en] (Win98; I ;Nav)".to_string()),
+        ];
+        assert_eq!(Some(Record::new(fields.clone(), data)), record);
+
+        let record = reader.read_record().unwrap().unwrap();
+        assert_eq!(
+            vec![
+                Value::Null,
+                Value::String("302".to_string()),
+                Value::Int(0),
+                Value::Null
+            ],
+            record.get(&[
+                "remote_user".to_string(),
+                "status".to_string(),
+                "body_bytes_sent".to_string(),
+                "referer".to_string(),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_json_reader() {
+        let content = r#"{"level": "error", "status": 502, "latency": 1.5, "cached": false, "http": {"method": "GET", "path": "/a"}, "tags": ["x", "y"]}
+
+{"level": "info", "status": 200, "user": null}"#;
+        let mut reader = ReaderBuilder::new("json".to_string()).with_reader(BufReader::new(content.as_bytes()));
+        let mut fields: Vec<String> = [
+            "level",
+            "status",
+            "latency",
+            "cached",
+            "http_method",
+            "http_path",
+            "tags",
+        ]
+        .iter()
+        .map(|field| field.to_string())
+        .collect();
+
+        let record = reader.read_record().unwrap();
+        let data = vec![
+            Value::String("error".to_string()),
+            Value::Int(502),
+            Value::Float(OrderedFloat::from(1.5)),
+            Value::Boolean(false),
+            Value::String("GET".to_string()),
+            Value::String("/a".to_string()),
+            Value::Array(vec![Value::String("x".to_string()), Value::String("y".to_string())]),
+        ];
+        assert_eq!(Some(Record::new(fields.clone(), data)), record);
+
+        //The blank line is skipped, the keys of the first line are null on the second one
+        let record = reader.read_record().unwrap();
+        fields.push("user".to_string());
+        let data = vec![
+            Value::String("info".to_string()),
+            Value::Int(200),
+            Value::Null,
+            Value::Null,
+            Value::Null,
+            Value::Null,
+            Value::Null,
+            Value::Null,
+        ];
+        assert_eq!(Some(Record::new(fields, data)), record);
+        assert_eq!(None, reader.read_record().unwrap());
+
+        let mut reader = ReaderBuilder::new("json".to_string()).with_reader(BufReader::new("[1, 2]".as_bytes()));
+        assert!(matches!(reader.read_record(), Err(ReaderError::NotJsonObject)));
+    }
+
+    #[test]
+    fn test_detect_format() {
+        let elb = r#"2015-11-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 42355 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#;
+        let alb = r#"http 2018-07-02T22:23:00.186641Z app/my-loadbalancer/50dc6c495c0c9188 192.168.131.39:2817 10.0.0.1:80 0.000 0.001 0.000 200 200 34 366 "GET http://www.example.com:80/ HTTP/1.1" "curl/7.46.0" - - arn:aws:elasticloadbalancing:us-east-2:123456789012:targetgroup/my-targets/73e2d6bc24d8a067 "Root=1-58337262-36d228ad5d99923122bbe354" "-" "-" 0 2018-07-02T22:22:48.364000Z "forward" "-" "-""#;
+        let squid = r#"1515734740.494      1 [MASKEDIPADDRESS] TCP_DENIED/407 3922 CONNECT d.dropbox.com:443 - HIER_NONE/- text/html"#;
+        let nginx = r#"127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] "GET /apache_pb.gif HTTP/1.0" 200 2326 "-" "curl/7.38.0""#;
+
+        let content = format!("{}\n{}\n", elb, elb);
+        let table_name = detect_format(BufReader::new(content.as_bytes()), 5).unwrap();
+        assert_eq!("elb", table_name);
+
+        let table_name = detect_format(BufReader::new(alb.as_bytes()), 5).unwrap();
+        assert_eq!("alb", table_name);
+
+        let table_name = detect_format(BufReader::new(squid.as_bytes()), 5).unwrap();
+        assert_eq!("squid", table_name);
+
+        let table_name = detect_format(BufReader::new(nginx.as_bytes()), 5).unwrap();
+        assert_eq!("nginx", table_name);
+
+        let json = r#"{"level": "info", "status": 200}"#;
+        let table_name = detect_format(BufReader::new(json.as_bytes()), 5).unwrap();
+        assert_eq!("json", table_name);
+
+        let content = format!("{}\n{}\n", elb, alb);
+        let result = detect_format(BufReader::new(content.as_bytes()), 5);
+        assert!(matches!(result, Err(ReaderError::UnknownFormat)));
+    }
+
+    #[test]
+    fn test_reader_with_raw_line() {
+        let content = "2015-11-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 42355 \"GET https://example.com:443/ HTTP/1.1\" \"curl/7.38.0\" - -\n";
+        let mut reader = ReaderBuilder::new("elb".to_string())
+            .raw_line(true)
+            .with_reader(BufReader::new(content.as_bytes()));
+        let record = reader.read_record().unwrap().unwrap();
+        let variables = record.to_variables();
+
+        assert_eq!(
+            Some(&Value::String(content.trim_end().to_string())),
+            variables.get("line")
+        );
+        assert_eq!(Some(&Value::Int(42355)), variables.get("sent_bytes"));
+    }
+
+    #[test]
+    fn test_reader_with_multiline() {
+        let first = "2015-11-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 500 500 0 42355 \"GET https://example.com:443/ HTTP/1.1\" \"curl/7.38.0\" - -";
+        let trace = "    at com.example.Handler.handle(Handler.java:42)";
+        let second = "2015-11-07T18:45:34.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 512 \"GET https://example.com:443/ HTTP/1.1\" \"curl/7.38.0\" - -";
+        let content = format!("{}\n{}\n{}", first, trace, second);
+        let mut reader = ReaderBuilder::new("elb".to_string())
+            .raw_line(true)
+            .multiline(Some(RecordStart::from_str(r"^\d{4}-").unwrap()))
+            .with_reader(BufReader::new(content.as_bytes()));
+
+        let variables = reader.read_record().unwrap().unwrap().to_variables();
+        assert_eq!(
+            Some(&Value::String(format!("{}\n{}", first, trace))),
+            variables.get("line")
+        );
+        assert_eq!(
+            Some(&Value::String("500".to_string())),
+            variables.get("elb_status_code")
+        );
+
+        let variables = reader.read_record().unwrap().unwrap().to_variables();
+        assert_eq!(Some(&Value::String(second.to_string())), variables.get("line"));
+        assert_eq!(Some(&Value::Int(512)), variables.get("sent_bytes"));
+        assert_eq!(3, reader.line_number);
+
+        assert_eq!(None, reader.read_record().unwrap());
+    }
+
+    #[test]
+    fn test_w3c_reader() {
+        let content = r#"#Software: Microsoft Internet Information Services 10.0
+#Version: 1.0
+#Date: 2019-06-07 18:45:33
+#Fields: date time c-ip cs-method cs-uri-stem sc-status cs(User-Agent)
+2019-06-07 18:45:33 10.0.0.215 GET /index.html 200 curl/7.38.0
+# a comment in the middle
+2019-06-07 18:45:34 10.0.0.216 POST /login 302
+"#;
+        let mut reader = ReaderBuilder::new("w3c".to_string()).with_reader(BufReader::new(content.as_bytes()));
+
+        let record = reader.read_record().unwrap().unwrap();
+        let field_names: Vec<String> = [
+            "date",
+            "time",
+            "c_ip",
+            "cs_method",
+            "cs_uri_stem",
+            "sc_status",
+            "cs_user_agent",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        assert_eq!(field_names.as_slice(), record.field_names());
+        assert_eq!(
+            vec![
+                Value::String("/index.html".to_string()),
+                Value::String("curl/7.38.0".to_string())
+            ],
+            record.get(&["cs_uri_stem".to_string(), "cs_user_agent".to_string()])
+        );
+
+        //The comment is skipped and the missing trailing field is null
+        let record = reader.read_record().unwrap().unwrap();
+        assert_eq!(
+            vec![Value::String("POST".to_string()), Value::Null],
+            record.get(&["cs_method".to_string(), "cs_user_agent".to_string()])
+        );
+        assert_eq!(None, reader.read_record().unwrap());
+
+        let content = "#Version: 1.0\n2019-06-07 18:45:33 10.0.0.215\n";
+        let mut reader = ReaderBuilder::new("w3c".to_string()).with_reader(BufReader::new(content.as_bytes()));
+        assert!(reader.read_record().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_reader_on_fifo() {
+        use std::io::Write;
+        use std::process::Command;
+
+        let line = r#"2015-11-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 42355 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("elb.pipe");
+        assert!(Command::new("mkfifo").arg(&path).status().unwrap().success());
+        assert!(is_fifo(&path));
+
+        //Two writers one after the other, the first one closing looks like the end of the input
+        let writer_path = path.clone();
+        let writer = thread::spawn(move || {
+            for _ in 0..2 {
+                let mut fifo = std::fs::OpenOptions::new().write(true).open(&writer_path).unwrap();
+                writeln!(fifo, "{}", line).unwrap();
+                drop(fifo);
+                thread::sleep(Duration::from_millis(50));
+            }
+        });
+
+        let mut reader = ReaderBuilder::new("elb".to_string())
+            .follow(true)
+            .with_fifo_path(&path)
+            .unwrap();
+        for _ in 0..2 {
+            let record = reader.read_record().unwrap().unwrap();
+            assert_eq!(Some(&Value::Int(42355)), record.to_variables().get("sent_bytes"));
+        }
+        writer.join().unwrap();
+
+        //Without following, the last writer closing ends the input
+        let writer_path = path.clone();
+        let writer = thread::spawn(move || {
+            let mut fifo = std::fs::OpenOptions::new().write(true).open(&writer_path).unwrap();
+            writeln!(fifo, "{}", line).unwrap();
+        });
+
+        let mut reader = ReaderBuilder::new("elb".to_string()).with_fifo_path(&path).unwrap();
+        assert!(reader.read_record().unwrap().is_some());
+        assert_eq!(None, reader.read_record().unwrap());
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn test_reader_on_concatenated_gzip_members() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        let lines = [
+            r#"2015-11-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 42355 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#,
+            r#"2015-11-07T18:45:34.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 512 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#,
+        ];
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("elb.log.gz");
+        let mut file = File::create(&path).unwrap();
+        for line in lines.iter() {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            writeln!(encoder, "{}", line).unwrap();
+            file.write_all(&encoder.finish().unwrap()).unwrap();
+        }
+        drop(file);
+
+        let mut reader = ReaderBuilder::new("elb".to_string()).with_gzip_path(&path).unwrap();
+        let mut sent_bytes = Vec::new();
+        while let Some(record) = reader.read_record().unwrap() {
+            sent_bytes.push(record.to_variables().get("sent_bytes").cloned());
+        }
+
+        assert_eq!(vec![Some(Value::Int(42355)), Some(Value::Int(512))], sent_bytes);
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn test_reader_on_gzip_stdin() {
+        use flate2::write::GzEncoder;
+        use flate2::Compression;
+        use std::io::Write;
+
+        //A pipe handing out a single byte per read, the magic is still seen whole
+        struct Trickle(io::Cursor<Vec<u8>>);
+
+        impl io::Read for Trickle {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                let len = buf.len().min(1);
+                self.0.read(&mut buf[..len])
+            }
+        }
+
+        let lines = [
+            r#"2015-11-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 42355 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#,
+            r#"2015-11-07T18:45:34.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 512 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#,
+        ];
+        let mut plain = Vec::new();
+        let mut gzip = Vec::new();
+        for line in lines.iter() {
+            writeln!(plain, "{}", line).unwrap();
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            writeln!(encoder, "{}", line).unwrap();
+            gzip.extend(encoder.finish().unwrap());
+        }
+
+        for content in vec![gzip, plain].into_iter() {
+            let mut reader = ReaderBuilder::new("elb".to_string())
+                .with_sniffed_reader(Trickle(io::Cursor::new(content)))
+                .unwrap();
+            let mut sent_bytes = Vec::new();
+            while let Some(record) = reader.read_record().unwrap() {
+                sent_bytes.push(record.to_variables().get("sent_bytes").cloned());
+            }
+
+            assert_eq!(vec![Some(Value::Int(42355)), Some(Value::Int(512))], sent_bytes);
+        }
+
+        //An empty stream is shorter than the magic and still just ends
+        let mut reader = ReaderBuilder::new("elb".to_string())
+            .with_sniffed_reader(io::empty())
+            .unwrap();
+        assert_eq!(None, reader.read_record().unwrap());
+    }
+
+    #[test]
+    fn test_reader_with_number_format() {
+        let content = r#"2015-11-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 1,234.5 0.001035 0.000025 200 200 2,048 1,536,000 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#;
+        let mut reader = ReaderBuilder::new("elb".to_string())
+            .number_format(NumberFormat::CommaGrouped)
+            .with_reader(BufReader::new(content.as_bytes()));
+        let variables = reader.read_record().unwrap().unwrap().to_variables();
+        assert_eq!(Some(&Value::Int(1_536_000)), variables.get("sent_bytes"));
+        assert_eq!(Some(&Value::Int(2048)), variables.get("received_bytes"));
+        assert_eq!(
+            Some(&Value::Float(OrderedFloat::from(1234.5))),
+            variables.get("request_processing_time")
+        );
+
+        let content = r#"2015-11-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 1.234,5 0,001035 0,000025 200 200 2.048 1.536.000 "GET https://example.com:443/ HTTP/1.1" "curl/7.38.0" - -"#;
+        let mut reader = ReaderBuilder::new("elb".to_string())
+            .number_format(NumberFormat::DecimalComma)
+            .with_reader(BufReader::new(content.as_bytes()));
+        let variables = reader.read_record().unwrap().unwrap().to_variables();
+        assert_eq!(Some(&Value::Int(1_536_000)), variables.get("sent_bytes"));
+        assert_eq!(
+            Some(&Value::Float(OrderedFloat::from(1234.5))),
+            variables.get("request_processing_time")
+        );
+        assert_eq!(
+            Some(&Value::Float(OrderedFloat::from(0.001035))),
+            variables.get("backend_processing_time")
+        );
+
+        let mut reader = ReaderBuilder::new("elb".to_string()).with_reader(BufReader::new(content.as_bytes()));
+        assert!(reader.read_record().is_err());
+    }
+
     #[test]
     fn test_reader_on_empty_input() {
         let content = r#"                   \n          "#;
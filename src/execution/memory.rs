@@ -0,0 +1,201 @@
+use crate::common::types::Value;
+use std::fs;
+use std::io;
+use std::mem;
+use std::result;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tempfile::NamedTempFile;
+
+#[derive(Debug)]
+struct Budget {
+    limit: u64,
+    used: AtomicU64,
+}
+
+//The bytes the blocking operators of a query may retain together. Every operator holding one shares
+//the same count, the default has no limit and counts nothing. The sizes are estimated from the values
+//held rather than measured from the allocator.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MemoryBudget {
+    budget: Option<Arc<Budget>>,
+}
+
+impl PartialEq for MemoryBudget {
+    fn eq(&self, other: &Self) -> bool {
+        self.limit() == other.limit()
+    }
+}
+
+impl Eq for MemoryBudget {}
+
+impl MemoryBudget {
+    pub(crate) fn new(limit: u64) -> Self {
+        MemoryBudget {
+            budget: Some(Arc::new(Budget {
+                limit,
+                used: AtomicU64::new(0),
+            })),
+        }
+    }
+
+    pub(crate) fn limit(&self) -> Option<u64> {
+        self.budget.as_ref().map(|budget| budget.limit)
+    }
+
+    pub(crate) fn reserve(&self) -> Reservation {
+        Reservation {
+            budget: self.clone(),
+            bytes: 0,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn used(&self) -> u64 {
+        self.budget
+            .as_ref()
+            .map(|budget| budget.used.load(Ordering::SeqCst))
+            .unwrap_or(0)
+    }
+}
+
+//The share of the budget held by one operator, given back when it is cleared or dropped
+#[derive(Debug)]
+pub(crate) struct Reservation {
+    budget: MemoryBudget,
+    bytes: u64,
+}
+
+impl Reservation {
+    //Count the bytes against the budget, false once the query as a whole has gone over it. The size is
+    //only estimated when there is a limit to check it against.
+    pub(crate) fn grow<F>(&mut self, size: F) -> bool
+    where
+        F: FnOnce() -> u64,
+    {
+        match &self.budget.budget {
+            Some(budget) => {
+                let bytes = size();
+                self.bytes += bytes;
+                budget.used.fetch_add(bytes, Ordering::SeqCst) + bytes <= budget.limit
+            }
+            None => true,
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        if let Some(budget) = &self.budget.budget {
+            budget.used.fetch_sub(self.bytes, Ordering::SeqCst);
+        }
+        self.bytes = 0;
+    }
+
+    pub(crate) fn limit(&self) -> u64 {
+        self.budget.limit().unwrap_or(u64::MAX)
+    }
+
+    pub(crate) fn is_limited(&self) -> bool {
+        self.budget.limit().is_some()
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+pub(crate) fn value_size(value: &Value) -> u64 {
+    let heap = match value {
+        Value::String(s) => s.len() as u64,
+        Value::HttpRequest(request) => request.to_string().len() as u64,
+        Value::Host(host) => host.to_string().len() as u64,
+        Value::Array(values) => values.iter().map(value_size).sum(),
+        _ => 0,
+    };
+
+    mem::size_of::<Value>() as u64 + heap
+}
+
+pub(crate) fn values_size(values: &[Value]) -> u64 {
+    values.iter().map(value_size).sum()
+}
+
+//A size with an optional binary suffix, 512m is 512 MiB
+pub(crate) fn parse_size(s: &str) -> result::Result<u64, String> {
+    let s = s.trim().to_ascii_lowercase();
+    let (digits, multiplier) = match s.chars().last() {
+        Some('k') => (&s[..s.len() - 1], 1 << 10),
+        Some('m') => (&s[..s.len() - 1], 1 << 20),
+        Some('g') => (&s[..s.len() - 1], 1 << 30),
+        _ => (&s[..], 1),
+    };
+
+    match digits.parse::<u64>() {
+        Ok(n) if n > 0 => n
+            .checked_mul(multiplier)
+            .ok_or_else(|| format!("memory size {} is too large", s)),
+        _ => Err(format!(
+            "memory size must be a positive number of bytes with an optional k, m or g, not {}",
+            s
+        )),
+    }
+}
+
+//A temporary file holding what an operator moved out of memory, removed once it is dropped. It is
+//created under a random name that no other file had, readable only by the user.
+#[derive(Debug)]
+pub(crate) struct SpillFile {
+    file: NamedTempFile,
+}
+
+impl SpillFile {
+    pub(crate) fn create() -> io::Result<Self> {
+        let file = tempfile::Builder::new().prefix("logq-spill-").tempfile()?;
+        Ok(SpillFile { file })
+    }
+
+    pub(crate) fn as_file(&self) -> &fs::File {
+        self.file.as_file()
+    }
+
+    //A new handle reading the file from its start
+    pub(crate) fn reopen(&self) -> io::Result<fs::File> {
+        self.file.reopen()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(Ok(512 * 1024 * 1024), parse_size("512m"));
+        assert_eq!(Ok(2 * 1024), parse_size("2K"));
+        assert_eq!(Ok(3 * 1024 * 1024 * 1024), parse_size("3g"));
+        assert_eq!(Ok(1000), parse_size("1000"));
+        assert!(parse_size("0m").is_err());
+        assert!(parse_size("m").is_err());
+        assert!(parse_size("12x").is_err());
+    }
+
+    #[test]
+    fn test_shared_budget() {
+        let budget = MemoryBudget::new(100);
+        let mut group_by = budget.reserve();
+        let mut order_by = budget.reserve();
+
+        assert!(group_by.grow(|| 60));
+        assert!(!order_by.grow(|| 50));
+        assert_eq!(110, budget.used());
+
+        group_by.clear();
+        assert_eq!(50, budget.used());
+        drop(order_by);
+        assert_eq!(0, budget.used());
+
+        let mut unlimited = MemoryBudget::default().reserve();
+        assert!(unlimited.grow(|| u64::MAX));
+    }
+}
@@ -1,3 +1,7 @@
+pub mod cost;
 pub mod datasource;
+pub mod memory;
+pub mod snapshot;
 pub mod stream;
 pub mod types;
+pub mod warnings;
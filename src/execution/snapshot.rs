@@ -0,0 +1,286 @@
+use super::types::{AggregateError, NamedAggregate, PartialState};
+use crate::common;
+use crate::common::types::{Tuple, Value, VariableName};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::result;
+use std::str::FromStr;
+
+pub(crate) type SnapshotResult<T> = result::Result<T, SnapshotError>;
+
+#[derive(Fail, Debug)]
+pub(crate) enum SnapshotError {
+    #[fail(display = "Snapshot Io Error: {}", _0)]
+    Io(#[cause] io::Error),
+    #[fail(display = "Snapshot Json Error: {}", _0)]
+    Json(#[cause] json::Error),
+    #[fail(display = "Invalid Snapshot: {}", _0)]
+    Invalid(String),
+    #[fail(display = "The snapshot doesn't match the query: {}", _0)]
+    Mismatch(String),
+    #[fail(display = "{}() can't be kept in a snapshot, only sum, count and avg can", _0)]
+    Unsupported(String),
+}
+
+impl PartialEq for SnapshotError {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (SnapshotError::Io(_), SnapshotError::Io(_)) => true,
+            (SnapshotError::Json(_), SnapshotError::Json(_)) => true,
+            (SnapshotError::Invalid(a), SnapshotError::Invalid(b)) => a == b,
+            (SnapshotError::Mismatch(a), SnapshotError::Mismatch(b)) => a == b,
+            (SnapshotError::Unsupported(a), SnapshotError::Unsupported(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> SnapshotError {
+        SnapshotError::Io(err)
+    }
+}
+
+impl From<json::Error> for SnapshotError {
+    fn from(err: json::Error) -> SnapshotError {
+        SnapshotError::Json(err)
+    }
+}
+
+//The name, the function and the aggregated expression of every aggregate, a snapshot only loads into
+//the same shape
+fn aggregate_shape(aggregates: &[NamedAggregate]) -> SnapshotResult<Vec<(String, String, String)>> {
+    let mut shape = Vec::with_capacity(aggregates.len());
+    for named_aggregate in aggregates.iter() {
+        let aggregate = named_aggregate.aggregate.to_json();
+        let function = aggregate["aggregate"].as_str().unwrap_or_default().to_string();
+        if !["sum", "count", "avg"].contains(&function.as_str()) {
+            return Err(SnapshotError::Unsupported(function));
+        }
+        shape.push((
+            named_aggregate.name_opt.clone().unwrap_or_default(),
+            function,
+            aggregate["argument"].dump(),
+        ));
+    }
+
+    Ok(shape)
+}
+
+//A key value tagged with its type, so a timestamp or a host reads back as itself rather than a string.
+//An array is a json array of its values tagged in turn.
+pub(crate) fn encode_value(value: &Value) -> SnapshotResult<json::JsonValue> {
+    let encoded = match value {
+        Value::Null => return Ok(json::Null),
+        Value::Int(i) => format!("i:{}", i),
+        Value::Float(f) => format!("f:{}", f),
+        Value::Boolean(b) => format!("b:{}", b),
+        Value::String(s) => format!("s:{}", s),
+        Value::DateTime(dt) => format!("d:{}", dt.to_rfc3339()),
+        Value::Host(host) => format!("h:{}", host),
+        Value::HttpRequest(request) => format!("r:{}", request),
+        Value::Array(values) => {
+            return Ok(values
+                .iter()
+                .map(encode_value)
+                .collect::<SnapshotResult<Vec<_>>>()?
+                .into());
+        }
+    };
+
+    Ok(encoded.into())
+}
+
+pub(crate) fn decode_value(value: &json::JsonValue) -> SnapshotResult<Value> {
+    if value.is_null() {
+        return Ok(Value::Null);
+    }
+    if value.is_array() {
+        let values = value.members().map(decode_value).collect::<SnapshotResult<Vec<_>>>()?;
+        return Ok(Value::Array(values));
+    }
+
+    let invalid = || SnapshotError::Invalid(format!("unknown key value {}", value));
+    let s = value.as_str().ok_or_else(invalid)?;
+    let (tag, body) = (s.get(..2).ok_or_else(invalid)?, &s[2..]);
+    let decoded = match tag {
+        "i:" => body.parse::<i32>().ok().map(Value::Int),
+        "f:" => body.parse::<f32>().ok().map(|f| Value::Float(f.into())),
+        "b:" => body.parse::<bool>().ok().map(Value::Boolean),
+        "s:" => Some(Value::String(body.to_string())),
+        "d:" => chrono::DateTime::parse_from_rfc3339(body).ok().map(Value::DateTime),
+        "h:" => common::types::parse_host(body).ok().map(Value::Host),
+        "r:" => common::types::parse_http_request(body).ok().map(Value::HttpRequest),
+        _ => None,
+    };
+
+    decoded.ok_or_else(invalid)
+}
+
+//Write the partial state of every group to the file, replacing the previous snapshot. It is written
+//beside the file first and renamed over it, so a failed run leaves the previous snapshot whole.
+pub(crate) fn save(
+    path: &Path,
+    keys: &[VariableName],
+    aggregates: &[NamedAggregate],
+    groups: &[Option<Tuple>],
+) -> SnapshotResult<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let file = tempfile::Builder::new().prefix(".logq-snapshot-").tempfile_in(dir)?;
+    write(file.as_file(), keys, aggregates, groups)?;
+    file.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+pub(crate) fn write<W: Write>(
+    mut writer: W,
+    keys: &[VariableName],
+    aggregates: &[NamedAggregate],
+    groups: &[Option<Tuple>],
+) -> SnapshotResult<()> {
+    let shape = aggregate_shape(aggregates)?;
+
+    let mut obj = json::JsonValue::new_object();
+    obj["keys"] = keys.to_vec().into();
+    obj["aggregates"] = shape
+        .iter()
+        .map(|(name, function, argument)| json::array![name.as_str(), function.as_str(), argument.as_str()])
+        .collect::<Vec<_>>()
+        .into();
+
+    let mut encoded_groups = Vec::with_capacity(groups.len());
+    for key in groups.iter() {
+        let mut group = json::JsonValue::new_object();
+        group["key"] = match key {
+            Some(values) => values
+                .iter()
+                .map(encode_value)
+                .collect::<SnapshotResult<Vec<_>>>()?
+                .into(),
+            None => json::Null,
+        };
+
+        let mut states = Vec::with_capacity(aggregates.len());
+        for named_aggregate in aggregates.iter() {
+            //Every value of the group was skipped, there is no state to keep
+            let state = match named_aggregate.aggregate.get_partial(key) {
+                Ok(state) => state.to_string().into(),
+                Err(AggregateError::KeyNotFound) => json::Null,
+                Err(e) => return Err(SnapshotError::Invalid(e.to_string())),
+            };
+            states.push(state);
+        }
+        group["states"] = states.into();
+        encoded_groups.push(group);
+    }
+    obj["groups"] = encoded_groups.into();
+
+    writer.write_all(obj.dump().as_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+//Merge the partial states of a snapshot into the aggregates, returning the groups it holds. A missing
+//file is the first run and holds no group.
+pub(crate) fn load(
+    path: &Path,
+    keys: &[VariableName],
+    aggregates: &mut [NamedAggregate],
+) -> SnapshotResult<Vec<Option<Tuple>>> {
+    match fs::File::open(path) {
+        Ok(file) => read(file, keys, aggregates),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub(crate) fn read<R: Read>(
+    mut reader: R,
+    keys: &[VariableName],
+    aggregates: &mut [NamedAggregate],
+) -> SnapshotResult<Vec<Option<Tuple>>> {
+    let shape = aggregate_shape(aggregates)?;
+    let mut content = String::new();
+    reader.read_to_string(&mut content)?;
+    let obj = json::parse(&content)?;
+
+    let saved_keys: Vec<String> = obj["keys"]
+        .members()
+        .filter_map(|k| k.as_str())
+        .map(String::from)
+        .collect();
+    if saved_keys != keys {
+        return Err(SnapshotError::Mismatch(format!(
+            "grouped by [{}] instead of [{}]",
+            saved_keys.join(", "),
+            keys.join(", ")
+        )));
+    }
+    let saved_shape: Vec<(String, String, String)> = obj["aggregates"]
+        .members()
+        .map(|triple| {
+            (
+                triple[0].as_str().unwrap_or_default().to_string(),
+                triple[1].as_str().unwrap_or_default().to_string(),
+                triple[2].as_str().unwrap_or_default().to_string(),
+            )
+        })
+        .collect();
+    if saved_shape != shape {
+        let describe = |shape: &[(String, String, String)]| {
+            shape
+                .iter()
+                .map(|(name, function, argument)| format!("{}({}) as {}", function, argument, name))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        return Err(SnapshotError::Mismatch(format!(
+            "aggregates [{}] instead of [{}]",
+            describe(&saved_shape),
+            describe(&shape)
+        )));
+    }
+
+    let mut groups = Vec::new();
+    for group in obj["groups"].members() {
+        let key = if group["key"].is_null() {
+            None
+        } else {
+            Some(
+                group["key"]
+                    .members()
+                    .map(decode_value)
+                    .collect::<SnapshotResult<Vec<_>>>()?,
+            )
+        };
+        if key.as_ref().map(Vec::len).unwrap_or(0) != keys.len() {
+            return Err(SnapshotError::Invalid(
+                "a group key has the wrong number of values".to_string(),
+            ));
+        }
+
+        for (named_aggregate, state) in aggregates.iter_mut().zip(group["states"].members()) {
+            if state.is_null() {
+                continue;
+            }
+
+            let state = state
+                .as_str()
+                .and_then(|s| PartialState::from_str(s).ok())
+                .ok_or_else(|| SnapshotError::Invalid(format!("unknown aggregate state {}", state)))?;
+            named_aggregate
+                .aggregate
+                .merge_partial(key.clone(), state)
+                .map_err(|e| SnapshotError::Invalid(e.to_string()))?;
+        }
+        groups.push(key);
+    }
+
+    Ok(groups)
+}
@@ -1,19 +1,86 @@
 use super::datasource::RecordRead;
-use super::types::{Aggregate, Formula, Named, NamedAggregate, StreamResult};
+use super::memory::{self, MemoryBudget, Reservation, SpillFile};
+use super::snapshot::{self, SnapshotError};
+use super::types::{
+    self, Aggregate, AggregateError, Expression, ExpressionError, FlushEmit, Formula, JoinType, Named, NamedAggregate,
+    Ordering, StreamError, StreamResult, ValueType,
+};
 use crate::common;
 use crate::common::types::{Tuple, Value, VariableName, Variables};
 use prettytable::Cell;
+use std::cell::RefCell;
 use std::collections::hash_set;
+use std::collections::BinaryHeap;
+use std::collections::HashMap;
 use std::collections::VecDeque;
-#[derive(Debug, PartialEq, Eq)]
-pub(crate) struct Record {
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+//The field names of the records of a stream, shared by all of them rather than copied into each one
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub(crate) struct Schema {
     field_names: Vec<VariableName>,
+}
+
+impl Schema {
+    pub(crate) fn new(field_names: Vec<VariableName>) -> Self {
+        Schema { field_names }
+    }
+
+    pub(crate) fn field_names(&self) -> &[VariableName] {
+        &self.field_names
+    }
+}
+
+//The schema of the output of a stream derived from the schema of its input, only derived again when
+//the input schema changes
+#[derive(Debug, Default)]
+struct DerivedSchema {
+    cached: Option<(Arc<Schema>, Arc<Schema>)>,
+}
+
+impl DerivedSchema {
+    fn get<F>(&mut self, input: &Arc<Schema>, derive: F) -> Arc<Schema>
+    where
+        F: FnOnce(&Schema) -> Schema,
+    {
+        if let Some((cached_input, output)) = &self.cached {
+            if Arc::ptr_eq(cached_input, input) || cached_input == input {
+                return output.clone();
+            }
+        }
+
+        let output = Arc::new(derive(input));
+        self.cached = Some((input.clone(), output.clone()));
+        output
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Record {
+    schema: Arc<Schema>,
     data: Vec<Value>,
 }
 
 impl Record {
     pub(crate) fn new(field_names: Vec<VariableName>, data: Vec<Value>) -> Self {
-        Record { field_names, data }
+        Record::with_schema(Arc::new(Schema::new(field_names)), data)
+    }
+
+    pub(crate) fn with_schema(schema: Arc<Schema>, data: Vec<Value>) -> Self {
+        Record { schema, data }
+    }
+
+    pub(crate) fn schema(&self) -> &Arc<Schema> {
+        &self.schema
+    }
+
+    pub(crate) fn field_names(&self) -> &[VariableName] {
+        self.schema.field_names()
     }
 
     pub(crate) fn get(&self, field_names: &[VariableName]) -> Vec<Value> {
@@ -32,17 +99,22 @@ impl Record {
     pub(crate) fn to_variables(&self) -> Variables {
         let mut variables = Variables::default();
 
-        for i in 0..self.field_names.len() {
-            variables.insert(self.field_names[i].clone(), self.data[i].clone());
+        for (name, value) in self.field_names().iter().zip(self.data.iter()) {
+            variables.insert(name.clone(), value.clone());
         }
 
         variables
     }
 
+    //The bytes of the values, the schema is shared with the other records of the stream
+    pub(crate) fn approximate_size(&self) -> u64 {
+        memory::values_size(&self.data)
+    }
+
     pub(crate) fn to_tuples(&self) -> Vec<(VariableName, Value)> {
         let mut res = Vec::new();
-        for i in 0..self.field_names.len() {
-            res.push((self.field_names[i].clone(), self.data[i].clone()));
+        for (name, value) in self.field_names().iter().zip(self.data.iter()) {
+            res.push((name.clone(), value.clone()));
         }
 
         res
@@ -60,6 +132,7 @@ impl Record {
                 Value::DateTime(dt) => Cell::new(&*dt.to_string()),
                 Value::HttpRequest(request) => Cell::new(&*request.to_string()),
                 Value::Host(host) => Cell::new(&*host.to_string()),
+                Value::Array(values) => Cell::new(&common::types::format_array(values)),
             })
             .collect()
     }
@@ -76,6 +149,7 @@ impl Record {
                 Value::DateTime(dt) => dt.to_string(),
                 Value::HttpRequest(request) => request.to_string(),
                 Value::Host(host) => host.to_string(),
+                Value::Array(values) => common::types::format_array(values),
             })
             .collect()
     }
@@ -86,20 +160,72 @@ pub(crate) trait RecordStream {
     fn close(&self);
 }
 
+//prev(column) reads the variable of the column under this prefix, the value the column had in the
+//record before. It follows the order the records reach the Map, so it is only meaningful over input
+//that is already in time order, and it sees the rows the WHERE clause later drops.
+pub(crate) const PREVIOUS_PREFIX: &str = "__prev_";
+
 pub(crate) struct MapStream {
     pub(crate) named_list: Vec<Named>,
     pub(crate) variables: Variables,
     pub(crate) source: Box<dyn RecordStream>,
+    schema: DerivedSchema,
+    //The variables of the last record, only kept when an expression uses prev()
+    previous: Option<Option<Variables>>,
 }
 
 impl MapStream {
     pub(crate) fn new(named_list: Vec<Named>, variables: Variables, source: Box<dyn RecordStream>) -> Self {
+        let uses_previous = named_list.iter().any(|named| match named {
+            Named::Expression(expr, _) => uses_previous(expr),
+            Named::Star => false,
+        });
+
         MapStream {
             named_list,
             variables,
             source,
+            schema: DerivedSchema::default(),
+            previous: if uses_previous { Some(None) } else { None },
+        }
+    }
+}
+
+fn uses_previous(expr: &Expression) -> bool {
+    match expr {
+        Expression::Variable(name) => name.starts_with(PREVIOUS_PREFIX),
+        Expression::Function(_, arguments) => arguments.iter().any(|argument| match argument {
+            Named::Expression(expr, _) => uses_previous(expr),
+            Named::Star => false,
+        }),
+        Expression::Logic(formula) => formula_uses_previous(formula),
+    }
+}
+
+fn formula_uses_previous(formula: &Formula) -> bool {
+    match formula {
+        Formula::Constant(_) => false,
+        Formula::And(left, right) | Formula::Or(left, right) => {
+            formula_uses_previous(left) || formula_uses_previous(right)
+        }
+        Formula::Not(child) => formula_uses_previous(child),
+        Formula::Predicate(_, left, right) => uses_previous(left) || uses_previous(right),
+        Formula::InList(child, list) => uses_previous(child) || list.iter().any(uses_previous),
+    }
+}
+
+pub(crate) fn map_field_names(named_list: &[Named], input: &[VariableName]) -> Vec<VariableName> {
+    let mut field_names = Vec::new();
+    for (idx, named) in named_list.iter().enumerate() {
+        match named {
+            Named::Expression(_, Some(name)) => field_names.push(name.clone()),
+            //Give the column a positional name if not provided.
+            Named::Expression(_, None) => field_names.push(format!("{:02}", idx)),
+            Named::Star => field_names.extend(input.iter().cloned()),
         }
     }
+
+    field_names
 }
 
 impl RecordStream for MapStream {
@@ -109,34 +235,36 @@ impl RecordStream for MapStream {
 
     fn next(&mut self) -> StreamResult<Option<Record>> {
         if let Some(record) = self.source.next()? {
-            let variables = common::types::merge(self.variables.clone(), record.to_variables());
+            let mut variables = common::types::merge(self.variables.clone(), record.to_variables());
+            if let Some(previous) = self.previous.as_mut() {
+                //Null for the first record
+                for field_name in record.field_names().iter() {
+                    let value = previous
+                        .as_ref()
+                        .and_then(|previous| previous.get(field_name).cloned())
+                        .unwrap_or(Value::Null);
+                    variables.insert(format!("{}{}", PREVIOUS_PREFIX, field_name), value);
+                }
+                *previous = Some(record.to_variables());
+            }
 
-            let mut field_names = Vec::new();
-            let mut data = Vec::new();
-            for (idx, named) in self.named_list.iter().enumerate() {
+            let named_list = &self.named_list;
+            let schema = self.schema.get(record.schema(), |input| {
+                Schema::new(map_field_names(named_list, input.field_names()))
+            });
+
+            let mut data = Vec::with_capacity(schema.field_names().len());
+            for named in self.named_list.iter() {
                 match named {
-                    Named::Expression(expr, name_opt) => {
-                        let name = if let Some(name) = name_opt {
-                            name.clone()
-                        } else {
-                            //Give the column a positional name if not provided.
-                            format!("{:02}", idx)
-                        };
-
-                        field_names.push(name);
+                    Named::Expression(expr, _) => {
                         let v = expr.expression_value(variables.clone())?;
                         data.push(v);
                     }
-                    Named::Star => {
-                        for (k, v) in record.to_tuples().into_iter() {
-                            field_names.push(k);
-                            data.push(v);
-                        }
-                    }
+                    Named::Star => data.extend(record.data.iter().cloned()),
                 }
             }
 
-            let record = Record::new(field_names, data);
+            let record = Record::with_schema(schema, data);
             Ok(Some(record))
         } else {
             Ok(None)
@@ -145,31 +273,50 @@ impl RecordStream for MapStream {
 }
 
 pub(crate) struct LimitStream {
-    curr: u32,
-    row_count: u32,
+    curr: i32,
+    row_count: i32,
     source: Box<dyn RecordStream>,
+    trailing: VecDeque<Record>,
 }
 
 impl LimitStream {
-    pub(crate) fn new(row_count: u32, source: Box<dyn RecordStream>) -> Self {
+    pub(crate) fn new(row_count: i32, source: Box<dyn RecordStream>) -> Self {
         LimitStream {
             curr: 0,
             row_count,
             source,
+            trailing: VecDeque::new(),
         }
     }
 }
 
 impl RecordStream for LimitStream {
     fn next(&mut self) -> StreamResult<Option<Record>> {
-        while let Some(record) = self.source.next()? {
-            if self.curr < self.row_count {
-                self.curr += 1;
-                return Ok(Some(record));
+        if self.row_count < 0 {
+            //Hold back the last N records, they will never be emitted
+            let skipped = self.row_count.unsigned_abs() as usize;
+            while let Some(record) = self.source.next()? {
+                self.trailing.push_back(record);
+                if self.trailing.len() > skipped {
+                    return Ok(self.trailing.pop_front());
+                }
             }
+
+            return Ok(None);
         }
 
-        Ok(None)
+        //Once the limit is reached, the rest of the source is left unread
+        if self.curr >= self.row_count {
+            return Ok(None);
+        }
+
+        match self.source.next()? {
+            Some(record) => {
+                self.curr += 1;
+                Ok(Some(record))
+            }
+            None => Ok(None),
+        }
     }
 
     fn close(&self) {
@@ -212,200 +359,106 @@ impl RecordStream for FilterStream {
     }
 }
 
-pub(crate) struct InMemoryStream {
-    pub(crate) data: VecDeque<Record>,
+pub(crate) struct ChangedByStream {
+    column_name: VariableName,
+    previous: Option<Value>,
+    source: Box<dyn RecordStream>,
 }
 
-impl InMemoryStream {
-    pub(crate) fn new(data: VecDeque<Record>) -> InMemoryStream {
-        InMemoryStream { data }
+impl ChangedByStream {
+    pub(crate) fn new(column_name: VariableName, source: Box<dyn RecordStream>) -> Self {
+        ChangedByStream {
+            column_name,
+            previous: None,
+            source,
+        }
     }
 }
 
-impl RecordStream for InMemoryStream {
+impl RecordStream for ChangedByStream {
     fn next(&mut self) -> StreamResult<Option<Record>> {
-        if let Some(record) = self.data.pop_front() {
-            Ok(Some(record))
-        } else {
-            Ok(None)
+        while let Some(record) = self.source.next()? {
+            let current = match record.to_variables().get(&self.column_name) {
+                Some(value) => value.clone(),
+                None => {
+                    return Err(ExpressionError::KeyNotFound.into());
+                }
+            };
+
+            if self.previous.as_ref() != Some(&current) {
+                self.previous = Some(current);
+                return Ok(Some(record));
+            }
         }
+
+        Ok(None)
     }
 
-    fn close(&self) {}
+    fn close(&self) {
+        self.source.close();
+    }
 }
 
-pub(crate) struct GroupByStream {
-    keys: Vec<VariableName>,
-    variables: Variables,
-    aggregates: Vec<NamedAggregate>,
+pub(crate) struct EnforceSchemaStream {
+    contract: Vec<(VariableName, ValueType)>,
+    every_row: bool,
+    checked: bool,
     source: Box<dyn RecordStream>,
-    group_iterator: Option<hash_set::IntoIter<Option<Tuple>>>,
 }
 
-impl<'a> GroupByStream {
+impl EnforceSchemaStream {
     pub(crate) fn new(
-        keys: Vec<VariableName>,
-        variables: Variables,
-        aggregates: Vec<NamedAggregate>,
+        contract: Vec<(VariableName, ValueType)>,
+        every_row: bool,
         source: Box<dyn RecordStream>,
     ) -> Self {
-        GroupByStream {
-            keys,
-            variables,
-            aggregates,
+        EnforceSchemaStream {
+            contract,
+            every_row,
+            checked: false,
             source,
-            group_iterator: None,
         }
     }
-}
 
-impl RecordStream for GroupByStream {
-    fn next(&mut self) -> StreamResult<Option<Record>> {
-        if self.group_iterator.is_none() {
-            let mut groups: hash_set::HashSet<Option<Tuple>> = hash_set::HashSet::new();
-            while let Some(record) = self.source.next()? {
-                let variables = common::types::merge(self.variables.clone(), record.to_variables());
-                let key = if self.keys.is_empty() {
-                    None
-                } else {
-                    Some(record.get(&self.keys))
-                };
-
-                groups.insert(key.clone());
-                for named_agg in self.aggregates.iter_mut() {
-                    match &mut named_agg.aggregate {
-                        Aggregate::Avg(ref mut inner, named) => {
-                            let val = match named {
-                                Named::Expression(expr, _) => expr.expression_value(variables.clone())?,
-                                Named::Star => {
-                                    unreachable!();
-                                }
-                            };
+    //The columns have to be the ones of the contract in its order, a null fits any type
+    fn check(&self, record: &Record) -> StreamResult<()> {
+        let expected: Vec<&VariableName> = self.contract.iter().map(|(column_name, _)| column_name).collect();
+        let actual: Vec<&VariableName> = record.field_names().iter().collect();
+        if expected != actual {
+            return Err(StreamError::SchemaMismatch(format!(
+                "columns [{}] instead of [{}]",
+                actual.iter().map(|s| s.as_str()).collect::<Vec<_>>().join(", "),
+                expected.iter().map(|s| s.as_str()).collect::<Vec<_>>().join(", ")
+            )));
+        }
 
-                            inner.add_record(key.clone(), val)?;
-                        }
-                        Aggregate::Count(ref mut inner, named) => {
-                            match named {
-                                Named::Expression(expr, _) => {
-                                    let val = expr.expression_value(variables.clone())?;
-                                    inner.add_record(key.clone(), val)?;
-                                }
-                                Named::Star => {
-                                    inner.add_row(key.clone())?;
-                                }
-                            };
-                        }
-                        Aggregate::First(ref mut inner, named) => {
-                            match named {
-                                Named::Expression(expr, _) => {
-                                    let val = expr.expression_value(variables.clone())?;
-                                    inner.add_record(key.clone(), val)?;
-                                }
-                                Named::Star => {
-                                    unreachable!();
-                                }
-                            };
-                        }
-                        Aggregate::Last(ref mut inner, named) => {
-                            match named {
-                                Named::Expression(expr, _) => {
-                                    let val = expr.expression_value(variables.clone())?;
-                                    inner.add_record(key.clone(), val)?;
-                                }
-                                Named::Star => {
-                                    unreachable!();
-                                }
-                            };
-                        }
-                        Aggregate::Max(ref mut inner, named) => {
-                            match named {
-                                Named::Expression(expr, _) => {
-                                    let val = expr.expression_value(variables.clone())?;
-                                    inner.add_record(key.clone(), val)?;
-                                }
-                                Named::Star => {
-                                    unreachable!();
-                                }
-                            };
-                        }
-                        Aggregate::Min(ref mut inner, named) => {
-                            match named {
-                                Named::Expression(expr, _) => {
-                                    let val = expr.expression_value(variables.clone())?;
-                                    inner.add_record(key.clone(), val)?;
-                                }
-                                Named::Star => {
-                                    unreachable!();
-                                }
-                            };
-                        }
-                        Aggregate::Sum(ref mut inner, named) => {
-                            match named {
-                                Named::Expression(expr, _) => {
-                                    let val = expr.expression_value(variables.clone())?;
-                                    inner.add_record(key.clone(), val)?;
-                                }
-                                Named::Star => {
-                                    unreachable!();
-                                }
-                            };
-                        }
-                        Aggregate::ApproxCountDistinct(ref mut inner, named) => {
-                            match named {
-                                Named::Expression(expr, _) => {
-                                    let val = expr.expression_value(variables.clone())?;
-                                    inner.add_record(key.clone(), val)?;
-                                }
-                                Named::Star => {
-                                    unreachable!();
-                                }
-                            };
-                        }
-                        Aggregate::PercentileDisc(ref mut inner, column_name) => {
-                            let val = variables.get(column_name).unwrap();
-                            inner.add_record(key.clone(), val.clone())?;
-                        }
-                        Aggregate::ApproxPercentile(ref mut inner, column_name) => {
-                            let val = variables.get(column_name).unwrap();
-                            inner.add_record(key.clone(), val.clone())?;
-                        }
-                    }
+        for ((column_name, value_type), value) in self.contract.iter().zip(record.data.iter()) {
+            match ValueType::of(value) {
+                Some(actual_type) if actual_type != *value_type => {
+                    return Err(StreamError::SchemaMismatch(format!(
+                        "{} is {} instead of {}",
+                        column_name, actual_type, value_type
+                    )));
                 }
+                _ => {}
             }
-
-            self.group_iterator = Some(groups.into_iter());
         }
 
-        let iter = self.group_iterator.as_mut().unwrap();
-        if let Some(key) = iter.next() {
-            let mut values: Vec<Value> = Vec::new();
-            let mut fields: Vec<VariableName> = Vec::new();
-
-            if let Some(values_in_key) = &key {
-                for k in self.keys.iter() {
-                    fields.push(k.clone());
-                }
-
-                for v in values_in_key {
-                    values.push(v.clone());
-                }
-            }
+        Ok(())
+    }
+}
 
-            for named_agg in self.aggregates.iter_mut() {
-                if let Some(ref field_name) = named_agg.name_opt {
-                    fields.push(field_name.clone());
-                } else {
-                    //FIXME: Insert empty string for now
-                    fields.push("".to_string());
+impl RecordStream for EnforceSchemaStream {
+    fn next(&mut self) -> StreamResult<Option<Record>> {
+        match self.source.next()? {
+            Some(record) => {
+                if !self.checked || self.every_row {
+                    self.check(&record)?;
+                    self.checked = true;
                 }
-                let v = named_agg.aggregate.get_aggregated(&key)?;
-                values.push(v);
+                Ok(Some(record))
             }
-
-            let record = Record::new(fields, values);
-            Ok(Some(record))
-        } else {
-            Ok(None)
+            None => Ok(None),
         }
     }
 
@@ -414,128 +467,2444 @@ impl RecordStream for GroupByStream {
     }
 }
 
-pub(crate) struct LogFileStream {
-    pub(crate) reader: Box<dyn RecordRead>,
+//Reads the whole source on the first call, then hands the records out sorted by the columns in turn,
+//or by the stable hash of their values. Once the records read so far go over the memory budget they
+//are sorted into a run on disk, and the runs are merged with the records left in memory when the
+//source ends.
+pub(crate) struct OrderByStream {
+    column_names: Vec<VariableName>,
+    orderings: Vec<Ordering>,
+    by_row_hash: bool,
+    source: Box<dyn RecordStream>,
+    runs: Option<Vec<SortedRun>>,
+    //The next record of every run with its sort key, only used when there is more than one run
+    heads: Vec<Option<(SortKey, Record)>>,
+    reservation: Reservation,
 }
 
-impl RecordStream for LogFileStream {
+enum SortKey {
+    Values(Vec<Option<Value>>),
+    RowHash(u64),
+}
+
+enum SortedRun {
+    Memory(VecDeque<Record>),
+    //The file is removed once the run is dropped
+    File {
+        lines: io::Lines<io::BufReader<fs::File>>,
+        _spill_file: SpillFile,
+    },
+}
+
+impl SortedRun {
     fn next(&mut self) -> StreamResult<Option<Record>> {
-        if let Some(record) = self.reader.read_record()? {
-            Ok(Some(record))
-        } else {
-            Ok(None)
+        match self {
+            SortedRun::Memory(records) => Ok(records.pop_front()),
+            SortedRun::File { lines, .. } => match lines.next() {
+                Some(line) => {
+                    let record = decode_record(&line.map_err(StreamError::SpillIo)?).map_err(spill_error)?;
+                    Ok(Some(record))
+                }
+                None => Ok(None),
+            },
         }
     }
+}
 
-    fn close(&self) {}
+//A spilled record is a line of its field names and its values, typed as the keys of a snapshot are
+fn encode_record(record: &Record) -> snapshot::SnapshotResult<String> {
+    let values = record
+        .data
+        .iter()
+        .map(snapshot::encode_value)
+        .collect::<snapshot::SnapshotResult<Vec<_>>>()?;
+    Ok(json::array![record.field_names().to_vec(), values].dump())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::common::types::Value;
-    use crate::execution::stream::{Record, RecordStream};
-    use crate::execution::types;
-    use crate::execution::types::Expression;
+fn decode_record(line: &str) -> snapshot::SnapshotResult<Record> {
+    let line = json::parse(line)?;
+    let field_names = line[0]
+        .members()
+        .map(|name| name.as_str().map(String::from))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| SnapshotError::Invalid("a spilled record has an unnamed field".to_string()))?;
+    let values = line[1]
+        .members()
+        .map(snapshot::decode_value)
+        .collect::<snapshot::SnapshotResult<Vec<_>>>()?;
+    Ok(Record::new(field_names, values))
+}
 
-    #[test]
-    fn test_limit_stream() {
-        let mut variables: Variables = Variables::default();
-        variables.insert("const".to_string(), Value::String("example.com".to_string()));
+//The errors of writing or reading back a spill, told apart from the ones of a --snapshot file
+fn spill_error(err: SnapshotError) -> StreamError {
+    match err {
+        SnapshotError::Io(e) => StreamError::SpillIo(e),
+        SnapshotError::Unsupported(function) => StreamError::SpillUnsupported(format!("{}()", function)),
+        e => StreamError::Snapshot(e),
+    }
+}
 
-        let mut records = VecDeque::new();
-        records.push_back(Record::new(
-            vec!["host".to_string(), "port".to_string()],
-            vec![Value::String("example01.com".to_string()), Value::Int(8000)],
-        ));
-        records.push_back(Record::new(
-            vec!["host".to_string(), "port".to_string()],
-            vec![Value::String("example.com".to_string()), Value::Int(8001)],
-        ));
-        records.push_back(Record::new(
-            vec!["host".to_string(), "port".to_string()],
-            vec![Value::String("example01.com".to_string()), Value::Int(8002)],
-        ));
-        let stream = Box::new(InMemoryStream::new(records));
+fn compare_sort_keys_by(orderings: &[Ordering], a: &SortKey, b: &SortKey) -> std::cmp::Ordering {
+    let (a, b) = match (a, b) {
+        (SortKey::Values(a), SortKey::Values(b)) => (a, b),
+        (SortKey::RowHash(a), SortKey::RowHash(b)) => return a.cmp(b),
+        //The records of a stream are all sorted the same way
+        _ => return std::cmp::Ordering::Equal,
+    };
+    for (idx, ordering) in orderings.iter().enumerate() {
+        let cmp = types::compare_sort_keys(a[idx].as_ref(), b[idx].as_ref());
+        let cmp = match ordering {
+            Ordering::Asc => cmp,
+            Ordering::Desc => cmp.reverse(),
+        };
+        if cmp != std::cmp::Ordering::Equal {
+            return cmp;
+        }
+    }
 
-        let mut limit_stream = LimitStream::new(1, stream);
+    std::cmp::Ordering::Equal
+}
 
-        let mut result = Vec::new();
-        while let Some(n) = limit_stream.next().unwrap() {
-            result.push(n);
+impl OrderByStream {
+    pub(crate) fn new(
+        column_names: Vec<VariableName>,
+        orderings: Vec<Ordering>,
+        source: Box<dyn RecordStream>,
+    ) -> Self {
+        OrderByStream {
+            column_names,
+            orderings,
+            by_row_hash: false,
+            source,
+            runs: None,
+            heads: Vec::new(),
+            reservation: MemoryBudget::default().reserve(),
         }
+    }
 
-        let expected = vec![Record::new(
-            vec!["host".to_string(), "port".to_string()],
-            vec![Value::String("example01.com".to_string()), Value::Int(8000)],
-        )];
-
-        assert_eq!(expected, result);
+    //The rows are handed out by the hash of the columns, the same order whatever order they came in
+    pub(crate) fn by_row_hash(column_names: Vec<VariableName>, source: Box<dyn RecordStream>) -> Self {
+        OrderByStream {
+            by_row_hash: true,
+            ..OrderByStream::new(column_names, Vec::new(), source)
+        }
     }
 
-    #[test]
-    fn test_filter_stream() {
-        let left = Box::new(types::Expression::Variable("host".to_string()));
-        let right = Box::new(types::Expression::Variable("const".to_string()));
-        let rel = types::Relation::Equal;
-        let predicate = types::Formula::Predicate(rel, left, right);
+    pub(crate) fn with_memory_budget(mut self, memory_budget: &MemoryBudget) -> Self {
+        self.reservation = memory_budget.reserve();
+        self
+    }
 
-        let mut variables: Variables = Variables::default();
-        variables.insert("const".to_string(), Value::String("example.com".to_string()));
+    fn sort_key(&self, record: &Record) -> StreamResult<SortKey> {
+        if self.by_row_hash {
+            return Ok(SortKey::RowHash(types::row_hash(record, &self.column_names)?));
+        }
 
-        let mut records = VecDeque::new();
-        records.push_back(Record::new(
-            vec!["host".to_string(), "port".to_string()],
-            vec![Value::String("example01.com".to_string()), Value::Int(8000)],
-        ));
-        records.push_back(Record::new(
-            vec!["host".to_string(), "port".to_string()],
-            vec![Value::String("example.com".to_string()), Value::Int(8001)],
-        ));
-        records.push_back(Record::new(
-            vec!["host".to_string(), "port".to_string()],
-            vec![Value::String("example01.com".to_string()), Value::Int(8002)],
-        ));
-        let stream = Box::new(InMemoryStream::new(records));
+        let variables = record.to_variables();
+        Ok(SortKey::Values(
+            self.column_names
+                .iter()
+                .map(|column_name| variables.get(column_name).cloned())
+                .collect(),
+        ))
+    }
 
-        let mut filtered_stream = FilterStream::new(predicate, variables, stream);
+    //A stable sort, the records equal on every column keep the order they came in
+    fn sort(&self, records: &mut [(SortKey, Record)]) {
+        records.sort_by(|(a, _), (b, _)| compare_sort_keys_by(&self.orderings, a, b));
+    }
 
-        let mut result = Vec::new();
-        while let Some(n) = filtered_stream.next().unwrap() {
-            result.push(n);
+    fn spill_run(&self, records: Vec<(SortKey, Record)>) -> StreamResult<SortedRun> {
+        let spill_file = SpillFile::create().map_err(StreamError::SpillIo)?;
+        let mut writer = io::BufWriter::new(spill_file.as_file());
+        for (_, record) in records.iter() {
+            let line = encode_record(record).map_err(spill_error)?;
+            writeln!(writer, "{}", line).map_err(StreamError::SpillIo)?;
         }
+        writer.flush().map_err(StreamError::SpillIo)?;
 
-        let expected = vec![Record::new(
-            vec!["host".to_string(), "port".to_string()],
-            vec![Value::String("example.com".to_string()), Value::Int(8001)],
-        )];
-
-        assert_eq!(expected, result);
+        let reader = io::BufReader::new(spill_file.reopen().map_err(StreamError::SpillIo)?);
+        Ok(SortedRun::File {
+            lines: reader.lines(),
+            _spill_file: spill_file,
+        })
     }
 
-    #[test]
-    fn test_map_stream_with_star() {
-        let named_list = vec![Named::Star];
-
-        let mut variables: Variables = Variables::default();
-        variables.insert("const".to_string(), Value::String("example.com".to_string()));
+    fn sort_runs(&mut self) -> StreamResult<()> {
+        let mut runs = Vec::new();
+        let mut records = Vec::new();
+        while let Some(record) = self.source.next()? {
+            let within_budget = self.reservation.grow(|| record.approximate_size());
+            records.push((self.sort_key(&record)?, record));
+            if !within_budget {
+                //The records before this one go to disk, spilling can't make room for a record that
+                //doesn't fit on its own
+                let last = records.pop();
+                if records.is_empty() {
+                    return Err(StreamError::MemoryBudget(self.reservation.limit()));
+                }
 
-        let mut records = VecDeque::new();
-        records.push_back(Record::new(
-            vec!["host".to_string(), "port".to_string()],
-            vec![Value::String("example01.com".to_string()), Value::Int(8000)],
-        ));
-        records.push_back(Record::new(
-            vec!["host".to_string(), "port".to_string()],
-            vec![Value::String("example.com".to_string()), Value::Int(8001)],
-        ));
-        records.push_back(Record::new(
-            vec!["host".to_string(), "port".to_string()],
-            vec![Value::String("example01.com".to_string()), Value::Int(8002)],
+                let mut spilled = std::mem::take(&mut records);
+                self.sort(&mut spilled);
+                runs.push(self.spill_run(spilled)?);
+                self.reservation.clear();
+                if let Some((key, record)) = last {
+                    if !self.reservation.grow(|| record.approximate_size()) {
+                        return Err(StreamError::MemoryBudget(self.reservation.limit()));
+                    }
+                    records.push((key, record));
+                }
+            }
+        }
+        self.sort(&mut records);
+        runs.push(SortedRun::Memory(
+            records.into_iter().map(|(_, record)| record).collect(),
         ));
-        let stream = Box::new(InMemoryStream::new(records));
 
-        let mut filtered_stream = MapStream::new(named_list, variables, stream);
+        if runs.len() > 1 {
+            for run in runs.iter_mut() {
+                let head = match run.next()? {
+                    Some(record) => Some((self.sort_key(&record)?, record)),
+                    None => None,
+                };
+                self.heads.push(head);
+            }
+        }
+        self.runs = Some(runs);
+        Ok(())
+    }
+
+    //The smallest head, the earliest run on a tie as the runs are in the order their records came in
+    fn merge_next(&mut self) -> StreamResult<Option<Record>> {
+        let mut smallest: Option<usize> = None;
+        for (idx, head) in self.heads.iter().enumerate() {
+            if let Some((key, _)) = head {
+                let is_smaller = match smallest {
+                    Some(smallest_idx) => {
+                        let smallest_key = &self.heads[smallest_idx].as_ref().unwrap().0;
+                        compare_sort_keys_by(&self.orderings, key, smallest_key) == std::cmp::Ordering::Less
+                    }
+                    None => true,
+                };
+                if is_smaller {
+                    smallest = Some(idx);
+                }
+            }
+        }
+
+        let idx = match smallest {
+            Some(idx) => idx,
+            None => return Ok(None),
+        };
+        let next = match self.runs.as_mut() {
+            Some(runs) => runs[idx].next()?,
+            None => None,
+        };
+        let next_head = match next {
+            Some(record) => Some((self.sort_key(&record)?, record)),
+            None => None,
+        };
+        let head = std::mem::replace(&mut self.heads[idx], next_head);
+        Ok(head.map(|(_, record)| record))
+    }
+}
+
+impl RecordStream for OrderByStream {
+    fn next(&mut self) -> StreamResult<Option<Record>> {
+        if self.runs.is_none() {
+            self.sort_runs()?;
+        }
+
+        match self.runs.as_mut() {
+            Some(runs) if runs.len() == 1 => runs[0].next(),
+            _ => self.merge_next(),
+        }
+    }
+
+    fn close(&self) {
+        self.source.close();
+    }
+}
+
+//Passes each row on the first time it is seen and drops its repeats. A row is emitted as soon as it is
+//read, but the set of the distinct rows seen so far is kept until the end, so the memory grows with the
+//number of distinct rows rather than staying constant.
+pub(crate) struct DistinctStream {
+    seen: hash_set::HashSet<Vec<(VariableName, Value)>>,
+    source: Box<dyn RecordStream>,
+    reservation: Reservation,
+}
+
+impl DistinctStream {
+    pub(crate) fn new(source: Box<dyn RecordStream>) -> Self {
+        DistinctStream {
+            seen: hash_set::HashSet::new(),
+            source,
+            reservation: MemoryBudget::default().reserve(),
+        }
+    }
+
+    //A row is emitted as soon as it is first seen, so the set of seen rows can't be spilled and going
+    //over the budget fails the query
+    pub(crate) fn with_memory_budget(mut self, memory_budget: &MemoryBudget) -> Self {
+        self.reservation = memory_budget.reserve();
+        self
+    }
+}
+
+impl RecordStream for DistinctStream {
+    fn next(&mut self) -> StreamResult<Option<Record>> {
+        while let Some(record) = self.source.next()? {
+            if self.seen.insert(record.to_tuples()) {
+                if !self.reservation.grow(|| record.approximate_size()) {
+                    return Err(StreamError::MemoryBudget(self.reservation.limit()));
+                }
+                return Ok(Some(record));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn close(&self) {
+        self.source.close();
+    }
+}
+
+//The next record of one of the merged sources with its sort key. BinaryHeap pops the greatest so the
+//order is reversed to pop the smallest key, and equal keys go to the earlier source to keep the merge
+//stable.
+struct MergeHead {
+    key: Value,
+    source_idx: usize,
+    record: Record,
+}
+
+impl Ord for MergeHead {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        types::compare_sort_keys(Some(&other.key), Some(&self.key)).then_with(|| other.source_idx.cmp(&self.source_idx))
+    }
+}
+
+impl PartialOrd for MergeHead {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for MergeHead {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == std::cmp::Ordering::Equal
+    }
+}
+
+impl Eq for MergeHead {}
+
+//Merges sources that are each sorted by the column into one sorted stream. Only the next record of
+//every source is held, so unlike ORDER BY nothing is buffered, but the sources have to be sorted
+//already: a source going back in order fails the query rather than silently breaking the order.
+pub(crate) struct MergeSortedStream {
+    column_name: VariableName,
+    sources: Vec<Box<dyn RecordStream>>,
+    heads: BinaryHeap<MergeHead>,
+    started: bool,
+}
+
+impl MergeSortedStream {
+    pub(crate) fn new(column_name: VariableName, sources: Vec<Box<dyn RecordStream>>) -> Self {
+        MergeSortedStream {
+            column_name,
+            sources,
+            heads: BinaryHeap::new(),
+            started: false,
+        }
+    }
+
+    //Reads the next record of the source onto the heap, the key of its previous record is the lower bound
+    fn advance(&mut self, source_idx: usize, previous_key: Option<&Value>) -> StreamResult<()> {
+        if let Some(record) = self.sources[source_idx].next()? {
+            let key = match record.field_names().iter().position(|name| name == &self.column_name) {
+                Some(idx) => record.data[idx].clone(),
+                None => {
+                    return Err(StreamError::UnknownColumn(self.column_name.clone()));
+                }
+            };
+            if let Some(previous_key) = previous_key {
+                if types::compare_sort_keys(Some(&key), Some(previous_key)) == std::cmp::Ordering::Less {
+                    return Err(StreamError::UnsortedSource(self.column_name.clone()));
+                }
+            }
+
+            self.heads.push(MergeHead {
+                key,
+                source_idx,
+                record,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+impl RecordStream for MergeSortedStream {
+    fn next(&mut self) -> StreamResult<Option<Record>> {
+        if !self.started {
+            self.started = true;
+            for source_idx in 0..self.sources.len() {
+                self.advance(source_idx, None)?;
+            }
+        }
+
+        match self.heads.pop() {
+            Some(head) => {
+                self.advance(head.source_idx, Some(&head.key))?;
+                Ok(Some(head.record))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn close(&self) {
+        for source in self.sources.iter() {
+            source.close();
+        }
+    }
+}
+
+//Removes the helper columns a plan only needed internally, e.g. an aggregate computed for ORDER BY
+pub(crate) struct DropColumnsStream {
+    column_names: Vec<VariableName>,
+    source: Box<dyn RecordStream>,
+    schema: DerivedSchema,
+}
+
+impl DropColumnsStream {
+    pub(crate) fn new(column_names: Vec<VariableName>, source: Box<dyn RecordStream>) -> Self {
+        DropColumnsStream {
+            column_names,
+            source,
+            schema: DerivedSchema::default(),
+        }
+    }
+}
+
+impl RecordStream for DropColumnsStream {
+    fn next(&mut self) -> StreamResult<Option<Record>> {
+        match self.source.next()? {
+            Some(record) => {
+                let column_names = &self.column_names;
+                let schema = self.schema.get(record.schema(), |input| {
+                    Schema::new(
+                        input
+                            .field_names()
+                            .iter()
+                            .filter(|field_name| !column_names.contains(field_name))
+                            .cloned()
+                            .collect(),
+                    )
+                });
+                let data = record
+                    .field_names()
+                    .iter()
+                    .zip(record.data.iter())
+                    .filter(|(field_name, _)| !self.column_names.contains(field_name))
+                    .map(|(_, value)| value.clone())
+                    .collect();
+                Ok(Some(Record::with_schema(schema, data)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn close(&self) {
+        self.source.close();
+    }
+}
+
+//A record for each element of an array column, the other fields repeated. A record whose array is empty
+//or null has no element and is dropped, any other value passes through as it is.
+pub(crate) struct UnnestStream {
+    column_name: VariableName,
+    source: Box<dyn RecordStream>,
+    pending: VecDeque<Record>,
+}
+
+impl UnnestStream {
+    pub(crate) fn new(column_name: VariableName, source: Box<dyn RecordStream>) -> Self {
+        UnnestStream {
+            column_name,
+            source,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl RecordStream for UnnestStream {
+    fn next(&mut self) -> StreamResult<Option<Record>> {
+        loop {
+            if let Some(record) = self.pending.pop_front() {
+                return Ok(Some(record));
+            }
+
+            let record = match self.source.next()? {
+                Some(record) => record,
+                None => {
+                    return Ok(None);
+                }
+            };
+            let idx = match record
+                .field_names()
+                .iter()
+                .position(|field_name| *field_name == self.column_name)
+            {
+                Some(idx) => idx,
+                None => {
+                    return Err(StreamError::UnknownColumn(self.column_name.clone()));
+                }
+            };
+
+            match &record.data[idx] {
+                Value::Array(values) => {
+                    for value in values.iter() {
+                        let mut data = record.data.clone();
+                        data[idx] = value.clone();
+                        self.pending
+                            .push_back(Record::with_schema(record.schema().clone(), data));
+                    }
+                }
+                Value::Null => {}
+                _ => {
+                    return Ok(Some(record));
+                }
+            }
+        }
+    }
+
+    fn close(&self) {
+        self.source.close();
+    }
+}
+
+//Pairs every record of the source with each of the right records, the fields of both side by side.
+//The right side is read before the first row, the source is read a record at a time as the rows are
+//emitted, and the join fails once it would emit more rows than the limit.
+pub(crate) struct JoinStream {
+    join_type: JoinType,
+    source: Box<dyn RecordStream>,
+    right_source: Box<dyn RecordStream>,
+    right: Vec<Record>,
+    loaded: bool,
+    current: Option<Record>,
+    right_idx: usize,
+    emitted: u64,
+    row_limit: u64,
+    schema: DerivedSchema,
+    reservation: Reservation,
+}
+
+impl JoinStream {
+    pub(crate) fn new(
+        join_type: JoinType,
+        source: Box<dyn RecordStream>,
+        right_source: Box<dyn RecordStream>,
+        row_limit: u64,
+    ) -> Self {
+        JoinStream {
+            join_type,
+            source,
+            right_source,
+            right: Vec::new(),
+            loaded: false,
+            current: None,
+            right_idx: 0,
+            emitted: 0,
+            row_limit,
+            schema: DerivedSchema::default(),
+            reservation: MemoryBudget::default().reserve(),
+        }
+    }
+
+    //The right side is kept in memory for the whole join, so it counts against the budget and fails
+    //the query rather than spilling
+    pub(crate) fn with_memory_budget(mut self, memory_budget: &MemoryBudget) -> Self {
+        self.reservation = memory_budget.reserve();
+        self
+    }
+
+    fn load(&mut self) -> StreamResult<()> {
+        while let Some(record) = self.right_source.next()? {
+            if !self.reservation.grow(|| record.approximate_size()) {
+                return Err(StreamError::MemoryBudget(self.reservation.limit()));
+            }
+            self.right.push(record);
+        }
+
+        self.loaded = true;
+        Ok(())
+    }
+}
+
+impl RecordStream for JoinStream {
+    fn next(&mut self) -> StreamResult<Option<Record>> {
+        if !self.loaded {
+            self.load()?;
+        }
+
+        match self.join_type {
+            JoinType::Cross => loop {
+                if let Some(left) = &self.current {
+                    if let Some(right) = self.right.get(self.right_idx) {
+                        if self.emitted >= self.row_limit {
+                            return Err(StreamError::JoinLimit(self.row_limit));
+                        }
+                        self.right_idx += 1;
+                        self.emitted += 1;
+
+                        let mut clash = None;
+                        let schema = self.schema.get(left.schema(), |input| {
+                            let mut field_names = input.field_names().to_vec();
+                            clash = right
+                                .field_names()
+                                .iter()
+                                .find(|name| field_names.contains(name))
+                                .cloned();
+                            field_names.extend(right.field_names().iter().cloned());
+                            Schema::new(field_names)
+                        });
+                        if let Some(column_name) = clash {
+                            return Err(StreamError::JoinColumnClash(column_name));
+                        }
+                        let mut data = left.data.clone();
+                        data.extend(right.data.iter().cloned());
+                        return Ok(Some(Record::with_schema(schema, data)));
+                    }
+                }
+
+                //Without a right record there's nothing to pair the source with, so it isn't read
+                if self.right.is_empty() {
+                    return Ok(None);
+                }
+
+                match self.source.next()? {
+                    Some(record) => {
+                        self.current = Some(record);
+                        self.right_idx = 0;
+                    }
+                    None => return Ok(None),
+                }
+            },
+        }
+    }
+
+    fn close(&self) {
+        self.source.close();
+    }
+}
+
+//Picks the given columns of every record in the given order, a column the records don't have is an error
+pub(crate) struct SelectColumnsStream {
+    schema: Arc<Schema>,
+    source: Box<dyn RecordStream>,
+}
+
+impl SelectColumnsStream {
+    pub(crate) fn new(column_names: Vec<VariableName>, source: Box<dyn RecordStream>) -> Self {
+        SelectColumnsStream {
+            schema: Arc::new(Schema::new(column_names)),
+            source,
+        }
+    }
+}
+
+impl RecordStream for SelectColumnsStream {
+    fn next(&mut self) -> StreamResult<Option<Record>> {
+        match self.source.next()? {
+            Some(record) => {
+                let mut data = Vec::with_capacity(self.schema.field_names().len());
+                for column_name in self.schema.field_names().iter() {
+                    match record
+                        .field_names()
+                        .iter()
+                        .position(|field_name| field_name == column_name)
+                    {
+                        Some(idx) => data.push(record.data[idx].clone()),
+                        None => {
+                            return Err(StreamError::UnknownColumn(column_name.clone()));
+                        }
+                    }
+                }
+                Ok(Some(Record::with_schema(self.schema.clone(), data)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn close(&self) {
+        self.source.close();
+    }
+}
+
+//Renders every value as a string so each column has a single type whatever the rows hold: nulls are
+//empty, floats have six decimal places, timestamps are RFC 3339 and everything else uses its display form.
+pub(crate) struct StringifyStream {
+    source: Box<dyn RecordStream>,
+}
+
+impl StringifyStream {
+    pub(crate) fn new(source: Box<dyn RecordStream>) -> Self {
+        StringifyStream { source }
+    }
+}
+
+fn stringify(value: Value) -> Value {
+    let s = match value {
+        Value::String(s) => s,
+        Value::Int(i) => i.to_string(),
+        Value::Float(f) => format!("{:.6}", f.into_inner()),
+        Value::Boolean(b) => b.to_string(),
+        Value::Null => String::new(),
+        Value::DateTime(dt) => dt.to_rfc3339(),
+        Value::HttpRequest(request) => request.to_string(),
+        Value::Host(host) => host.to_string(),
+        Value::Array(values) => common::types::format_array(&values),
+    };
+
+    Value::String(s)
+}
+
+impl RecordStream for StringifyStream {
+    fn next(&mut self) -> StreamResult<Option<Record>> {
+        match self.source.next()? {
+            Some(record) => {
+                let data = record.data.into_iter().map(stringify).collect();
+                Ok(Some(Record::with_schema(record.schema, data)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn close(&self) {
+        self.source.close();
+    }
+}
+
+pub(crate) struct InMemoryStream {
+    pub(crate) data: VecDeque<Record>,
+}
+
+impl InMemoryStream {
+    pub(crate) fn new(data: VecDeque<Record>) -> InMemoryStream {
+        InMemoryStream { data }
+    }
+}
+
+impl RecordStream for InMemoryStream {
+    fn next(&mut self) -> StreamResult<Option<Record>> {
+        if let Some(record) = self.data.pop_front() {
+            Ok(Some(record))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn close(&self) {}
+}
+
+//The source of a Tee shared by its two branches. The records the detail reads are kept for the summary,
+//which reads them back before the rest of the source the detail left unread.
+struct TeeSource {
+    source: Box<dyn RecordStream>,
+    kept: VecDeque<Record>,
+    reservation: Reservation,
+}
+
+struct TeeDetailInput {
+    shared: Rc<RefCell<TeeSource>>,
+}
+
+impl RecordStream for TeeDetailInput {
+    fn next(&mut self) -> StreamResult<Option<Record>> {
+        let mut shared = self.shared.borrow_mut();
+        match shared.source.next()? {
+            Some(record) => {
+                if !shared.reservation.grow(|| record.approximate_size()) {
+                    return Err(StreamError::MemoryBudget(shared.reservation.limit()));
+                }
+                shared.kept.push_back(record.clone());
+                Ok(Some(record))
+            }
+            None => Ok(None),
+        }
+    }
+
+    //The summary still reads the source
+    fn close(&self) {}
+}
+
+struct TeeSummaryInput {
+    shared: Rc<RefCell<TeeSource>>,
+}
+
+impl RecordStream for TeeSummaryInput {
+    fn next(&mut self) -> StreamResult<Option<Record>> {
+        let mut shared = self.shared.borrow_mut();
+        match shared.kept.pop_front() {
+            Some(record) => {
+                if shared.kept.is_empty() {
+                    shared.reservation.clear();
+                }
+                Ok(Some(record))
+            }
+            None => shared.source.next(),
+        }
+    }
+
+    fn close(&self) {
+        self.shared.borrow().source.close();
+    }
+}
+
+//Feeds a single scan of the source to a detail and a summary plan. Every detail row is emitted as the
+//source is read, followed by the summary rows. Only the records the detail has read and the summary
+//hasn't are kept, and they count against the memory budget.
+pub(crate) struct TeeStream {
+    outputs: VecDeque<Box<dyn RecordStream>>,
+}
+
+impl TeeStream {
+    //The inputs of the detail and the summary plans, in that order
+    pub(crate) fn inputs(
+        source: Box<dyn RecordStream>,
+        memory_budget: &MemoryBudget,
+    ) -> (Box<dyn RecordStream>, Box<dyn RecordStream>) {
+        let shared = Rc::new(RefCell::new(TeeSource {
+            source,
+            kept: VecDeque::new(),
+            reservation: memory_budget.reserve(),
+        }));
+        let detail = TeeDetailInput { shared: shared.clone() };
+        let summary = TeeSummaryInput { shared };
+        (Box::new(detail), Box::new(summary))
+    }
+
+    pub(crate) fn new(detail: Box<dyn RecordStream>, summary: Box<dyn RecordStream>) -> Self {
+        TeeStream {
+            outputs: vec![detail, summary].into_iter().collect(),
+        }
+    }
+}
+
+impl RecordStream for TeeStream {
+    fn next(&mut self) -> StreamResult<Option<Record>> {
+        while let Some(output) = self.outputs.front_mut() {
+            if let Some(record) = output.next()? {
+                return Ok(Some(record));
+            }
+            self.outputs.pop_front();
+        }
+
+        Ok(None)
+    }
+
+    fn close(&self) {
+        for output in self.outputs.iter() {
+            output.close();
+        }
+    }
+}
+
+//The spilled groups are split by the hash of their key into this many parts, each merged back on its own
+const SPILL_PARTITIONS: usize = 16;
+//The state of an aggregate is counted at a fixed size, and the values kept by percentile_disc,
+//percentile_cont, entropy and distinct_ratio are counted on top as they are added
+const GROUP_STATE_SIZE: u64 = 64;
+
+fn group_size(key: &Option<Tuple>, aggregate_count: usize) -> u64 {
+    let key_size = key.as_ref().map(|values| memory::values_size(values)).unwrap_or(0);
+    key_size + aggregate_count as u64 * GROUP_STATE_SIZE
+}
+
+pub(crate) struct GroupByStream {
+    keys: Vec<VariableName>,
+    variables: Variables,
+    aggregates: Vec<NamedAggregate>,
+    initial_aggregates: Vec<NamedAggregate>,
+    source: Box<dyn RecordStream>,
+    group_iterator: Option<hash_set::IntoIter<Option<Tuple>>>,
+    partial: bool,
+    window: Option<Duration>,
+    clock: Box<dyn Fn() -> Instant>,
+    exhausted: bool,
+    skip_empty: bool,
+    saw_records: bool,
+    flush_emit: FlushEmit,
+    //The records of the previous flush and the ones emitted so far by this flush, by group
+    previous: HashMap<Option<Tuple>, Record>,
+    current: HashMap<Option<Tuple>, Record>,
+    pending: VecDeque<Record>,
+    snapshot: Option<PathBuf>,
+    reservation: Reservation,
+    //The runs of partial states written while reading, by partition, and the partitions left to emit
+    spilled: Vec<Vec<SpillFile>>,
+    spilled_partitions: VecDeque<Vec<SpillFile>>,
+}
+
+impl<'a> GroupByStream {
+    pub(crate) fn new(
+        keys: Vec<VariableName>,
+        variables: Variables,
+        aggregates: Vec<NamedAggregate>,
+        source: Box<dyn RecordStream>,
+    ) -> Self {
+        GroupByStream {
+            keys,
+            variables,
+            initial_aggregates: aggregates.clone(),
+            aggregates,
+            source,
+            group_iterator: None,
+            partial: false,
+            window: None,
+            clock: Box::new(Instant::now),
+            exhausted: false,
+            skip_empty: false,
+            saw_records: false,
+            flush_emit: FlushEmit::default(),
+            previous: HashMap::new(),
+            current: HashMap::new(),
+            pending: VecDeque::new(),
+            snapshot: None,
+            reservation: MemoryBudget::default().reserve(),
+            spilled: Vec::new(),
+            spilled_partitions: VecDeque::new(),
+        }
+    }
+
+    //Emit the partial state of each aggregate instead of the final value
+    pub(crate) fn with_partial(mut self, partial: bool) -> Self {
+        self.partial = partial;
+        self
+    }
+
+    //Only meant for a source that never ends, like a followed stdin. Once the interval of wall time
+    //has passed, the groups accumulated so far are emitted and the aggregates start over. The clock
+    //is checked as records arrive, so a quiet source delays the flush until its next record.
+    pub(crate) fn with_window(mut self, window: Option<Duration>) -> Self {
+        self.window = window;
+        self
+    }
+
+    //Without keys, a source without any record still makes one row of zero counts and nulls as in SQL,
+    //unless it is skipped. The partial states of nothing are not emitted either way, merging them is a no-op.
+    pub(crate) fn with_skip_empty(mut self, skip_empty: bool) -> Self {
+        self.skip_empty = skip_empty;
+        self
+    }
+
+    pub(crate) fn with_flush_emit(mut self, flush_emit: FlushEmit) -> Self {
+        self.flush_emit = flush_emit;
+        self
+    }
+
+    //The groups saved by a previous run are loaded before the first record, and all of them are saved
+    //back once the source ends, so the aggregation picks up over the next batch of logs
+    pub(crate) fn with_snapshot(mut self, snapshot: Option<PathBuf>) -> Self {
+        self.snapshot = snapshot;
+        self
+    }
+
+    //Over the budget the partial states of the groups are moved to disk and the aggregates start over.
+    //Once the source ends every part of the spilled groups is merged back and emitted in turn, so only
+    //the aggregates that have a partial state, sum, count and avg, can be spilled.
+    pub(crate) fn with_memory_budget(mut self, memory_budget: &MemoryBudget) -> Self {
+        self.reservation = memory_budget.reserve();
+        self
+    }
+
+    #[cfg(test)]
+    pub(crate) fn with_clock(mut self, clock: Box<dyn Fn() -> Instant>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    //The key of the record's group and the bytes the aggregates kept of its values
+    fn add_record(&mut self, record: Record) -> StreamResult<(Option<Tuple>, u64)> {
+        let variables = common::types::merge(self.variables.clone(), record.to_variables());
+        let key = if self.keys.is_empty() {
+            None
+        } else {
+            Some(record.get(&self.keys))
+        };
+        let measure = self.reservation.is_limited();
+        let mut retained = 0;
+
+        for named_agg in self.aggregates.iter_mut() {
+            match &mut named_agg.aggregate {
+                Aggregate::Avg(ref mut inner, named) => {
+                    let val = match named {
+                        Named::Expression(expr, _) => expr.expression_value(variables.clone())?,
+                        Named::Star => {
+                            unreachable!();
+                        }
+                    };
+
+                    inner.add_record(key.clone(), val)?;
+                }
+                Aggregate::Count(ref mut inner, named) => {
+                    match named {
+                        Named::Expression(expr, _) => {
+                            let val = expr.expression_value(variables.clone())?;
+                            inner.add_record(key.clone(), val)?;
+                        }
+                        Named::Star => {
+                            inner.add_row(key.clone())?;
+                        }
+                    };
+                }
+                Aggregate::First(ref mut inner, named) => {
+                    match named {
+                        Named::Expression(expr, _) => {
+                            let val = expr.expression_value(variables.clone())?;
+                            inner.add_record(key.clone(), val)?;
+                        }
+                        Named::Star => {
+                            unreachable!();
+                        }
+                    };
+                }
+                Aggregate::Last(ref mut inner, named) => {
+                    match named {
+                        Named::Expression(expr, _) => {
+                            let val = expr.expression_value(variables.clone())?;
+                            inner.add_record(key.clone(), val)?;
+                        }
+                        Named::Star => {
+                            unreachable!();
+                        }
+                    };
+                }
+                Aggregate::Max(ref mut inner, named) => {
+                    match named {
+                        Named::Expression(expr, _) => {
+                            let val = expr.expression_value(variables.clone())?;
+                            inner.add_record(key.clone(), val)?;
+                        }
+                        Named::Star => {
+                            unreachable!();
+                        }
+                    };
+                }
+                Aggregate::Min(ref mut inner, named) => {
+                    match named {
+                        Named::Expression(expr, _) => {
+                            let val = expr.expression_value(variables.clone())?;
+                            inner.add_record(key.clone(), val)?;
+                        }
+                        Named::Star => {
+                            unreachable!();
+                        }
+                    };
+                }
+                Aggregate::Sum(ref mut inner, named) => {
+                    match named {
+                        Named::Expression(expr, _) => {
+                            let val = expr.expression_value(variables.clone())?;
+                            inner.add_record(key.clone(), val)?;
+                        }
+                        Named::Star => {
+                            unreachable!();
+                        }
+                    };
+                }
+                Aggregate::ApproxCountDistinct(ref mut inner, named) => {
+                    match named {
+                        Named::Expression(expr, _) => {
+                            let val = expr.expression_value(variables.clone())?;
+                            inner.add_record(key.clone(), val)?;
+                        }
+                        Named::Star => {
+                            unreachable!();
+                        }
+                    };
+                }
+                Aggregate::PercentileDisc(ref mut inner, column_name) => {
+                    let val = variables.get(column_name).unwrap();
+                    if measure && *val != Value::Null {
+                        retained += memory::value_size(val);
+                    }
+                    inner.add_record(key.clone(), val.clone())?;
+                }
+                Aggregate::PercentileCont(ref mut inner, column_name) => {
+                    let val = variables.get(column_name).unwrap();
+                    if measure && *val != Value::Null {
+                        retained += std::mem::size_of::<f64>() as u64;
+                    }
+                    inner.add_record(key.clone(), val.clone())?;
+                }
+                Aggregate::ApproxPercentile(ref mut inner, column_name) => {
+                    let val = variables.get(column_name).unwrap();
+                    inner.add_record(key.clone(), val.clone())?;
+                }
+                Aggregate::CountBuckets(ref mut inner, formulas) => {
+                    let mut matches = Vec::with_capacity(formulas.len());
+                    for formula in formulas.iter() {
+                        matches.push(formula.evaluate(variables.clone())?);
+                    }
+                    inner.add_row(key.clone(), &matches)?;
+                }
+                Aggregate::ConditionalRatio(ref mut inner, numerator, denominator) => {
+                    let numerator = numerator.evaluate(variables.clone())?;
+                    let denominator = denominator.evaluate(variables.clone())?;
+                    inner.add_row(key.clone(), numerator, denominator)?;
+                }
+                Aggregate::GeoMean(ref mut inner, named) => {
+                    let val = match named {
+                        Named::Expression(expr, _) => expr.expression_value(variables.clone())?,
+                        Named::Star => {
+                            unreachable!();
+                        }
+                    };
+
+                    inner.add_record(key.clone(), val)?;
+                }
+                Aggregate::Entropy(ref mut inner, named) => {
+                    let val = match named {
+                        Named::Expression(expr, _) => expr.expression_value(variables.clone())?,
+                        Named::Star => {
+                            unreachable!();
+                        }
+                    };
+
+                    let seen = inner
+                        .frequencies
+                        .get(&key)
+                        .map(|frequencies| frequencies.contains_key(&val))
+                        .unwrap_or(false);
+                    if measure && !seen {
+                        retained += memory::value_size(&val);
+                    }
+                    inner.add_record(key.clone(), val)?;
+                }
+                Aggregate::DistinctRatio(ref mut inner, named) => {
+                    let val = match named {
+                        Named::Expression(expr, _) => expr.expression_value(variables.clone())?,
+                        Named::Star => {
+                            unreachable!();
+                        }
+                    };
+
+                    let seen = inner
+                        .groups
+                        .get(&key)
+                        .map(|(distinct, _)| distinct.contains(&val))
+                        .unwrap_or(false);
+                    if measure && val != Value::Null && !seen {
+                        retained += memory::value_size(&val);
+                    }
+                    inner.add_record(key.clone(), val)?;
+                }
+                Aggregate::Variance(ref mut inner, named) => {
+                    let val = match named {
+                        Named::Expression(expr, _) => expr.expression_value(variables.clone())?,
+                        Named::Star => {
+                            unreachable!();
+                        }
+                    };
+
+                    inner.add_record(key.clone(), val)?;
+                }
+                Aggregate::StdDev(ref mut inner, named) => {
+                    let val = match named {
+                        Named::Expression(expr, _) => expr.expression_value(variables.clone())?,
+                        Named::Star => {
+                            unreachable!();
+                        }
+                    };
+
+                    inner.add_record(key.clone(), val)?;
+                }
+                Aggregate::GroupDuration(ref mut inner, named) => {
+                    match named {
+                        Named::Expression(expr, _) => {
+                            let val = expr.expression_value(variables.clone())?;
+                            inner.add_record(key.clone(), val)?;
+                        }
+                        Named::Star => {
+                            unreachable!();
+                        }
+                    };
+                }
+            }
+        }
+
+        Ok((key, retained))
+    }
+
+    //Read until the source ends or, in windowed mode, until the interval has passed
+    fn fill_groups(&mut self) -> StreamResult<()> {
+        let started = (self.clock)();
+        let mut groups: hash_set::HashSet<Option<Tuple>> = hash_set::HashSet::new();
+        if let Some(path) = &self.snapshot {
+            if !self.saw_records {
+                let loaded = snapshot::load(path, &self.keys, &mut self.aggregates)?;
+                self.saw_records = !loaded.is_empty();
+                groups.extend(loaded);
+            }
+        }
+
+        let aggregate_count = self.aggregates.len();
+        loop {
+            match self.source.next()? {
+                Some(record) => {
+                    let (key, retained) = self.add_record(record)?;
+                    let within_budget = if groups.contains(&key) {
+                        self.reservation.grow(|| retained)
+                    } else {
+                        let within_budget = self.reservation.grow(|| retained + group_size(&key, aggregate_count));
+                        groups.insert(key);
+                        within_budget
+                    };
+                    //A snapshot is saved from the groups in memory, and spilling can't make room
+                    //for a group that doesn't fit on its own
+                    if !within_budget {
+                        if groups.len() == 1 || self.snapshot.is_some() {
+                            return Err(StreamError::MemoryBudget(self.reservation.limit()));
+                        }
+                        self.spill(&mut groups)?;
+                    }
+                    self.saw_records = true;
+
+                    if let Some(window) = self.window {
+                        if (self.clock)().duration_since(started) >= window {
+                            break;
+                        }
+                    }
+                }
+                None => {
+                    self.exhausted = true;
+                    break;
+                }
+            }
+        }
+
+        if !self.spilled.is_empty() {
+            self.spill(&mut groups)?;
+            self.spilled_partitions = self.spilled.drain(..).filter(|runs| !runs.is_empty()).collect();
+        }
+
+        if let (Some(path), true) = (&self.snapshot, self.exhausted) {
+            let groups: Vec<Option<Tuple>> = groups.iter().cloned().collect();
+            snapshot::save(path, &self.keys, &self.aggregates, &groups)?;
+        }
+
+        self.group_iterator = Some(groups.into_iter());
+        Ok(())
+    }
+
+    fn spill(&mut self, groups: &mut hash_set::HashSet<Option<Tuple>>) -> StreamResult<()> {
+        if self.spilled.is_empty() {
+            self.spilled = (0..SPILL_PARTITIONS).map(|_| Vec::new()).collect();
+        }
+
+        let mut partitions: Vec<Vec<Option<Tuple>>> = vec![Vec::new(); SPILL_PARTITIONS];
+        for key in groups.drain() {
+            let partition = match &key {
+                Some(values) => types::values_hash(values.iter()) as usize % SPILL_PARTITIONS,
+                None => 0,
+            };
+            partitions[partition].push(key);
+        }
+        for (idx, keys) in partitions.iter().enumerate() {
+            if keys.is_empty() {
+                continue;
+            }
+            let spill_file = SpillFile::create().map_err(StreamError::SpillIo)?;
+            snapshot::write(spill_file.as_file(), &self.keys, &self.aggregates, keys).map_err(spill_error)?;
+            self.spilled[idx].push(spill_file);
+        }
+
+        self.aggregates = self.initial_aggregates.clone();
+        self.reservation.clear();
+        Ok(())
+    }
+
+    //Merge the runs of one part of the spilled groups, the only groups in memory while they are emitted
+    fn load_partition(&mut self, runs: Vec<SpillFile>) -> StreamResult<()> {
+        self.aggregates = self.initial_aggregates.clone();
+        self.reservation.clear();
+
+        let aggregate_count = self.aggregates.len();
+        let mut groups = hash_set::HashSet::new();
+        for run in runs.iter() {
+            let file = run.reopen().map_err(StreamError::SpillIo)?;
+            let keys = snapshot::read(file, &self.keys, &mut self.aggregates).map_err(spill_error)?;
+            for key in keys.into_iter() {
+                if !groups.contains(&key) {
+                    if !self.reservation.grow(|| group_size(&key, aggregate_count)) {
+                        return Err(StreamError::MemoryBudget(self.reservation.limit()));
+                    }
+                    groups.insert(key);
+                }
+            }
+        }
+
+        self.group_iterator = Some(groups.into_iter());
+        Ok(())
+    }
+
+    fn group_record(&mut self, key: Option<Tuple>) -> StreamResult<Record> {
+        let mut values: Vec<Value> = Vec::new();
+        let mut fields: Vec<VariableName> = Vec::new();
+
+        if let Some(values_in_key) = &key {
+            for k in self.keys.iter() {
+                fields.push(k.clone());
+            }
+
+            for v in values_in_key {
+                values.push(v.clone());
+            }
+        }
+        let key_count = fields.len();
+
+        for named_agg in self.aggregates.iter_mut() {
+            if let Aggregate::CountBuckets(ref inner, _) = named_agg.aggregate {
+                if self.partial {
+                    return Err(AggregateError::PartialStateNotSupported.into());
+                }
+                fields.extend(inner.field_names(&named_agg.name_opt));
+                values.extend(inner.get_aggregated(&key)?);
+                continue;
+            }
+
+            if let Some(ref field_name) = named_agg.name_opt {
+                fields.push(field_name.clone());
+            } else {
+                //FIXME: Insert empty string for now
+                fields.push("".to_string());
+            }
+            let v = if self.partial {
+                let state = named_agg.aggregate.get_partial(&key)?;
+                Value::String(state.to_string())
+            } else {
+                named_agg.aggregate.get_aggregated(&key)?
+            };
+            values.push(v);
+        }
+
+        //The merge tells the keys from the states by the list of aggregate columns, not by their values
+        if self.partial {
+            let aggregate_fields = fields[key_count..]
+                .iter()
+                .map(|field| Value::String(field.clone()))
+                .collect();
+            fields.push(super::types::PARTIAL_AGGREGATES_COLUMN.to_string());
+            values.push(Value::Array(aggregate_fields));
+        }
+
+        Ok(Record::new(fields, values))
+    }
+
+    fn empty_record(&self) -> Record {
+        let mut values: Vec<Value> = Vec::new();
+        let mut fields: Vec<VariableName> = Vec::new();
+
+        for named_agg in self.aggregates.iter() {
+            if let Aggregate::CountBuckets(ref inner, _) = named_agg.aggregate {
+                fields.extend(inner.field_names(&named_agg.name_opt));
+                values.extend((0..inner.bucket_count).map(|_| Value::Int(0)));
+                continue;
+            }
+
+            fields.push(named_agg.name_opt.clone().unwrap_or_default());
+            values.push(named_agg.aggregate.empty_value());
+        }
+
+        Record::new(fields, values)
+    }
+
+    fn is_changed(&mut self, key: Option<Tuple>, record: &Record) -> bool {
+        if self.flush_emit == FlushEmit::All {
+            return true;
+        }
+
+        let changed = self.previous.get(&key) != Some(record);
+        self.current.insert(key, record.clone());
+        changed
+    }
+
+    //Keep the groups of this flush to compare the next one against, the groups gone since the previous
+    //flush keep their keys and get nulls for the aggregates. The source ending right after a flush
+    //doesn't count as a flush of its own.
+    fn end_flush(&mut self) {
+        if self.flush_emit == FlushEmit::All || (self.exhausted && self.current.is_empty()) {
+            return;
+        }
+
+        let current = std::mem::take(&mut self.current);
+        let previous = std::mem::replace(&mut self.previous, current);
+        if self.flush_emit == FlushEmit::ChangedAndRemoved {
+            let key_count = self.keys.len();
+            for (key, record) in previous.into_iter() {
+                if !self.previous.contains_key(&key) {
+                    let data = record
+                        .data
+                        .into_iter()
+                        .enumerate()
+                        .map(|(idx, value)| if idx < key_count { value } else { Value::Null })
+                        .collect();
+                    self.pending.push_back(Record::with_schema(record.schema, data));
+                }
+            }
+        }
+    }
+}
+
+impl RecordStream for GroupByStream {
+    fn next(&mut self) -> StreamResult<Option<Record>> {
+        loop {
+            if let Some(record) = self.pending.pop_front() {
+                return Ok(Some(record));
+            }
+
+            if let Some(iter) = self.group_iterator.as_mut() {
+                if let Some(key) = iter.next() {
+                    let record = self.group_record(key.clone())?;
+                    if self.is_changed(key, &record) {
+                        return Ok(Some(record));
+                    }
+                    continue;
+                }
+
+                if let Some(runs) = self.spilled_partitions.pop_front() {
+                    self.load_partition(runs)?;
+                    continue;
+                }
+
+                self.group_iterator = None;
+                self.end_flush();
+                if self.exhausted {
+                    if self.keys.is_empty() && !self.saw_records && !self.skip_empty && !self.partial {
+                        self.pending.push_back(self.empty_record());
+                    }
+                } else {
+                    //The window has been flushed, start over with fresh aggregates
+                    self.aggregates = self.initial_aggregates.clone();
+                    self.reservation.clear();
+                }
+                continue;
+            }
+
+            if self.exhausted {
+                return Ok(None);
+            }
+            self.fill_groups()?;
+        }
+    }
+
+    fn close(&self) {
+        self.source.close();
+    }
+}
+
+pub(crate) struct LogFileStream {
+    pub(crate) reader: Box<dyn RecordRead>,
+}
+
+impl RecordStream for LogFileStream {
+    fn next(&mut self) -> StreamResult<Option<Record>> {
+        if let Some(record) = self.reader.read_record()? {
+            Ok(Some(record))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn close(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::types::Value;
+    use crate::execution::datasource::ReaderBuilder;
+    use crate::execution::stream::{Record, RecordStream};
+    use crate::execution::types;
+    use crate::execution::types::{Expression, Formula, Relation};
+    use ordered_float::OrderedFloat;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_limit_stream() {
+        let mut variables: Variables = Variables::default();
+        variables.insert("const".to_string(), Value::String("example.com".to_string()));
+
+        let mut records = VecDeque::new();
+        records.push_back(Record::new(
+            vec!["host".to_string(), "port".to_string()],
+            vec![Value::String("example01.com".to_string()), Value::Int(8000)],
+        ));
+        records.push_back(Record::new(
+            vec!["host".to_string(), "port".to_string()],
+            vec![Value::String("example.com".to_string()), Value::Int(8001)],
+        ));
+        records.push_back(Record::new(
+            vec!["host".to_string(), "port".to_string()],
+            vec![Value::String("example01.com".to_string()), Value::Int(8002)],
+        ));
+        let stream = Box::new(InMemoryStream::new(records));
+
+        let mut limit_stream = LimitStream::new(1, stream);
+
+        let mut result = Vec::new();
+        while let Some(n) = limit_stream.next().unwrap() {
+            result.push(n);
+        }
+
+        let expected = vec![Record::new(
+            vec!["host".to_string(), "port".to_string()],
+            vec![Value::String("example01.com".to_string()), Value::Int(8000)],
+        )];
+
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn test_limit_stream_with_negative_row_count() {
+        let mut records = VecDeque::new();
+        for port in 8000..8005 {
+            records.push_back(Record::new(vec!["port".to_string()], vec![Value::Int(port)]));
+        }
+        let stream = Box::new(InMemoryStream::new(records));
+
+        let mut limit_stream = LimitStream::new(-2, stream);
+
+        let mut result = Vec::new();
+        while let Some(n) = limit_stream.next().unwrap() {
+            result.push(n);
+        }
+
+        let expected: Vec<Record> = (8000..8003)
+            .map(|port| Record::new(vec!["port".to_string()], vec![Value::Int(port)]))
+            .collect();
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn test_changed_by_stream() {
+        let states = ["healthy", "healthy", "unhealthy", "unhealthy", "unhealthy", "healthy"];
+        let mut records = VecDeque::new();
+        for (i, state) in states.iter().enumerate() {
+            records.push_back(Record::new(
+                vec!["seq".to_string(), "state".to_string()],
+                vec![Value::Int(i as i32), Value::String(state.to_string())],
+            ));
+        }
+        let stream = Box::new(InMemoryStream::new(records));
+
+        let mut changed_by_stream = ChangedByStream::new("state".to_string(), stream);
+
+        let mut result = Vec::new();
+        while let Some(record) = changed_by_stream.next().unwrap() {
+            result.push(record.to_variables().get("seq").cloned().unwrap());
+        }
+
+        assert_eq!(vec![Value::Int(0), Value::Int(2), Value::Int(5)], result);
+    }
+
+    #[test]
+    fn test_enforce_schema_stream() {
+        let collect = |contract: Vec<(&str, ValueType)>, every_row: bool| {
+            let mut records = VecDeque::new();
+            for requests in [Value::Int(3), Value::Null, Value::Float(OrderedFloat(1.5))].iter() {
+                records.push_back(Record::new(
+                    vec!["host".to_string(), "requests".to_string()],
+                    vec![Value::String("a".to_string()), requests.clone()],
+                ));
+            }
+            let contract = contract
+                .into_iter()
+                .map(|(column_name, value_type)| (column_name.to_string(), value_type))
+                .collect();
+
+            let mut stream = EnforceSchemaStream::new(contract, every_row, Box::new(InMemoryStream::new(records)));
+            let mut count = 0;
+            while stream.next()?.is_some() {
+                count += 1;
+            }
+            Ok(count)
+        };
+
+        //Only the first record is checked, the float of the last one goes through
+        let matching: StreamResult<usize> =
+            collect(vec![("host", ValueType::String), ("requests", ValueType::Int)], false);
+        assert_eq!(Ok(3), matching);
+        //The null fits, the float doesn't
+        assert_eq!(
+            Err(StreamError::SchemaMismatch(
+                "requests is float instead of int".to_string()
+            )),
+            collect(vec![("host", ValueType::String), ("requests", ValueType::Int)], true)
+        );
+        assert_eq!(
+            Err(StreamError::SchemaMismatch(
+                "host is string instead of host".to_string()
+            )),
+            collect(vec![("host", ValueType::Host), ("requests", ValueType::Int)], false)
+        );
+        assert_eq!(
+            Err(StreamError::SchemaMismatch(
+                "columns [host, requests] instead of [requests, host]".to_string()
+            )),
+            collect(vec![("requests", ValueType::Int), ("host", ValueType::String)], false)
+        );
+    }
+
+    #[test]
+    fn test_order_by_stream() {
+        let rows = vec![
+            (1, "b", Value::Int(200), Value::Float(OrderedFloat(0.5))),
+            (2, "a", Value::Int(404), Value::Int(2)),
+            (3, "c", Value::Int(200), Value::Int(3)),
+            (4, "d", Value::Int(404), Value::Null),
+            (5, "e", Value::Int(200), Value::Float(OrderedFloat(3.0))),
+            (6, "f", Value::Int(404), Value::Float(OrderedFloat(2.5))),
+        ];
+        let mut records = VecDeque::new();
+        for (seq, target, status, latency) in rows.into_iter() {
+            records.push_back(Record::new(
+                vec![
+                    "seq".to_string(),
+                    "target".to_string(),
+                    "status".to_string(),
+                    "latency".to_string(),
+                ],
+                vec![Value::Int(seq), Value::String(target.to_string()), status, latency],
+            ));
+        }
+        //Without a latency column at all
+        records.push_back(Record::new(
+            vec!["seq".to_string(), "status".to_string()],
+            vec![Value::Int(7), Value::Int(200)],
+        ));
+        let sorted = |memory_budget: MemoryBudget| -> StreamResult<Vec<Value>> {
+            let stream = Box::new(InMemoryStream::new(records.clone()));
+            let mut order_by_stream = OrderByStream::new(
+                vec!["status".to_string(), "latency".to_string()],
+                vec![Ordering::Asc, Ordering::Desc],
+                stream,
+            )
+            .with_memory_budget(&memory_budget);
+
+            let mut result = Vec::new();
+            while let Some(record) = order_by_stream.next()? {
+                result.push(record.get(&["seq".to_string()]).remove(0));
+            }
+            Ok(result)
+        };
+
+        //3 and 5 tie on 3 and keep their order, the nulls and the missing column come first as the
+        //latency is descending
+        let expected: Vec<Value> = [7, 3, 5, 1, 4, 6, 2].iter().map(|seq| Value::Int(*seq)).collect();
+        assert_eq!(Ok(expected.clone()), sorted(MemoryBudget::default()));
+
+        //A budget of about two records spills sorted runs of them to disk and merges the runs back
+        let record_size = records[0].approximate_size();
+        assert_eq!(Ok(expected), sorted(MemoryBudget::new(record_size * 2)));
+        assert_eq!(
+            Err(StreamError::MemoryBudget(record_size / 2)),
+            sorted(MemoryBudget::new(record_size / 2))
+        );
+    }
+
+    #[test]
+    fn test_distinct_stream() {
+        let mut records = VecDeque::new();
+        for (host, port) in [
+            ("a.com", 80),
+            ("b.com", 80),
+            ("a.com", 80),
+            ("a.com", 443),
+            ("b.com", 80),
+        ]
+        .iter()
+        {
+            records.push_back(Record::new(
+                vec!["host".to_string(), "port".to_string()],
+                vec![Value::String(host.to_string()), Value::Int(*port)],
+            ));
+        }
+        //The same pairs in another column order make another row
+        records.push_back(Record::new(
+            vec!["port".to_string(), "host".to_string()],
+            vec![Value::Int(80), Value::String("a.com".to_string())],
+        ));
+
+        let mut distinct_stream = DistinctStream::new(Box::new(InMemoryStream::new(records)));
+        let mut result = Vec::new();
+        while let Some(record) = distinct_stream.next().unwrap() {
+            result.push(record.to_tuples());
+        }
+
+        let expected: Vec<Vec<(VariableName, Value)>> = vec![
+            vec![
+                ("host".to_string(), Value::String("a.com".to_string())),
+                ("port".to_string(), Value::Int(80)),
+            ],
+            vec![
+                ("host".to_string(), Value::String("b.com".to_string())),
+                ("port".to_string(), Value::Int(80)),
+            ],
+            vec![
+                ("host".to_string(), Value::String("a.com".to_string())),
+                ("port".to_string(), Value::Int(443)),
+            ],
+            vec![
+                ("port".to_string(), Value::Int(80)),
+                ("host".to_string(), Value::String("a.com".to_string())),
+            ],
+        ];
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn test_merge_sorted_stream() {
+        let source = |rows: &[(&str, &str)]| -> Box<dyn RecordStream> {
+            let mut records = VecDeque::new();
+            for (timestamp, file) in rows.iter() {
+                records.push_back(Record::new(
+                    vec!["timestamp".to_string(), "file".to_string()],
+                    vec![
+                        Value::DateTime(chrono::DateTime::parse_from_rfc3339(timestamp).unwrap()),
+                        Value::String(file.to_string()),
+                    ],
+                ));
+            }
+            Box::new(InMemoryStream::new(records))
+        };
+
+        let first = source(&[
+            ("2019-06-07T10:00:00Z", "first"),
+            ("2019-06-07T10:00:02Z", "first"),
+            ("2019-06-07T10:00:05Z", "first"),
+        ]);
+        //The equal timestamps go to the first source, and the sources are of different lengths
+        let second = source(&[
+            ("2019-06-07T10:00:01Z", "second"),
+            ("2019-06-07T10:00:02Z", "second"),
+            ("2019-06-07T10:00:03Z", "second"),
+            ("2019-06-07T10:00:04Z", "second"),
+            ("2019-06-08T00:00:00+09:00", "second"),
+        ]);
+        let mut merge_stream = MergeSortedStream::new("timestamp".to_string(), vec![first, second]);
+        let mut result = Vec::new();
+        while let Some(record) = merge_stream.next().unwrap() {
+            let variables = record.to_variables();
+            let timestamp = match variables.get("timestamp") {
+                Some(Value::DateTime(dt)) => dt.to_rfc3339(),
+                other => panic!("unexpected {:?}", other),
+            };
+            result.push((timestamp, variables.get("file").cloned().unwrap()));
+        }
+
+        let expected: Vec<(String, Value)> = vec![
+            ("2019-06-07T10:00:00+00:00", "first"),
+            ("2019-06-07T10:00:01+00:00", "second"),
+            ("2019-06-07T10:00:02+00:00", "first"),
+            ("2019-06-07T10:00:02+00:00", "second"),
+            ("2019-06-07T10:00:03+00:00", "second"),
+            ("2019-06-07T10:00:04+00:00", "second"),
+            ("2019-06-07T10:00:05+00:00", "first"),
+            ("2019-06-08T00:00:00+09:00", "second"),
+        ]
+        .into_iter()
+        .map(|(timestamp, file)| (timestamp.to_string(), Value::String(file.to_string())))
+        .collect();
+        assert_eq!(expected, result);
+
+        let sorted = source(&[("2019-06-07T10:00:00Z", "sorted")]);
+        let unsorted = source(&[
+            ("2019-06-07T10:00:02Z", "unsorted"),
+            ("2019-06-07T10:00:01Z", "unsorted"),
+        ]);
+        let mut merge_stream = MergeSortedStream::new("timestamp".to_string(), vec![sorted, unsorted]);
+        assert!(merge_stream.next().is_ok());
+        assert_eq!(
+            Err(StreamError::UnsortedSource("timestamp".to_string())),
+            merge_stream.next()
+        );
+    }
+
+    #[test]
+    fn test_hash_order_stream() {
+        let record = |seq: i32| {
+            Record::new(
+                vec!["seq".to_string(), "path".to_string(), "parts".to_string()],
+                vec![
+                    Value::Int(seq),
+                    Value::String(format!("/item/{}", seq)),
+                    Value::Array(vec![Value::String("item".to_string()), Value::Int(seq)]),
+                ],
+            )
+        };
+        let collect = |seqs: Vec<i32>, memory_budget: &MemoryBudget| {
+            let records: VecDeque<Record> = seqs.into_iter().map(record).collect();
+            let stream = Box::new(InMemoryStream::new(records));
+
+            let mut hash_order_stream =
+                OrderByStream::by_row_hash(vec!["path".to_string()], stream).with_memory_budget(memory_budget);
+            let mut result = Vec::new();
+            while let Some(record) = hash_order_stream.next().unwrap() {
+                result.push(record.get(&["seq".to_string()]).remove(0));
+            }
+            result
+        };
+
+        //The rows arriving in the opposite order come out the same, and not in the order of time
+        let in_time_order = collect((0..20).collect(), &MemoryBudget::default());
+        assert_eq!(
+            in_time_order,
+            collect((0..20).rev().collect(), &MemoryBudget::default())
+        );
+        assert_eq!(20, in_time_order.len());
+        assert_ne!((0..20).map(Value::Int).collect::<Vec<_>>(), in_time_order);
+
+        //Over the budget the rows and their arrays are spilled to sorted runs and merged back
+        let memory_budget = MemoryBudget::new(record(0).approximate_size() * 3);
+        assert_eq!(in_time_order, collect((0..20).collect(), &memory_budget));
+
+        let mut records = VecDeque::new();
+        records.push_back(Record::new(vec!["seq".to_string()], vec![Value::Int(0)]));
+        let mut hash_order_stream =
+            OrderByStream::by_row_hash(vec!["path".to_string()], Box::new(InMemoryStream::new(records)));
+        assert_eq!(
+            Err(StreamError::UnknownColumn("path".to_string())),
+            hash_order_stream.next()
+        );
+    }
+
+    #[test]
+    fn test_log_file_stream() {
+        let content = concat!(
+            r#"2015-11-07T18:45:33.559871Z elb1 78.168.134.92:4586 10.0.0.215:80 0.000036 0.001035 0.000025 200 200 0 42355 "GET https://example.com:443/ HTTP/1.1" "curl/7.46.0" ECDHE-RSA-AES128-GCM-SHA256 TLSv1.2"#,
+            "\n",
+            r#"2015-11-07T18:45:37.691548Z elb1 176.219.166.226:48384 10.0.2.143:80 0.000023 0.000348 0.000025 404 404 0 41690 "GET http://example.com:80/ HTTP/1.1" "curl/7.46.0" - -"#,
+            "\n",
+        );
+        let reader = ReaderBuilder::new("elb".to_string()).with_reader(std::io::BufReader::new(content.as_bytes()));
+        let mut stream = LogFileStream {
+            reader: Box::new(reader),
+        };
+
+        let mut rows = Vec::new();
+        while let Some(record) = stream.next().unwrap() {
+            let variables = record.to_variables();
+            rows.push((
+                variables.get("elbname").cloned(),
+                variables.get("client_and_port").cloned(),
+                variables.get("elb_status_code").cloned(),
+                variables.contains_key("timestamp"),
+            ));
+        }
+
+        let elb = || Some(Value::String("elb1".to_string()));
+        let host = |s: &str| Some(Value::Host(common::types::parse_host(s).unwrap()));
+        assert_eq!(
+            vec![
+                (
+                    elb(),
+                    host("78.168.134.92:4586"),
+                    Some(Value::String("200".to_string())),
+                    true
+                ),
+                (
+                    elb(),
+                    host("176.219.166.226:48384"),
+                    Some(Value::String("404".to_string())),
+                    true
+                ),
+            ],
+            rows
+        );
+    }
+
+    //Counts the records pulled out of the source
+    struct CountingStream {
+        source: InMemoryStream,
+        pulled: Rc<Cell<usize>>,
+    }
+
+    impl RecordStream for CountingStream {
+        fn next(&mut self) -> StreamResult<Option<Record>> {
+            self.pulled.set(self.pulled.get() + 1);
+            self.source.next()
+        }
+
+        fn close(&self) {}
+    }
+
+    #[test]
+    fn test_limit_stream_stops_reading() {
+        let mut records = VecDeque::new();
+        for port in 8000..8005 {
+            records.push_back(Record::new(vec!["port".to_string()], vec![Value::Int(port)]));
+        }
+        let pulled = Rc::new(Cell::new(0));
+        let stream = Box::new(CountingStream {
+            source: InMemoryStream::new(records),
+            pulled: pulled.clone(),
+        });
+
+        let mut limit_stream = LimitStream::new(2, stream);
+
+        let mut result = Vec::new();
+        while let Some(record) = limit_stream.next().unwrap() {
+            result.push(record);
+        }
+
+        let expected: Vec<Record> = (8000..8002)
+            .map(|port| Record::new(vec!["port".to_string()], vec![Value::Int(port)]))
+            .collect();
+        assert_eq!(expected, result);
+        assert_eq!(2, pulled.get());
+    }
+
+    //Advances the shared clock by a fixed step for every record it hands out
+    struct PacedStream {
+        source: InMemoryStream,
+        clock: Rc<Cell<Instant>>,
+        step: Duration,
+    }
+
+    impl RecordStream for PacedStream {
+        fn next(&mut self) -> StreamResult<Option<Record>> {
+            let record = self.source.next()?;
+            if record.is_some() {
+                self.clock.set(self.clock.get() + self.step);
+            }
+            Ok(record)
+        }
+
+        fn close(&self) {}
+    }
+
+    #[test]
+    fn test_group_by_stream() {
+        let mut records = VecDeque::new();
+        for (host, latency) in [("a", 1.0), ("b", 4.0), ("a", 3.0), ("b", 2.0), ("b", 6.0)].iter() {
+            records.push_back(Record::new(
+                vec!["host".to_string(), "latency".to_string()],
+                vec![Value::String(host.to_string()), Value::Float(OrderedFloat(*latency))],
+            ));
+        }
+        let stream = Box::new(InMemoryStream::new(records));
+        let aggregates = vec![
+            NamedAggregate::new(
+                Aggregate::Avg(
+                    types::AvgAggregate::new(),
+                    Named::Expression(Expression::Variable("latency".to_string()), Some("latency".to_string())),
+                ),
+                Some("avg_latency".to_string()),
+            ),
+            NamedAggregate::new(
+                Aggregate::Count(types::CountAggregate::new(), Named::Star),
+                Some("requests".to_string()),
+            ),
+        ];
+
+        let mut group_by_stream =
+            GroupByStream::new(vec!["host".to_string()], Variables::default(), aggregates, stream);
+
+        //One record per group once the source is exhausted, in no particular order
+        let mut result = Vec::new();
+        while let Some(record) = group_by_stream.next().unwrap() {
+            result.push(record.get(&["host".to_string(), "avg_latency".to_string(), "requests".to_string()]));
+        }
+        result.sort_by_key(|values| format!("{:?}", values));
+        let expected = vec![
+            vec![
+                Value::String("a".to_string()),
+                Value::Float(OrderedFloat(2.0)),
+                Value::Int(2),
+            ],
+            vec![
+                Value::String("b".to_string()),
+                Value::Float(OrderedFloat(4.0)),
+                Value::Int(3),
+            ],
+        ];
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn test_group_by_stream_with_window() {
+        let mut records = VecDeque::new();
+        for target in ["a", "a", "b", "b", "c"].iter() {
+            records.push_back(Record::new(
+                vec!["target".to_string()],
+                vec![Value::String(target.to_string())],
+            ));
+        }
+
+        let clock = Rc::new(Cell::new(Instant::now()));
+        let stream = Box::new(PacedStream {
+            source: InMemoryStream::new(records),
+            clock: clock.clone(),
+            step: Duration::from_secs(40),
+        });
+        let aggregates = vec![NamedAggregate::new(
+            Aggregate::Count(types::CountAggregate::new(), Named::Star),
+            Some("n".to_string()),
+        )];
+
+        let mut group_by_stream =
+            GroupByStream::new(vec!["target".to_string()], Variables::default(), aggregates, stream)
+                .with_window(Some(Duration::from_secs(60)))
+                .with_clock(Box::new(move || clock.get()));
+
+        //Every second record crosses the 60s interval, the last one is flushed at the end of the source
+        let mut result = Vec::new();
+        while let Some(record) = group_by_stream.next().unwrap() {
+            result.push(record);
+        }
+        let expected: Vec<Record> = [("a", 2), ("b", 2), ("c", 1)]
+            .iter()
+            .map(|(target, n)| {
+                Record::new(
+                    vec!["target".to_string(), "n".to_string()],
+                    vec![Value::String(target.to_string()), Value::Int(*n)],
+                )
+            })
+            .collect();
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn test_group_by_stream_emits_changed_groups() {
+        let collect = |flush_emit: FlushEmit| {
+            let mut records = VecDeque::new();
+            for (target, n) in [("a", 1), ("b", 5), ("a", 2), ("b", 5), ("c", 1), ("c", 1)].iter() {
+                records.push_back(Record::new(
+                    vec!["target".to_string(), "n".to_string()],
+                    vec![Value::String(target.to_string()), Value::Int(*n)],
+                ));
+            }
+
+            let clock = Rc::new(Cell::new(Instant::now()));
+            let stream = Box::new(PacedStream {
+                source: InMemoryStream::new(records),
+                clock: clock.clone(),
+                step: Duration::from_secs(40),
+            });
+            let aggregates = vec![NamedAggregate::new(
+                Aggregate::Max(
+                    types::MaxAggregate::new(),
+                    Named::Expression(Expression::Variable("n".to_string()), Some("n".to_string())),
+                ),
+                Some("max_n".to_string()),
+            )];
+
+            let mut group_by_stream =
+                GroupByStream::new(vec!["target".to_string()], Variables::default(), aggregates, stream)
+                    .with_window(Some(Duration::from_secs(60)))
+                    .with_flush_emit(flush_emit)
+                    .with_clock(Box::new(move || clock.get()));
+
+            //Two records per flush, the order of the groups within a flush isn't fixed
+            let mut result = Vec::new();
+            while let Some(record) = group_by_stream.next().unwrap() {
+                result.push(record.get(&["target".to_string(), "max_n".to_string()]));
+            }
+            result.sort_by_key(|values| format!("{:?}", values));
+            result
+        };
+        let row = |target: &str, max_n: Value| vec![Value::String(target.to_string()), max_n];
+
+        //b is the same in the second flush and is left out, a changed
+        assert_eq!(
+            vec![
+                row("a", Value::Int(1)),
+                row("a", Value::Int(2)),
+                row("b", Value::Int(5)),
+                row("c", Value::Int(1)),
+            ],
+            collect(FlushEmit::Changed)
+        );
+        assert_eq!(
+            vec![
+                row("a", Value::Int(1)),
+                row("a", Value::Int(2)),
+                row("a", Value::Null),
+                row("b", Value::Int(5)),
+                row("b", Value::Null),
+                row("c", Value::Int(1)),
+            ],
+            collect(FlushEmit::ChangedAndRemoved)
+        );
+        assert_eq!(5, collect(FlushEmit::All).len());
+    }
+
+    #[test]
+    fn test_group_by_stream_continues_from_snapshot() {
+        let path = std::env::temp_dir().join(format!("logq-snapshot-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let run = |keys: Vec<VariableName>, summed: &str, batch: &[(&str, i32)]| {
+            let mut records = VecDeque::new();
+            for (target, n) in batch.iter() {
+                records.push_back(Record::new(
+                    vec!["target".to_string(), "n".to_string()],
+                    vec![Value::String(target.to_string()), Value::Int(*n)],
+                ));
+            }
+            let aggregates = vec![
+                NamedAggregate::new(
+                    Aggregate::Sum(
+                        types::SumAggregate::new(),
+                        Named::Expression(Expression::Variable(summed.to_string()), None),
+                    ),
+                    Some("total".to_string()),
+                ),
+                NamedAggregate::new(
+                    Aggregate::Count(types::CountAggregate::new(), Named::Star),
+                    Some("requests".to_string()),
+                ),
+            ];
+
+            let stream = Box::new(InMemoryStream::new(records));
+            let mut group_by_stream =
+                GroupByStream::new(keys, Variables::default(), aggregates, stream).with_snapshot(Some(path.clone()));
+            let mut result = Vec::new();
+            while let Some(record) = group_by_stream.next()? {
+                result.push(record.get(&["target".to_string(), "total".to_string(), "requests".to_string()]));
+            }
+            result.sort_by_key(|values| format!("{:?}", values));
+            Ok(result)
+        };
+        let row = |target: &str, total: f32, requests: i32| {
+            vec![
+                Value::String(target.to_string()),
+                Value::Float(OrderedFloat(total)),
+                Value::Int(requests),
+            ]
+        };
+        let keys = vec!["target".to_string()];
+
+        //No snapshot yet, the first run starts from nothing and saves its groups
+        let first: StreamResult<Vec<Vec<Value>>> = run(keys.clone(), "n", &[("a", 1), ("b", 2), ("a", 3)]);
+        assert_eq!(Ok(vec![row("a", 4.0, 2), row("b", 2.0, 1)]), first);
+
+        //The second run adds to the saved groups, the ones it doesn't see are still emitted
+        let second = run(keys.clone(), "n", &[("a", 10), ("c", 5)]);
+        assert_eq!(Ok(vec![row("a", 14.0, 3), row("b", 2.0, 1), row("c", 5.0, 1)]), second);
+
+        let mismatch = run(vec!["n".to_string()], "n", &[("a", 1)]);
+        assert_eq!(
+            Err(StreamError::Snapshot(snapshot::SnapshotError::Mismatch(
+                "grouped by [target] instead of [n]".to_string()
+            ))),
+            mismatch
+        );
+
+        //The same function under the same name over another column is another aggregate
+        let mismatch = run(keys.clone(), "bytes", &[("a", 1)]);
+        match mismatch {
+            Err(StreamError::Snapshot(snapshot::SnapshotError::Mismatch(message))) => {
+                assert!(message.contains("\"bytes\""), "{}", message)
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(
+            Ok(vec![row("a", 14.0, 3), row("b", 2.0, 1), row("c", 5.0, 1)]),
+            run(keys, "n", &[])
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_group_by_stream_spills_over_memory_budget() {
+        let mut records = VecDeque::new();
+        let mut expected: HashMap<String, (f32, i32)> = HashMap::new();
+        for n in 0..200 {
+            let target = format!("t{}", n % 40);
+            let group = expected.entry(target.clone()).or_insert((0.0, 0));
+            group.0 += n as f32;
+            group.1 += 1;
+            records.push_back(Record::new(
+                vec!["target".to_string(), "n".to_string()],
+                vec![Value::String(target), Value::Int(n)],
+            ));
+        }
+        let sum = || {
+            NamedAggregate::new(
+                Aggregate::Sum(
+                    types::SumAggregate::new(),
+                    Named::Expression(Expression::Variable("n".to_string()), Some("n".to_string())),
+                ),
+                Some("total".to_string()),
+            )
+        };
+        let count = || {
+            NamedAggregate::new(
+                Aggregate::Count(types::CountAggregate::new(), Named::Star),
+                Some("requests".to_string()),
+            )
+        };
+        let group_by = |aggregates: Vec<NamedAggregate>, memory_budget: &MemoryBudget| {
+            let stream = Box::new(InMemoryStream::new(records.clone()));
+            GroupByStream::new(vec!["target".to_string()], Variables::default(), aggregates, stream)
+                .with_memory_budget(memory_budget)
+        };
+
+        //Room for a few groups, so the forty of them are spilled over and over
+        let memory_budget = MemoryBudget::new(1000);
+        let mut group_by_stream = group_by(vec![sum(), count()], &memory_budget);
+        let mut result = HashMap::new();
+        while let Some(record) = group_by_stream.next().unwrap() {
+            //Only the part of the spilled groups being emitted is in memory
+            assert!(memory_budget.used() <= 1000);
+            let values = record.get(&["target".to_string(), "total".to_string(), "requests".to_string()]);
+            match &values[..] {
+                [Value::String(target), Value::Float(total), Value::Int(requests)] => {
+                    result.insert(target.clone(), (total.into_inner(), *requests));
+                }
+                _ => panic!("unexpected record {:?}", values),
+            }
+        }
+        assert_eq!(expected, result);
+        drop(group_by_stream);
+        assert_eq!(0, memory_budget.used());
+
+        let max = NamedAggregate::new(
+            Aggregate::Max(
+                types::MaxAggregate::new(),
+                Named::Expression(Expression::Variable("n".to_string()), Some("n".to_string())),
+            ),
+            Some("largest".to_string()),
+        );
+        let mut group_by_stream = group_by(vec![count(), max], &MemoryBudget::new(1000));
+        assert_eq!(
+            Err(StreamError::SpillUnsupported("max()".to_string())),
+            group_by_stream.next()
+        );
+
+        //Not even a single group fits
+        let mut group_by_stream = group_by(vec![sum(), count()], &MemoryBudget::new(100));
+        assert_eq!(Err(StreamError::MemoryBudget(100)), group_by_stream.next());
+
+        //The values kept for an exact percentile count against the budget, even in a single group
+        let percentile = NamedAggregate::new(
+            Aggregate::PercentileDisc(
+                types::PercentileDiscAggregate::new(OrderedFloat::from(0.5), Ordering::Asc),
+                "n".to_string(),
+            ),
+            Some("median".to_string()),
+        );
+        let stream = Box::new(InMemoryStream::new(records.clone()));
+        let mut group_by_stream = GroupByStream::new(vec![], Variables::default(), vec![percentile], stream)
+            .with_memory_budget(&MemoryBudget::new(1000));
+        assert_eq!(Err(StreamError::MemoryBudget(1000)), group_by_stream.next());
+    }
+
+    #[test]
+    fn test_select_columns_stream() {
+        let mut records = VecDeque::new();
+        records.push_back(Record::new(
+            vec![
+                "timestamp".to_string(),
+                "client_and_port".to_string(),
+                "elb_status_code".to_string(),
+            ],
+            vec![
+                Value::String("2019-06-07T18:45:33.559871Z".to_string()),
+                Value::String("78.168.134.92:4586".to_string()),
+                Value::Int(200),
+            ],
+        ));
+        let stream = Box::new(InMemoryStream::new(records.clone()));
+        let mut select_stream = SelectColumnsStream::new(
+            vec!["elb_status_code".to_string(), "client_and_port".to_string()],
+            stream,
+        );
+
+        let expected = Record::new(
+            vec!["elb_status_code".to_string(), "client_and_port".to_string()],
+            vec![Value::Int(200), Value::String("78.168.134.92:4586".to_string())],
+        );
+        assert_eq!(Some(expected), select_stream.next().unwrap());
+        assert_eq!(None, select_stream.next().unwrap());
+
+        let stream = Box::new(InMemoryStream::new(records));
+        let mut select_stream = SelectColumnsStream::new(vec!["request_url".to_string()], stream);
+        assert_eq!(
+            Err(StreamError::UnknownColumn("request_url".to_string())),
+            select_stream.next()
+        );
+    }
+
+    #[test]
+    fn test_stringify_stream() {
+        let dt = chrono::DateTime::parse_from_rfc3339("2019-06-07T18:45:33.559871Z").unwrap();
+        let mut records = VecDeque::new();
+        records.push_back(Record::new(
+            vec!["a".to_string(), "b".to_string()],
+            vec![Value::Int(1), Value::Float(OrderedFloat::from(0.5))],
+        ));
+        records.push_back(Record::new(
+            vec!["a".to_string(), "b".to_string()],
+            vec![Value::Null, Value::DateTime(dt)],
+        ));
+        records.push_back(Record::new(
+            vec!["a".to_string(), "b".to_string()],
+            vec![Value::String("x".to_string()), Value::Boolean(true)],
+        ));
+        let mut stream = StringifyStream::new(Box::new(InMemoryStream::new(records)));
+
+        let mut result = Vec::new();
+        while let Some(record) = stream.next().unwrap() {
+            result.push(record.to_csv_record());
+        }
+
+        let expected = vec![
+            vec!["1".to_string(), "0.500000".to_string()],
+            vec!["".to_string(), "2019-06-07T18:45:33.559871+00:00".to_string()],
+            vec!["x".to_string(), "true".to_string()],
+        ];
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn test_join_stream() {
+        let records = |column_name: &str, count: i32| -> Box<dyn RecordStream> {
+            let records = (0..count)
+                .map(|i| Record::new(vec![column_name.to_string()], vec![Value::Int(i)]))
+                .collect();
+            Box::new(InMemoryStream::new(records))
+        };
+
+        let mut join_stream = JoinStream::new(JoinType::Cross, records("a", 2), records("b", 2), 4);
+        let mut count = 0;
+        while join_stream.next().unwrap().is_some() {
+            count += 1;
+        }
+        assert_eq!(4, count);
+
+        //The rows within the limit are emitted before the join fails
+        let mut join_stream = JoinStream::new(JoinType::Cross, records("a", 3), records("b", 2), 4);
+        for _ in 0..4 {
+            assert!(join_stream.next().unwrap().is_some());
+        }
+        assert_eq!(Err(StreamError::JoinLimit(4)), join_stream.next());
+
+        //The source is read as the rows are emitted, reading it all up front would never end
+        struct EndlessStream;
+        impl RecordStream for EndlessStream {
+            fn next(&mut self) -> StreamResult<Option<Record>> {
+                Ok(Some(Record::new(vec!["a".to_string()], vec![Value::Int(0)])))
+            }
+
+            fn close(&self) {}
+        }
+        let mut join_stream = JoinStream::new(JoinType::Cross, Box::new(EndlessStream), records("b", 1), 2);
+        assert!(join_stream.next().unwrap().is_some());
+        assert!(join_stream.next().unwrap().is_some());
+        assert_eq!(Err(StreamError::JoinLimit(2)), join_stream.next());
+
+        let mut join_stream = JoinStream::new(JoinType::Cross, records("a", 2), records("a", 2), 4);
+        assert_eq!(Err(StreamError::JoinColumnClash("a".to_string())), join_stream.next());
+    }
+
+    #[test]
+    fn test_tee_stream() {
+        let record = |seq| Record::new(vec!["seq".to_string()], vec![Value::Int(seq)]);
+        let collect = |memory_budget: &MemoryBudget| -> StreamResult<Vec<Value>> {
+            let source = InMemoryStream::new((0..5).map(record).collect());
+            let (detail, summary) = TeeStream::inputs(Box::new(source), memory_budget);
+            let mut tee_stream = TeeStream::new(Box::new(LimitStream::new(2, detail)), summary);
+            let mut result = Vec::new();
+            while let Some(record) = tee_stream.next()? {
+                result.push(record.get(&["seq".to_string()]).remove(0));
+            }
+            Ok(result)
+        };
+
+        //The summary reads back the rows the detail kept, then the rest of the source
+        let expected: Vec<Value> = vec![0, 1, 0, 1, 2, 3, 4].into_iter().map(Value::Int).collect();
+        assert_eq!(Ok(expected.clone()), collect(&MemoryBudget::default()));
+        let memory_budget = MemoryBudget::new(record(0).approximate_size() * 2);
+        assert_eq!(Ok(expected), collect(&memory_budget));
+
+        let memory_budget = MemoryBudget::new(record(0).approximate_size());
+        assert_eq!(
+            Err(StreamError::MemoryBudget(record(0).approximate_size())),
+            collect(&memory_budget)
+        );
+    }
+
+    #[test]
+    fn test_filter_stream() {
+        let left = Box::new(types::Expression::Variable("host".to_string()));
+        let right = Box::new(types::Expression::Variable("const".to_string()));
+        let rel = types::Relation::Equal;
+        let predicate = types::Formula::Predicate(rel, left, right);
+
+        let mut variables: Variables = Variables::default();
+        variables.insert("const".to_string(), Value::String("example.com".to_string()));
+
+        let mut records = VecDeque::new();
+        records.push_back(Record::new(
+            vec!["host".to_string(), "port".to_string()],
+            vec![Value::String("example01.com".to_string()), Value::Int(8000)],
+        ));
+        records.push_back(Record::new(
+            vec!["host".to_string(), "port".to_string()],
+            vec![Value::String("example.com".to_string()), Value::Int(8001)],
+        ));
+        records.push_back(Record::new(
+            vec!["host".to_string(), "port".to_string()],
+            vec![Value::String("example01.com".to_string()), Value::Int(8002)],
+        ));
+        let stream = Box::new(InMemoryStream::new(records));
+
+        let mut filtered_stream = FilterStream::new(predicate, variables, stream);
+
+        let mut result = Vec::new();
+        while let Some(n) = filtered_stream.next().unwrap() {
+            result.push(n);
+        }
+
+        let expected = vec![Record::new(
+            vec!["host".to_string(), "port".to_string()],
+            vec![Value::String("example.com".to_string()), Value::Int(8001)],
+        )];
+
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn test_map_stream_with_star() {
+        let named_list = vec![Named::Star];
+
+        let mut variables: Variables = Variables::default();
+        variables.insert("const".to_string(), Value::String("example.com".to_string()));
+
+        let mut records = VecDeque::new();
+        records.push_back(Record::new(
+            vec!["host".to_string(), "port".to_string()],
+            vec![Value::String("example01.com".to_string()), Value::Int(8000)],
+        ));
+        records.push_back(Record::new(
+            vec!["host".to_string(), "port".to_string()],
+            vec![Value::String("example.com".to_string()), Value::Int(8001)],
+        ));
+        records.push_back(Record::new(
+            vec!["host".to_string(), "port".to_string()],
+            vec![Value::String("example01.com".to_string()), Value::Int(8002)],
+        ));
+        let stream = Box::new(InMemoryStream::new(records));
+
+        let mut filtered_stream = MapStream::new(named_list, variables, stream);
 
         let mut result = Vec::new();
         while let Some(n) = filtered_stream.next().unwrap() {
@@ -560,6 +2929,92 @@ mod tests {
         assert_eq!(expected, result);
     }
 
+    #[test]
+    fn test_map_stream_with_logic() {
+        let formula = Formula::Predicate(
+            Relation::MoreThan,
+            Box::new(Expression::Variable("port".to_string())),
+            Box::new(Expression::Variable("threshold".to_string())),
+        );
+        let named_list = vec![Named::Expression(
+            Expression::Logic(Box::new(formula)),
+            Some("high".to_string()),
+        )];
+
+        let mut records = VecDeque::new();
+        records.push_back(Record::new(vec!["port".to_string()], vec![Value::Int(8000)]));
+        records.push_back(Record::new(vec!["port".to_string()], vec![Value::Int(8001)]));
+        let stream = Box::new(InMemoryStream::new(records));
+
+        let mut variables: Variables = Variables::default();
+        variables.insert("threshold".to_string(), Value::Int(8000));
+
+        let mut mapped_stream = MapStream::new(named_list, variables, stream);
+
+        let mut result = Vec::new();
+        while let Some(n) = mapped_stream.next().unwrap() {
+            result.push(n);
+        }
+
+        let expected = vec![
+            Record::new(vec!["high".to_string()], vec![Value::Boolean(false)]),
+            Record::new(vec!["high".to_string()], vec![Value::Boolean(true)]),
+        ];
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn test_map_stream_shares_schema() {
+        let named_list = vec![
+            Named::Star,
+            Named::Expression(Expression::Variable("port".to_string()), Some("port_copy".to_string())),
+        ];
+
+        let schema = Arc::new(Schema::new(vec!["host".to_string(), "port".to_string()]));
+        let mut records = VecDeque::new();
+        for (host, port) in [("example01.com", 8000), ("example.com", 8001)].iter() {
+            records.push_back(Record::with_schema(
+                schema.clone(),
+                vec![Value::String(host.to_string()), Value::Int(*port)],
+            ));
+        }
+        records.push_back(Record::new(vec!["port".to_string()], vec![Value::Int(8002)]));
+        let stream = Box::new(InMemoryStream::new(records));
+
+        let mut map_stream = MapStream::new(named_list, Variables::default(), stream);
+        let mut result = Vec::new();
+        while let Some(record) = map_stream.next().unwrap() {
+            result.push(record);
+        }
+
+        //The same records as with a schema of their own
+        let expected = vec![
+            Record::new(
+                vec!["host".to_string(), "port".to_string(), "port_copy".to_string()],
+                vec![
+                    Value::String("example01.com".to_string()),
+                    Value::Int(8000),
+                    Value::Int(8000),
+                ],
+            ),
+            Record::new(
+                vec!["host".to_string(), "port".to_string(), "port_copy".to_string()],
+                vec![
+                    Value::String("example.com".to_string()),
+                    Value::Int(8001),
+                    Value::Int(8001),
+                ],
+            ),
+            Record::new(
+                vec!["port".to_string(), "port_copy".to_string()],
+                vec![Value::Int(8002), Value::Int(8002)],
+            ),
+        ];
+        assert_eq!(expected, result);
+        assert!(Arc::ptr_eq(result[0].schema(), result[1].schema()));
+        assert!(!Arc::ptr_eq(result[1].schema(), result[2].schema()));
+    }
+
     #[test]
     fn test_map_stream_with_names() {
         let named_list = vec![Named::Expression(
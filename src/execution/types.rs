@@ -1,14 +1,30 @@
-use super::datasource::{ReaderBuilder, ReaderError};
-use super::stream::{FilterStream, GroupByStream, InMemoryStream, LimitStream, LogFileStream, MapStream, RecordStream};
+use super::datasource::{is_fifo, ReaderBuilder, ReaderError, RecordRead};
+use super::memory::MemoryBudget;
+use super::snapshot::SnapshotError;
+use super::stream::{
+    map_field_names, ChangedByStream, DistinctStream, DropColumnsStream, EnforceSchemaStream, FilterStream,
+    GroupByStream, InMemoryStream, JoinStream, LimitStream, LogFileStream, MapStream, MergeSortedStream, OrderByStream,
+    Record, RecordStream, Schema, TeeStream, UnnestStream,
+};
+use super::warnings::{self, WarningKind};
 use crate::common;
 use crate::common::types::{DataSource, Tuple, Value, VariableName, Variables};
 use chrono::Timelike;
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use ordered_float::OrderedFloat;
 use pdatastructs::hyperloglog::HyperLogLog;
-use std::collections::VecDeque;
+#[cfg(feature = "publicsuffix")]
+use publicsuffix::Psl;
+use regex::Regex;
+use std::ffi::OsStr;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
 use std::io;
+use std::path::PathBuf;
 use std::result;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 use tdigest::TDigest;
 
 pub(crate) type EvaluateResult<T> = result::Result<T, EvaluateError>;
@@ -27,25 +43,41 @@ impl From<ExpressionError> for EvaluateError {
 
 pub(crate) type CreateStreamResult<T> = result::Result<T, CreateStreamError>;
 
-#[derive(Fail, PartialEq, Eq, Debug)]
-pub enum CreateStreamError {
-    #[fail(display = "Io Error")]
-    Io,
-    #[fail(display = "Reader Error")]
-    Reader,
+#[derive(Fail, Debug)]
+pub(crate) enum CreateStreamError {
+    #[fail(display = "{}", _0)]
+    Io(#[cause] io::Error),
+    #[fail(display = "{}", _0)]
+    Reader(#[cause] ReaderError),
     #[fail(display = "Stream Error")]
     Stream,
 }
 
+impl PartialEq for CreateStreamError {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (CreateStreamError::Io(_), CreateStreamError::Io(_)) => true,
+            (CreateStreamError::Reader(_), CreateStreamError::Reader(_)) => true,
+            (CreateStreamError::Stream, CreateStreamError::Stream) => true,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for CreateStreamError {}
+
 impl From<io::Error> for CreateStreamError {
-    fn from(_: io::Error) -> CreateStreamError {
-        CreateStreamError::Io
+    fn from(err: io::Error) -> CreateStreamError {
+        CreateStreamError::Io(err)
     }
 }
 
 impl From<ReaderError> for CreateStreamError {
-    fn from(_: ReaderError) -> CreateStreamError {
-        CreateStreamError::Reader
+    fn from(err: ReaderError) -> CreateStreamError {
+        match err {
+            ReaderError::Io(e) => CreateStreamError::Io(e),
+            _ => CreateStreamError::Reader(err),
+        }
     }
 }
 
@@ -57,7 +89,7 @@ impl From<StreamError> for CreateStreamError {
 
 pub(crate) type StreamResult<T> = result::Result<T, StreamError>;
 
-#[derive(Fail, PartialEq, Eq, Debug)]
+#[derive(Fail, Debug)]
 pub(crate) enum StreamError {
     #[fail(display = "{}", _0)]
     Get(#[cause] CreateStreamError),
@@ -65,10 +97,64 @@ pub(crate) enum StreamError {
     Evaluate(#[cause] EvaluateError),
     #[fail(display = "{}", _0)]
     Expression(#[cause] ExpressionError),
-    #[fail(display = "Reader Error")]
-    Reader,
-    #[fail(display = "Aggregate Error")]
-    Aggregate,
+    #[fail(display = "{}", _0)]
+    Reader(#[cause] ReaderError),
+    #[fail(display = "{}", _0)]
+    Aggregate(#[cause] AggregateError),
+    #[fail(display = "Unknown Column: {}", _0)]
+    UnknownColumn(String),
+    #[fail(
+        display = "The cross join went past {} rows, raise --cross-join-limit to allow more",
+        _0
+    )]
+    JoinLimit(u64),
+    #[fail(display = "{}", _0)]
+    Snapshot(#[cause] SnapshotError),
+    #[fail(display = "The output doesn't match the expected schema: {}", _0)]
+    SchemaMismatch(String),
+    #[fail(display = "A merged file is not sorted by {}", _0)]
+    UnsortedSource(String),
+    #[fail(
+        display = "The query needs more than its memory budget of {} bytes, raise --max-memory to allow more",
+        _0
+    )]
+    MemoryBudget(u64),
+    #[fail(display = "{} can't be spilled to disk, raise --max-memory to keep it in memory", _0)]
+    SpillUnsupported(String),
+    #[fail(display = "Spill Io Error: {}", _0)]
+    SpillIo(#[cause] io::Error),
+    #[fail(display = "Both sides of the join have a column {}", _0)]
+    JoinColumnClash(String),
+}
+
+impl PartialEq for StreamError {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (StreamError::Get(a), StreamError::Get(b)) => a == b,
+            (StreamError::Evaluate(a), StreamError::Evaluate(b)) => a == b,
+            (StreamError::Expression(a), StreamError::Expression(b)) => a == b,
+            (StreamError::Reader(_), StreamError::Reader(_)) => true,
+            (StreamError::Aggregate(a), StreamError::Aggregate(b)) => a == b,
+            (StreamError::UnknownColumn(a), StreamError::UnknownColumn(b)) => a == b,
+            (StreamError::JoinLimit(a), StreamError::JoinLimit(b)) => a == b,
+            (StreamError::Snapshot(a), StreamError::Snapshot(b)) => a == b,
+            (StreamError::SchemaMismatch(a), StreamError::SchemaMismatch(b)) => a == b,
+            (StreamError::UnsortedSource(a), StreamError::UnsortedSource(b)) => a == b,
+            (StreamError::MemoryBudget(a), StreamError::MemoryBudget(b)) => a == b,
+            (StreamError::SpillUnsupported(a), StreamError::SpillUnsupported(b)) => a == b,
+            (StreamError::SpillIo(_), StreamError::SpillIo(_)) => true,
+            (StreamError::JoinColumnClash(a), StreamError::JoinColumnClash(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for StreamError {}
+
+impl From<SnapshotError> for StreamError {
+    fn from(err: SnapshotError) -> StreamError {
+        StreamError::Snapshot(err)
+    }
 }
 
 impl From<CreateStreamError> for StreamError {
@@ -90,14 +176,14 @@ impl From<ExpressionError> for StreamError {
 }
 
 impl From<ReaderError> for StreamError {
-    fn from(_: ReaderError) -> StreamError {
-        StreamError::Reader
+    fn from(err: ReaderError) -> StreamError {
+        StreamError::Reader(err)
     }
 }
 
 impl From<AggregateError> for StreamError {
-    fn from(_: AggregateError) -> StreamError {
-        StreamError::Aggregate
+    fn from(err: AggregateError) -> StreamError {
+        StreamError::Aggregate(err)
     }
 }
 
@@ -115,6 +201,8 @@ pub(crate) enum ExpressionError {
     InvalidStar,
     #[fail(display = "Type Mismatch")]
     TypeMismatch,
+    #[fail(display = "Overflow")]
+    Overflow,
     #[fail(display = "{}", _0)]
     ParseTimeInterval(#[cause] common::types::ParseTimeIntervalError),
     #[fail(display = "TimeInterval Not Supported Yet")]
@@ -151,6 +239,83 @@ pub(crate) enum Ordering {
     Desc,
 }
 
+impl Ordering {
+    pub(crate) fn to_json(&self) -> json::JsonValue {
+        match self {
+            Ordering::Asc => "asc".into(),
+            Ordering::Desc => "desc".into(),
+        }
+    }
+}
+
+//The type of an output value, as a schema contract names it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ValueType {
+    Int,
+    Float,
+    Boolean,
+    String,
+    DateTime,
+    Host,
+    HttpRequest,
+    Array,
+}
+
+impl ValueType {
+    //A null has no type of its own
+    pub(crate) fn of(value: &Value) -> Option<ValueType> {
+        match value {
+            Value::Int(_) => Some(ValueType::Int),
+            Value::Float(_) => Some(ValueType::Float),
+            Value::Boolean(_) => Some(ValueType::Boolean),
+            Value::String(_) => Some(ValueType::String),
+            Value::DateTime(_) => Some(ValueType::DateTime),
+            Value::Host(_) => Some(ValueType::Host),
+            Value::HttpRequest(_) => Some(ValueType::HttpRequest),
+            Value::Array(_) => Some(ValueType::Array),
+            Value::Null => None,
+        }
+    }
+}
+
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ValueType::Int => "int",
+            ValueType::Float => "float",
+            ValueType::Boolean => "boolean",
+            ValueType::String => "string",
+            ValueType::DateTime => "datetime",
+            ValueType::Host => "host",
+            ValueType::HttpRequest => "request",
+            ValueType::Array => "array",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for ValueType {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "int" => Ok(ValueType::Int),
+            "float" => Ok(ValueType::Float),
+            "boolean" => Ok(ValueType::Boolean),
+            "string" => Ok(ValueType::String),
+            "datetime" => Ok(ValueType::DateTime),
+            "host" => Ok(ValueType::Host),
+            "request" => Ok(ValueType::HttpRequest),
+            "array" => Ok(ValueType::Array),
+            _ => Err(format!(
+                "unknown type {}, expected int, float, boolean, string, datetime, host, request or array",
+                s
+            )),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub(crate) enum Expression {
     Logic(Box<Formula>),
@@ -159,11 +324,32 @@ pub(crate) enum Expression {
 }
 
 impl Expression {
+    pub(crate) fn to_json(&self) -> json::JsonValue {
+        let mut obj = json::JsonValue::new_object();
+        match self {
+            Expression::Logic(formula) => {
+                obj["expression"] = "Logic".into();
+                obj["formula"] = formula.to_json();
+            }
+            Expression::Variable(name) => {
+                obj["expression"] = "Variable".into();
+                obj["name"] = name.as_str().into();
+            }
+            Expression::Function(name, arguments) => {
+                obj["expression"] = "Function".into();
+                obj["name"] = name.as_str().into();
+                obj["arguments"] = arguments.iter().map(Named::to_json).collect::<Vec<_>>().into();
+            }
+        }
+
+        obj
+    }
+
     pub(crate) fn expression_value(&self, variables: Variables) -> ExpressionResult<Value> {
         match self {
             Expression::Logic(formula) => {
-                let out = formula.evaluate(variables)?;
-                Ok(Value::Boolean(out))
+                let out = formula.truth(variables)?;
+                Ok(out.map(Value::Boolean).unwrap_or(Value::Null))
             }
             Expression::Variable(name) => {
                 if let Some(v) = variables.get(name) {
@@ -172,6 +358,29 @@ impl Expression {
                     Err(ExpressionError::KeyNotFound)
                 }
             }
+            //The arguments are evaluated in turn up to the first one that isn't null, so the ones after it
+            //can't fail the row
+            Expression::Function(name, arguments) if name == "coalesce" => {
+                if arguments.is_empty() {
+                    return Err(ExpressionError::InvalidArguments);
+                }
+
+                for arg in arguments.iter() {
+                    match arg {
+                        Named::Expression(expr, _) => {
+                            let value = expr.expression_value(variables.clone())?;
+                            if value != Value::Null {
+                                return Ok(value);
+                            }
+                        }
+                        Named::Star => {
+                            return Err(ExpressionError::InvalidStar);
+                        }
+                    }
+                }
+
+                Ok(Value::Null)
+            }
             Expression::Function(name, arguments) => {
                 let mut values: Vec<Value> = Vec::new();
                 for arg in arguments.iter() {
@@ -193,6 +402,89 @@ impl Expression {
     }
 }
 
+//The bytes a value is hashed from, tagged by the type so that 1 and "1" land apart. Spelled out here
+//rather than going through std's Hash, whose output may change between releases and platforms.
+fn canonical_bytes(value: &Value) -> Option<Vec<u8>> {
+    let (tag, body) = match value {
+        Value::Null => {
+            return None;
+        }
+        Value::Int(i) => (b'i', i.to_be_bytes().to_vec()),
+        Value::Float(f) => (b'f', f.into_inner().to_bits().to_be_bytes().to_vec()),
+        Value::Boolean(b) => (b'b', vec![*b as u8]),
+        Value::String(s) => (b's', s.as_bytes().to_vec()),
+        Value::DateTime(dt) => (b'd', dt.to_rfc3339().into_bytes()),
+        Value::HttpRequest(request) => (b'r', request.to_string().into_bytes()),
+        Value::Host(host) => (b'h', host.to_string().into_bytes()),
+        Value::Array(values) => (b'a', common::types::format_array(values).into_bytes()),
+    };
+
+    let mut bytes = Vec::with_capacity(body.len() + 1);
+    bytes.push(tag);
+    bytes.extend(body);
+    Some(bytes)
+}
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+//64 bit FNV-1a, small and fully specified so a bucket is the same on every machine
+fn fnv1a_64(bytes: &[u8]) -> u64 {
+    fnv1a_64_from(FNV_OFFSET_BASIS, bytes)
+}
+
+fn fnv1a_64_from(hash: u64, bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(hash, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME))
+}
+
+//The columns are length prefixed so that ("ab", "c") and ("a", "bc") hash apart, a null adds only its length
+pub(crate) fn row_hash(record: &Record, column_names: &[VariableName]) -> StreamResult<u64> {
+    let variables = record.to_variables();
+    let mut values = Vec::with_capacity(column_names.len());
+    for column_name in column_names.iter() {
+        let value = variables
+            .get(column_name)
+            .ok_or_else(|| StreamError::UnknownColumn(column_name.clone()))?;
+        values.push(value);
+    }
+
+    Ok(values_hash(values.into_iter()))
+}
+
+pub(crate) fn values_hash<'a, I>(values: I) -> u64
+where
+    I: Iterator<Item = &'a Value>,
+{
+    let mut bytes = Vec::new();
+    for value in values {
+        let value_bytes = canonical_bytes(value).unwrap_or_default();
+        bytes.extend((value_bytes.len() as u32).to_be_bytes());
+        bytes.extend(value_bytes);
+    }
+
+    fnv1a_64(&bytes)
+}
+
+fn float_operand(v: &Value) -> Option<f32> {
+    match v {
+        Value::Int(i) => Some(*i as f32),
+        Value::Float(f) => Some(f.into_inner()),
+        _ => None,
+    }
+}
+
+//An Int next to a Float is widened, two Ints keep the integer arithmetic
+fn float_operands(a: &Value, b: &Value) -> Option<(f32, f32)> {
+    match (a, b) {
+        (Value::Float(a), Value::Float(b)) => Some((a.into_inner(), b.into_inner())),
+        (Value::Int(a), Value::Float(b)) => Some((*a as f32, b.into_inner())),
+        (Value::Float(a), Value::Int(b)) => Some((a.into_inner(), *b as f32)),
+        _ => None,
+    }
+}
+
 fn evaluate_url_functions(func_name: &str, arguments: &[Value]) -> ExpressionResult<Value> {
     match func_name {
         "url_host" => {
@@ -350,6 +642,33 @@ fn evaluate_host_functions(func_name: &str, arguments: &[Value]) -> ExpressionRe
     }
 }
 
+//A UTC offset such as "+09:00", "-0530", "+02" or "utc"
+fn parse_utc_offset(s: &str) -> Option<chrono::FixedOffset> {
+    if s.eq_ignore_ascii_case("utc") || s.eq_ignore_ascii_case("z") {
+        return chrono::FixedOffset::east_opt(0);
+    }
+
+    let sign = match s.chars().next()? {
+        '+' => 1,
+        '-' => -1,
+        _ => return None,
+    };
+    let digits: String = s[1..].chars().filter(|c| *c != ':').collect();
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let (hours, minutes) = match digits.len() {
+        2 => (digits.parse::<i32>().ok()?, 0),
+        4 => (digits[..2].parse::<i32>().ok()?, digits[2..].parse::<i32>().ok()?),
+        _ => return None,
+    };
+    if minutes >= 60 {
+        return None;
+    }
+
+    chrono::FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
 fn evaluate(func_name: &str, arguments: &[Value]) -> ExpressionResult<Value> {
     if func_name.starts_with("url_") {
         return evaluate_url_functions(func_name, arguments);
@@ -366,8 +685,14 @@ fn evaluate(func_name: &str, arguments: &[Value]) -> ExpressionResult<Value> {
             }
 
             match (&arguments[0], &arguments[1]) {
+                //As in SQL, arithmetic with a null operand is null rather than an error, so that
+                //e.g. prev() on the first record makes a null delta
+                (Value::Null, _) | (_, Value::Null) => Ok(Value::Null),
                 (Value::Int(a), Value::Int(b)) => Ok(Value::Int(a + b)),
-                _ => Err(ExpressionError::InvalidArguments),
+                (a, b) => match float_operands(a, b) {
+                    Some((a, b)) => Ok(Value::Float(OrderedFloat::from(a + b))),
+                    None => Err(ExpressionError::InvalidArguments),
+                },
             }
         }
         "Minus" => {
@@ -376,8 +701,20 @@ fn evaluate(func_name: &str, arguments: &[Value]) -> ExpressionResult<Value> {
             }
 
             match (&arguments[0], &arguments[1]) {
+                (Value::Null, _) | (_, Value::Null) => Ok(Value::Null),
                 (Value::Int(a), Value::Int(b)) => Ok(Value::Int(a - b)),
-                _ => Err(ExpressionError::InvalidArguments),
+                //The seconds between two timestamps, an error when they lie too far apart to count in microseconds
+                (Value::DateTime(a), Value::DateTime(b)) => {
+                    let micros = a
+                        .signed_duration_since(*b)
+                        .num_microseconds()
+                        .ok_or(ExpressionError::Overflow)?;
+                    Ok(Value::Float(OrderedFloat::from(micros as f32 / 1_000_000.0)))
+                }
+                (a, b) => match float_operands(a, b) {
+                    Some((a, b)) => Ok(Value::Float(OrderedFloat::from(a - b))),
+                    None => Err(ExpressionError::InvalidArguments),
+                },
             }
         }
         "Times" => {
@@ -386,8 +723,12 @@ fn evaluate(func_name: &str, arguments: &[Value]) -> ExpressionResult<Value> {
             }
 
             match (&arguments[0], &arguments[1]) {
+                (Value::Null, _) | (_, Value::Null) => Ok(Value::Null),
                 (Value::Int(a), Value::Int(b)) => Ok(Value::Int(a * b)),
-                _ => Err(ExpressionError::InvalidArguments),
+                (a, b) => match float_operands(a, b) {
+                    Some((a, b)) => Ok(Value::Float(OrderedFloat::from(a * b))),
+                    None => Err(ExpressionError::InvalidArguments),
+                },
             }
         }
         "Divide" => {
@@ -396,7 +737,70 @@ fn evaluate(func_name: &str, arguments: &[Value]) -> ExpressionResult<Value> {
             }
 
             match (&arguments[0], &arguments[1]) {
+                (Value::Null, _) | (_, Value::Null) => Ok(Value::Null),
                 (Value::Int(a), Value::Int(b)) => Ok(Value::Int(a / b)),
+                (a, b) => match float_operands(a, b) {
+                    Some((a, b)) => Ok(Value::Float(OrderedFloat::from(a / b))),
+                    None => Err(ExpressionError::InvalidArguments),
+                },
+            }
+        }
+        //A ratio that is null rather than an error or an infinity when there is nothing to divide by, and a
+        //float even for two ints
+        "safe_divide" => {
+            if arguments.len() != 2 {
+                return Err(ExpressionError::InvalidArguments);
+            }
+
+            let operands = match (&arguments[0], &arguments[1]) {
+                (Value::Null, _) | (_, Value::Null) => return Ok(Value::Null),
+                (Value::Int(a), Value::Int(b)) => Some((*a as f32, *b as f32)),
+                (a, b) => float_operands(a, b),
+            };
+            match operands {
+                Some((_, 0.0)) => Ok(Value::Null),
+                Some((a, b)) => Ok(Value::Float(OrderedFloat::from(a / b))),
+                None => Err(ExpressionError::InvalidArguments),
+            }
+        }
+        //A float stays a float as in SQL, so the result type follows the column rather than the value. An
+        //int is already whole and comes back unchanged, round takes the halves away from zero
+        "abs" | "round" | "ceil" | "floor" => {
+            if arguments.len() != 1 {
+                return Err(ExpressionError::InvalidArguments);
+            }
+
+            match &arguments[0] {
+                Value::Int(i) => match func_name {
+                    "abs" => i.checked_abs().map(Value::Int).ok_or(ExpressionError::InvalidArguments),
+                    _ => Ok(Value::Int(*i)),
+                },
+                Value::Float(f) => {
+                    let f = f.into_inner();
+                    let result = match func_name {
+                        "abs" => f.abs(),
+                        "round" => f.round(),
+                        "ceil" => f.ceil(),
+                        _ => f.floor(),
+                    };
+                    Ok(Value::Float(OrderedFloat::from(result)))
+                }
+                Value::Null => Ok(Value::Null),
+                _ => Err(ExpressionError::InvalidArguments),
+            }
+        }
+        "zscore" => {
+            if arguments.len() != 3 {
+                return Err(ExpressionError::InvalidArguments);
+            }
+            if arguments.contains(&Value::Null) {
+                return Ok(Value::Null);
+            }
+
+            let operands: Option<Vec<f32>> = arguments.iter().map(float_operand).collect();
+            match operands.as_deref() {
+                Some([_, _, stddev]) if *stddev == 0.0 => Ok(Value::Null),
+                Some([value, mean, stddev]) => Ok(Value::Float(OrderedFloat::from((value - mean) / stddev))),
                 _ => Err(ExpressionError::InvalidArguments),
             }
         }
@@ -418,6 +822,32 @@ fn evaluate(func_name: &str, arguments: &[Value]) -> ExpressionResult<Value> {
                 _ => Err(ExpressionError::InvalidArguments),
             }
         }
+        //Heatmap keys, "00" to "23" and "Mon" to "Sun", in the offset of the timestamp unless another one
+        //is given
+        "hour_label" | "weekday_label" => {
+            let offset_opt = match arguments {
+                [_] => None,
+                [_, Value::String(offset)] => Some(parse_utc_offset(offset).ok_or(ExpressionError::InvalidArguments)?),
+                _ => return Err(ExpressionError::InvalidArguments),
+            };
+
+            match &arguments[0] {
+                Value::Null => Ok(Value::Null),
+                Value::DateTime(dt) => {
+                    let dt = match offset_opt {
+                        Some(offset) => dt.with_timezone(&offset),
+                        None => *dt,
+                    };
+                    let label = if func_name == "hour_label" {
+                        format!("{:02}", dt.hour())
+                    } else {
+                        dt.format("%a").to_string()
+                    };
+                    Ok(Value::String(label))
+                }
+                _ => Err(ExpressionError::InvalidArguments),
+            }
+        }
         "time_bucket" => {
             if arguments.len() != 2 {
                 return Err(ExpressionError::InvalidArguments);
@@ -513,619 +943,2841 @@ fn evaluate(func_name: &str, arguments: &[Value]) -> ExpressionResult<Value> {
                 _ => Err(ExpressionError::InvalidArguments),
             }
         }
-        _ => Err(ExpressionError::UnknownFunction),
-    }
-}
-
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub(crate) enum Relation {
-    Equal,
-    NotEqual,
-    MoreThan,
-    LessThan,
-    GreaterEqual,
-    LessEqual,
-}
-
-impl Relation {
-    pub(crate) fn apply(&self, variables: Variables, left: &Expression, right: &Expression) -> ExpressionResult<bool> {
-        let left_result = left.expression_value(variables.clone())?;
-        let right_result = right.expression_value(variables.clone())?;
-
-        match self {
-            Relation::Equal => Ok(left_result == right_result),
-            Relation::NotEqual => Ok(left_result != right_result),
-            Relation::GreaterEqual => match (left_result, right_result) {
-                (Value::Int(l), Value::Int(r)) => Ok(l >= r),
-                (Value::Float(l), Value::Float(r)) => Ok(l >= r),
-                _ => Err(ExpressionError::TypeMismatch),
-            },
-            Relation::LessEqual => match (left_result, right_result) {
-                (Value::Int(l), Value::Int(r)) => Ok(l <= r),
-                (Value::Float(l), Value::Float(r)) => Ok(l <= r),
-                _ => Err(ExpressionError::TypeMismatch),
-            },
-            Relation::MoreThan => match (left_result, right_result) {
-                (Value::Int(l), Value::Int(r)) => Ok(l > r),
-                (Value::Float(l), Value::Float(r)) => Ok(l > r),
-                _ => Err(ExpressionError::TypeMismatch),
-            },
-            Relation::LessThan => match (left_result, right_result) {
-                (Value::Int(l), Value::Int(r)) => Ok(l < r),
-                (Value::Float(l), Value::Float(r)) => Ok(l < r),
-                _ => Err(ExpressionError::TypeMismatch),
-            },
-        }
-    }
-}
-
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub(crate) enum Named {
-    Expression(Expression, Option<VariableName>),
-    Star,
-}
+        "truncate" => {
+            if arguments.len() != 2 && arguments.len() != 3 {
+                return Err(ExpressionError::InvalidArguments);
+            }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub(crate) enum Formula {
-    Constant(bool),
-    And(Box<Formula>, Box<Formula>),
-    Or(Box<Formula>, Box<Formula>),
-    Not(Box<Formula>),
-    Predicate(Relation, Box<Expression>, Box<Expression>),
-}
+            let with_ellipsis = match arguments.get(2) {
+                Some(Value::Boolean(b)) => *b,
+                Some(_) => {
+                    return Err(ExpressionError::InvalidArguments);
+                }
+                None => true,
+            };
 
-impl Formula {
-    pub(crate) fn evaluate(&self, variables: Variables) -> EvaluateResult<bool> {
-        match self {
-            Formula::And(left_formula, right_formula) => {
-                let left = left_formula.evaluate(variables.clone())?;
-                let right = right_formula.evaluate(variables.clone())?;
-                Ok(left && right)
-            }
-            Formula::Or(left_formula, right_formula) => {
-                let left = left_formula.evaluate(variables.clone())?;
-                let right = right_formula.evaluate(variables.clone())?;
-                Ok(left || right)
+            match (&arguments[0], &arguments[1]) {
+                (Value::String(s), Value::Int(n)) if *n >= 0 => {
+                    //Count by chars rather than bytes so multibyte characters are never split.
+                    let n = *n as usize;
+                    if s.chars().count() <= n {
+                        Ok(Value::String(s.clone()))
+                    } else {
+                        let mut truncated: String = s.chars().take(n).collect();
+                        if with_ellipsis {
+                            truncated.push('…');
+                        }
+                        Ok(Value::String(truncated))
+                    }
+                }
+                (Value::Null, Value::Int(_)) => Ok(Value::Null),
+                _ => Err(ExpressionError::InvalidArguments),
             }
-            Formula::Not(child_formula) => {
-                let child = child_formula.evaluate(variables.clone())?;
-                Ok(!child)
+        }
+        "upper" | "lower" | "length" => {
+            if arguments.len() != 1 {
+                return Err(ExpressionError::InvalidArguments);
             }
-            Formula::Predicate(relation, left_formula, right_formula) => {
-                let result = relation.apply(variables, left_formula, right_formula)?;
-                Ok(result)
+
+            let text = match string_argument(&arguments[0])? {
+                Some(text) => text,
+                None => {
+                    return Ok(Value::Null);
+                }
+            };
+            match func_name {
+                "upper" => Ok(Value::String(text.to_uppercase())),
+                "lower" => Ok(Value::String(text.to_lowercase())),
+                _ => Ok(Value::Int(text.chars().count() as i32)),
             }
-            Formula::Constant(value) => Ok(*value),
         }
-    }
-}
-
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub(crate) enum Node {
-    DataSource(DataSource, String),
-    Filter(Box<Node>, Box<Formula>),
-    Map(Vec<Named>, Box<Node>),
-    GroupBy(Vec<VariableName>, Vec<NamedAggregate>, Box<Node>),
-    Limit(u32, Box<Node>),
-    OrderBy(Vec<VariableName>, Vec<Ordering>, Box<Node>),
-}
-
-impl Node {
-    pub(crate) fn get(&self, variables: Variables) -> CreateStreamResult<Box<dyn RecordStream>> {
-        match self {
-            Node::Filter(source, formula) => {
-                let record_stream = source.get(variables.clone())?;
-                let stream = FilterStream::new(*formula.clone(), variables, record_stream);
-                Ok(Box::new(stream))
+        "substr" => {
+            if arguments.len() != 2 && arguments.len() != 3 {
+                return Err(ExpressionError::InvalidArguments);
             }
-            Node::Map(named_list, source) => {
-                let record_stream = source.get(variables.clone())?;
 
-                let stream = MapStream::new(named_list.clone(), variables, record_stream);
+            //The start counts from 1 as in SQL, a start past the end is an empty string and without a
+            //length the rest of the string is taken
+            let start = match &arguments[1] {
+                Value::Int(start) if *start >= 1 => (*start - 1) as usize,
+                _ => {
+                    return Err(ExpressionError::InvalidArguments);
+                }
+            };
+            let length = match arguments.get(2) {
+                Some(Value::Int(length)) if *length >= 0 => Some(*length as usize),
+                Some(_) => {
+                    return Err(ExpressionError::InvalidArguments);
+                }
+                None => None,
+            };
 
-                Ok(Box::new(stream))
-            }
-            Node::DataSource(data_source, table_name) => match data_source {
-                DataSource::File(path) => {
-                    let reader = ReaderBuilder::new(table_name.clone()).with_path(path)?;
-                    let stream = LogFileStream {
-                        reader: Box::new(reader),
+            match string_argument(&arguments[0])? {
+                Some(text) => {
+                    let chars = text.chars().skip(start);
+                    let substring: String = match length {
+                        Some(length) => chars.take(length).collect(),
+                        None => chars.collect(),
                     };
+                    Ok(Value::String(substring))
+                }
+                None => Ok(Value::Null),
+            }
+        }
+        "endpoint" => {
+            if arguments.len() != 2 {
+                return Err(ExpressionError::InvalidArguments);
+            }
 
-                    Ok(Box::new(stream))
+            let ip = match &arguments[0] {
+                Value::String(s) => Some(s.clone()),
+                Value::Null => None,
+                _ => {
+                    return Err(ExpressionError::InvalidArguments);
                 }
-                DataSource::Stdin => {
-                    let reader = ReaderBuilder::new(table_name.clone()).with_reader(io::stdin());
-                    let stream = LogFileStream {
-                        reader: Box::new(reader),
-                    };
+            };
 
-                    Ok(Box::new(stream))
+            let port = match &arguments[1] {
+                Value::Int(i) => Some(i.to_string()),
+                Value::String(s) => Some(s.clone()),
+                Value::Null => None,
+                _ => {
+                    return Err(ExpressionError::InvalidArguments);
                 }
-            },
-            Node::GroupBy(fields, named_aggregates, source) => {
-                let record_stream = source.get(variables.clone())?;
-                let stream = GroupByStream::new(fields.clone(), variables, named_aggregates.clone(), record_stream);
-                Ok(Box::new(stream))
+            };
+
+            match (ip, port) {
+                (Some(ip), Some(port)) => Ok(Value::String(format!("{}:{}", ip, port))),
+                (Some(ip), None) => Ok(Value::String(ip)),
+                (None, Some(port)) => Ok(Value::String(port)),
+                (None, None) => Ok(Value::Null),
             }
-            Node::Limit(row_count, source) => {
-                let record_stream = source.get(variables.clone())?;
-                let stream = LimitStream::new(*row_count, record_stream);
-                Ok(Box::new(stream))
+        }
+        "greatest" | "least" => {
+            if arguments.is_empty() {
+                return Err(ExpressionError::InvalidArguments);
             }
-            Node::OrderBy(column_names, orderings, source) => {
-                let mut record_stream = source.get(variables.clone())?;
-                let mut records = Vec::new();
 
-                while let Some(record) = record_stream.next()? {
-                    records.push(record);
+            //Nulls are skipped, the values left have to be comparable: all numbers, all timestamps and so on
+            let wanted = if func_name == "greatest" {
+                std::cmp::Ordering::Greater
+            } else {
+                std::cmp::Ordering::Less
+            };
+            let mut result: Option<&Value> = None;
+            for argument in arguments.iter() {
+                if *argument == Value::Null {
+                    continue;
                 }
 
-                records.sort_by(|a, b| {
-                    let a_variables = a.to_variables();
-                    let b_varialbes = b.to_variables();
+                result = match result {
+                    None => Some(argument),
+                    Some(current) => match compare_values(argument, current) {
+                        Some(ordering) if ordering == wanted => Some(argument),
+                        Some(_) => Some(current),
+                        None => {
+                            return Err(ExpressionError::InvalidArguments);
+                        }
+                    },
+                };
+            }
+
+            Ok(result.cloned().unwrap_or(Value::Null))
+        }
+        "ip_class" => {
+            if arguments.len() != 1 {
+                return Err(ExpressionError::InvalidArguments);
+            }
+
+            let ip = match &arguments[0] {
+                Value::String(s) => s.clone(),
+                Value::Host(host) => host.hostname.clone(),
+                Value::Null => {
+                    return Ok(Value::Null);
+                }
+                _ => {
+                    return Err(ExpressionError::InvalidArguments);
+                }
+            };
+
+            //Only range checks, link-local and IPv6 unique local addresses count as private
+            let class = match ip
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<std::net::IpAddr>()
+            {
+                Ok(ip) if ip.is_loopback() => "loopback",
+                Ok(std::net::IpAddr::V4(ip)) if ip.is_private() || ip.is_link_local() => "private",
+                Ok(std::net::IpAddr::V6(ip))
+                    if (ip.segments()[0] & 0xfe00) == 0xfc00 || (ip.segments()[0] & 0xffc0) == 0xfe80 =>
+                {
+                    "private"
+                }
+                Ok(_) => "public",
+                Err(_) => {
+                    return Ok(Value::Null);
+                }
+            };
+            Ok(Value::String(class.to_string()))
+        }
+        "to_int_or" => {
+            if arguments.len() != 2 {
+                return Err(ExpressionError::InvalidArguments);
+            }
+
+            //A float is truncated when it fits, anything that isn't a number falls back to the default
+            let parsed = match &arguments[0] {
+                Value::Int(i) => Some(*i),
+                Value::Float(f) => {
+                    let f = f.into_inner().trunc();
+                    if f.is_finite() && f >= i32::MIN as f32 && f <= i32::MAX as f32 {
+                        Some(f as i32)
+                    } else {
+                        None
+                    }
+                }
+                Value::String(s) => s.trim().parse::<i32>().ok(),
+                _ => None,
+            };
+            if parsed.is_none() && arguments[0] != Value::Null {
+                warnings::warn(WarningKind::DefaultFallback, || format!("{:?}", arguments[0]));
+            }
+            Ok(parsed.map(Value::Int).unwrap_or_else(|| arguments[1].clone()))
+        }
+        "to_float_or" => {
+            if arguments.len() != 2 {
+                return Err(ExpressionError::InvalidArguments);
+            }
+
+            let parsed = match &arguments[0] {
+                Value::Int(i) => Some(*i as f32),
+                Value::Float(f) => Some(f.into_inner()),
+                Value::String(s) => s.trim().parse::<f32>().ok().filter(|f| f.is_finite()),
+                _ => None,
+            };
+            if parsed.is_none() && arguments[0] != Value::Null {
+                warnings::warn(WarningKind::DefaultFallback, || format!("{:?}", arguments[0]));
+            }
+            Ok(parsed
+                .map(|f| Value::Float(OrderedFloat::from(f)))
+                .unwrap_or_else(|| arguments[1].clone()))
+        }
+        "trace_root" => {
+            if arguments.len() != 1 {
+                return Err(ExpressionError::InvalidArguments);
+            }
+
+            let trace_id = match &arguments[0] {
+                Value::String(s) => s,
+                Value::Null => {
+                    return Ok(Value::Null);
+                }
+                _ => {
+                    return Err(ExpressionError::InvalidArguments);
+                }
+            };
+
+            //"Self=1-67891234-...;Root=1-67891233-abcdef012345678912345678", the root id is a version, the
+            //epoch seconds and a random id, all in hex. The load balancer readers keep its quotes.
+            let root = trace_id
+                .trim_matches('"')
+                .split(';')
+                .filter_map(|field| field.trim().strip_prefix("Root="))
+                .next()
+                .filter(|root| {
+                    let parts: Vec<&str> = root.split('-').collect();
+                    parts.len() == 3
+                        && parts
+                            .iter()
+                            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_hexdigit()))
+                });
+            match root {
+                Some(root) => Ok(Value::String(root.to_string())),
+                None => Ok(Value::Null),
+            }
+        }
+        "hash_bucket" => {
+            if arguments.len() != 2 {
+                return Err(ExpressionError::InvalidArguments);
+            }
+
+            let bucket_count = match &arguments[1] {
+                Value::Int(n) if *n > 0 => *n as u64,
+                _ => {
+                    return Err(ExpressionError::InvalidArguments);
+                }
+            };
+            match canonical_bytes(&arguments[0]) {
+                Some(bytes) => Ok(Value::Int((fnv1a_64(&bytes) % bucket_count) as i32)),
+                None => Ok(Value::Null),
+            }
+        }
+        "array_percentile" => {
+            if arguments.len() != 2 {
+                return Err(ExpressionError::InvalidArguments);
+            }
+
+            let percentile = match &arguments[1] {
+                Value::Int(i) if *i == 0 || *i == 1 => *i as f32,
+                Value::Float(f) if (0.0..=1.0).contains(&f.into_inner()) => f.into_inner(),
+                _ => {
+                    return Err(ExpressionError::InvalidArguments);
+                }
+            };
+            let values = match &arguments[0] {
+                Value::Array(values) => values,
+                Value::Null => {
+                    return Ok(Value::Null);
+                }
+                _ => {
+                    return Err(ExpressionError::InvalidArguments);
+                }
+            };
+
+            let mut numbers = Vec::with_capacity(values.len());
+            for value in values.iter() {
+                match value {
+                    Value::Int(i) => numbers.push((OrderedFloat::from(*i as f32), value)),
+                    Value::Float(f) => numbers.push((*f, value)),
+                    Value::Null => {}
+                    _ => {
+                        return Err(ExpressionError::InvalidArguments);
+                    }
+                }
+            }
+            if numbers.is_empty() {
+                return Ok(Value::Null);
+            }
+
+            //The nearest rank, the smallest element with at least the percentile of the elements at or below it
+            numbers.sort_by_key(|(number, _)| *number);
+            let rank = (percentile * numbers.len() as f32).ceil() as usize;
+            Ok(numbers[rank.max(1) - 1].1.clone())
+        }
+        "array_nth_largest" => {
+            if arguments.len() != 2 {
+                return Err(ExpressionError::InvalidArguments);
+            }
+
+            let n = match &arguments[1] {
+                Value::Int(n) if *n > 0 => *n as usize,
+                _ => {
+                    return Err(ExpressionError::InvalidArguments);
+                }
+            };
+            let values = match &arguments[0] {
+                Value::Array(values) => values,
+                Value::Null => {
+                    return Ok(Value::Null);
+                }
+                _ => {
+                    return Err(ExpressionError::InvalidArguments);
+                }
+            };
+
+            let mut numbers = Vec::with_capacity(values.len());
+            for value in values.iter() {
+                match value {
+                    Value::Int(i) => numbers.push((OrderedFloat::from(*i as f32), value)),
+                    Value::Float(f) => numbers.push((*f, value)),
+                    Value::Null => {}
+                    _ => {
+                        return Err(ExpressionError::InvalidArguments);
+                    }
+                }
+            }
+
+            //Equal numbers count once, whether they come as an Int or a Float
+            numbers.sort_by_key(|(number, _)| std::cmp::Reverse(*number));
+            numbers.dedup_by_key(|(number, _)| *number);
+            Ok(numbers
+                .get(n - 1)
+                .map(|(_, value)| (*value).clone())
+                .unwrap_or(Value::Null))
+        }
+        "array_overlap" => {
+            if arguments.len() != 2 {
+                return Err(ExpressionError::InvalidArguments);
+            }
+
+            //The Jaccard index of the two arrays taken as sets, so a repeated element counts once
+            match (&arguments[0], &arguments[1]) {
+                (Value::Array(a), Value::Array(b)) => {
+                    let a: HashSet<&Value> = a.iter().collect();
+                    let b: HashSet<&Value> = b.iter().collect();
+                    let union = a.union(&b).count();
+                    if union == 0 {
+                        return Ok(Value::Null);
+                    }
+
+                    let intersection = a.intersection(&b).count();
+                    Ok(Value::Float(OrderedFloat::from(intersection as f32 / union as f32)))
+                }
+                (Value::Null, _) | (_, Value::Null) => Ok(Value::Null),
+                _ => Err(ExpressionError::InvalidArguments),
+            }
+        }
+        "hop" => {
+            if arguments.len() != 3 {
+                return Err(ExpressionError::InvalidArguments);
+            }
+
+            //The windows of the size starting every slide seconds since the epoch, those holding the
+            //timestamp are returned from the earliest start. The query has a row for each of them.
+            match &arguments[0] {
+                Value::DateTime(dt) => {
+                    let size = window_seconds(&arguments[1])?;
+                    let slide = window_seconds(&arguments[2])?;
+                    let timestamp = dt.timestamp();
+                    let last_start = timestamp - timestamp.rem_euclid(slide);
+                    let base = dt.with_nanosecond(0).unwrap();
+
+                    let mut starts = Vec::new();
+                    let mut start = last_start;
+                    while start > timestamp - size {
+                        starts.push(Value::DateTime(base - chrono::Duration::seconds(timestamp - start)));
+                        start -= slide;
+                    }
+                    starts.reverse();
+                    Ok(Value::Array(starts))
+                }
+                Value::Null => Ok(Value::Null),
+                _ => Err(ExpressionError::InvalidArguments),
+            }
+        }
+        "latency_tier" => {
+            if arguments.len() != 3 {
+                return Err(ExpressionError::InvalidArguments);
+            }
+
+            let mut numbers = Vec::new();
+            for argument in arguments.iter() {
+                match argument {
+                    Value::Int(i) => numbers.push(*i as f32),
+                    Value::Float(f) => numbers.push(f.into_inner()),
+                    Value::Null => {
+                        return Ok(Value::Null);
+                    }
+                    _ => {
+                        return Err(ExpressionError::InvalidArguments);
+                    }
+                }
+            }
+
+            //A value exactly on a threshold belongs to the faster tier
+            let tier = if numbers[0] <= numbers[1] {
+                "fast"
+            } else if numbers[0] <= numbers[2] {
+                "ok"
+            } else {
+                "slow"
+            };
+            Ok(Value::String(tier.to_string()))
+        }
+        #[cfg(feature = "publicsuffix")]
+        "registered_domain" => {
+            if arguments.len() != 1 {
+                return Err(ExpressionError::InvalidArguments);
+            }
+
+            let host = match &arguments[0] {
+                Value::String(s) => s.clone(),
+                Value::Host(host) => host.hostname.clone(),
+                Value::Null => {
+                    return Ok(Value::Null);
+                }
+                _ => {
+                    return Err(ExpressionError::InvalidArguments);
+                }
+            };
+
+            //An IP address has no registered domain, and neither has a bare public suffix
+            let host = host.trim_end_matches('.').to_ascii_lowercase();
+            if host.is_empty() || host.parse::<std::net::IpAddr>().is_ok() {
+                return Ok(Value::Null);
+            }
+
+            match PUBLIC_SUFFIX_LIST.domain(host.as_bytes()) {
+                Some(domain) => match std::str::from_utf8(domain.as_bytes()) {
+                    Ok(domain) => Ok(Value::String(domain.to_string())),
+                    Err(_) => Ok(Value::Null),
+                },
+                None => Ok(Value::Null),
+            }
+        }
+        "timestamp_diff" => {
+            if arguments.len() != 2 {
+                return Err(ExpressionError::InvalidArguments);
+            }
+
+            //The seconds from the second timestamp to the first, negative when the first is earlier
+            match (&arguments[0], &arguments[1]) {
+                (Value::DateTime(end), Value::DateTime(start)) => {
+                    let duration = end.signed_duration_since(*start);
+                    let seconds = match duration.num_microseconds() {
+                        Some(micros) => micros as f64 / 1_000_000.0,
+                        None => duration.num_milliseconds() as f64 / 1_000.0,
+                    };
+                    Ok(Value::Float(OrderedFloat::from(seconds as f32)))
+                }
+                (Value::Null, _) | (_, Value::Null) => Ok(Value::Null),
+                _ => Err(ExpressionError::InvalidArguments),
+            }
+        }
+        "tls_version" => {
+            if arguments.len() != 1 {
+                return Err(ExpressionError::InvalidArguments);
+            }
+
+            //TLSv1.2 becomes "1.2" and SSLv3 becomes "0.3" so the versions sort in their release order,
+            //a "-" from a plain HTTP listener is null and anything unrecognized passes through unchanged
+            match &arguments[0] {
+                Value::String(protocol) => {
+                    let version = match protocol.as_str() {
+                        "-" | "" => {
+                            return Ok(Value::Null);
+                        }
+                        "SSLv2" => "0.2",
+                        "SSLv3" => "0.3",
+                        "TLSv1" | "TLSv1.0" => "1.0",
+                        "TLSv1.1" => "1.1",
+                        "TLSv1.2" => "1.2",
+                        "TLSv1.3" => "1.3",
+                        other => other,
+                    };
+                    Ok(Value::String(version.to_string()))
+                }
+                Value::Null => Ok(Value::Null),
+                _ => Err(ExpressionError::InvalidArguments),
+            }
+        }
+        "weak_cipher" => {
+            if arguments.len() != 1 {
+                return Err(ExpressionError::InvalidArguments);
+            }
+
+            //A cipher is weak when one of its OpenSSL name components is in WEAK_CIPHER_COMPONENTS,
+            //unknown ciphers are not flagged and a "-" from a plain HTTP listener is null
+            match &arguments[0] {
+                Value::String(cipher) => {
+                    if cipher == "-" || cipher.is_empty() {
+                        return Ok(Value::Null);
+                    }
+
+                    let weak = cipher
+                        .split('-')
+                        .any(|component| WEAK_CIPHER_COMPONENTS.contains(&component.to_ascii_uppercase().as_str()));
+                    Ok(Value::Boolean(weak))
+                }
+                Value::Null => Ok(Value::Null),
+                _ => Err(ExpressionError::InvalidArguments),
+            }
+        }
+        "ua_family" => {
+            if arguments.len() != 1 {
+                return Err(ExpressionError::InvalidArguments);
+            }
+
+            match &arguments[0] {
+                Value::String(user_agent) => {
+                    let family = UA_FAMILY_RULES
+                        .iter()
+                        .find(|(regex, _)| regex.is_match(user_agent))
+                        .map_or("unknown", |(_, family)| family);
+                    Ok(Value::String(family.to_string()))
+                }
+                Value::Null => Ok(Value::Null),
+                _ => Err(ExpressionError::InvalidArguments),
+            }
+        }
+        "method_class" => {
+            if arguments.len() != 1 {
+                return Err(ExpressionError::InvalidArguments);
+            }
+
+            //A request is classified by its method, a method outside of the usual verbs is "other"
+            let method = match &arguments[0] {
+                Value::String(method) => method.to_ascii_uppercase(),
+                Value::HttpRequest(request) => request.http_method.to_ascii_uppercase(),
+                Value::Null => {
+                    return Ok(Value::Null);
+                }
+                _ => {
+                    return Err(ExpressionError::InvalidArguments);
+                }
+            };
+            let class = match method.as_str() {
+                "GET" | "HEAD" | "OPTIONS" => "read",
+                "POST" | "PUT" | "PATCH" | "DELETE" => "write",
+                _ => "other",
+            };
+            Ok(Value::String(class.to_string()))
+        }
+        "normalize_path" => {
+            if arguments.len() != 1 {
+                return Err(ExpressionError::InvalidArguments);
+            }
+
+            match &arguments[0] {
+                Value::String(path) => {
+                    let path = path.split(['?', '#']).next().unwrap_or_default();
+                    let segments: Vec<&str> = path
+                        .split('/')
+                        .map(|segment| {
+                            if NUMERIC_SEGMENT.is_match(segment) {
+                                ":id"
+                            } else if UUID_SEGMENT.is_match(segment) {
+                                ":uuid"
+                            } else {
+                                segment
+                            }
+                        })
+                        .collect();
+                    Ok(Value::String(segments.join("/")))
+                }
+                Value::Null => Ok(Value::Null),
+                _ => Err(ExpressionError::InvalidArguments),
+            }
+        }
+        //The nulls are skipped as in SQL, a null separator makes the whole of concat_ws null
+        "concat" => {
+            if arguments.is_empty() {
+                return Err(ExpressionError::InvalidArguments);
+            }
+
+            let texts: Vec<String> = arguments.iter().filter_map(concat_text).collect();
+            Ok(Value::String(texts.concat()))
+        }
+        "concat_ws" => {
+            if arguments.len() < 2 {
+                return Err(ExpressionError::InvalidArguments);
+            }
+
+            let separator = match &arguments[0] {
+                Value::String(separator) => separator,
+                Value::Null => {
+                    return Ok(Value::Null);
+                }
+                _ => {
+                    return Err(ExpressionError::InvalidArguments);
+                }
+            };
+            let texts: Vec<String> = arguments[1..].iter().filter_map(concat_text).collect();
+            Ok(Value::String(texts.join(separator)))
+        }
+        "dedup_key" => {
+            if arguments.is_empty() {
+                return Err(ExpressionError::InvalidArguments);
+            }
+
+            let tokens: Vec<String> = arguments.iter().map(dedup_token).collect();
+            Ok(Value::String(tokens.join("|")))
+        }
+        _ => Err(ExpressionError::UnknownFunction),
+    }
+}
+
+//The text the string functions work on, a request or a host is taken by its printed text
+fn string_argument(value: &Value) -> ExpressionResult<Option<String>> {
+    match value {
+        Value::String(s) => Ok(Some(s.clone())),
+        Value::HttpRequest(request) => Ok(Some(request.to_string())),
+        Value::Host(host) => Ok(Some(host.to_string())),
+        Value::Null => Ok(None),
+        _ => Err(ExpressionError::InvalidArguments),
+    }
+}
+
+//A window size or slide in whole seconds, a duration literal such as 5m or an interval such as "5 minutes"
+fn window_seconds(value: &Value) -> ExpressionResult<i64> {
+    let seconds = match value {
+        Value::Int(i) => i64::from(*i),
+        Value::Float(f) if f.into_inner().fract() == 0.0 => f.into_inner() as i64,
+        Value::String(s) => {
+            let time_interval = common::types::parse_time_interval(s)?;
+            let unit = match time_interval.unit {
+                common::types::TimeIntervalUnit::Second => 1,
+                common::types::TimeIntervalUnit::Minute => 60,
+                common::types::TimeIntervalUnit::Hour => 60 * 60,
+                common::types::TimeIntervalUnit::Day => 24 * 60 * 60,
+                //Months and years don't have a fixed length
+                _ => {
+                    return Err(ExpressionError::TimeIntervalNotSupported);
+                }
+            };
+            i64::from(time_interval.n) * unit
+        }
+        _ => {
+            return Err(ExpressionError::InvalidArguments);
+        }
+    };
+
+    match seconds {
+        0 => Err(ExpressionError::TimeIntervalZero),
+        s if s < 0 => Err(ExpressionError::InvalidArguments),
+        s => Ok(s),
+    }
+}
+
+//A value as the output prints it, so a float is 1.5 or 2 whether it is concatenated or printed alone
+//and a null is skipped
+fn concat_text(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        value => Some(common::types::value_text(value)),
+    }
+}
+
+//A null is the \N sentinel, which no escaped value can spell, and the | and \ within a value are escaped,
+//so two keys are equal only when all of their values are
+fn dedup_token(value: &Value) -> String {
+    let text = match value {
+        Value::Null => {
+            return "\\N".to_string();
+        }
+        Value::String(s) => s.clone(),
+        Value::Int(i) => i.to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::Boolean(b) => b.to_string(),
+        Value::DateTime(dt) => dt.to_rfc3339(),
+        Value::HttpRequest(request) => request.to_string(),
+        Value::Host(host) => host.to_string(),
+        Value::Array(values) => common::types::format_array(values),
+    };
+
+    text.replace('\\', "\\\\").replace('|', "\\|")
+}
+
+lazy_static! {
+    //The dynamic segments of a path, replaced by placeholders to group the requests by route
+    static ref NUMERIC_SEGMENT: Regex = Regex::new(r"^\d+$").unwrap();
+    static ref UUID_SEGMENT: Regex =
+        Regex::new(r"^(?i)[0-9a-f]{8}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{4}-[0-9a-f]{12}$").unwrap();
+}
+
+//Broken or export grade primitives, anonymous key exchanges and the null cipher
+const WEAK_CIPHER_COMPONENTS: &[&str] = &[
+    "RC2", "RC4", "DES", "3DES", "CBC3", "IDEA", "MD5", "NULL", "EXP", "EXPORT", "ADH", "AECDH",
+];
+
+#[cfg(feature = "publicsuffix")]
+lazy_static! {
+    //Embedded at build time, parsed on the first use
+    static ref PUBLIC_SUFFIX_LIST: publicsuffix::List = include_str!("public_suffix_list.dat").parse().unwrap();
+}
+
+lazy_static! {
+    //The first matching rule wins, so bots come before browsers and the browsers whose user agents
+    //embed other browser tokens (Edge and Opera carry "Chrome", Chrome carries "Safari") come first.
+    static ref UA_FAMILY_RULES: Vec<(Regex, &'static str)> = {
+        [
+            (r"(?i)googlebot", "GoogleBot"),
+            (r"(?i)bingbot", "BingBot"),
+            (r"(?i)yandexbot", "YandexBot"),
+            (r"(?i)baiduspider", "BaiduSpider"),
+            (r"(?i)(bot|crawler|spider)\b", "bot"),
+            (r"^curl/", "curl"),
+            (r"^Wget/", "wget"),
+            (r"^python-requests/", "python-requests"),
+            (r"Edg(e|A|iOS)?/", "Edge"),
+            (r"(OPR|Opera)/", "Opera"),
+            (r"(Chrome|CriOS)/", "Chrome"),
+            (r"(Firefox|FxiOS)/", "Firefox"),
+            (r"Version/[\d.]+.*Safari/", "Safari"),
+            (r"(MSIE |Trident/)", "IE"),
+        ]
+        .iter()
+        .map(|(pattern, family)| (Regex::new(pattern).unwrap(), *family))
+        .collect()
+    };
+}
+
+//A compiled regular expression, equal to another one with the same source
+#[derive(Debug, Clone)]
+pub(crate) struct RegexPattern(Regex);
+
+impl PartialEq for RegexPattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for RegexPattern {}
+
+impl FromStr for RegexPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        Regex::new(s).map(RegexPattern).map_err(|e| e.to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Relation {
+    Equal,
+    NotEqual,
+    MoreThan,
+    LessThan,
+    GreaterEqual,
+    LessEqual,
+    Contains,
+    Like,
+    NotLike,
+    RegexMatch(RegexPattern),
+    IsNull,
+    IsNotNull,
+}
+
+impl Relation {
+    pub(crate) fn to_json(&self) -> json::JsonValue {
+        format!("{:?}", self).into()
+    }
+
+    //As in SQL a comparison with a null is unknown, None, whatever the other side. Only IS NULL and
+    //IS NOT NULL tell a null apart.
+    pub(crate) fn truth(
+        &self,
+        variables: Variables,
+        left: &Expression,
+        right: &Expression,
+    ) -> ExpressionResult<Option<bool>> {
+        let left_result = left.expression_value(variables.clone())?;
+        let right_result = right.expression_value(variables.clone())?;
+
+        if left_result == Value::Null || right_result == Value::Null {
+            return Ok(match self {
+                Relation::IsNull => Some(left_result == Value::Null),
+                Relation::IsNotNull => Some(left_result != Value::Null),
+                _ => None,
+            });
+        }
+
+        let result = match self {
+            Relation::Equal => Ok(values_equal(&left_result, &right_result)),
+            Relation::NotEqual => match compare_numbers(&left_result, &right_result) {
+                Some(ordering) => Ok(ordering != std::cmp::Ordering::Equal),
+                None => Ok(left_result != right_result),
+            },
+            Relation::GreaterEqual => match compare_numbers(&left_result, &right_result) {
+                Some(ordering) => Ok(ordering != std::cmp::Ordering::Less),
+                None => Err(ExpressionError::TypeMismatch),
+            },
+            Relation::LessEqual => match compare_numbers(&left_result, &right_result) {
+                Some(ordering) => Ok(ordering != std::cmp::Ordering::Greater),
+                None => Err(ExpressionError::TypeMismatch),
+            },
+            Relation::MoreThan => match compare_numbers(&left_result, &right_result) {
+                Some(ordering) => Ok(ordering == std::cmp::Ordering::Greater),
+                None => Err(ExpressionError::TypeMismatch),
+            },
+            Relation::LessThan => match compare_numbers(&left_result, &right_result) {
+                Some(ordering) => Ok(ordering == std::cmp::Ordering::Less),
+                None => Err(ExpressionError::TypeMismatch),
+            },
+            Relation::Contains => match (left_result, right_result) {
+                (Value::String(l), Value::String(r)) => Ok(l.contains(&r)),
+                _ => Err(ExpressionError::TypeMismatch),
+            },
+            Relation::Like => like(&left_result, &right_result),
+            Relation::NotLike => like(&left_result, &right_result).map(|matched| !matched),
+            //The pattern was compiled from the right side when the plan was made
+            Relation::RegexMatch(pattern) => match left_result {
+                Value::String(s) => Ok(pattern.0.is_match(&s)),
+                Value::HttpRequest(request) => Ok(pattern.0.is_match(&request.to_string())),
+                Value::Host(host) => Ok(pattern.0.is_match(&host.to_string())),
+                _ => Err(ExpressionError::TypeMismatch),
+            },
+            Relation::IsNull => Ok(false),
+            Relation::IsNotNull => Ok(true),
+        };
+
+        result.map(Some)
+    }
+}
+
+//Numbers are equal by value whatever their type, anything else has to be the same value
+fn values_equal(left: &Value, right: &Value) -> bool {
+    match compare_numbers(left, right) {
+        Some(ordering) => ordering == std::cmp::Ordering::Equal,
+        None => left == right,
+    }
+}
+
+//A request or a host matches with its text, as it is printed
+fn like(left: &Value, right: &Value) -> ExpressionResult<bool> {
+    let pattern = match right {
+        Value::String(pattern) => pattern,
+        _ => return Err(ExpressionError::TypeMismatch),
+    };
+
+    match left {
+        Value::String(s) => Ok(like_match(s, pattern)),
+        Value::HttpRequest(request) => Ok(like_match(&request.to_string(), pattern)),
+        Value::Host(host) => Ok(like_match(&host.to_string(), pattern)),
+        _ => Err(ExpressionError::TypeMismatch),
+    }
+}
+
+//SQL LIKE over the whole text: % matches any run of characters, _ a single one, and a backslash makes the
+//next character literal, \% for a percent sign. A mismatch backtracks to the last %, which then takes
+//one more character.
+pub(crate) fn like_match(text: &str, pattern: &str) -> bool {
+    enum Token {
+        Literal(char),
+        AnyChar,
+        AnyString,
+    }
+
+    let mut tokens = Vec::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            '%' => Token::AnyString,
+            '_' => Token::AnyChar,
+            '\\' => Token::Literal(chars.next().unwrap_or('\\')),
+            c => Token::Literal(c),
+        });
+    }
+
+    let text: Vec<char> = text.chars().collect();
+    let (mut t, mut p) = (0, 0);
+    let mut last_any_string: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match tokens.get(p) {
+            Some(Token::AnyString) => {
+                last_any_string = Some((p, t));
+                p += 1;
+            }
+            Some(Token::AnyChar) => {
+                t += 1;
+                p += 1;
+            }
+            Some(Token::Literal(c)) if *c == text[t] => {
+                t += 1;
+                p += 1;
+            }
+            _ => match last_any_string {
+                Some((any_p, any_t)) => {
+                    last_any_string = Some((any_p, any_t + 1));
+                    p = any_p + 1;
+                    t = any_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    tokens[p..].iter().all(|token| matches!(token, Token::AnyString))
+}
+
+//Ints and floats compare by their numeric value whatever the variants, as OrderedFloat does NaN is
+//above every other number. Anything that isn't a pair of numbers has no numeric order.
+pub(crate) fn compare_numbers(left: &Value, right: &Value) -> Option<std::cmp::Ordering> {
+    let (l, r) = match (left, right) {
+        (Value::Int(l), Value::Int(r)) => return Some(l.cmp(r)),
+        (Value::Float(l), Value::Float(r)) => return Some(l.cmp(r)),
+        (Value::Int(l), Value::Float(r)) => (f64::from(*l), f64::from(r.into_inner())),
+        (Value::Float(l), Value::Int(r)) => (f64::from(l.into_inner()), f64::from(*r)),
+        _ => return None,
+    };
+
+    Some(OrderedFloat::from(l).cmp(&OrderedFloat::from(r)))
+}
+
+//The order of ORDER BY, numbers compare numerically and the other values with their own variant
+fn compare_values(left: &Value, right: &Value) -> Option<std::cmp::Ordering> {
+    if let Some(ordering) = compare_numbers(left, right) {
+        return Some(ordering);
+    }
+
+    match (left, right) {
+        (Value::Boolean(b1), Value::Boolean(b2)) => Some(b1.cmp(b2)),
+        (Value::String(s1), Value::String(s2)) => Some(s1.cmp(s2)),
+        (Value::DateTime(dt1), Value::DateTime(dt2)) => Some(dt1.cmp(dt2)),
+        (Value::Null, Value::Null) => Some(std::cmp::Ordering::Equal),
+        (Value::Host(h1), Value::Host(h2)) => Some(h1.to_string().cmp(&h2.to_string())),
+        (Value::HttpRequest(h1), Value::HttpRequest(h2)) => Some(h1.to_string().cmp(&h2.to_string())),
+        _ => None,
+    }
+}
+
+//The order of two sort keys in ascending order. Values of different types are ordered by type with
+//the nulls last, so that the order stays total, and a missing column sorts as a null.
+pub(crate) fn compare_sort_keys(left: Option<&Value>, right: Option<&Value>) -> std::cmp::Ordering {
+    let rank = |value: Option<&Value>| match value {
+        Some(Value::Int(_)) | Some(Value::Float(_)) => 0,
+        Some(Value::Boolean(_)) => 1,
+        Some(Value::String(_)) => 2,
+        Some(Value::DateTime(_)) => 3,
+        Some(Value::Host(_)) => 4,
+        Some(Value::HttpRequest(_)) => 5,
+        Some(Value::Array(_)) => 6,
+        Some(Value::Null) | None => 7,
+    };
+
+    match (left, right) {
+        (Some(l), Some(r)) => compare_values(l, r).unwrap_or_else(|| rank(left).cmp(&rank(right))),
+        _ => rank(left).cmp(&rank(right)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Named {
+    Expression(Expression, Option<VariableName>),
+    Star,
+}
+
+impl Named {
+    pub(crate) fn to_json(&self) -> json::JsonValue {
+        match self {
+            Named::Expression(expr, name_opt) => {
+                let mut obj = expr.to_json();
+                if let Some(name) = name_opt {
+                    obj["alias"] = name.as_str().into();
+                }
+                obj
+            }
+            Named::Star => "*".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Formula {
+    Constant(bool),
+    And(Box<Formula>, Box<Formula>),
+    Or(Box<Formula>, Box<Formula>),
+    Not(Box<Formula>),
+    Predicate(Relation, Box<Expression>, Box<Expression>),
+    InList(Box<Expression>, Vec<Expression>),
+}
+
+impl Formula {
+    pub(crate) fn to_json(&self) -> json::JsonValue {
+        let mut obj = json::JsonValue::new_object();
+        match self {
+            Formula::Constant(b) => {
+                obj["formula"] = "Constant".into();
+                obj["value"] = (*b).into();
+            }
+            Formula::And(left, right) => {
+                obj["formula"] = "And".into();
+                obj["children"] = vec![left.to_json(), right.to_json()].into();
+            }
+            Formula::Or(left, right) => {
+                obj["formula"] = "Or".into();
+                obj["children"] = vec![left.to_json(), right.to_json()].into();
+            }
+            Formula::Not(child) => {
+                obj["formula"] = "Not".into();
+                obj["children"] = vec![child.to_json()].into();
+            }
+            Formula::Predicate(relation, left, right) => {
+                obj["formula"] = "Predicate".into();
+                obj["relation"] = relation.to_json();
+                obj["left"] = left.to_json();
+                obj["right"] = right.to_json();
+            }
+            Formula::InList(child, list) => {
+                obj["formula"] = "InList".into();
+                obj["left"] = child.to_json();
+                obj["children"] = list.iter().map(|item| item.to_json()).collect::<Vec<_>>().into();
+            }
+        }
+
+        obj
+    }
+
+    //A filter keeps the record only when the formula is true, an unknown one drops it as false does
+    pub(crate) fn evaluate(&self, variables: Variables) -> EvaluateResult<bool> {
+        Ok(self.truth(variables)?.unwrap_or(false))
+    }
+
+    //The three-valued logic of SQL with None as unknown: false and unknown is false, true or unknown is
+    //true and not unknown stays unknown
+    pub(crate) fn truth(&self, variables: Variables) -> EvaluateResult<Option<bool>> {
+        match self {
+            Formula::And(left_formula, right_formula) => {
+                let left = left_formula.truth(variables.clone())?;
+                if left == Some(false) {
+                    return Ok(Some(false));
+                }
+                let right = right_formula.truth(variables)?;
+                match (left, right) {
+                    (_, Some(false)) => Ok(Some(false)),
+                    (Some(true), Some(true)) => Ok(Some(true)),
+                    _ => Ok(None),
+                }
+            }
+            Formula::Or(left_formula, right_formula) => {
+                let left = left_formula.truth(variables.clone())?;
+                if left == Some(true) {
+                    return Ok(Some(true));
+                }
+                let right = right_formula.truth(variables)?;
+                match (left, right) {
+                    (_, Some(true)) => Ok(Some(true)),
+                    (Some(false), Some(false)) => Ok(Some(false)),
+                    _ => Ok(None),
+                }
+            }
+            Formula::Not(child_formula) => {
+                let child = child_formula.truth(variables)?;
+                Ok(child.map(|b| !b))
+            }
+            Formula::Predicate(relation, left_formula, right_formula) => {
+                let result = relation.truth(variables, left_formula, right_formula)?;
+                Ok(result)
+            }
+            Formula::InList(child, list) => {
+                if list.is_empty() {
+                    return Ok(Some(false));
+                }
+
+                //The left side is evaluated once and the list only up to the first equal item, a null
+                //item makes a miss unknown as the equivalent chain of ORs would
+                let value = child.expression_value(variables.clone())?;
+                if value == Value::Null {
+                    return Ok(None);
+                }
+                let mut saw_null = false;
+                for item in list.iter() {
+                    let item_value = item.expression_value(variables.clone())?;
+                    if item_value == Value::Null {
+                        saw_null = true;
+                    } else if values_equal(&value, &item_value) {
+                        return Ok(Some(true));
+                    }
+                }
+
+                Ok(if saw_null { None } else { Some(false) })
+            }
+            Formula::Constant(value) => Ok(Some(*value)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Node {
+    DataSource(DataSource, ReaderBuilder),
+    Filter(Box<Node>, Box<Formula>),
+    Map(Vec<Named>, Box<Node>),
+    GroupBy {
+        fields: Vec<VariableName>,
+        named_aggregates: Vec<NamedAggregate>,
+        source: Box<Node>,
+        //Emits the partial state of the aggregates for a later merge rather than their values
+        partial: bool,
+        //Flushes the groups every interval of wall time rather than at the end
+        window: Option<Duration>,
+        skip_empty: bool,
+        flush_emit: FlushEmit,
+        snapshot: Option<PathBuf>,
+        memory_budget: MemoryBudget,
+    },
+    Limit(i32, Box<Node>),
+    OrderBy {
+        column_names: Vec<VariableName>,
+        orderings: Vec<Ordering>,
+        source: Box<Node>,
+        memory_budget: MemoryBudget,
+    },
+    ChangedBy(VariableName, Box<Node>),
+    //Sorted by a stable hash of the columns, the order is the same on every run but unrelated to time
+    HashOrder(Vec<VariableName>, Box<Node>, MemoryBudget),
+    //Fails the query once a record doesn't have the columns and types of the contract, only the first
+    //record is checked unless every row is asked for
+    EnforceSchema(Vec<(VariableName, ValueType)>, bool, Box<Node>),
+    //Drops the rows already seen, keeping every distinct row in memory
+    Distinct {
+        source: Box<Node>,
+        memory_budget: MemoryBudget,
+    },
+    //A k-way merge of sources each sorted in ascending order of the column, globally ordered without a sort
+    MergeSorted(VariableName, Vec<Node>),
+    //Feeds the source to a detail and a summary plan, keeping what the detail read for the summary
+    Tee(Box<Node>, Box<Node>, Box<Node>, MemoryBudget),
+    TeeInput,
+    DropColumns(Vec<VariableName>, Box<Node>),
+    //A record per element of the array column, e.g. per window of hop()
+    Unnest(VariableName, Box<Node>),
+    //The left side streams, the right one is read into memory, up to a number of joined rows
+    Join {
+        join_type: JoinType,
+        left: Box<Node>,
+        right: Box<Node>,
+        row_limit: u64,
+        memory_budget: MemoryBudget,
+    },
+}
+
+//Every file is read by a reader of its own and the merge interleaves their records by the column
+pub(crate) fn merge_sorted_files(paths: &[PathBuf], column_name: &str, reader_builder: &ReaderBuilder) -> Node {
+    let sources = paths
+        .iter()
+        .map(|path| Node::DataSource(DataSource::File(path.clone()), reader_builder.clone()))
+        .collect();
+
+    Node::MergeSorted(column_name.to_string(), sources)
+}
+
+impl Node {
+    //Swap the data source leaf for a TeeInput, returning the detached source
+    pub(crate) fn detach_source(self) -> (Node, Node) {
+        match self {
+            Node::DataSource(_, _) | Node::MergeSorted(_, _) => (self, Node::TeeInput),
+            Node::Filter(source, formula) => {
+                let (data_source, source) = source.detach_source();
+                (data_source, Node::Filter(Box::new(source), formula))
+            }
+            Node::Map(named_list, source) => {
+                let (data_source, source) = source.detach_source();
+                (data_source, Node::Map(named_list, Box::new(source)))
+            }
+            Node::GroupBy {
+                fields,
+                named_aggregates,
+                source,
+                partial,
+                window,
+                skip_empty,
+                flush_emit,
+                snapshot,
+                memory_budget,
+            } => {
+                let (data_source, source) = source.detach_source();
+                (
+                    data_source,
+                    Node::GroupBy {
+                        fields,
+                        named_aggregates,
+                        source: Box::new(source),
+                        partial,
+                        window,
+                        skip_empty,
+                        flush_emit,
+                        snapshot,
+                        memory_budget,
+                    },
+                )
+            }
+            Node::Limit(row_count, source) => {
+                let (data_source, source) = source.detach_source();
+                (data_source, Node::Limit(row_count, Box::new(source)))
+            }
+            Node::OrderBy {
+                column_names,
+                orderings,
+                source,
+                memory_budget,
+            } => {
+                let (data_source, source) = source.detach_source();
+                (
+                    data_source,
+                    Node::OrderBy {
+                        column_names,
+                        orderings,
+                        source: Box::new(source),
+                        memory_budget,
+                    },
+                )
+            }
+            Node::ChangedBy(column_name, source) => {
+                let (data_source, source) = source.detach_source();
+                (data_source, Node::ChangedBy(column_name, Box::new(source)))
+            }
+            Node::HashOrder(column_names, source, memory_budget) => {
+                let (data_source, source) = source.detach_source();
+                (
+                    data_source,
+                    Node::HashOrder(column_names, Box::new(source), memory_budget),
+                )
+            }
+            Node::EnforceSchema(contract, every_row, source) => {
+                let (data_source, source) = source.detach_source();
+                (data_source, Node::EnforceSchema(contract, every_row, Box::new(source)))
+            }
+            Node::Distinct { source, memory_budget } => {
+                let (data_source, source) = source.detach_source();
+                (
+                    data_source,
+                    Node::Distinct {
+                        source: Box::new(source),
+                        memory_budget,
+                    },
+                )
+            }
+            Node::Tee(source, detail, summary, memory_budget) => {
+                let (data_source, source) = source.detach_source();
+                (data_source, Node::Tee(Box::new(source), detail, summary, memory_budget))
+            }
+            Node::TeeInput => (Node::TeeInput, Node::TeeInput),
+            Node::DropColumns(column_names, source) => {
+                let (data_source, source) = source.detach_source();
+                (data_source, Node::DropColumns(column_names, Box::new(source)))
+            }
+            Node::Unnest(column_name, source) => {
+                let (data_source, source) = source.detach_source();
+                (data_source, Node::Unnest(column_name, Box::new(source)))
+            }
+            Node::Join {
+                join_type,
+                left,
+                right,
+                row_limit,
+                memory_budget,
+            } => {
+                let (data_source, left) = left.detach_source();
+                (
+                    data_source,
+                    Node::Join {
+                        join_type,
+                        left: Box::new(left),
+                        right,
+                        row_limit,
+                        memory_budget,
+                    },
+                )
+            }
+        }
+    }
+
+    //The columns of the records the plan emits, known without reading the input. None when they depend
+    //on the data, a log declaring its own fields or a tee whose summary rows have columns of their own.
+    pub(crate) fn output_names(&self) -> Option<Vec<VariableName>> {
+        match self {
+            Node::DataSource(data_source, reader_builder) => match data_source {
+                DataSource::Values(_, column_names) => Some(column_names.clone()),
+                _ => reader_builder.field_names(),
+            },
+            Node::Filter(source, _)
+            | Node::Limit(_, source)
+            | Node::OrderBy { source, .. }
+            | Node::ChangedBy(_, source)
+            | Node::HashOrder(_, source, _)
+            | Node::EnforceSchema(_, _, source)
+            | Node::Distinct { source, .. }
+            | Node::Unnest(_, source) => source.output_names(),
+            Node::Map(named_list, source) => {
+                let input = if named_list.iter().any(|named| matches!(named, Named::Star)) {
+                    source.output_names()?
+                } else {
+                    Vec::new()
+                };
+                Some(map_field_names(named_list, &input))
+            }
+            Node::GroupBy {
+                fields,
+                named_aggregates,
+                partial,
+                ..
+            } => {
+                let mut field_names = fields.clone();
+                for named_agg in named_aggregates.iter() {
+                    if let Aggregate::CountBuckets(ref inner, _) = named_agg.aggregate {
+                        field_names.extend(inner.field_names(&named_agg.name_opt));
+                    } else {
+                        field_names.push(named_agg.name_opt.clone().unwrap_or_default());
+                    }
+                }
+                if *partial {
+                    field_names.push(PARTIAL_AGGREGATES_COLUMN.to_string());
+                }
+                Some(field_names)
+            }
+            Node::MergeSorted(_, sources) => sources.first().and_then(|source| source.output_names()),
+            Node::DropColumns(column_names, source) => {
+                let mut field_names = source.output_names()?;
+                field_names.retain(|field_name| !column_names.contains(field_name));
+                Some(field_names)
+            }
+            Node::Join { left, right, .. } => {
+                let mut field_names = left.output_names()?;
+                field_names.extend(right.output_names()?);
+                Some(field_names)
+            }
+            Node::Tee(_, _, _, _) | Node::TeeInput => None,
+        }
+    }
+
+    //A structured form of the plan for tooling, each operator names itself and nests its children
+    pub(crate) fn to_json(&self) -> json::JsonValue {
+        let mut obj = json::JsonValue::new_object();
+        match self {
+            Node::DataSource(data_source, reader_builder) => {
+                obj["operator"] = "DataSource".into();
+                obj["table"] = reader_builder.table_name().into();
+                obj["source"] = match data_source {
+                    DataSource::File(path) => path.to_string_lossy().to_string().into(),
+                    DataSource::Stdin => "stdin".into(),
+                    DataSource::Values(_, _) => "values".into(),
+                    DataSource::SortedFiles(paths, _) => paths
+                        .iter()
+                        .map(|path| path.to_string_lossy().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                        .into(),
+                };
+            }
+            Node::Filter(source, formula) => {
+                obj["operator"] = "Filter".into();
+                obj["formula"] = formula.to_json();
+                obj["children"] = vec![source.to_json()].into();
+            }
+            Node::Map(named_list, source) => {
+                obj["operator"] = "Map".into();
+                obj["expressions"] = named_list.iter().map(Named::to_json).collect::<Vec<_>>().into();
+                obj["children"] = vec![source.to_json()].into();
+            }
+            Node::GroupBy {
+                fields,
+                named_aggregates,
+                source,
+                partial,
+                window,
+                skip_empty,
+                flush_emit,
+                snapshot,
+                ..
+            } => {
+                obj["operator"] = "GroupBy".into();
+                obj["fields"] = fields.clone().into();
+                obj["aggregates"] = named_aggregates
+                    .iter()
+                    .map(NamedAggregate::to_json)
+                    .collect::<Vec<_>>()
+                    .into();
+                obj["partial"] = (*partial).into();
+                obj["skip_empty"] = (*skip_empty).into();
+                if let Some(window) = window {
+                    obj["flush_interval_secs"] = window.as_secs().into();
+                    obj["flush_emit"] = format!("{:?}", flush_emit).into();
+                }
+                if let Some(snapshot) = snapshot {
+                    obj["snapshot"] = snapshot.display().to_string().into();
+                }
+                obj["children"] = vec![source.to_json()].into();
+            }
+            Node::Limit(row_count, source) => {
+                obj["operator"] = "Limit".into();
+                obj["row_count"] = (*row_count).into();
+                obj["children"] = vec![source.to_json()].into();
+            }
+            Node::OrderBy {
+                column_names,
+                orderings,
+                source,
+                ..
+            } => {
+                obj["operator"] = "OrderBy".into();
+                obj["columns"] = column_names.clone().into();
+                obj["orderings"] = orderings.iter().map(Ordering::to_json).collect::<Vec<_>>().into();
+                obj["children"] = vec![source.to_json()].into();
+            }
+            Node::ChangedBy(column_name, source) => {
+                obj["operator"] = "ChangedBy".into();
+                obj["column"] = column_name.as_str().into();
+                obj["children"] = vec![source.to_json()].into();
+            }
+            Node::HashOrder(column_names, source, _) => {
+                obj["operator"] = "HashOrder".into();
+                obj["columns"] = column_names.clone().into();
+                obj["children"] = vec![source.to_json()].into();
+            }
+            Node::EnforceSchema(contract, every_row, source) => {
+                obj["operator"] = "EnforceSchema".into();
+                obj["contract"] = contract
+                    .iter()
+                    .map(|(column_name, value_type)| format!("{}:{}", column_name, value_type))
+                    .collect::<Vec<_>>()
+                    .into();
+                obj["every_row"] = (*every_row).into();
+                obj["children"] = vec![source.to_json()].into();
+            }
+            Node::Distinct { source, .. } => {
+                obj["operator"] = "Distinct".into();
+                obj["children"] = vec![source.to_json()].into();
+            }
+            Node::MergeSorted(column_name, sources) => {
+                obj["operator"] = "MergeSorted".into();
+                obj["column"] = column_name.as_str().into();
+                obj["children"] = sources.iter().map(Node::to_json).collect::<Vec<_>>().into();
+            }
+            Node::Tee(source, detail, summary, _) => {
+                obj["operator"] = "Tee".into();
+                obj["children"] = vec![source.to_json(), detail.to_json(), summary.to_json()].into();
+            }
+            Node::TeeInput => {
+                obj["operator"] = "TeeInput".into();
+            }
+            Node::DropColumns(column_names, source) => {
+                obj["operator"] = "DropColumns".into();
+                obj["columns"] = column_names.clone().into();
+                obj["children"] = vec![source.to_json()].into();
+            }
+            Node::Unnest(column_name, source) => {
+                obj["operator"] = "Unnest".into();
+                obj["column"] = column_name.clone().into();
+                obj["children"] = vec![source.to_json()].into();
+            }
+            Node::Join {
+                join_type,
+                left,
+                right,
+                row_limit,
+                ..
+            } => {
+                obj["operator"] = "Join".into();
+                obj["join_type"] = format!("{:?}", join_type).into();
+                obj["row_limit"] = (*row_limit).into();
+                obj["children"] = vec![left.to_json(), right.to_json()].into();
+            }
+        }
+
+        obj
+    }
+
+    pub(crate) fn get(&self, variables: Variables) -> CreateStreamResult<Box<dyn RecordStream>> {
+        self.get_with_input(variables, None)
+    }
+
+    //The input is the source of an enclosing Tee as read by this branch, it replaces the TeeInput leaf
+    pub(crate) fn get_with_input(
+        &self,
+        variables: Variables,
+        input: Option<Box<dyn RecordStream>>,
+    ) -> CreateStreamResult<Box<dyn RecordStream>> {
+        match self {
+            Node::Filter(source, formula) => {
+                let record_stream = source.get_with_input(variables.clone(), input)?;
+                let stream = FilterStream::new(*formula.clone(), variables, record_stream);
+                Ok(Box::new(stream))
+            }
+            Node::Map(named_list, source) => {
+                let record_stream = source.get_with_input(variables.clone(), input)?;
+
+                let stream = MapStream::new(named_list.clone(), variables, record_stream);
+
+                Ok(Box::new(stream))
+            }
+            Node::DataSource(data_source, reader_builder) => match data_source {
+                DataSource::File(path) => {
+                    let reader: Box<dyn RecordRead> = if path.extension() == Some(OsStr::new("gz")) {
+                        Box::new(reader_builder.with_gzip_path(path)?)
+                    } else if is_fifo(path) {
+                        Box::new(reader_builder.with_fifo_path(path)?)
+                    } else {
+                        Box::new(reader_builder.with_path(path)?)
+                    };
+                    let stream = LogFileStream { reader };
+
+                    Ok(Box::new(stream))
+                }
+                DataSource::Stdin => {
+                    let reader = reader_builder.with_sniffed_reader(io::stdin())?;
+                    let stream = LogFileStream {
+                        reader: Box::new(reader),
+                    };
+
+                    Ok(Box::new(stream))
+                }
+                DataSource::Values(rows, column_names) => {
+                    let schema = Arc::new(Schema::new(column_names.clone()));
+                    let records = rows
+                        .iter()
+                        .map(|row| Record::with_schema(schema.clone(), row.clone()))
+                        .collect();
+                    let stream = InMemoryStream::new(records);
+
+                    Ok(Box::new(stream))
+                }
+                DataSource::SortedFiles(paths, column_name) => {
+                    merge_sorted_files(paths, column_name, reader_builder).get_with_input(variables, input)
+                }
+            },
+            Node::GroupBy {
+                fields,
+                named_aggregates,
+                source,
+                partial,
+                window,
+                skip_empty,
+                flush_emit,
+                snapshot,
+                memory_budget,
+            } => {
+                let record_stream = source.get_with_input(variables.clone(), input)?;
+                let stream = GroupByStream::new(fields.clone(), variables, named_aggregates.clone(), record_stream)
+                    .with_partial(*partial)
+                    .with_window(*window)
+                    .with_skip_empty(*skip_empty)
+                    .with_flush_emit(*flush_emit)
+                    .with_snapshot(snapshot.clone())
+                    .with_memory_budget(memory_budget);
+                Ok(Box::new(stream))
+            }
+            Node::Limit(row_count, source) => {
+                let record_stream = source.get_with_input(variables.clone(), input)?;
+                let stream = LimitStream::new(*row_count, record_stream);
+                Ok(Box::new(stream))
+            }
+            Node::ChangedBy(column_name, source) => {
+                let record_stream = source.get_with_input(variables, input)?;
+                let stream = ChangedByStream::new(column_name.clone(), record_stream);
+                Ok(Box::new(stream))
+            }
+            Node::HashOrder(column_names, source, memory_budget) => {
+                let record_stream = source.get_with_input(variables, input)?;
+                let stream =
+                    OrderByStream::by_row_hash(column_names.clone(), record_stream).with_memory_budget(memory_budget);
+                Ok(Box::new(stream))
+            }
+            Node::EnforceSchema(contract, every_row, source) => {
+                let record_stream = source.get_with_input(variables, input)?;
+                let stream = EnforceSchemaStream::new(contract.clone(), *every_row, record_stream);
+                Ok(Box::new(stream))
+            }
+            Node::Distinct { source, memory_budget } => {
+                let record_stream = source.get_with_input(variables, input)?;
+                let stream = DistinctStream::new(record_stream).with_memory_budget(memory_budget);
+                Ok(Box::new(stream))
+            }
+            Node::MergeSorted(column_name, sources) => {
+                let mut record_streams = Vec::new();
+                for source in sources.iter() {
+                    record_streams.push(source.get(variables.clone())?);
+                }
+                let stream = MergeSortedStream::new(column_name.clone(), record_streams);
+                Ok(Box::new(stream))
+            }
+            Node::DropColumns(column_names, source) => {
+                let record_stream = source.get_with_input(variables, input)?;
+                let stream = DropColumnsStream::new(column_names.clone(), record_stream);
+                Ok(Box::new(stream))
+            }
+            Node::Unnest(column_name, source) => {
+                let record_stream = source.get_with_input(variables, input)?;
+                let stream = UnnestStream::new(column_name.clone(), record_stream);
+                Ok(Box::new(stream))
+            }
+            Node::Tee(source, detail, summary, memory_budget) => {
+                let record_stream = source.get_with_input(variables.clone(), input)?;
+                let (detail_input, summary_input) = TeeStream::inputs(record_stream, memory_budget);
+                let detail_stream = detail.get_with_input(variables.clone(), Some(detail_input))?;
+                let summary_stream = summary.get_with_input(variables, Some(summary_input))?;
+                let stream = TeeStream::new(detail_stream, summary_stream);
+                Ok(Box::new(stream))
+            }
+            Node::Join {
+                join_type,
+                left,
+                right,
+                row_limit,
+                memory_budget,
+            } => {
+                let record_stream = left.get_with_input(variables.clone(), input)?;
+                let right_stream = right.get(variables)?;
+                let stream = JoinStream::new(*join_type, record_stream, right_stream, *row_limit)
+                    .with_memory_budget(memory_budget);
+                Ok(Box::new(stream))
+            }
+            Node::TeeInput => input.ok_or(CreateStreamError::Stream),
+            Node::OrderBy {
+                column_names,
+                orderings,
+                source,
+                memory_budget,
+            } => {
+                let record_stream = source.get_with_input(variables, input)?;
+                let stream = OrderByStream::new(column_names.clone(), orderings.clone(), record_stream)
+                    .with_memory_budget(memory_budget);
+                Ok(Box::new(stream))
+            }
+        }
+    }
+}
+
+pub(crate) type AggregateResult<T> = result::Result<T, AggregateError>;
+
+#[derive(Fail, PartialEq, Eq, Debug)]
+pub(crate) enum AggregateError {
+    #[fail(display = "Key Not Found")]
+    KeyNotFound,
+    #[fail(display = "Invalid Type")]
+    InvalidType,
+    #[fail(display = "Partial State Not Supported")]
+    PartialStateNotSupported,
+    #[fail(display = "Non-finite Value: {}", _0)]
+    NonFiniteValue(String),
+    #[fail(display = "Non-numeric Value: {}", _0)]
+    NonNumericValue(String),
+}
+
+//How sum, avg, min and max treat NaN and infinite inputs. Skip is the default so a single bad
+//value doesn't poison a whole group; propagate keeps them and orders NaN above every other float,
+//as OrderedFloat does, so max yields NaN while min ignores it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum NanPolicy {
+    Propagate,
+    #[default]
+    Skip,
+    Error,
+}
+
+impl FromStr for NanPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "propagate" => Ok(NanPolicy::Propagate),
+            "skip" => Ok(NanPolicy::Skip),
+            "error" => Ok(NanPolicy::Error),
+            _ => Err("unknown nan policy".to_string()),
+        }
+    }
+}
+
+impl NanPolicy {
+    fn admit(self, value: &Value) -> AggregateResult<bool> {
+        match value {
+            Value::Float(f) if !f.is_finite() => match self {
+                NanPolicy::Propagate => Ok(true),
+                NanPolicy::Skip => Ok(false),
+                NanPolicy::Error => Err(AggregateError::NonFiniteValue(f.to_string())),
+            },
+            _ => Ok(true),
+        }
+    }
+}
+
+//Which groups a windowed group by emits on each flush. Changed compares every group against the
+//previous flush and leaves out the ones with the same values, removed also marks the groups missing
+//from this flush with null aggregates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub(crate) enum FlushEmit {
+    #[default]
+    All,
+    Changed,
+    ChangedAndRemoved,
+}
+
+impl FromStr for FlushEmit {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(FlushEmit::All),
+            "changed" => Ok(FlushEmit::Changed),
+            "changed-and-removed" => Ok(FlushEmit::ChangedAndRemoved),
+            _ => Err("unknown flush emit mode".to_string()),
+        }
+    }
+}
+
+//How the records of the two sides of a join are combined, a cross join pairs every left record with
+//every right one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum JoinType {
+    Cross,
+}
+
+//The cross join of a stream with a table of n rows is n times the stream, so the joined rows are capped
+pub(crate) const DEFAULT_JOIN_ROW_LIMIT: u64 = 1_000_000;
+
+//The column of a partial record listing its aggregate columns, the others are the group by keys
+pub(crate) const PARTIAL_AGGREGATES_COLUMN: &str = "_partial_aggregates";
+
+//The intermediate state of an aggregate, which can be emitted by one run and merged by another
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum PartialState {
+    Sum(OrderedFloat<f64>),
+    Count(i64),
+    Avg(OrderedFloat<f64>, i64),
+}
+
+impl fmt::Display for PartialState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PartialState::Sum(sum) => write!(f, "sum:{}", sum),
+            PartialState::Count(count) => write!(f, "count:{}", count),
+            PartialState::Avg(average, count) => write!(f, "avg:{}:{}", average, count),
+        }
+    }
+}
+
+impl FromStr for PartialState {
+    type Err = AggregateError;
+
+    fn from_str(s: &str) -> AggregateResult<Self> {
+        let parts: Vec<&str> = s.split(':').collect();
+        match parts.as_slice() {
+            ["sum", sum] => {
+                let sum = sum.parse::<f64>().map_err(|_| AggregateError::InvalidType)?;
+                Ok(PartialState::Sum(OrderedFloat::from(sum)))
+            }
+            ["count", count] => {
+                let count = count.parse::<i64>().map_err(|_| AggregateError::InvalidType)?;
+                Ok(PartialState::Count(count))
+            }
+            ["avg", average, count] => {
+                let average = average.parse::<f64>().map_err(|_| AggregateError::InvalidType)?;
+                let count = count.parse::<i64>().map_err(|_| AggregateError::InvalidType)?;
+                Ok(PartialState::Avg(OrderedFloat::from(average), count))
+            }
+            _ => Err(AggregateError::InvalidType),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct NamedAggregate {
+    pub(crate) aggregate: Aggregate,
+    pub(crate) name_opt: Option<String>,
+}
+
+impl NamedAggregate {
+    pub(crate) fn new(aggregate: Aggregate, name_opt: Option<String>) -> Self {
+        NamedAggregate { aggregate, name_opt }
+    }
+
+    pub(crate) fn to_json(&self) -> json::JsonValue {
+        let mut obj = self.aggregate.to_json();
+        if let Some(name) = &self.name_opt {
+            obj["alias"] = name.as_str().into();
+        }
+        obj
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Aggregate {
+    Avg(AvgAggregate, Named),
+    Count(CountAggregate, Named),
+    First(FirstAggregate, Named),
+    Last(LastAggregate, Named),
+    Max(MaxAggregate, Named),
+    Min(MinAggregate, Named),
+    Sum(SumAggregate, Named),
+    ApproxCountDistinct(ApproxCountDistinctAggregate, Named),
+    PercentileDisc(PercentileDiscAggregate, String),
+    PercentileCont(PercentileContAggregate, String),
+    ApproxPercentile(ApproxPercentileAggregate, String),
+    CountBuckets(CountBucketsAggregate, Vec<Formula>),
+    ConditionalRatio(ConditionalRatioAggregate, Formula, Formula),
+    GroupDuration(GroupDurationAggregate, Named),
+    GeoMean(GeoMeanAggregate, Named),
+    Entropy(EntropyAggregate, Named),
+    DistinctRatio(DistinctRatioAggregate, Named),
+    Variance(VarianceAggregate, Named),
+    StdDev(StdDevAggregate, Named),
+}
+
+impl Aggregate {
+    pub(crate) fn to_json(&self) -> json::JsonValue {
+        let mut obj = json::JsonValue::new_object();
+        let (name, argument) = match self {
+            Aggregate::Avg(_, named) => ("avg", named.to_json()),
+            Aggregate::Count(_, named) => ("count", named.to_json()),
+            Aggregate::First(_, named) => ("first", named.to_json()),
+            Aggregate::Last(_, named) => ("last", named.to_json()),
+            Aggregate::Max(_, named) => ("max", named.to_json()),
+            Aggregate::Min(_, named) => ("min", named.to_json()),
+            Aggregate::Sum(_, named) => ("sum", named.to_json()),
+            Aggregate::ApproxCountDistinct(_, named) => ("approx_count_distinct", named.to_json()),
+            Aggregate::PercentileDisc(_, column_name) => ("percentile_disc", column_name.as_str().into()),
+            Aggregate::PercentileCont(_, column_name) => ("percentile_cont", column_name.as_str().into()),
+            Aggregate::ApproxPercentile(_, column_name) => ("approx_percentile", column_name.as_str().into()),
+            Aggregate::CountBuckets(_, formulas) => (
+                "count_buckets",
+                formulas.iter().map(Formula::to_json).collect::<Vec<_>>().into(),
+            ),
+            Aggregate::ConditionalRatio(_, numerator, denominator) => (
+                "conditional_ratio",
+                vec![numerator.to_json(), denominator.to_json()].into(),
+            ),
+            Aggregate::GroupDuration(_, named) => ("group_duration", named.to_json()),
+            Aggregate::GeoMean(_, named) => ("geo_mean", named.to_json()),
+            Aggregate::Entropy(_, named) => ("entropy", named.to_json()),
+            Aggregate::DistinctRatio(_, named) => ("distinct_ratio", named.to_json()),
+            Aggregate::Variance(_, named) => ("variance", named.to_json()),
+            Aggregate::StdDev(_, named) => ("stddev", named.to_json()),
+        };
+        obj["aggregate"] = name.into();
+        obj["argument"] = argument;
+
+        obj
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Aggregate::Avg(..) => "avg",
+            Aggregate::Count(..) => "count",
+            Aggregate::First(..) => "first",
+            Aggregate::Last(..) => "last",
+            Aggregate::Max(..) => "max",
+            Aggregate::Min(..) => "min",
+            Aggregate::Sum(..) => "sum",
+            Aggregate::ApproxCountDistinct(..) => "approx_count_distinct",
+            Aggregate::PercentileDisc(..) => "percentile_disc",
+            Aggregate::ApproxPercentile(..) => "approx_percentile",
+            Aggregate::CountBuckets(..) => "count_buckets",
+            Aggregate::ConditionalRatio(..) => "conditional_ratio",
+            Aggregate::GroupDuration(..) => "group_duration",
+            Aggregate::GeoMean(..) => "geo_mean",
+            Aggregate::Entropy(..) => "entropy",
+            Aggregate::Variance(..) => "variance",
+            Aggregate::StdDev(..) => "stddev",
+            Aggregate::PercentileCont(..) => "percentile_cont",
+            Aggregate::DistinctRatio(..) => "distinct_ratio",
+        }
+    }
+
+    pub(crate) fn has_partial_state(&self) -> bool {
+        matches!(self, Aggregate::Avg(..) | Aggregate::Count(..) | Aggregate::Sum(..))
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn add_record(&mut self, key: Option<Tuple>, value: Value) -> AggregateResult<()> {
+        match self {
+            Aggregate::Avg(agg, _) => agg.add_record(key, value),
+            Aggregate::Count(agg, _) => agg.add_record(key, value),
+            Aggregate::First(agg, _) => agg.add_record(key, value),
+            Aggregate::Last(agg, _) => agg.add_record(key, value),
+            Aggregate::Sum(agg, _) => agg.add_record(key, value),
+            Aggregate::Max(agg, _) => agg.add_record(key, value),
+            Aggregate::Min(agg, _) => agg.add_record(key, value),
+            Aggregate::ApproxCountDistinct(agg, _) => agg.add_record(key, value),
+            Aggregate::PercentileDisc(agg, _) => agg.add_record(key, value),
+            Aggregate::PercentileCont(agg, _) => agg.add_record(key, value),
+            Aggregate::ApproxPercentile(agg, _) => agg.add_record(key, value),
+            //The buckets are fed with the predicate results through add_row instead
+            Aggregate::CountBuckets(_, _) => Err(AggregateError::InvalidType),
+            Aggregate::ConditionalRatio(_, _, _) => Err(AggregateError::InvalidType),
+            Aggregate::GroupDuration(agg, _) => agg.add_record(key, value),
+            Aggregate::GeoMean(agg, _) => agg.add_record(key, value),
+            Aggregate::Entropy(agg, _) => agg.add_record(key, value),
+            Aggregate::DistinctRatio(agg, _) => agg.add_record(key, value),
+            Aggregate::Variance(agg, _) => agg.add_record(key, value),
+            Aggregate::StdDev(agg, _) => agg.add_record(key, value),
+        }
+    }
+    pub(crate) fn get_aggregated(&mut self, key: &Option<Tuple>) -> AggregateResult<Value> {
+        match self {
+            Aggregate::Avg(agg, _) => agg.get_aggregated(key),
+            Aggregate::Count(agg, _) => agg.get_aggregated(key),
+            Aggregate::First(agg, _) => agg.get_aggregated(key),
+            Aggregate::Last(agg, _) => agg.get_aggregated(key),
+            Aggregate::Sum(agg, _) => agg.get_aggregated(key),
+            Aggregate::Max(agg, _) => agg.get_aggregated(key),
+            Aggregate::Min(agg, _) => agg.get_aggregated(key),
+            Aggregate::ApproxCountDistinct(agg, _) => agg.get_aggregated(key),
+            Aggregate::PercentileDisc(agg, _) => agg.get_aggregated(key),
+            Aggregate::PercentileCont(agg, _) => agg.get_aggregated(key),
+            Aggregate::ApproxPercentile(agg, _) => agg.get_aggregated(key),
+            Aggregate::CountBuckets(_, _) => Err(AggregateError::InvalidType),
+            Aggregate::ConditionalRatio(agg, _, _) => agg.get_aggregated(key),
+            Aggregate::GroupDuration(agg, _) => agg.get_aggregated(key),
+            Aggregate::GeoMean(agg, _) => agg.get_aggregated(key),
+            Aggregate::Entropy(agg, _) => agg.get_aggregated(key),
+            Aggregate::DistinctRatio(agg, _) => agg.get_aggregated(key),
+            Aggregate::Variance(agg, _) => agg.get_aggregated(key),
+            Aggregate::StdDev(agg, _) => agg.get_aggregated(key),
+        }
+    }
+
+    //The value over no rows at all, as SQL has it: the counts are zero and everything else is null
+    pub(crate) fn empty_value(&self) -> Value {
+        match self {
+            Aggregate::Count(_, _) | Aggregate::ApproxCountDistinct(_, _) => Value::Int(0),
+            _ => Value::Null,
+        }
+    }
+
+    pub(crate) fn get_partial(&self, key: &Option<Tuple>) -> AggregateResult<PartialState> {
+        match self {
+            Aggregate::Avg(agg, _) => agg.get_partial(key),
+            Aggregate::Count(agg, _) => agg.get_partial(key),
+            Aggregate::Sum(agg, _) => agg.get_partial(key),
+            _ => Err(AggregateError::PartialStateNotSupported),
+        }
+    }
+
+    pub(crate) fn merge_partial(&mut self, key: Option<Tuple>, state: PartialState) -> AggregateResult<()> {
+        match self {
+            Aggregate::Avg(agg, _) => agg.merge_partial(key, state),
+            Aggregate::Count(agg, _) => agg.merge_partial(key, state),
+            Aggregate::Sum(agg, _) => agg.merge_partial(key, state),
+            _ => Err(AggregateError::PartialStateNotSupported),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PercentileDiscAggregate {
+    pub(crate) partitions: HashMap<Option<Tuple>, Vec<Value>>,
+    pub(crate) percentile: OrderedFloat<f32>,
+    pub(crate) ordering: Ordering,
+}
+
+impl PercentileDiscAggregate {
+    pub(crate) fn new(percentile: OrderedFloat<f32>, ordering: Ordering) -> Self {
+        PercentileDiscAggregate {
+            partitions: HashMap::new(),
+            percentile,
+            ordering,
+        }
+    }
+
+    pub(crate) fn add_record(&mut self, key: Option<Tuple>, value: Value) -> AggregateResult<()> {
+        if value == Value::Null {
+            return Ok(());
+        }
+
+        let v = self.partitions.entry(key).or_insert(Vec::new());
+        v.push(value);
+
+        Ok(())
+    }
+
+    pub(crate) fn get_aggregated(&self, key: &Option<Tuple>) -> AggregateResult<Value> {
+        //FIXME: expensive operation
+        let mut v = match self.partitions.get(key) {
+            Some(v) => v.clone(),
+            None => {
+                return Ok(Value::Null);
+            }
+        };
+        v.sort_by(|a, b| match (a, b) {
+            (Value::Int(i1), Value::Int(i2)) => match self.ordering {
+                Ordering::Asc => i1.cmp(i2),
+                Ordering::Desc => i2.cmp(i1),
+            },
+            (Value::Boolean(b1), Value::Boolean(b2)) => match self.ordering {
+                Ordering::Asc => b1.cmp(b2),
+                Ordering::Desc => b2.cmp(b1),
+            },
+            (Value::Float(f1), Value::Float(f2)) => match self.ordering {
+                Ordering::Asc => f1.cmp(f2),
+                Ordering::Desc => f2.cmp(f1),
+            },
+            (Value::DateTime(dt1), Value::DateTime(dt2)) => match self.ordering {
+                Ordering::Asc => dt1.cmp(dt2),
+                Ordering::Desc => dt2.cmp(dt1),
+            },
+            (Value::String(s1), Value::String(s2)) => match self.ordering {
+                Ordering::Asc => s1.cmp(s2),
+                Ordering::Desc => s2.cmp(s1),
+            },
+            (Value::Null, Value::Null) => std::cmp::Ordering::Equal,
+            (Value::Host(h1), Value::Host(h2)) => {
+                let s1 = h1.to_string();
+                let s2 = h2.to_string();
+
+                match self.ordering {
+                    Ordering::Asc => s1.cmp(&s2),
+                    Ordering::Desc => s2.cmp(&s1),
+                }
+            }
+            (Value::HttpRequest(h1), Value::HttpRequest(h2)) => {
+                let s1 = h1.to_string();
+                let s2 = h2.to_string();
+
+                match self.ordering {
+                    Ordering::Asc => s1.cmp(&s2),
+                    Ordering::Desc => s2.cmp(&s1),
+                }
+            }
+            _ => {
+                unreachable!();
+            }
+        });
+
+        let f32_percentile: f32 = self.percentile.into();
+        let idx: usize = ((v.len() as f32) * f32_percentile) as usize;
+        let ans = v[idx].clone();
+        Ok(ans)
+    }
+}
+
+//The exact continuous percentile, interpolated linearly between the two values around the rank
+//percentile * (n - 1) as SQL's percentile_cont does, so the median of 1, 2, 3, 4 is 2.5. Every value of
+//a group is kept and sorted at the end like PercentileDisc, only numbers can be interpolated.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PercentileContAggregate {
+    pub(crate) partitions: HashMap<Option<Tuple>, Vec<OrderedFloat<f64>>>,
+    pub(crate) percentile: OrderedFloat<f32>,
+    pub(crate) ordering: Ordering,
+}
+
+impl PercentileContAggregate {
+    pub(crate) fn new(percentile: OrderedFloat<f32>, ordering: Ordering) -> Self {
+        PercentileContAggregate {
+            partitions: HashMap::new(),
+            percentile,
+            ordering,
+        }
+    }
+
+    pub(crate) fn add_record(&mut self, key: Option<Tuple>, value: Value) -> AggregateResult<()> {
+        let new_value = match value {
+            Value::Int(i) => f64::from(i),
+            Value::Float(f) => f64::from(f.into_inner()),
+            Value::Null => {
+                return Ok(());
+            }
+            other => {
+                return Err(AggregateError::NonNumericValue(format!("{:?}", other)));
+            }
+        };
+
+        self.partitions
+            .entry(key)
+            .or_default()
+            .push(OrderedFloat::from(new_value));
+
+        Ok(())
+    }
+
+    pub(crate) fn get_aggregated(&self, key: &Option<Tuple>) -> AggregateResult<Value> {
+        let mut v = match self.partitions.get(key) {
+            Some(v) if !v.is_empty() => v.clone(),
+            _ => {
+                return Ok(Value::Null);
+            }
+        };
+        match self.ordering {
+            Ordering::Asc => v.sort(),
+            Ordering::Desc => v.sort_by(|a, b| b.cmp(a)),
+        }
+
+        let percentile = f64::from(self.percentile.into_inner()).clamp(0.0, 1.0);
+        let rank = percentile * (v.len() - 1) as f64;
+        let lower = v[rank.floor() as usize].into_inner();
+        let upper = v[rank.ceil() as usize].into_inner();
+        let ans = lower + (rank - rank.floor()) * (upper - lower);
+
+        Ok(Value::Float(OrderedFloat::from(ans as f32)))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ApproxPercentileAggregate {
+    pub(crate) partitions: HashMap<Option<Tuple>, TDigest>,
+    pub(crate) buffer: HashMap<Option<Tuple>, Vec<Value>>,
+    pub(crate) percentile: OrderedFloat<f32>,
+    pub(crate) ordering: Ordering,
+}
+
+impl ApproxPercentileAggregate {
+    pub(crate) fn new(percentile: OrderedFloat<f32>, ordering: Ordering) -> Self {
+        ApproxPercentileAggregate {
+            partitions: HashMap::new(),
+            buffer: HashMap::new(),
+            percentile,
+            ordering,
+        }
+    }
+
+    pub(crate) fn add_record(&mut self, key: Option<Tuple>, value: Value) -> AggregateResult<()> {
+        if value == Value::Null {
+            return Ok(());
+        }
+
+        let buf = self.buffer.entry(key.clone()).or_insert(Vec::new());
+        buf.push(value);
+
+        if buf.len() < 10000 {
+            Ok(())
+        } else {
+            let v = self
+                .partitions
+                .entry(key.clone())
+                .or_insert(TDigest::new_with_size(100));
+
+            let mut fvec = Vec::new();
+            for val in buf.iter() {
+                match val {
+                    Value::Float(f) => {
+                        fvec.push(f64::from(f.into_inner()));
+                    }
+                    Value::Int(i) => {
+                        fvec.push(f64::from(*i));
+                    }
+                    _ => {
+                        return Err(AggregateError::InvalidType);
+                    }
+                }
+            }
 
-                    for idx in 0..column_names.len() {
-                        let column_name = &column_names[idx];
-                        let curr_ordering = &orderings[idx];
+            let new_digest = v.merge_unsorted(fvec);
+            self.partitions.insert(key, new_digest);
+            buf.clear();
 
-                        let a_value = a_variables.get(column_name).unwrap();
-                        let b_value = b_varialbes.get(column_name).unwrap();
+            Ok(())
+        }
+    }
 
-                        match (a_value, b_value) {
-                            (Value::Int(i1), Value::Int(i2)) => match curr_ordering {
-                                Ordering::Asc => {
-                                    return i1.cmp(i2);
-                                }
-                                Ordering::Desc => {
-                                    return i2.cmp(i1);
-                                }
-                            },
-                            (Value::Boolean(b1), Value::Boolean(b2)) => match curr_ordering {
-                                Ordering::Asc => {
-                                    return b1.cmp(b2);
-                                }
-                                Ordering::Desc => {
-                                    return b2.cmp(b1);
-                                }
-                            },
-                            (Value::Float(f1), Value::Float(f2)) => match curr_ordering {
-                                Ordering::Asc => {
-                                    return f1.cmp(f2);
-                                }
-                                Ordering::Desc => {
-                                    return f2.cmp(f1);
-                                }
-                            },
-                            (Value::String(s1), Value::String(s2)) => match curr_ordering {
-                                Ordering::Asc => {
-                                    return s1.cmp(s2);
-                                }
-                                Ordering::Desc => {
-                                    return s2.cmp(s1);
-                                }
-                            },
-                            (Value::DateTime(dt1), Value::DateTime(dt2)) => match curr_ordering {
-                                Ordering::Asc => {
-                                    return dt1.cmp(dt2);
-                                }
-                                Ordering::Desc => {
-                                    return dt2.cmp(dt1);
-                                }
-                            },
-                            (Value::Null, Value::Null) => {
-                                return std::cmp::Ordering::Equal;
-                            }
-                            (Value::Host(h1), Value::Host(h2)) => {
-                                let s1 = h1.to_string();
-                                let s2 = h2.to_string();
-
-                                match curr_ordering {
-                                    Ordering::Asc => {
-                                        return s1.cmp(&s2);
-                                    }
-                                    Ordering::Desc => {
-                                        return s2.cmp(&s1);
-                                    }
-                                }
-                            }
-                            (Value::HttpRequest(h1), Value::HttpRequest(h2)) => {
-                                let s1 = h1.to_string();
-                                let s2 = h2.to_string();
-
-                                match curr_ordering {
-                                    Ordering::Asc => {
-                                        return s1.cmp(&s2);
-                                    }
-                                    Ordering::Desc => {
-                                        return s2.cmp(&s1);
-                                    }
-                                }
-                            }
-                            _ => {
-                                unreachable!();
-                            }
-                        }
+    pub(crate) fn get_aggregated(&mut self, key: &Option<Tuple>) -> AggregateResult<Value> {
+        let buf = self.buffer.entry(key.clone()).or_insert(Vec::new());
+        let t = if !buf.is_empty() {
+            let v = self
+                .partitions
+                .entry(key.clone())
+                .or_insert(TDigest::new_with_size(100));
+
+            let mut fvec = Vec::new();
+            for val in buf.iter() {
+                match val {
+                    Value::Float(f) => {
+                        fvec.push(f64::from(f.into_inner()));
+                    }
+                    Value::Int(i) => {
+                        fvec.push(f64::from(*i));
+                    }
+                    _ => {
+                        return Err(AggregateError::InvalidType);
                     }
+                }
+            }
 
-                    std::cmp::Ordering::Equal
-                });
+            v.merge_unsorted(fvec)
+        } else {
+            match self.partitions.get(key) {
+                Some(t) => t.clone(),
+                None => {
+                    return Ok(Value::Null);
+                }
+            }
+        };
 
-                let stream = InMemoryStream::new(VecDeque::from(records));
-                Ok(Box::new(stream))
+        let f32_percentile: f32 = self.percentile.into();
+        let f64_percentile: f64 = f64::from(f32_percentile);
+        let f64_ans = t.estimate_quantile(f64_percentile);
+        let ans = Value::Float(OrderedFloat::from(f64_ans as f32));
+        Ok(ans)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct AvgAggregate {
+    //Kept as f64 so merging the partial states of many runs doesn't compound the f32 rounding
+    pub(crate) averages: HashMap<Option<Tuple>, OrderedFloat<f64>>,
+    pub(crate) counts: HashMap<Option<Tuple>, i64>,
+    pub(crate) nan_policy: NanPolicy,
+    //The groups seen so far whose every value was skipped, which aggregate to null
+    pub(crate) empty_groups: HashSet<Option<Tuple>>,
+}
+
+impl AvgAggregate {
+    pub(crate) fn new() -> Self {
+        AvgAggregate {
+            averages: HashMap::new(),
+            counts: HashMap::new(),
+            nan_policy: NanPolicy::default(),
+            empty_groups: HashSet::new(),
+        }
+    }
+
+    pub(crate) fn with_nan_policy(mut self, nan_policy: NanPolicy) -> Self {
+        self.nan_policy = nan_policy;
+        self
+    }
+
+    fn skip(&mut self, key: Option<Tuple>) {
+        if !self.averages.contains_key(&key) {
+            self.empty_groups.insert(key);
+        }
+    }
+
+    pub(crate) fn add_record(&mut self, key: Option<Tuple>, value: Value) -> AggregateResult<()> {
+        if !self.nan_policy.admit(&value)? {
+            self.skip(key);
+            return Ok(());
+        }
+
+        let new_value: f64 = match value {
+            Value::Int(i) => i as f64,
+            Value::Float(f) => f64::from(f.into_inner()),
+            //A missing value, like the -1 processing time of a request the backend never answered
+            Value::Null => {
+                self.skip(key);
+                return Ok(());
+            }
+            _ => {
+                return Err(AggregateError::InvalidType);
+            }
+        };
+
+        if let (Some(&average), Some(&count)) = (self.averages.get(&key), self.counts.get(&key)) {
+            let new_count = count + 1;
+            let new_average = (average.into_inner() * (count as f64) + new_value) / (new_count as f64);
+            self.averages.insert(key.clone(), OrderedFloat::from(new_average));
+            self.counts.insert(key.clone(), new_count);
+            Ok(())
+        } else {
+            self.averages.insert(key.clone(), OrderedFloat::from(new_value));
+            self.counts.insert(key.clone(), 1);
+
+            Ok(())
+        }
+    }
+
+    pub(crate) fn get_aggregated(&self, key: &Option<Tuple>) -> AggregateResult<Value> {
+        if let Some(&average) = self.averages.get(key) {
+            Ok(Value::Float(OrderedFloat::from(average.into_inner() as f32)))
+        } else if self.empty_groups.contains(key) {
+            Ok(Value::Null)
+        } else {
+            Err(AggregateError::KeyNotFound)
+        }
+    }
+
+    pub(crate) fn get_partial(&self, key: &Option<Tuple>) -> AggregateResult<PartialState> {
+        if let (Some(&average), Some(&count)) = (self.averages.get(key), self.counts.get(key)) {
+            Ok(PartialState::Avg(average, count))
+        } else {
+            Err(AggregateError::KeyNotFound)
+        }
+    }
+
+    pub(crate) fn merge_partial(&mut self, key: Option<Tuple>, state: PartialState) -> AggregateResult<()> {
+        let (other_average, other_count) = match state {
+            PartialState::Avg(average, count) => (average, count),
+            _ => {
+                return Err(AggregateError::InvalidType);
             }
+        };
+
+        if let (Some(&average), Some(&count)) = (self.averages.get(&key), self.counts.get(&key)) {
+            let new_count = count + other_count;
+            let new_average = (average.into_inner() * (count as f64)
+                + other_average.into_inner() * (other_count as f64))
+                / (new_count as f64);
+            self.averages.insert(key.clone(), OrderedFloat::from(new_average));
+            self.counts.insert(key, new_count);
+        } else {
+            self.averages.insert(key.clone(), other_average);
+            self.counts.insert(key, other_count);
         }
+
+        Ok(())
     }
 }
 
-pub(crate) type AggregateResult<T> = result::Result<T, AggregateError>;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SumAggregate {
+    //Kept as f64 so merging the partial states of many runs doesn't compound the f32 rounding
+    pub(crate) sums: HashMap<Option<Tuple>, OrderedFloat<f64>>,
+    pub(crate) nan_policy: NanPolicy,
+    pub(crate) empty_groups: HashSet<Option<Tuple>>,
+}
 
-#[derive(Fail, PartialEq, Eq, Debug)]
-pub enum AggregateError {
-    #[fail(display = "Key Not Found")]
-    KeyNotFound,
-    #[fail(display = "Invalid Type")]
-    InvalidType,
+impl SumAggregate {
+    pub(crate) fn new() -> Self {
+        SumAggregate {
+            sums: HashMap::new(),
+            nan_policy: NanPolicy::default(),
+            empty_groups: HashSet::new(),
+        }
+    }
+
+    pub(crate) fn with_nan_policy(mut self, nan_policy: NanPolicy) -> Self {
+        self.nan_policy = nan_policy;
+        self
+    }
+
+    fn skip(&mut self, key: Option<Tuple>) {
+        if !self.sums.contains_key(&key) {
+            self.empty_groups.insert(key);
+        }
+    }
+
+    pub(crate) fn add_record(&mut self, key: Option<Tuple>, value: Value) -> AggregateResult<()> {
+        if !self.nan_policy.admit(&value)? {
+            self.skip(key);
+            return Ok(());
+        }
+
+        let new_value: f64 = match value {
+            Value::Int(i) => i as f64,
+            Value::Float(f) => f64::from(f.into_inner()),
+            //A missing value, like the -1 processing time of a request the backend never answered
+            Value::Null => {
+                self.skip(key);
+                return Ok(());
+            }
+            _ => {
+                return Err(AggregateError::InvalidType);
+            }
+        };
+
+        self.add_sum(key, new_value);
+        Ok(())
+    }
+
+    fn add_sum(&mut self, key: Option<Tuple>, value: f64) {
+        let sum = self.sums.entry(key).or_insert_with(|| OrderedFloat::from(0.0));
+        *sum = OrderedFloat::from(sum.into_inner() + value);
+    }
+
+    pub(crate) fn get_aggregated(&self, key: &Option<Tuple>) -> AggregateResult<Value> {
+        if let Some(&sum) = self.sums.get(key) {
+            Ok(Value::Float(OrderedFloat::from(sum.into_inner() as f32)))
+        } else if self.empty_groups.contains(key) {
+            Ok(Value::Null)
+        } else {
+            Err(AggregateError::KeyNotFound)
+        }
+    }
+
+    pub(crate) fn get_partial(&self, key: &Option<Tuple>) -> AggregateResult<PartialState> {
+        if let Some(&sum) = self.sums.get(key) {
+            Ok(PartialState::Sum(sum))
+        } else {
+            Err(AggregateError::KeyNotFound)
+        }
+    }
+
+    pub(crate) fn merge_partial(&mut self, key: Option<Tuple>, state: PartialState) -> AggregateResult<()> {
+        if let PartialState::Sum(other_sum) = state {
+            self.add_sum(key, other_sum.into_inner());
+            Ok(())
+        } else {
+            Err(AggregateError::InvalidType)
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub(crate) struct NamedAggregate {
-    pub(crate) aggregate: Aggregate,
-    pub(crate) name_opt: Option<String>,
+pub(crate) struct CountAggregate {
+    pub(crate) counts: HashMap<Option<Tuple>, i64>,
+}
+
+impl CountAggregate {
+    pub(crate) fn new() -> Self {
+        CountAggregate { counts: HashMap::new() }
+    }
+
+    pub(crate) fn add_record(&mut self, key: Option<Tuple>, value: Value) -> AggregateResult<()> {
+        if let Value::Null = value {
+            //Null value doesn't contribute to the total count
+            return Ok(());
+        };
+
+        if let Some(&count) = self.counts.get(&key) {
+            let new_count = count + 1;
+            self.counts.insert(key.clone(), new_count);
+            Ok(())
+        } else {
+            self.counts.insert(key.clone(), 1);
+
+            Ok(())
+        }
+    }
+
+    pub(crate) fn add_row(&mut self, key: Option<Tuple>) -> AggregateResult<()> {
+        if let Some(&count) = self.counts.get(&key) {
+            let new_count = count + 1;
+            self.counts.insert(key.clone(), new_count);
+            Ok(())
+        } else {
+            self.counts.insert(key.clone(), 1);
+
+            Ok(())
+        }
+    }
+
+    pub(crate) fn get_aggregated(&self, key: &Option<Tuple>) -> AggregateResult<Value> {
+        if let Some(&counts) = self.counts.get(key) {
+            Ok(Value::Int(counts as i32))
+        } else {
+            Err(AggregateError::KeyNotFound)
+        }
+    }
+
+    pub(crate) fn get_partial(&self, key: &Option<Tuple>) -> AggregateResult<PartialState> {
+        if let Some(&count) = self.counts.get(key) {
+            Ok(PartialState::Count(count))
+        } else {
+            Err(AggregateError::KeyNotFound)
+        }
+    }
+
+    pub(crate) fn merge_partial(&mut self, key: Option<Tuple>, state: PartialState) -> AggregateResult<()> {
+        if let PartialState::Count(other_count) = state {
+            let count = self.counts.get(&key).cloned().unwrap_or(0);
+            self.counts.insert(key, count + other_count);
+            Ok(())
+        } else {
+            Err(AggregateError::InvalidType)
+        }
+    }
+}
+
+//Counts the rows matching each predicate in a single pass, one output column per predicate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct CountBucketsAggregate {
+    pub(crate) counts: HashMap<Option<Tuple>, Vec<i64>>,
+    pub(crate) bucket_count: usize,
 }
 
-impl NamedAggregate {
-    pub(crate) fn new(aggregate: Aggregate, name_opt: Option<String>) -> Self {
-        NamedAggregate { aggregate, name_opt }
+impl CountBucketsAggregate {
+    pub(crate) fn new(bucket_count: usize) -> Self {
+        CountBucketsAggregate {
+            counts: HashMap::new(),
+            bucket_count,
+        }
+    }
+
+    pub(crate) fn add_row(&mut self, key: Option<Tuple>, matches: &[bool]) -> AggregateResult<()> {
+        if matches.len() != self.bucket_count {
+            return Err(AggregateError::InvalidType);
+        }
+
+        let bucket_count = self.bucket_count;
+        let counts = self.counts.entry(key).or_insert_with(|| vec![0; bucket_count]);
+        for (count, matched) in counts.iter_mut().zip(matches.iter()) {
+            if *matched {
+                *count += 1;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn get_aggregated(&self, key: &Option<Tuple>) -> AggregateResult<Vec<Value>> {
+        if let Some(counts) = self.counts.get(key) {
+            Ok(counts.iter().map(|&c| Value::Int(c as i32)).collect())
+        } else {
+            Err(AggregateError::KeyNotFound)
+        }
+    }
+
+    //The columns are named after the alias, or "count_buckets" without one, suffixed by the predicate index
+    pub(crate) fn field_names(&self, name_opt: &Option<String>) -> Vec<String> {
+        let prefix = name_opt.clone().unwrap_or_else(|| "count_buckets".to_string());
+        (0..self.bucket_count).map(|i| format!("{}_{}", prefix, i)).collect()
     }
 }
 
+//The ratio of the rows matching the numerator to the rows matching the denominator, counted in
+//two buckets. A group where no row matches the denominator has a null ratio.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub(crate) enum Aggregate {
-    Avg(AvgAggregate, Named),
-    Count(CountAggregate, Named),
-    First(FirstAggregate, Named),
-    Last(LastAggregate, Named),
-    Max(MaxAggregate, Named),
-    Min(MinAggregate, Named),
-    Sum(SumAggregate, Named),
-    ApproxCountDistinct(ApproxCountDistinctAggregate, Named),
-    PercentileDisc(PercentileDiscAggregate, String),
-    ApproxPercentile(ApproxPercentileAggregate, String),
+pub(crate) struct ConditionalRatioAggregate {
+    pub(crate) buckets: CountBucketsAggregate,
 }
 
-impl Aggregate {
-    #[allow(dead_code)]
-    pub(crate) fn add_record(&mut self, key: Option<Tuple>, value: Value) -> AggregateResult<()> {
-        match self {
-            Aggregate::Avg(agg, _) => agg.add_record(key, value),
-            Aggregate::Count(agg, _) => agg.add_record(key, value),
-            Aggregate::First(agg, _) => agg.add_record(key, value),
-            Aggregate::Last(agg, _) => agg.add_record(key, value),
-            Aggregate::Sum(agg, _) => agg.add_record(key, value),
-            Aggregate::Max(agg, _) => agg.add_record(key, value),
-            Aggregate::Min(agg, _) => agg.add_record(key, value),
-            Aggregate::ApproxCountDistinct(agg, _) => agg.add_record(key, value),
-            Aggregate::PercentileDisc(agg, _) => agg.add_record(key, value),
-            Aggregate::ApproxPercentile(agg, _) => agg.add_record(key, value),
+impl ConditionalRatioAggregate {
+    pub(crate) fn new() -> Self {
+        ConditionalRatioAggregate {
+            buckets: CountBucketsAggregate::new(2),
         }
     }
-    pub(crate) fn get_aggregated(&mut self, key: &Option<Tuple>) -> AggregateResult<Value> {
-        match self {
-            Aggregate::Avg(agg, _) => agg.get_aggregated(key),
-            Aggregate::Count(agg, _) => agg.get_aggregated(key),
-            Aggregate::First(agg, _) => agg.get_aggregated(key),
-            Aggregate::Last(agg, _) => agg.get_aggregated(key),
-            Aggregate::Sum(agg, _) => agg.get_aggregated(key),
-            Aggregate::Max(agg, _) => agg.get_aggregated(key),
-            Aggregate::Min(agg, _) => agg.get_aggregated(key),
-            Aggregate::ApproxCountDistinct(agg, _) => agg.get_aggregated(key),
-            Aggregate::PercentileDisc(agg, _) => agg.get_aggregated(key),
-            Aggregate::ApproxPercentile(agg, _) => agg.get_aggregated(key),
+
+    pub(crate) fn add_row(&mut self, key: Option<Tuple>, numerator: bool, denominator: bool) -> AggregateResult<()> {
+        self.buckets.add_row(key, &[numerator, denominator])
+    }
+
+    pub(crate) fn get_aggregated(&self, key: &Option<Tuple>) -> AggregateResult<Value> {
+        let counts = self.buckets.counts.get(key).ok_or(AggregateError::KeyNotFound)?;
+        if counts[1] == 0 {
+            Ok(Value::Null)
+        } else {
+            Ok(Value::Float(OrderedFloat::from(counts[0] as f32 / counts[1] as f32)))
         }
     }
 }
 
+//exp(mean(ln(x))), kept as a running sum of logarithms and a count like Avg. The logarithm is only
+//defined for positive numbers so zero and negative values are skipped, a group without any positive
+//value is null.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub(crate) struct PercentileDiscAggregate {
-    pub(crate) partitions: HashMap<Option<Tuple>, Vec<Value>>,
-    pub(crate) percentile: OrderedFloat<f32>,
-    pub(crate) ordering: Ordering,
+pub(crate) struct GeoMeanAggregate {
+    pub(crate) log_sums: HashMap<Option<Tuple>, OrderedFloat<f64>>,
+    pub(crate) counts: HashMap<Option<Tuple>, i64>,
+    pub(crate) nan_policy: NanPolicy,
 }
 
-impl PercentileDiscAggregate {
-    pub(crate) fn new(percentile: OrderedFloat<f32>, ordering: Ordering) -> Self {
-        PercentileDiscAggregate {
-            partitions: HashMap::new(),
-            percentile,
-            ordering,
+impl GeoMeanAggregate {
+    pub(crate) fn new() -> Self {
+        GeoMeanAggregate {
+            log_sums: HashMap::new(),
+            counts: HashMap::new(),
+            nan_policy: NanPolicy::default(),
         }
     }
 
-    pub(crate) fn add_record(&mut self, key: Option<Tuple>, value: Value) -> AggregateResult<()> {
-        let v = self.partitions.entry(key).or_insert(Vec::new());
-        v.push(value);
-
-        Ok(())
+    pub(crate) fn with_nan_policy(mut self, nan_policy: NanPolicy) -> Self {
+        self.nan_policy = nan_policy;
+        self
     }
 
-    pub(crate) fn get_aggregated(&self, key: &Option<Tuple>) -> AggregateResult<Value> {
-        //FIXME: expensive operation
-        let mut v = self.partitions.get(key).unwrap().clone();
-        v.sort_by(|a, b| match (a, b) {
-            (Value::Int(i1), Value::Int(i2)) => match self.ordering {
-                Ordering::Asc => i1.cmp(i2),
-                Ordering::Desc => i2.cmp(i1),
-            },
-            (Value::Boolean(b1), Value::Boolean(b2)) => match self.ordering {
-                Ordering::Asc => b1.cmp(b2),
-                Ordering::Desc => b2.cmp(b1),
-            },
-            (Value::Float(f1), Value::Float(f2)) => match self.ordering {
-                Ordering::Asc => f1.cmp(f2),
-                Ordering::Desc => f2.cmp(f1),
-            },
-            (Value::DateTime(dt1), Value::DateTime(dt2)) => match self.ordering {
-                Ordering::Asc => dt1.cmp(dt2),
-                Ordering::Desc => dt2.cmp(dt1),
-            },
-            (Value::String(s1), Value::String(s2)) => match self.ordering {
-                Ordering::Asc => s1.cmp(s2),
-                Ordering::Desc => s2.cmp(s1),
-            },
-            (Value::Null, Value::Null) => std::cmp::Ordering::Equal,
-            (Value::Host(h1), Value::Host(h2)) => {
-                let s1 = h1.to_string();
-                let s2 = h2.to_string();
-
-                match self.ordering {
-                    Ordering::Asc => s1.cmp(&s2),
-                    Ordering::Desc => s2.cmp(&s1),
-                }
-            }
-            (Value::HttpRequest(h1), Value::HttpRequest(h2)) => {
-                let s1 = h1.to_string();
-                let s2 = h2.to_string();
+    pub(crate) fn add_record(&mut self, key: Option<Tuple>, value: Value) -> AggregateResult<()> {
+        if !self.nan_policy.admit(&value)? {
+            return Ok(());
+        }
 
-                match self.ordering {
-                    Ordering::Asc => s1.cmp(&s2),
-                    Ordering::Desc => s2.cmp(&s1),
-                }
+        let new_value = match value {
+            Value::Int(i) => f64::from(i),
+            Value::Float(f) => f64::from(f.into_inner()),
+            //A missing value, like the -1 processing time of a request the backend never answered
+            Value::Null => {
+                return Ok(());
             }
             _ => {
-                unreachable!();
+                return Err(AggregateError::InvalidType);
             }
-        });
+        };
+        if new_value <= 0.0 {
+            return Ok(());
+        }
 
-        let f32_percentile: f32 = self.percentile.into();
-        let idx: usize = ((v.len() as f32) * f32_percentile) as usize;
-        let ans = v[idx].clone();
-        Ok(ans)
+        let log_sum = self
+            .log_sums
+            .entry(key.clone())
+            .or_insert_with(|| OrderedFloat::from(0.0));
+        *log_sum = OrderedFloat::from(log_sum.into_inner() + new_value.ln());
+        *self.counts.entry(key).or_insert(0) += 1;
+
+        Ok(())
+    }
+
+    pub(crate) fn get_aggregated(&self, key: &Option<Tuple>) -> AggregateResult<Value> {
+        if let (Some(log_sum), Some(&count)) = (self.log_sums.get(key), self.counts.get(key)) {
+            let geo_mean = (log_sum.into_inner() / count as f64).exp();
+            Ok(Value::Float(OrderedFloat::from(geo_mean as f32)))
+        } else {
+            //Every value of the group was skipped
+            Ok(Value::Null)
+        }
     }
 }
 
+//The sample variance of a group with Welford's online algorithm, a count, the running mean and the
+//sum of squared differences from it, so the values are never kept and no large sums cancel out. A
+//group with fewer than two values has no sample variance and is null.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub(crate) struct ApproxPercentileAggregate {
-    pub(crate) partitions: HashMap<Option<Tuple>, TDigest>,
-    pub(crate) buffer: HashMap<Option<Tuple>, Vec<Value>>,
-    pub(crate) percentile: OrderedFloat<f32>,
-    pub(crate) ordering: Ordering,
+pub(crate) struct VarianceAggregate {
+    pub(crate) states: HashMap<Option<Tuple>, (i64, OrderedFloat<f64>, OrderedFloat<f64>)>,
+    pub(crate) nan_policy: NanPolicy,
 }
 
-impl ApproxPercentileAggregate {
-    pub(crate) fn new(percentile: OrderedFloat<f32>, ordering: Ordering) -> Self {
-        ApproxPercentileAggregate {
-            partitions: HashMap::new(),
-            buffer: HashMap::new(),
-            percentile,
-            ordering,
+impl VarianceAggregate {
+    pub(crate) fn new() -> Self {
+        VarianceAggregate {
+            states: HashMap::new(),
+            nan_policy: NanPolicy::default(),
         }
     }
 
-    pub(crate) fn add_record(&mut self, key: Option<Tuple>, value: Value) -> AggregateResult<()> {
-        let buf = self.buffer.entry(key.clone()).or_insert(Vec::new());
-        buf.push(value);
+    pub(crate) fn with_nan_policy(mut self, nan_policy: NanPolicy) -> Self {
+        self.nan_policy = nan_policy;
+        self
+    }
 
-        if buf.len() < 10000 {
-            Ok(())
-        } else {
-            let v = self
-                .partitions
-                .entry(key.clone())
-                .or_insert(TDigest::new_with_size(100));
+    pub(crate) fn add_record(&mut self, key: Option<Tuple>, value: Value) -> AggregateResult<()> {
+        if !self.nan_policy.admit(&value)? {
+            return Ok(());
+        }
 
-            let mut fvec = Vec::new();
-            for val in buf.iter() {
-                match val {
-                    Value::Float(f) => {
-                        fvec.push(f64::from(f.into_inner()));
-                    }
-                    Value::Int(i) => {
-                        fvec.push(f64::from(*i));
-                    }
-                    _ => {
-                        return Err(AggregateError::InvalidType);
-                    }
-                }
+        let new_value = match value {
+            Value::Int(i) => f64::from(i),
+            Value::Float(f) => f64::from(f.into_inner()),
+            Value::Null => {
+                return Ok(());
             }
+            other => {
+                return Err(AggregateError::NonNumericValue(format!("{:?}", other)));
+            }
+        };
 
-            let new_digest = v.merge_unsorted(fvec);
-            self.partitions.insert(key, new_digest);
-            buf.clear();
+        let (count, mean, m2) = self
+            .states
+            .entry(key)
+            .or_insert((0, OrderedFloat::from(0.0), OrderedFloat::from(0.0)));
+        *count += 1;
+        let delta = new_value - mean.into_inner();
+        *mean = OrderedFloat::from(mean.into_inner() + delta / *count as f64);
+        *m2 = OrderedFloat::from(m2.into_inner() + delta * (new_value - mean.into_inner()));
 
-            Ok(())
-        }
+        Ok(())
     }
 
-    pub(crate) fn get_aggregated(&mut self, key: &Option<Tuple>) -> AggregateResult<Value> {
-        let buf = self.buffer.entry(key.clone()).or_insert(Vec::new());
-        let t = if !buf.is_empty() {
-            let v = self
-                .partitions
-                .entry(key.clone())
-                .or_insert(TDigest::new_with_size(100));
-
-            let mut fvec = Vec::new();
-            for val in buf.iter() {
-                match val {
-                    Value::Float(f) => {
-                        fvec.push(f64::from(f.into_inner()));
-                    }
-                    Value::Int(i) => {
-                        fvec.push(f64::from(*i));
-                    }
-                    _ => {
-                        return Err(AggregateError::InvalidType);
-                    }
-                }
-            }
-
-            v.merge_unsorted(fvec)
-        } else {
-            self.partitions.get(key).unwrap().clone()
-        };
+    pub(crate) fn get_variance(&self, key: &Option<Tuple>) -> Option<f64> {
+        match self.states.get(key) {
+            Some(&(count, _, m2)) if count > 1 => Some(m2.into_inner() / (count - 1) as f64),
+            _ => None,
+        }
+    }
 
-        let f32_percentile: f32 = self.percentile.into();
-        let f64_percentile: f64 = f64::from(f32_percentile);
-        let f64_ans = t.estimate_quantile(f64_percentile);
-        let ans = Value::Float(OrderedFloat::from(f64_ans as f32));
-        Ok(ans)
+    pub(crate) fn get_aggregated(&self, key: &Option<Tuple>) -> AggregateResult<Value> {
+        match self.get_variance(key) {
+            Some(variance) => Ok(Value::Float(OrderedFloat::from(variance as f32))),
+            None => Ok(Value::Null),
+        }
     }
 }
 
+//The square root of the sample variance, accumulated the same way
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub(crate) struct AvgAggregate {
-    pub(crate) averages: HashMap<Option<Tuple>, OrderedFloat<f32>>,
-    pub(crate) counts: HashMap<Option<Tuple>, i64>,
+pub(crate) struct StdDevAggregate {
+    pub(crate) variance: VarianceAggregate,
 }
 
-impl AvgAggregate {
+impl StdDevAggregate {
     pub(crate) fn new() -> Self {
-        AvgAggregate {
-            averages: HashMap::new(),
-            counts: HashMap::new(),
+        StdDevAggregate {
+            variance: VarianceAggregate::new(),
         }
     }
 
-    pub(crate) fn add_record(&mut self, key: Option<Tuple>, value: Value) -> AggregateResult<()> {
-        let new_value: OrderedFloat<f32> = match value {
-            Value::Int(i) => OrderedFloat::from(i as f32),
-            Value::Float(f) => f,
-            _ => {
-                return Err(AggregateError::InvalidType);
-            }
-        };
-
-        if let (Some(&average), Some(&count)) = (self.averages.get(&key), self.counts.get(&key)) {
-            let new_count = count + 1;
-            let f32_average: f32 = average.into();
-            let f32_new_value: f32 = new_value.into();
-            let new_average: f32 = (f32_average * (count as f32) + f32_new_value) / (new_count as f32);
-            self.averages.insert(key.clone(), OrderedFloat::from(new_average));
-            self.counts.insert(key.clone(), new_count);
-            Ok(())
-        } else {
-            self.averages.insert(key.clone(), new_value);
-            self.counts.insert(key.clone(), 1);
+    pub(crate) fn with_nan_policy(mut self, nan_policy: NanPolicy) -> Self {
+        self.variance = self.variance.with_nan_policy(nan_policy);
+        self
+    }
 
-            Ok(())
-        }
+    pub(crate) fn add_record(&mut self, key: Option<Tuple>, value: Value) -> AggregateResult<()> {
+        self.variance.add_record(key, value)
     }
 
     pub(crate) fn get_aggregated(&self, key: &Option<Tuple>) -> AggregateResult<Value> {
-        if let Some(&average) = self.averages.get(key) {
-            Ok(Value::Float(average))
-        } else {
-            Err(AggregateError::KeyNotFound)
+        match self.variance.get_variance(key) {
+            Some(variance) => Ok(Value::Float(OrderedFloat::from(variance.sqrt() as f32))),
+            None => Ok(Value::Null),
         }
     }
 }
 
+//Shannon entropy in bits of the values of a group, -sum(p * log2(p)) over the frequency of each
+//distinct value. Every distinct value of every group is kept with its count, so the memory grows with
+//the number of distinct values like PercentileDisc rather than staying constant like Avg.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub(crate) struct SumAggregate {
-    pub(crate) sums: HashMap<Option<Tuple>, OrderedFloat<f32>>,
+pub(crate) struct EntropyAggregate {
+    pub(crate) frequencies: HashMap<Option<Tuple>, HashMap<Value, i64>>,
 }
 
-impl SumAggregate {
+impl EntropyAggregate {
     pub(crate) fn new() -> Self {
-        SumAggregate { sums: HashMap::new() }
+        EntropyAggregate {
+            frequencies: HashMap::new(),
+        }
     }
 
     pub(crate) fn add_record(&mut self, key: Option<Tuple>, value: Value) -> AggregateResult<()> {
-        let new_value: OrderedFloat<f32> = match value {
-            Value::Int(i) => OrderedFloat::from(i as f32),
-            Value::Float(f) => f,
-            _ => {
-                return Err(AggregateError::InvalidType);
+        *self.frequencies.entry(key).or_default().entry(value).or_insert(0) += 1;
+
+        Ok(())
+    }
+
+    pub(crate) fn get_aggregated(&self, key: &Option<Tuple>) -> AggregateResult<Value> {
+        match self.frequencies.get(key) {
+            Some(frequencies) if !frequencies.is_empty() => {
+                let total = frequencies.values().sum::<i64>() as f64;
+                let entropy = frequencies
+                    .values()
+                    .map(|&count| {
+                        let p = count as f64 / total;
+                        -p * p.log2()
+                    })
+                    .sum::<f64>();
+                //A single distinct value would otherwise come out as -0
+                Ok(Value::Float(OrderedFloat::from(entropy.abs() as f32)))
             }
-        };
+            _ => Ok(Value::Null),
+        }
+    }
+}
+
+//The distinct values of a group over its values, from above 0 up to 1 when every value is distinct, to
+//flag the columns too varied to group by. The nulls are skipped and a group of only nulls is null. Every
+//distinct value of every group is kept, so the memory grows with the cardinality being measured, where
+//approx_count_distinct stays constant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct DistinctRatioAggregate {
+    pub(crate) groups: HashMap<Option<Tuple>, (HashSet<Value>, i64)>,
+}
 
-        if let Some(&average) = self.sums.get(&key) {
-            let f32_average: f32 = average.into();
-            let f32_new_value: f32 = new_value.into();
-            let new_average: f32 = f32_average + f32_new_value;
-            self.sums.insert(key.clone(), OrderedFloat::from(new_average));
-            Ok(())
-        } else {
-            self.sums.insert(key.clone(), new_value);
+impl DistinctRatioAggregate {
+    pub(crate) fn new() -> Self {
+        DistinctRatioAggregate { groups: HashMap::new() }
+    }
 
-            Ok(())
+    pub(crate) fn add_record(&mut self, key: Option<Tuple>, value: Value) -> AggregateResult<()> {
+        if value == Value::Null {
+            return Ok(());
         }
+
+        let (distinct, total) = self.groups.entry(key).or_default();
+        distinct.insert(value);
+        *total += 1;
+
+        Ok(())
     }
 
     pub(crate) fn get_aggregated(&self, key: &Option<Tuple>) -> AggregateResult<Value> {
-        if let Some(&average) = self.sums.get(key) {
-            Ok(Value::Float(average))
-        } else {
-            Err(AggregateError::KeyNotFound)
+        match self.groups.get(key) {
+            Some((distinct, total)) if *total > 0 => {
+                let ratio = distinct.len() as f64 / *total as f64;
+                Ok(Value::Float(OrderedFloat::from(ratio as f32)))
+            }
+            _ => Ok(Value::Null),
         }
     }
 }
 
+//The seconds between the earliest and the latest timestamp of a group, null when the group has a
+//single timestamp since there is no span to measure
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub(crate) struct CountAggregate {
-    pub(crate) counts: HashMap<Option<Tuple>, i64>,
+pub(crate) struct GroupDurationAggregate {
+    pub(crate) spans: HashMap<
+        Option<Tuple>,
+        (
+            chrono::DateTime<chrono::offset::FixedOffset>,
+            chrono::DateTime<chrono::offset::FixedOffset>,
+            usize,
+        ),
+    >,
 }
 
-impl CountAggregate {
+impl GroupDurationAggregate {
     pub(crate) fn new() -> Self {
-        CountAggregate { counts: HashMap::new() }
+        GroupDurationAggregate { spans: HashMap::new() }
     }
 
     pub(crate) fn add_record(&mut self, key: Option<Tuple>, value: Value) -> AggregateResult<()> {
-        if let Value::Null = value {
-            //Null value doesn't contribute to the total count
-            return Ok(());
+        let timestamp = match value {
+            Value::DateTime(timestamp) => timestamp,
+            Value::Null => {
+                return Ok(());
+            }
+            _ => {
+                return Err(AggregateError::InvalidType);
+            }
         };
 
-        if let Some(&count) = self.counts.get(&key) {
-            let new_count = count + 1;
-            self.counts.insert(key.clone(), new_count);
-            Ok(())
-        } else {
-            self.counts.insert(key.clone(), 1);
-
-            Ok(())
+        let span = self.spans.entry(key).or_insert((timestamp, timestamp, 0));
+        if timestamp < span.0 {
+            span.0 = timestamp;
         }
-    }
-
-    pub(crate) fn add_row(&mut self, key: Option<Tuple>) -> AggregateResult<()> {
-        if let Some(&count) = self.counts.get(&key) {
-            let new_count = count + 1;
-            self.counts.insert(key.clone(), new_count);
-            Ok(())
-        } else {
-            self.counts.insert(key.clone(), 1);
-
-            Ok(())
+        if timestamp > span.1 {
+            span.1 = timestamp;
         }
+        span.2 += 1;
+
+        Ok(())
     }
 
     pub(crate) fn get_aggregated(&self, key: &Option<Tuple>) -> AggregateResult<Value> {
-        if let Some(&counts) = self.counts.get(key) {
-            Ok(Value::Int(counts as i32))
-        } else {
-            Err(AggregateError::KeyNotFound)
+        match self.spans.get(key) {
+            Some((first, last, count)) if *count > 1 => {
+                let duration = last.signed_duration_since(*first);
+                let seconds = match duration.num_microseconds() {
+                    Some(micros) => micros as f64 / 1_000_000.0,
+                    None => duration.num_milliseconds() as f64 / 1_000.0,
+                };
+                Ok(Value::Float(OrderedFloat::from(seconds as f32)))
+            }
+            _ => Ok(Value::Null),
         }
     }
 }
@@ -1133,19 +3785,41 @@ impl CountAggregate {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub(crate) struct MaxAggregate {
     pub(crate) maxs: HashMap<Option<Tuple>, Value>,
+    pub(crate) nan_policy: NanPolicy,
+    pub(crate) empty_groups: HashSet<Option<Tuple>>,
 }
 
 impl MaxAggregate {
     pub(crate) fn new() -> Self {
-        MaxAggregate { maxs: HashMap::new() }
+        MaxAggregate {
+            maxs: HashMap::new(),
+            nan_policy: NanPolicy::default(),
+            empty_groups: HashSet::new(),
+        }
+    }
+
+    pub(crate) fn with_nan_policy(mut self, nan_policy: NanPolicy) -> Self {
+        self.nan_policy = nan_policy;
+        self
+    }
+
+    fn skip(&mut self, key: Option<Tuple>) {
+        if !self.maxs.contains_key(&key) {
+            self.empty_groups.insert(key);
+        }
     }
 
     pub(crate) fn add_record(&mut self, key: Option<Tuple>, value: Value) -> AggregateResult<()> {
+        //A missing value, like the -1 processing time of a request the backend never answered
+        if value == Value::Null || !self.nan_policy.admit(&value)? {
+            self.skip(key);
+            return Ok(());
+        }
+
         if let Some(candidate) = self.maxs.get(&key) {
-            let less_than = match (candidate, &value) {
-                (Value::Int(i1), Value::Int(i2)) => *i1 < *i2,
-                (Value::Float(f1), Value::Float(f2)) => *f1 < *f2,
-                _ => {
+            let less_than = match compare_numbers(candidate, &value) {
+                Some(ordering) => ordering == std::cmp::Ordering::Less,
+                None => {
                     return Err(AggregateError::InvalidType);
                 }
             };
@@ -1164,6 +3838,8 @@ impl MaxAggregate {
     pub(crate) fn get_aggregated(&self, key: &Option<Tuple>) -> AggregateResult<Value> {
         if let Some(first) = self.maxs.get(key) {
             Ok(first.clone())
+        } else if self.empty_groups.contains(key) {
+            Ok(Value::Null)
         } else {
             Err(AggregateError::KeyNotFound)
         }
@@ -1173,19 +3849,41 @@ impl MaxAggregate {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub(crate) struct MinAggregate {
     pub(crate) mins: HashMap<Option<Tuple>, Value>,
+    pub(crate) nan_policy: NanPolicy,
+    pub(crate) empty_groups: HashSet<Option<Tuple>>,
 }
 
 impl MinAggregate {
     pub(crate) fn new() -> Self {
-        MinAggregate { mins: HashMap::new() }
+        MinAggregate {
+            mins: HashMap::new(),
+            nan_policy: NanPolicy::default(),
+            empty_groups: HashSet::new(),
+        }
+    }
+
+    pub(crate) fn with_nan_policy(mut self, nan_policy: NanPolicy) -> Self {
+        self.nan_policy = nan_policy;
+        self
+    }
+
+    fn skip(&mut self, key: Option<Tuple>) {
+        if !self.mins.contains_key(&key) {
+            self.empty_groups.insert(key);
+        }
     }
 
     pub(crate) fn add_record(&mut self, key: Option<Tuple>, value: Value) -> AggregateResult<()> {
+        //A missing value, like the -1 processing time of a request the backend never answered
+        if value == Value::Null || !self.nan_policy.admit(&value)? {
+            self.skip(key);
+            return Ok(());
+        }
+
         if let Some(candidate) = self.mins.get(&key) {
-            let greater_than = match (candidate, &value) {
-                (Value::Int(i1), Value::Int(i2)) => *i1 > *i2,
-                (Value::Float(f1), Value::Float(f2)) => *f1 > *f2,
-                _ => {
+            let greater_than = match compare_numbers(candidate, &value) {
+                Some(ordering) => ordering == std::cmp::Ordering::Greater,
+                None => {
                     return Err(AggregateError::InvalidType);
                 }
             };
@@ -1204,6 +3902,8 @@ impl MinAggregate {
     pub(crate) fn get_aggregated(&self, key: &Option<Tuple>) -> AggregateResult<Value> {
         if let Some(first) = self.mins.get(key) {
             Ok(first.clone())
+        } else if self.empty_groups.contains(key) {
+            Ok(Value::Null)
         } else {
             Err(AggregateError::KeyNotFound)
         }
@@ -1263,9 +3963,62 @@ impl LastAggregate {
     }
 }
 
+//FNV-1a keyed by the seed of the run, the same seed hashes the values the same way on every machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SeededState {
+    seed: u64,
+}
+
+impl BuildHasher for SeededState {
+    type Hasher = SeededHasher;
+
+    fn build_hasher(&self) -> SeededHasher {
+        let mut hasher = SeededHasher { hash: FNV_OFFSET_BASIS };
+        hasher.write_u64(self.seed);
+        hasher
+    }
+}
+
+//The integers are written little endian and a usize as 64 bits, so the hash doesn't depend on the platform
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SeededHasher {
+    hash: u64,
+}
+
+impl Hasher for SeededHasher {
+    fn finish(&self) -> u64 {
+        self.hash
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.hash = fnv1a_64_from(self.hash, bytes);
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct ApproxCountDistinctAggregate {
-    pub(crate) counts: HashMap<Option<Tuple>, HyperLogLog<Value>>,
+    pub(crate) counts: HashMap<Option<Tuple>, HyperLogLog<Value, SeededState>>,
+    hash_state: SeededState,
 }
 
 impl PartialEq for ApproxCountDistinctAggregate {
@@ -1278,8 +4031,11 @@ impl PartialEq for ApproxCountDistinctAggregate {
 impl Eq for ApproxCountDistinctAggregate {}
 
 impl ApproxCountDistinctAggregate {
-    pub(crate) fn new() -> Self {
-        ApproxCountDistinctAggregate { counts: HashMap::new() }
+    pub(crate) fn new(seed: u64) -> Self {
+        ApproxCountDistinctAggregate {
+            counts: HashMap::new(),
+            hash_state: SeededState { seed },
+        }
     }
 
     pub(crate) fn add_record(&mut self, key: Option<Tuple>, value: Value) -> AggregateResult<()> {
@@ -1292,7 +4048,8 @@ impl ApproxCountDistinctAggregate {
             hll.add(&value);
             Ok(())
         } else {
-            self.counts.insert(key.clone(), HyperLogLog::new(8));
+            self.counts
+                .insert(key.clone(), HyperLogLog::with_hash(8, self.hash_state));
 
             Ok(())
         }
@@ -1310,6 +4067,7 @@ impl ApproxCountDistinctAggregate {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use chrono::TimeZone;
 
     #[test]
     fn test_avg_aggregate_with_one_element() {
@@ -1350,69 +4108,696 @@ mod tests {
     }
 
     #[test]
-    fn test_first_aggregate() {
-        let mut iter = Aggregate::First(FirstAggregate::new(), Named::Star);
-        let tuple = Some(vec![Value::String("key".to_string())]);
-        for i in 0..13 {
-            let value = Value::Int(i);
-            let _ = iter.add_record(tuple.clone(), value);
+    fn test_conditional_ratio_aggregate() {
+        let mut agg = ConditionalRatioAggregate::new();
+        let errors = Some(vec![Value::String("errors".to_string())]);
+        for i in 0..8 {
+            agg.add_row(errors.clone(), i < 2, true).unwrap();
+        }
+        assert_eq!(Ok(Value::Float(OrderedFloat::from(0.25))), agg.get_aggregated(&errors));
+
+        let unmatched = Some(vec![Value::String("unmatched".to_string())]);
+        agg.add_row(unmatched.clone(), false, false).unwrap();
+        assert_eq!(Ok(Value::Null), agg.get_aggregated(&unmatched));
+
+        let missing = Some(vec![Value::String("missing".to_string())]);
+        assert_eq!(Err(AggregateError::KeyNotFound), agg.get_aggregated(&missing));
+    }
+
+    #[test]
+    fn test_percentile_cont_aggregate() {
+        let percentile_cont = |percentile: f32, ordering: Ordering| {
+            let mut iter = Aggregate::PercentileCont(
+                PercentileContAggregate::new(OrderedFloat::from(percentile), ordering),
+                "value".to_string(),
+            );
+            let tuple = Some(vec![Value::String("key".to_string())]);
+            for value in [
+                Value::Int(3),
+                Value::Float(OrderedFloat::from(1.0)),
+                Value::Null,
+                Value::Int(4),
+                Value::Int(2),
+            ]
+            .iter()
+            {
+                iter.add_record(tuple.clone(), value.clone()).unwrap();
+            }
+
+            match iter.get_aggregated(&tuple) {
+                Ok(Value::Float(f)) => f.into_inner(),
+                other => panic!("unexpected {:?}", other),
+            }
+        };
+
+        //The median of 1, 2, 3, 4 is halfway between 2 and 3, and the 0.9 rank is 2.7, 70% of the way
+        //from 3 to 4
+        assert!((percentile_cont(0.5, Ordering::Asc) - 2.5).abs() < 1e-5);
+        assert!((percentile_cont(0.9, Ordering::Asc) - 3.7).abs() < 1e-5);
+        assert!((percentile_cont(0.9, Ordering::Desc) - 1.3).abs() < 1e-5);
+        assert!((percentile_cont(0.0, Ordering::Asc) - 1.0).abs() < 1e-5);
+        assert!((percentile_cont(1.0, Ordering::Asc) - 4.0).abs() < 1e-5);
+
+        let mut agg = PercentileContAggregate::new(OrderedFloat::from(0.5), Ordering::Asc);
+        let missing = Some(vec![Value::String("missing".to_string())]);
+        assert_eq!(Ok(Value::Null), agg.get_aggregated(&missing));
+        assert_eq!(
+            Err(AggregateError::NonNumericValue("Boolean(true)".to_string())),
+            agg.add_record(None, Value::Boolean(true))
+        );
+    }
+
+    #[test]
+    fn test_variance_and_std_dev_aggregates() {
+        let tuple = Some(vec![Value::String("key".to_string())]);
+        //The mean of 2, 4, 4, 4, 5, 5, 7, 9 is 5 and the squared differences sum to 32, so the sample
+        //variance is 32 / 7, the null is skipped
+        let values = vec![
+            Value::Int(2),
+            Value::Int(4),
+            Value::Float(OrderedFloat::from(4.0)),
+            Value::Int(4),
+            Value::Null,
+            Value::Int(5),
+            Value::Int(5),
+            Value::Float(OrderedFloat::from(7.0)),
+            Value::Int(9),
+        ];
+
+        let mut variance = Aggregate::Variance(VarianceAggregate::new(), Named::Star);
+        let mut std_dev = Aggregate::StdDev(StdDevAggregate::new(), Named::Star);
+        for value in values.into_iter() {
+            variance.add_record(tuple.clone(), value.clone()).unwrap();
+            std_dev.add_record(tuple.clone(), value).unwrap();
+        }
+
+        match variance.get_aggregated(&tuple) {
+            Ok(Value::Float(f)) => assert!((f.into_inner() - 32.0 / 7.0).abs() < 1e-5),
+            other => panic!("unexpected {:?}", other),
+        }
+        match std_dev.get_aggregated(&tuple) {
+            Ok(Value::Float(f)) => assert!((f.into_inner() - (32.0f32 / 7.0).sqrt()).abs() < 1e-5),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let single = Some(vec![Value::String("single".to_string())]);
+        variance.add_record(single.clone(), Value::Int(3)).unwrap();
+        std_dev.add_record(single.clone(), Value::Int(3)).unwrap();
+        assert_eq!(Ok(Value::Null), variance.get_aggregated(&single));
+        assert_eq!(Ok(Value::Null), std_dev.get_aggregated(&single));
+
+        assert_eq!(
+            Err(AggregateError::NonNumericValue("String(\"abc\")".to_string())),
+            std_dev.add_record(tuple, Value::String("abc".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_geo_mean_aggregate() {
+        let mut iter = Aggregate::GeoMean(GeoMeanAggregate::new(), Named::Star);
+        let tuple = Some(vec![Value::String("key".to_string())]);
+        //(2 * 8 * 4) ^ (1 / 3) = 4, the non-positive values are skipped
+        for value in [
+            Value::Int(2),
+            Value::Float(OrderedFloat::from(8.0)),
+            Value::Int(0),
+            Value::Int(4),
+            Value::Float(OrderedFloat::from(-1.5)),
+        ]
+        .iter()
+        {
+            iter.add_record(tuple.clone(), value.clone()).unwrap();
+        }
+
+        match iter.get_aggregated(&tuple) {
+            Ok(Value::Float(geo_mean)) => assert!((geo_mean.into_inner() - 4.0).abs() < 1e-5),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let skipped = Some(vec![Value::String("skipped".to_string())]);
+        iter.add_record(skipped.clone(), Value::Int(0)).unwrap();
+        assert_eq!(Ok(Value::Null), iter.get_aggregated(&skipped));
+    }
+
+    #[test]
+    fn test_entropy_aggregate() {
+        let mut iter = Aggregate::Entropy(EntropyAggregate::new(), Named::Star);
+        let uniform = Some(vec![Value::String("uniform".to_string())]);
+        for value in ["/a", "/b", "/a", "/b"].iter() {
+            iter.add_record(uniform.clone(), Value::String(value.to_string()))
+                .unwrap();
+        }
+        assert_eq!(Ok(Value::Float(OrderedFloat::from(1.0))), iter.get_aggregated(&uniform));
+
+        let single = Some(vec![Value::String("single".to_string())]);
+        for _ in 0..3 {
+            iter.add_record(single.clone(), Value::String("/a".to_string()))
+                .unwrap();
+        }
+        assert_eq!(Ok(Value::Float(OrderedFloat::from(0.0))), iter.get_aggregated(&single));
+
+        let empty = Some(vec![Value::String("empty".to_string())]);
+        assert_eq!(Ok(Value::Null), iter.get_aggregated(&empty));
+    }
+
+    #[test]
+    fn test_distinct_ratio_aggregate() {
+        let mut iter = Aggregate::DistinctRatio(DistinctRatioAggregate::new(), Named::Star);
+        //Two distinct values of eight, the null is skipped
+        let paths = Some(vec![Value::String("paths".to_string())]);
+        for value in ["/a", "/b", "/a", "/a", "/b", "/a", "/a", "/b"].iter() {
+            iter.add_record(paths.clone(), Value::String(value.to_string()))
+                .unwrap();
+        }
+        iter.add_record(paths.clone(), Value::Null).unwrap();
+        assert_eq!(Ok(Value::Float(OrderedFloat::from(0.25))), iter.get_aggregated(&paths));
+
+        let ids = Some(vec![Value::String("ids".to_string())]);
+        for id in 0..5 {
+            iter.add_record(ids.clone(), Value::Int(id)).unwrap();
+        }
+        assert_eq!(Ok(Value::Float(OrderedFloat::from(1.0))), iter.get_aggregated(&ids));
+
+        let nulls = Some(vec![Value::String("nulls".to_string())]);
+        iter.add_record(nulls.clone(), Value::Null).unwrap();
+        assert_eq!(Ok(Value::Null), iter.get_aggregated(&nulls));
+
+        let empty = Some(vec![Value::String("empty".to_string())]);
+        assert_eq!(Ok(Value::Null), iter.get_aggregated(&empty));
+    }
+
+    #[test]
+    fn test_group_duration_aggregate() {
+        let mut agg = GroupDurationAggregate::new();
+        let key = Some(vec![Value::String("10.0.0.215:80".to_string())]);
+        for timestamp in ["2019-06-07T18:45:33Z", "2019-06-07T18:45:03Z", "2019-06-07T18:46:03Z"].iter() {
+            let timestamp = chrono::DateTime::parse_from_rfc3339(timestamp).unwrap();
+            agg.add_record(key.clone(), Value::DateTime(timestamp)).unwrap();
+        }
+        assert_eq!(Ok(Value::Float(OrderedFloat::from(60.0))), agg.get_aggregated(&key));
+
+        let single = Some(vec![Value::String("10.0.0.216:80".to_string())]);
+        let timestamp = chrono::DateTime::parse_from_rfc3339("2019-06-07T18:45:33Z").unwrap();
+        agg.add_record(single.clone(), Value::DateTime(timestamp)).unwrap();
+        assert_eq!(Ok(Value::Null), agg.get_aggregated(&single));
+
+        assert_eq!(Err(AggregateError::InvalidType), agg.add_record(key, Value::Int(1)));
+    }
+
+    #[test]
+    fn test_first_aggregate() {
+        let mut iter = Aggregate::First(FirstAggregate::new(), Named::Star);
+        let tuple = Some(vec![Value::String("key".to_string())]);
+        for i in 0..13 {
+            let value = Value::Int(i);
+            let _ = iter.add_record(tuple.clone(), value);
+        }
+
+        let aggregate = iter.get_aggregated(&tuple);
+        assert_eq!(Ok(Value::Int(0)), aggregate);
+    }
+
+    #[test]
+    fn test_last_aggregate() {
+        let mut iter = Aggregate::Last(LastAggregate::new(), Named::Star);
+        let tuple = Some(vec![Value::String("key".to_string())]);
+        for i in 0..13 {
+            let value = Value::Int(i);
+            let _ = iter.add_record(tuple.clone(), value);
+        }
+
+        let aggregate = iter.get_aggregated(&tuple);
+        assert_eq!(Ok(Value::Int(12)), aggregate);
+    }
+
+    #[test]
+    fn test_sum_aggregate_with_many_elements() {
+        let mut iter = Aggregate::Sum(SumAggregate::new(), Named::Star);
+        let tuple = Some(vec![Value::String("key".to_string())]);
+
+        for i in 1..=10 {
+            let value = Value::Float(OrderedFloat::from(i as f32));
+            let _ = iter.add_record(tuple.clone(), value);
+        }
+
+        let aggregate = iter.get_aggregated(&tuple);
+        assert_eq!(Ok(Value::Float(OrderedFloat::from(55.0))), aggregate);
+    }
+
+    #[test]
+    fn test_max_aggregate() {
+        let mut iter = Aggregate::Max(MaxAggregate::new(), Named::Star);
+        let tuple = Some(vec![Value::String("key".to_string())]);
+        for i in 0..13 {
+            let value = Value::Int(i);
+            let _ = iter.add_record(tuple.clone(), value);
+        }
+
+        let aggregate = iter.get_aggregated(&tuple);
+        assert_eq!(Ok(Value::Int(12)), aggregate);
+    }
+
+    #[test]
+    fn test_min_aggregate() {
+        let mut iter = Aggregate::Min(MinAggregate::new(), Named::Star);
+        let tuple = Some(vec![Value::String("key".to_string())]);
+        for i in 0..13 {
+            let value = Value::Int(i);
+            let _ = iter.add_record(tuple.clone(), value);
+        }
+
+        let aggregate = iter.get_aggregated(&tuple);
+        assert_eq!(Ok(Value::Int(0)), aggregate);
+    }
+
+    #[test]
+    fn test_order_by_mixed_numbers() {
+        let values = vec![
+            Value::Float(OrderedFloat::from(2.5)),
+            Value::Int(10),
+            Value::Int(-1),
+            Value::Float(OrderedFloat::from(9.75)),
+            Value::Int(3),
+        ];
+        let records: Vec<Record> = values
+            .into_iter()
+            .map(|value| Record::new(vec!["n".to_string()], vec![value]))
+            .collect();
+
+        let node = Node::OrderBy {
+            column_names: vec!["n".to_string()],
+            orderings: vec![Ordering::Asc],
+            source: Box::new(Node::TeeInput),
+            memory_budget: MemoryBudget::default(),
+        };
+        let mut stream = node
+            .get_with_input(
+                common::types::empty_variables(),
+                Some(Box::new(InMemoryStream::new(records.into_iter().collect()))),
+            )
+            .unwrap();
+        let mut result = Vec::new();
+        while let Some(record) = stream.next().unwrap() {
+            result.push(record.get(&["n".to_string()]).remove(0));
+        }
+
+        let expected = vec![
+            Value::Int(-1),
+            Value::Float(OrderedFloat::from(2.5)),
+            Value::Int(3),
+            Value::Float(OrderedFloat::from(9.75)),
+            Value::Int(10),
+        ];
+        assert_eq!(expected, result);
+    }
+
+    #[test]
+    fn test_relation_mixed_numbers() {
+        let mut variables = common::types::empty_variables();
+        variables.insert("int".to_string(), Value::Int(3));
+        variables.insert("float".to_string(), Value::Float(OrderedFloat::from(2.5)));
+        variables.insert("whole".to_string(), Value::Float(OrderedFloat::from(3.0)));
+        let int = Expression::Variable("int".to_string());
+        let float = Expression::Variable("float".to_string());
+
+        assert_eq!(
+            Ok(Some(true)),
+            Relation::MoreThan.truth(variables.clone(), &int, &float)
+        );
+        assert_eq!(
+            Ok(Some(false)),
+            Relation::LessEqual.truth(variables.clone(), &int, &float)
+        );
+
+        let whole = Expression::Variable("whole".to_string());
+        assert_eq!(Ok(Some(true)), Relation::Equal.truth(variables.clone(), &int, &whole));
+        assert_eq!(Ok(Some(false)), Relation::NotEqual.truth(variables, &whole, &int));
+    }
+
+    #[test]
+    fn test_relation_booleans() {
+        let mut variables = common::types::empty_variables();
+        variables.insert("yes".to_string(), Value::Boolean(true));
+        variables.insert("no".to_string(), Value::Boolean(false));
+        let yes = Expression::Variable("yes".to_string());
+        let no = Expression::Variable("no".to_string());
+        let constant = Expression::Logic(Box::new(Formula::Constant(true)));
+
+        assert_eq!(
+            Ok(Some(true)),
+            Relation::Equal.truth(variables.clone(), &yes, &constant)
+        );
+        assert_eq!(Ok(Some(false)), Relation::Equal.truth(variables.clone(), &yes, &no));
+        assert_eq!(
+            Ok(Some(true)),
+            Relation::NotEqual.truth(variables.clone(), &no, &constant)
+        );
+        assert_eq!(
+            Err(ExpressionError::TypeMismatch),
+            Relation::LessThan.truth(variables, &no, &yes)
+        );
+    }
+
+    #[test]
+    fn test_like_match() {
+        assert!(like_match("GET /index.html", "GET %"));
+        assert!(!like_match("POST /upload", "GET %"));
+        assert!(like_match("abc", "a_c"));
+        assert!(!like_match("abbc", "a_c"));
+        assert!(like_match("abc", "%"));
+        assert!(like_match("", "%"));
+        assert!(!like_match("", "_"));
+        assert!(like_match("mississippi", "%iss%ppi"));
+        assert!(!like_match("mississippi", "%iss%pp"));
+        assert!(like_match("100%", "100\\%"));
+        assert!(!like_match("1000", "100\\%"));
+        assert!(like_match("a_b", "a\\_b"));
+        assert!(!like_match("axb", "a\\_b"));
+    }
+
+    #[test]
+    fn test_relation_with_null() {
+        let mut variables = common::types::empty_variables();
+        variables.insert("null".to_string(), Value::Null);
+        variables.insert("one".to_string(), Value::Int(1));
+        let null = Expression::Variable("null".to_string());
+        let one = Expression::Variable("one".to_string());
+
+        assert_eq!(Ok(None), Relation::Equal.truth(variables.clone(), &null, &null));
+        assert_eq!(Ok(None), Relation::Equal.truth(variables.clone(), &null, &one));
+        assert_eq!(Ok(None), Relation::NotEqual.truth(variables.clone(), &one, &null));
+        assert_eq!(Ok(None), Relation::LessThan.truth(variables.clone(), &null, &one));
+        assert_eq!(Ok(Some(true)), Relation::IsNull.truth(variables.clone(), &null, &null));
+        assert_eq!(Ok(Some(false)), Relation::IsNull.truth(variables.clone(), &one, &null));
+        assert_eq!(
+            Ok(Some(true)),
+            Relation::IsNotNull.truth(variables.clone(), &one, &null)
+        );
+
+        let unknown = || {
+            Box::new(Formula::Predicate(
+                Relation::Equal,
+                Box::new(null.clone()),
+                Box::new(one.clone()),
+            ))
+        };
+        assert_eq!(Ok(None), Formula::Not(unknown()).truth(variables.clone()));
+        assert_eq!(
+            Ok(Some(false)),
+            Formula::And(unknown(), Box::new(Formula::Constant(false))).truth(variables.clone())
+        );
+        assert_eq!(
+            Ok(Some(true)),
+            Formula::Or(unknown(), Box::new(Formula::Constant(true))).truth(variables.clone())
+        );
+        assert_eq!(Ok(false), Formula::Not(unknown()).evaluate(variables.clone()));
+        assert_eq!(
+            Ok(Value::Null),
+            Expression::Logic(unknown()).expression_value(variables)
+        );
+    }
+
+    #[test]
+    fn test_coalesce_expression() {
+        let mut variables = common::types::empty_variables();
+        variables.insert("user_agent".to_string(), Value::Null);
+        variables.insert("referer".to_string(), Value::Null);
+        variables.insert("host".to_string(), Value::String("example.com".to_string()));
+
+        let argument = |name: &str| Named::Expression(Expression::Variable(name.to_string()), None);
+        let coalesce = |arguments: Vec<Named>| Expression::Function("coalesce".to_string(), arguments);
+
+        let expr = coalesce(vec![argument("user_agent"), argument("referer"), argument("host")]);
+        assert_eq!(
+            Ok(Value::String("example.com".to_string())),
+            expr.expression_value(variables.clone())
+        );
+
+        let expr = coalesce(vec![argument("user_agent"), argument("referer")]);
+        assert_eq!(Ok(Value::Null), expr.expression_value(variables.clone()));
+
+        //A missing column past the first value is never looked up
+        let expr = coalesce(vec![argument("host"), argument("missing")]);
+        assert_eq!(
+            Ok(Value::String("example.com".to_string())),
+            expr.expression_value(variables.clone())
+        );
+        let expr = coalesce(vec![argument("user_agent"), argument("missing")]);
+        assert_eq!(
+            Err(ExpressionError::KeyNotFound),
+            expr.expression_value(variables.clone())
+        );
+
+        assert_eq!(
+            Err(ExpressionError::InvalidArguments),
+            coalesce(vec![]).expression_value(variables)
+        );
+    }
+
+    #[test]
+    fn test_in_list_formula() {
+        let mut variables = common::types::empty_variables();
+        variables.insert("status".to_string(), Value::Int(403));
+        variables.insert("method".to_string(), Value::String("POST".to_string()));
+        variables.insert("null".to_string(), Value::Null);
+        for (name, value) in [
+            ("c_400", Value::Int(400)),
+            ("c_401", Value::Int(401)),
+            ("c_403", Value::Float(OrderedFloat::from(403.0))),
+            ("c_get", Value::String("GET".to_string())),
+            ("c_post", Value::String("POST".to_string())),
+        ]
+        .iter()
+        {
+            variables.insert(name.to_string(), value.clone());
         }
+        let var = |name: &str| Expression::Variable(name.to_string());
+        let in_list = |name: &str, list: &[&str]| {
+            Formula::InList(Box::new(var(name)), list.iter().map(|item| var(item)).collect())
+        };
 
-        let aggregate = iter.get_aggregated(&tuple);
-        assert_eq!(Ok(Value::Int(0)), aggregate);
+        assert_eq!(
+            Ok(Some(true)),
+            in_list("status", &["c_400", "c_401", "c_403"]).truth(variables.clone())
+        );
+        assert_eq!(
+            Ok(Some(false)),
+            in_list("status", &["c_400", "c_401"]).truth(variables.clone())
+        );
+        assert_eq!(
+            Ok(Some(true)),
+            in_list("method", &["c_get", "c_post"]).truth(variables.clone())
+        );
+        assert_eq!(Ok(Some(false)), in_list("method", &["c_get"]).truth(variables.clone()));
+        assert_eq!(Ok(Some(false)), in_list("status", &[]).truth(variables.clone()));
+        assert_eq!(Ok(Some(false)), in_list("null", &[]).truth(variables.clone()));
+        assert_eq!(Ok(None), in_list("null", &["c_400"]).truth(variables.clone()));
+        assert_eq!(Ok(None), in_list("status", &["c_400", "null"]).truth(variables.clone()));
+        assert_eq!(
+            Ok(Some(true)),
+            in_list("status", &["null", "c_403"]).truth(variables.clone())
+        );
+
+        //The items after the first equal one are not evaluated
+        assert_eq!(
+            Ok(Some(true)),
+            in_list("method", &["c_post", "missing"]).truth(variables.clone())
+        );
+        assert!(in_list("method", &["c_get", "missing"]).truth(variables).is_err());
     }
 
     #[test]
-    fn test_last_aggregate() {
-        let mut iter = Aggregate::Last(LastAggregate::new(), Named::Star);
+    fn test_max_aggregate_mixed_numbers() {
+        let mut iter = Aggregate::Max(MaxAggregate::new(), Named::Star);
         let tuple = Some(vec![Value::String("key".to_string())]);
-        for i in 0..13 {
-            let value = Value::Int(i);
-            let _ = iter.add_record(tuple.clone(), value);
-        }
+        iter.add_record(tuple.clone(), Value::Int(3)).unwrap();
+        iter.add_record(tuple.clone(), Value::Float(OrderedFloat::from(3.5)))
+            .unwrap();
+        iter.add_record(tuple.clone(), Value::Int(2)).unwrap();
 
-        let aggregate = iter.get_aggregated(&tuple);
-        assert_eq!(Ok(Value::Int(12)), aggregate);
+        assert_eq!(Ok(Value::Float(OrderedFloat::from(3.5))), iter.get_aggregated(&tuple));
     }
 
     #[test]
-    fn test_sum_aggregate_with_many_elements() {
-        let mut iter = Aggregate::Sum(SumAggregate::new(), Named::Star);
+    fn test_partial_state_round_trip() {
         let tuple = Some(vec![Value::String("key".to_string())]);
+        let aggregates = vec![
+            Aggregate::Sum(SumAggregate::new(), Named::Star),
+            Aggregate::Count(CountAggregate::new(), Named::Star),
+            Aggregate::Avg(AvgAggregate::new(), Named::Star),
+        ];
+        let expected = vec![
+            Value::Float(OrderedFloat::from(55.0)),
+            Value::Int(10),
+            Value::Float(OrderedFloat::from(5.5)),
+        ];
+
+        for (aggregate, expected_value) in aggregates.into_iter().zip(expected) {
+            let mut first_shard = aggregate.clone();
+            let mut second_shard = aggregate.clone();
+            for i in 1..=4 {
+                first_shard.add_record(tuple.clone(), Value::Int(i)).unwrap();
+            }
+            for i in 5..=10 {
+                second_shard.add_record(tuple.clone(), Value::Int(i)).unwrap();
+            }
 
-        for i in 1..=10 {
-            let value = Value::Float(OrderedFloat::from(i as f32));
-            let _ = iter.add_record(tuple.clone(), value);
+            let mut merged = aggregate.clone();
+            for shard in [first_shard, second_shard].iter() {
+                let serialized = shard.get_partial(&tuple).unwrap().to_string();
+                let state = PartialState::from_str(&serialized).unwrap();
+                merged.merge_partial(tuple.clone(), state).unwrap();
+            }
+
+            assert_eq!(Ok(expected_value), merged.get_aggregated(&tuple));
         }
 
-        let aggregate = iter.get_aggregated(&tuple);
-        assert_eq!(Ok(Value::Float(OrderedFloat::from(55.0))), aggregate);
+        //2^24 + 1 has no f32, so the merged state would drift if it were rounded on every merge
+        let mut sum = Aggregate::Sum(SumAggregate::new(), Named::Star);
+        for serialized in ["sum:16777217", "sum:1"].iter() {
+            let state = PartialState::from_str(serialized).unwrap();
+            sum.merge_partial(tuple.clone(), state).unwrap();
+        }
+        assert_eq!(
+            Ok(PartialState::Sum(OrderedFloat::from(16_777_218.0))),
+            sum.get_partial(&tuple)
+        );
+
+        assert_eq!(Err(AggregateError::InvalidType), PartialState::from_str("sum:abc"));
+        let mut sum = Aggregate::Sum(SumAggregate::new(), Named::Star);
+        assert_eq!(
+            Err(AggregateError::InvalidType),
+            sum.merge_partial(tuple.clone(), PartialState::Count(1))
+        );
+        let max = Aggregate::Max(MaxAggregate::new(), Named::Star);
+        assert_eq!(Err(AggregateError::PartialStateNotSupported), max.get_partial(&tuple));
     }
 
     #[test]
-    fn test_max_aggregate() {
-        let mut iter = Aggregate::Max(MaxAggregate::new(), Named::Star);
+    fn test_nan_policy() {
         let tuple = Some(vec![Value::String("key".to_string())]);
-        for i in 0..13 {
-            let value = Value::Int(i);
-            let _ = iter.add_record(tuple.clone(), value);
+        let values = [
+            Value::Float(OrderedFloat::from(1.0)),
+            Value::Float(OrderedFloat::from(f32::NAN)),
+            Value::Float(OrderedFloat::from(3.0)),
+        ];
+
+        let mut sum = SumAggregate::new();
+        let mut avg = AvgAggregate::new();
+        let mut max = MaxAggregate::new();
+        let mut min = MinAggregate::new();
+        for value in values.iter() {
+            sum.add_record(tuple.clone(), value.clone()).unwrap();
+            avg.add_record(tuple.clone(), value.clone()).unwrap();
+            max.add_record(tuple.clone(), value.clone()).unwrap();
+            min.add_record(tuple.clone(), value.clone()).unwrap();
+        }
+        assert_eq!(Ok(Value::Float(OrderedFloat::from(4.0))), sum.get_aggregated(&tuple));
+        assert_eq!(Ok(Value::Float(OrderedFloat::from(2.0))), avg.get_aggregated(&tuple));
+        assert_eq!(Ok(Value::Float(OrderedFloat::from(3.0))), max.get_aggregated(&tuple));
+        assert_eq!(Ok(Value::Float(OrderedFloat::from(1.0))), min.get_aggregated(&tuple));
+
+        let mut sum = SumAggregate::new().with_nan_policy(NanPolicy::Propagate);
+        let mut max = MaxAggregate::new().with_nan_policy(NanPolicy::Propagate);
+        let mut min = MinAggregate::new().with_nan_policy(NanPolicy::Propagate);
+        for value in values.iter() {
+            sum.add_record(tuple.clone(), value.clone()).unwrap();
+            max.add_record(tuple.clone(), value.clone()).unwrap();
+            min.add_record(tuple.clone(), value.clone()).unwrap();
+        }
+        match sum.get_aggregated(&tuple) {
+            Ok(Value::Float(f)) => assert!(f.is_nan()),
+            other => panic!("expected NaN, got {:?}", other),
         }
+        match max.get_aggregated(&tuple) {
+            Ok(Value::Float(f)) => assert!(f.is_nan()),
+            other => panic!("expected NaN, got {:?}", other),
+        }
+        assert_eq!(Ok(Value::Float(OrderedFloat::from(1.0))), min.get_aggregated(&tuple));
 
-        let aggregate = iter.get_aggregated(&tuple);
-        assert_eq!(Ok(Value::Int(12)), aggregate);
+        let mut avg = AvgAggregate::new().with_nan_policy(NanPolicy::Error);
+        avg.add_record(tuple.clone(), values[0].clone()).unwrap();
+        assert_eq!(
+            Err(AggregateError::NonFiniteValue("NaN".to_string())),
+            avg.add_record(tuple.clone(), values[1].clone())
+        );
+
+        let mut sum = SumAggregate::new();
+        sum.add_record(tuple.clone(), Value::Float(OrderedFloat::from(f32::INFINITY)))
+            .unwrap();
+        assert_eq!(Ok(Value::Null), sum.get_aggregated(&tuple));
+        let unknown = Some(vec![Value::String("unknown".to_string())]);
+        assert_eq!(Err(AggregateError::KeyNotFound), sum.get_aggregated(&unknown));
+        assert_eq!(Err(AggregateError::KeyNotFound), avg.get_aggregated(&unknown));
+        assert_eq!(Err(AggregateError::KeyNotFound), max.get_aggregated(&unknown));
+        assert_eq!(Err(AggregateError::KeyNotFound), min.get_aggregated(&unknown));
     }
 
     #[test]
-    fn test_min_aggregate() {
-        let mut iter = Aggregate::Min(MinAggregate::new(), Named::Star);
+    fn test_aggregates_skip_null() {
         let tuple = Some(vec![Value::String("key".to_string())]);
-        for i in 0..13 {
-            let value = Value::Int(i);
-            let _ = iter.add_record(tuple.clone(), value);
+        let values = [
+            Value::Float(OrderedFloat::from(0.5)),
+            Value::Null,
+            Value::Float(OrderedFloat::from(2.0)),
+        ];
+
+        let mut max = MaxAggregate::new();
+        let mut min = MinAggregate::new();
+        let mut geo_mean = GeoMeanAggregate::new();
+        for value in values.iter() {
+            max.add_record(tuple.clone(), value.clone()).unwrap();
+            min.add_record(tuple.clone(), value.clone()).unwrap();
+            geo_mean.add_record(tuple.clone(), value.clone()).unwrap();
         }
+        assert_eq!(Ok(Value::Float(OrderedFloat::from(2.0))), max.get_aggregated(&tuple));
+        assert_eq!(Ok(Value::Float(OrderedFloat::from(0.5))), min.get_aggregated(&tuple));
+        assert_eq!(
+            Ok(Value::Float(OrderedFloat::from(1.0))),
+            geo_mean.get_aggregated(&tuple)
+        );
 
-        let aggregate = iter.get_aggregated(&tuple);
-        assert_eq!(Ok(Value::Int(0)), aggregate);
+        //A group of nulls alone is null
+        let mut max = MaxAggregate::new();
+        max.add_record(tuple.clone(), Value::Null).unwrap();
+        assert_eq!(Ok(Value::Null), max.get_aggregated(&tuple));
+    }
+
+    #[test]
+    fn test_node_to_json() {
+        let formula = Formula::Predicate(
+            Relation::Equal,
+            Box::new(Expression::Variable("host".to_string())),
+            Box::new(Expression::Variable("const_000000000".to_string())),
+        );
+        let map = Node::Map(
+            vec![Named::Expression(
+                Expression::Variable("host".to_string()),
+                Some("host".to_string()),
+            )],
+            Box::new(Node::DataSource(
+                DataSource::Stdin,
+                ReaderBuilder::new("elb".to_string()),
+            )),
+        );
+        let filter = Node::Filter(Box::new(map), Box::new(formula));
+
+        let ans = filter.to_json();
+        assert_eq!("Filter", ans["operator"]);
+        assert_eq!("Predicate", ans["formula"]["formula"]);
+        assert_eq!("Equal", ans["formula"]["relation"]);
+        assert_eq!("host", ans["formula"]["left"]["name"]);
+
+        let map_json = &ans["children"][0];
+        assert_eq!("Map", map_json["operator"]);
+        assert_eq!("Variable", map_json["expressions"][0]["expression"]);
+        assert_eq!("host", map_json["expressions"][0]["alias"]);
+
+        let data_source_json = &map_json["children"][0];
+        assert_eq!("DataSource", data_source_json["operator"]);
+        assert_eq!("elb", data_source_json["table"]);
+        assert_eq!("stdin", data_source_json["source"]);
+        assert!(data_source_json["children"].is_null());
     }
 
     #[test]
@@ -1465,11 +4850,101 @@ mod tests {
 
         let v = evaluate("Divide", &vec![Value::Int(2), Value::Int(2)]).unwrap();
         assert_eq!(v, Value::Int(1));
+        let v = evaluate("Times", &[Value::Int(3), Value::Float(OrderedFloat::from(100.0))]).unwrap();
+        assert_eq!(v, Value::Float(OrderedFloat::from(300.0)));
+        let v = evaluate("Divide", &[Value::Float(OrderedFloat::from(300.0)), Value::Int(4)]).unwrap();
+        assert_eq!(v, Value::Float(OrderedFloat::from(75.0)));
+        let v = evaluate("Minus", &[Value::Int(3), Value::Null]).unwrap();
+        assert_eq!(v, Value::Null);
+
+        let float = |f: f32| Value::Float(OrderedFloat::from(f));
+        let cases = [
+            ("abs", float(-2.5), float(2.5)),
+            ("abs", Value::Int(-3), Value::Int(3)),
+            ("round", float(2.5), float(3.0)),
+            ("round", float(-2.5), float(-3.0)),
+            ("round", float(2.4), float(2.0)),
+            ("ceil", float(2.5), float(3.0)),
+            ("ceil", float(-2.5), float(-2.0)),
+            ("floor", float(2.5), float(2.0)),
+            ("floor", float(-2.5), float(-3.0)),
+            ("round", Value::Int(-7), Value::Int(-7)),
+            ("floor", Value::Int(7), Value::Int(7)),
+        ];
+        for (func_name, argument, expected) in cases.iter() {
+            let v = evaluate(func_name, &[argument.clone()]).unwrap();
+            assert_eq!(*expected, v);
+        }
+        for func_name in ["abs", "round", "ceil", "floor"].iter() {
+            assert_eq!(Ok(Value::Null), evaluate(func_name, &[Value::Null]));
+            assert_eq!(
+                Err(ExpressionError::InvalidArguments),
+                evaluate(func_name, &[Value::String("2.5".to_string())])
+            );
+        }
+        assert_eq!(
+            Err(ExpressionError::InvalidArguments),
+            evaluate("abs", &[Value::Int(std::i32::MIN)])
+        );
+
+        let v = evaluate("safe_divide", &[Value::Int(3), Value::Int(4)]).unwrap();
+        assert_eq!(v, Value::Float(OrderedFloat::from(0.75)));
+        let v = evaluate("safe_divide", &[Value::Float(OrderedFloat::from(1.5)), Value::Int(-3)]).unwrap();
+        assert_eq!(v, Value::Float(OrderedFloat::from(-0.5)));
+        let v = evaluate("safe_divide", &[Value::Int(3), Value::Int(0)]).unwrap();
+        assert_eq!(v, Value::Null);
+        let v = evaluate("safe_divide", &[Value::Int(3), Value::Float(OrderedFloat::from(0.0))]).unwrap();
+        assert_eq!(v, Value::Null);
+        let v = evaluate("safe_divide", &[Value::Null, Value::Int(2)]).unwrap();
+        assert_eq!(v, Value::Null);
+        let v = evaluate("safe_divide", &[Value::Int(2), Value::Null]).unwrap();
+        assert_eq!(v, Value::Null);
+        assert_eq!(
+            Err(ExpressionError::InvalidArguments),
+            evaluate("safe_divide", &[Value::String("3".to_string()), Value::Int(2)])
+        );
+        assert_eq!(
+            Err(ExpressionError::InvalidArguments),
+            evaluate("safe_divide", &[Value::Int(3)])
+        );
+
+        let v = evaluate(
+            "zscore",
+            &[Value::Int(130), Value::Float(OrderedFloat::from(100.0)), Value::Int(20)],
+        )
+        .unwrap();
+        assert_eq!(v, Value::Float(OrderedFloat::from(1.5)));
+        let v = evaluate("zscore", &[Value::Int(70), Value::Int(100), Value::Int(20)]).unwrap();
+        assert_eq!(v, Value::Float(OrderedFloat::from(-1.5)));
+        let v = evaluate("zscore", &[Value::Int(70), Value::Int(100), Value::Int(0)]).unwrap();
+        assert_eq!(v, Value::Null);
+        let v = evaluate("zscore", &[Value::Null, Value::Int(100), Value::Int(20)]).unwrap();
+        assert_eq!(v, Value::Null);
+        assert_eq!(
+            Err(ExpressionError::InvalidArguments),
+            evaluate(
+                "zscore",
+                &[Value::String("70".to_string()), Value::Int(100), Value::Int(20)]
+            )
+        );
+        assert_eq!(
+            Err(ExpressionError::InvalidArguments),
+            evaluate("zscore", &[Value::Int(70), Value::Int(100)])
+        );
 
         let dt = Value::DateTime(chrono::DateTime::parse_from_rfc3339("2015-11-07T18:45:37.691548Z").unwrap());
         let expected_dt = Value::DateTime(chrono::DateTime::parse_from_rfc3339("2015-11-07T18:45:35.000000Z").unwrap());
         let bucket_dt = evaluate("time_bucket", &vec![Value::String("5 seconds".to_string()), dt.clone()]).unwrap();
         assert_eq!(expected_dt, bucket_dt);
+        let v = evaluate("Minus", &[dt.clone(), expected_dt.clone()]).unwrap();
+        assert_eq!(v, Value::Float(OrderedFloat::from(2.691548)));
+        let offset = chrono::FixedOffset::east(0);
+        let far_past =
+            Value::DateTime(offset.from_utc_datetime(&chrono::NaiveDate::from_ymd(-200_000, 1, 1).and_hms(0, 0, 0)));
+        let far_future =
+            Value::DateTime(offset.from_utc_datetime(&chrono::NaiveDate::from_ymd(200_000, 1, 1).and_hms(0, 0, 0)));
+        let res = evaluate("Minus", &[far_future, far_past]);
+        assert_eq!(res, Err(ExpressionError::Overflow));
 
         let expected_dt = Value::DateTime(chrono::DateTime::parse_from_rfc3339("2015-11-07T18:45:00.000000Z").unwrap());
         let bucket_dt = evaluate("time_bucket", &vec![Value::String("5 minutes".to_string()), dt.clone()]).unwrap();
@@ -1482,4 +4957,691 @@ mod tests {
         let hour = evaluate("date_part", &vec![Value::String("second".to_string()), dt.clone()]).unwrap();
         assert_eq!(Value::Float(OrderedFloat::from(37.0)), hour);
     }
+
+    #[test]
+    fn test_evaluate_time_labels() {
+        let label = |func_name: &str, dt: &Value, offset_opt: Option<&str>| {
+            let mut arguments = vec![dt.clone()];
+            if let Some(offset) = offset_opt {
+                arguments.push(Value::String(offset.to_string()));
+            }
+            evaluate(func_name, &arguments)
+        };
+        let string = |s: &str| Ok(Value::String(s.to_string()));
+
+        //A Saturday evening in UTC is already Sunday morning in Tokyo
+        let dt = Value::DateTime(chrono::DateTime::parse_from_rfc3339("2015-11-07T18:45:37.691548Z").unwrap());
+        assert_eq!(string("18"), label("hour_label", &dt, None));
+        assert_eq!(string("Sat"), label("weekday_label", &dt, None));
+        assert_eq!(string("03"), label("hour_label", &dt, Some("+09:00")));
+        assert_eq!(string("Sun"), label("weekday_label", &dt, Some("+09:00")));
+        assert_eq!(string("00"), label("hour_label", &dt, Some("+0530")));
+
+        //A timestamp keeps its own offset without one given
+        let local = Value::DateTime(chrono::DateTime::parse_from_rfc3339("2015-11-08T03:45:37+09:00").unwrap());
+        assert_eq!(string("03"), label("hour_label", &local, None));
+        assert_eq!(string("18"), label("hour_label", &local, Some("utc")));
+
+        assert_eq!(Ok(Value::Null), label("weekday_label", &Value::Null, None));
+        assert_eq!(
+            Err(ExpressionError::InvalidArguments),
+            label("hour_label", &dt, Some("tokyo"))
+        );
+        assert_eq!(
+            Err(ExpressionError::InvalidArguments),
+            label("hour_label", &Value::String("18:45".to_string()), None)
+        );
+    }
+
+    #[test]
+    fn test_evaluate_endpoint() {
+        let ip = Value::String("10.0.0.1".to_string());
+
+        let v = evaluate("endpoint", &[ip.clone(), Value::Int(80)]).unwrap();
+        assert_eq!(Value::String("10.0.0.1:80".to_string()), v);
+
+        let v = evaluate("endpoint", &[ip, Value::Null]).unwrap();
+        assert_eq!(Value::String("10.0.0.1".to_string()), v);
+
+        let v = evaluate("endpoint", &[Value::Null, Value::Null]).unwrap();
+        assert_eq!(Value::Null, v);
+
+        let v = evaluate("endpoint", &[Value::Boolean(true), Value::Null]);
+        assert_eq!(Err(ExpressionError::InvalidArguments), v);
+    }
+
+    #[test]
+    fn test_evaluate_latency_tier() {
+        let t1 = Value::Float(OrderedFloat::from(0.1));
+        let t2 = Value::Float(OrderedFloat::from(0.5));
+        let cases = [
+            (Value::Float(OrderedFloat::from(0.05)), "fast"),
+            (Value::Float(OrderedFloat::from(0.1)), "fast"),
+            (Value::Float(OrderedFloat::from(0.3)), "ok"),
+            (Value::Float(OrderedFloat::from(0.5)), "ok"),
+            (Value::Int(1), "slow"),
+        ];
+
+        for (latency, tier) in cases.iter() {
+            let v = evaluate("latency_tier", &[latency.clone(), t1.clone(), t2.clone()]).unwrap();
+            assert_eq!(Value::String(tier.to_string()), v);
+        }
+
+        let v = evaluate("latency_tier", &[Value::Null, t1.clone(), t2.clone()]).unwrap();
+        assert_eq!(Value::Null, v);
+
+        let v = evaluate("latency_tier", &[Value::String("fast".to_string()), t1, t2]);
+        assert_eq!(Err(ExpressionError::InvalidArguments), v);
+    }
+
+    #[test]
+    fn test_evaluate_array_overlap() {
+        let array = |paths: &[&str]| Value::Array(paths.iter().map(|path| Value::String(path.to_string())).collect());
+        let overlap = |a: Value, b: Value| evaluate("array_overlap", &[a, b]);
+
+        let v = overlap(array(&["/a", "/b", "/a"]), array(&["/b", "/a"])).unwrap();
+        assert_eq!(Value::Float(OrderedFloat::from(1.0)), v);
+        let v = overlap(array(&["/a", "/b"]), array(&["/c"])).unwrap();
+        assert_eq!(Value::Float(OrderedFloat::from(0.0)), v);
+        //{/b, /c} of {/a, /b, /c, /d}
+        let v = overlap(array(&["/a", "/b", "/c"]), array(&["/b", "/c", "/d", "/c"])).unwrap();
+        assert_eq!(Value::Float(OrderedFloat::from(0.5)), v);
+
+        assert_eq!(Ok(Value::Null), overlap(array(&[]), array(&[])));
+        let v = overlap(array(&[]), array(&["/a"])).unwrap();
+        assert_eq!(Value::Float(OrderedFloat::from(0.0)), v);
+        assert_eq!(Ok(Value::Null), overlap(Value::Null, array(&["/a"])));
+        assert_eq!(
+            Err(ExpressionError::InvalidArguments),
+            overlap(Value::String("/a".to_string()), array(&["/a"]))
+        );
+    }
+
+    #[test]
+    fn test_evaluate_hop() {
+        let dt = Value::DateTime(chrono::DateTime::parse_from_rfc3339("2019-06-07T18:45:33.559871Z").unwrap());
+        let minute = |m: u32| {
+            let window = format!("2019-06-07T18:{:02}:00Z", m);
+            Value::DateTime(chrono::DateTime::parse_from_rfc3339(&window).unwrap())
+        };
+
+        //The 5 minute windows starting every minute from 18:41 to 18:45 hold 18:45:33
+        let expected = Value::Array((41..=45).map(minute).collect());
+        let v = evaluate(
+            "hop",
+            &[dt.clone(), Value::Float(OrderedFloat::from(300.0)), Value::Int(60)],
+        )
+        .unwrap();
+        assert_eq!(expected, v);
+        let v = evaluate(
+            "hop",
+            &[
+                dt.clone(),
+                Value::String("5 minutes".to_string()),
+                Value::String("1 minute".to_string()),
+            ],
+        )
+        .unwrap();
+        assert_eq!(expected, v);
+
+        //Tumbling windows when the slide is the size
+        let v = evaluate("hop", &[dt.clone(), Value::Int(300), Value::Int(300)]).unwrap();
+        assert_eq!(Value::Array(vec![minute(45)]), v);
+        //A window ends before the next one starts, 18:44:00 to 18:45:30 misses 18:45:33
+        let v = evaluate("hop", &[dt.clone(), Value::Int(90), Value::Int(60)]).unwrap();
+        assert_eq!(Value::Array(vec![minute(45)]), v);
+
+        assert_eq!(
+            Ok(Value::Null),
+            evaluate("hop", &[Value::Null, Value::Int(300), Value::Int(60)])
+        );
+        assert_eq!(
+            Err(ExpressionError::TimeIntervalZero),
+            evaluate("hop", &[dt.clone(), Value::Int(300), Value::Int(0)])
+        );
+        assert_eq!(
+            Err(ExpressionError::TimeIntervalNotSupported),
+            evaluate("hop", &[dt, Value::String("1 month".to_string()), Value::Int(60)])
+        );
+    }
+
+    #[test]
+    fn test_evaluate_array_percentile() {
+        let latencies = Value::Array((1..=10).rev().map(Value::Int).collect());
+        let p50 = evaluate(
+            "array_percentile",
+            &[latencies.clone(), Value::Float(OrderedFloat::from(0.5))],
+        )
+        .unwrap();
+        assert_eq!(Value::Int(5), p50);
+        let p95 = evaluate(
+            "array_percentile",
+            &[latencies.clone(), Value::Float(OrderedFloat::from(0.95))],
+        )
+        .unwrap();
+        assert_eq!(Value::Int(10), p95);
+        let p0 = evaluate("array_percentile", &[latencies, Value::Int(0)]).unwrap();
+        assert_eq!(Value::Int(1), p0);
+
+        let floats = Value::Array(vec![
+            Value::Float(OrderedFloat::from(0.3)),
+            Value::Null,
+            Value::Float(OrderedFloat::from(0.1)),
+            Value::Int(2),
+        ]);
+        let p50 = evaluate("array_percentile", &[floats, Value::Float(OrderedFloat::from(0.5))]).unwrap();
+        assert_eq!(Value::Float(OrderedFloat::from(0.3)), p50);
+
+        let v = evaluate(
+            "array_percentile",
+            &[Value::Array(vec![]), Value::Float(OrderedFloat::from(0.5))],
+        )
+        .unwrap();
+        assert_eq!(Value::Null, v);
+
+        let v = evaluate(
+            "array_percentile",
+            &[
+                Value::Array(vec![Value::Int(1), Value::String("slow".to_string())]),
+                Value::Float(OrderedFloat::from(0.5)),
+            ],
+        );
+        assert_eq!(Err(ExpressionError::InvalidArguments), v);
+
+        let v = evaluate("array_percentile", &[Value::Array(vec![Value::Int(1)]), Value::Int(95)]);
+        assert_eq!(Err(ExpressionError::InvalidArguments), v);
+    }
+
+    #[test]
+    fn test_evaluate_array_nth_largest() {
+        let latencies = Value::Array(vec![
+            Value::Float(OrderedFloat::from(0.2)),
+            Value::Float(OrderedFloat::from(0.9)),
+            Value::Null,
+            Value::Float(OrderedFloat::from(0.5)),
+        ]);
+        let v = evaluate("array_nth_largest", &[latencies.clone(), Value::Int(1)]).unwrap();
+        assert_eq!(Value::Float(OrderedFloat::from(0.9)), v);
+        let v = evaluate("array_nth_largest", &[latencies.clone(), Value::Int(2)]).unwrap();
+        assert_eq!(Value::Float(OrderedFloat::from(0.5)), v);
+        let v = evaluate("array_nth_largest", &[latencies, Value::Int(4)]).unwrap();
+        assert_eq!(Value::Null, v);
+
+        let duplicates = Value::Array(vec![Value::Int(7), Value::Int(7), Value::Int(3), Value::Int(7)]);
+        let v = evaluate("array_nth_largest", &[duplicates.clone(), Value::Int(2)]).unwrap();
+        assert_eq!(Value::Int(3), v);
+        let v = evaluate("array_nth_largest", &[duplicates, Value::Int(3)]).unwrap();
+        assert_eq!(Value::Null, v);
+
+        let v = evaluate(
+            "array_nth_largest",
+            &[Value::Array(vec![Value::Int(1), Value::Boolean(true)]), Value::Int(1)],
+        );
+        assert_eq!(Err(ExpressionError::InvalidArguments), v);
+
+        let v = evaluate("array_nth_largest", &[Value::Array(vec![Value::Int(1)]), Value::Int(0)]);
+        assert_eq!(Err(ExpressionError::InvalidArguments), v);
+    }
+
+    #[cfg(feature = "publicsuffix")]
+    #[test]
+    fn test_evaluate_registered_domain() {
+        let cases = [
+            ("a.b.example.co.uk", Value::String("example.co.uk".to_string())),
+            ("www.example.com", Value::String("example.com".to_string())),
+            ("example.com", Value::String("example.com".to_string())),
+            ("co.uk", Value::Null),
+            ("10.0.0.1", Value::Null),
+            ("", Value::Null),
+        ];
+
+        for (host, expected) in cases.iter() {
+            let v = evaluate("registered_domain", &[Value::String(host.to_string())]).unwrap();
+            assert_eq!(*expected, v);
+        }
+
+        let v = evaluate("registered_domain", &[Value::Null]).unwrap();
+        assert_eq!(Value::Null, v);
+    }
+
+    #[test]
+    fn test_evaluate_timestamp_diff() {
+        let start = Value::DateTime(chrono::DateTime::parse_from_rfc3339("2019-06-07T18:45:33.500000Z").unwrap());
+        let end = Value::DateTime(chrono::DateTime::parse_from_rfc3339("2019-06-07T18:45:35.750000Z").unwrap());
+
+        let v = evaluate("timestamp_diff", &[end.clone(), start.clone()]).unwrap();
+        assert_eq!(Value::Float(OrderedFloat::from(2.25)), v);
+
+        let v = evaluate("timestamp_diff", &[start.clone(), end.clone()]).unwrap();
+        assert_eq!(Value::Float(OrderedFloat::from(-2.25)), v);
+
+        let v = evaluate("timestamp_diff", &[Value::Null, start.clone()]).unwrap();
+        assert_eq!(Value::Null, v);
+
+        let v = evaluate("timestamp_diff", &[end, Value::Null]).unwrap();
+        assert_eq!(Value::Null, v);
+
+        let v = evaluate("timestamp_diff", &[Value::Int(1), start]);
+        assert_eq!(Err(ExpressionError::InvalidArguments), v);
+    }
+
+    #[test]
+    fn test_evaluate_greatest_and_least() {
+        let created = Value::DateTime(chrono::DateTime::parse_from_rfc3339("2019-06-07T18:45:33Z").unwrap());
+        let updated = Value::DateTime(chrono::DateTime::parse_from_rfc3339("2019-06-07T19:02:11Z").unwrap());
+
+        let arguments = [created.clone(), Value::Null, updated.clone()];
+        assert_eq!(Ok(updated.clone()), evaluate("greatest", &arguments));
+        assert_eq!(Ok(created.clone()), evaluate("least", &arguments));
+
+        let v = evaluate(
+            "greatest",
+            &[Value::Int(3), Value::Float(OrderedFloat::from(4.5)), Value::Int(-1)],
+        );
+        assert_eq!(Ok(Value::Float(OrderedFloat::from(4.5))), v);
+
+        assert_eq!(Ok(Value::Null), evaluate("greatest", &[Value::Null, Value::Null]));
+        assert_eq!(
+            Err(ExpressionError::InvalidArguments),
+            evaluate("least", &[created, Value::Int(1)])
+        );
+    }
+
+    #[test]
+    fn test_evaluate_ip_class() {
+        let cases = [
+            ("10.0.0.215", "private"),
+            ("192.168.1.20", "private"),
+            ("172.16.4.2", "private"),
+            ("127.0.0.1", "loopback"),
+            ("78.168.134.92", "public"),
+            ("::1", "loopback"),
+            ("fd12:3456:789a::1", "private"),
+            ("2001:4860:4860::8888", "public"),
+        ];
+
+        for (ip, class) in cases.iter() {
+            let v = evaluate("ip_class", &[Value::String(ip.to_string())]).unwrap();
+            assert_eq!(Value::String(class.to_string()), v);
+        }
+
+        let host = common::types::parse_host("10.0.0.215:80").unwrap();
+        let v = evaluate("ip_class", &[Value::Host(host)]).unwrap();
+        assert_eq!(Value::String("private".to_string()), v);
+
+        let v = evaluate("ip_class", &[Value::String("example.com".to_string())]).unwrap();
+        assert_eq!(Value::Null, v);
+    }
+
+    #[test]
+    fn test_evaluate_tls_version() {
+        let v = evaluate("tls_version", &[Value::String("TLSv1.2".to_string())]).unwrap();
+        assert_eq!(Value::String("1.2".to_string()), v);
+
+        let v = evaluate("tls_version", &[Value::String("TLSv1".to_string())]).unwrap();
+        assert_eq!(Value::String("1.0".to_string()), v);
+
+        let v = evaluate("tls_version", &[Value::String("SSLv3".to_string())]).unwrap();
+        assert_eq!(Value::String("0.3".to_string()), v);
+
+        let v = evaluate("tls_version", &[Value::String("-".to_string())]).unwrap();
+        assert_eq!(Value::Null, v);
+
+        let v = evaluate("tls_version", &[Value::String("QUICv1".to_string())]).unwrap();
+        assert_eq!(Value::String("QUICv1".to_string()), v);
+    }
+
+    #[test]
+    fn test_evaluate_weak_cipher() {
+        let v = evaluate(
+            "weak_cipher",
+            &[Value::String("ECDHE-RSA-AES128-GCM-SHA256".to_string())],
+        )
+        .unwrap();
+        assert_eq!(Value::Boolean(false), v);
+
+        let v = evaluate("weak_cipher", &[Value::String("ECDHE-RSA-RC4-SHA".to_string())]).unwrap();
+        assert_eq!(Value::Boolean(true), v);
+
+        let v = evaluate("weak_cipher", &[Value::String("DES-CBC3-SHA".to_string())]).unwrap();
+        assert_eq!(Value::Boolean(true), v);
+
+        let v = evaluate("weak_cipher", &[Value::String("-".to_string())]).unwrap();
+        assert_eq!(Value::Null, v);
+    }
+
+    #[test]
+    fn test_evaluate_to_int_or() {
+        let to_int_or = |value: Value| evaluate("to_int_or", &[value, Value::Int(0)]);
+        assert_eq!(Ok(Value::Int(42)), to_int_or(Value::String(" 42 ".to_string())));
+        assert_eq!(Ok(Value::Int(-7)), to_int_or(Value::String("-7".to_string())));
+        assert_eq!(Ok(Value::Int(0)), to_int_or(Value::String("-".to_string())));
+        assert_eq!(Ok(Value::Int(0)), to_int_or(Value::String("4.2".to_string())));
+        assert_eq!(Ok(Value::Int(0)), to_int_or(Value::Null));
+        assert_eq!(Ok(Value::Int(13)), to_int_or(Value::Int(13)));
+        assert_eq!(Ok(Value::Int(4)), to_int_or(Value::Float(OrderedFloat::from(4.9))));
+        assert_eq!(Ok(Value::Int(0)), to_int_or(Value::Float(OrderedFloat::from(f32::NAN))));
+        assert_eq!(
+            Ok(Value::Null),
+            evaluate("to_int_or", &[Value::String("n/a".to_string()), Value::Null])
+        );
+    }
+
+    #[test]
+    fn test_evaluate_to_float_or() {
+        let default = Value::Float(OrderedFloat::from(-1.0));
+        let to_float_or = |value: Value| evaluate("to_float_or", &[value, default.clone()]);
+        assert_eq!(
+            Ok(Value::Float(OrderedFloat::from(0.25))),
+            to_float_or(Value::String("0.25".to_string()))
+        );
+        assert_eq!(Ok(default.clone()), to_float_or(Value::String("fast".to_string())));
+        assert_eq!(Ok(default.clone()), to_float_or(Value::String("inf".to_string())));
+        assert_eq!(Ok(Value::Float(OrderedFloat::from(3.0))), to_float_or(Value::Int(3)));
+        assert_eq!(
+            Ok(Value::Float(OrderedFloat::from(1.5))),
+            to_float_or(Value::Float(OrderedFloat::from(1.5)))
+        );
+    }
+
+    #[test]
+    fn test_evaluate_trace_root() {
+        let root = "1-58337262-36d228ad5d99923122bbe354";
+        for trace_id in [
+            format!("Root={}", root),
+            format!("Self=1-58337263-12d228ad5d99923122bbe354;Root={};Sampled=1", root),
+        ]
+        .iter()
+        {
+            assert_eq!(
+                Ok(Value::String(root.to_string())),
+                evaluate("trace_root", &[Value::String(trace_id.clone())])
+            );
+        }
+
+        for trace_id in [
+            "-",
+            "Self=1-58337263-12d228ad5d99923122bbe354",
+            "Root=1-5833zz62",
+            "Root=",
+        ]
+        .iter()
+        {
+            assert_eq!(
+                Ok(Value::Null),
+                evaluate("trace_root", &[Value::String(trace_id.to_string())])
+            );
+        }
+        assert_eq!(Ok(Value::Null), evaluate("trace_root", &[Value::Null]));
+
+        let line = r#"http 2018-07-02T22:23:00.186641Z app/my-loadbalancer/50dc6c495c0c9188 192.168.131.39:2817 10.0.0.1:80 0.000 0.001 0.000 200 200 34 366 "GET http://www.example.com:80/ HTTP/1.1" "curl/7.46.0" - - arn:aws:elasticloadbalancing:us-east-2:123456789012:targetgroup/my-targets/73e2d6bc24d8a067 "Root=1-58337262-36d228ad5d99923122bbe354" "-" "-" 0 2018-07-02T22:22:48.364000Z "forward" "-" "-""#;
+        let mut reader = ReaderBuilder::new("alb".to_string()).with_reader(io::BufReader::new(line.as_bytes()));
+        let record = reader.read_record().unwrap().unwrap();
+        let trace_id = record.get(&["trace_id".to_string()]).remove(0);
+        assert_eq!(Ok(Value::String(root.to_string())), evaluate("trace_root", &[trace_id]));
+    }
+
+    #[test]
+    fn test_seeded_state() {
+        let hash = |seed: u64| {
+            let mut hasher = SeededState { seed }.build_hasher();
+            hasher.write(b"a");
+            hasher.finish()
+        };
+        assert_eq!(0xe604_613a_248f_f1ac, hash(0));
+        assert_eq!(0x3ae0_cb2f_5f3e_5f4a, hash(42));
+    }
+
+    #[test]
+    fn test_evaluate_hash_bucket() {
+        //FNV-1a of the empty input is its offset basis
+        assert_eq!(0xcbf2_9ce4_8422_2325, fnv1a_64(b""));
+        assert_eq!(0xaf63_dc4c_8601_ec8c, fnv1a_64(b"a"));
+
+        let bucket = |key: Value| evaluate("hash_bucket", &[key, Value::Int(8)]).unwrap();
+        let first = bucket(Value::String("10.0.0.215".to_string()));
+        for _ in 0..3 {
+            assert_eq!(first, bucket(Value::String("10.0.0.215".to_string())));
+        }
+        assert_eq!(
+            Value::Int(0),
+            evaluate("hash_bucket", &[Value::Int(42), Value::Int(1)]).unwrap()
+        );
+        assert_eq!(Value::Null, bucket(Value::Null));
+
+        let mut counts = [0; 4];
+        for i in 0..4000 {
+            match evaluate("hash_bucket", &[Value::String(format!("/path/{}", i)), Value::Int(4)]) {
+                Ok(Value::Int(b)) if (0..4).contains(&b) => counts[b as usize] += 1,
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+        for count in counts.iter() {
+            assert!(*count > 800 && *count < 1200, "{:?}", counts);
+        }
+
+        assert_eq!(
+            Err(ExpressionError::InvalidArguments),
+            evaluate("hash_bucket", &[Value::Int(1), Value::Int(0)])
+        );
+    }
+
+    #[test]
+    fn test_evaluate_normalize_path() {
+        let cases = [
+            ("/users/123/orders/42", "/users/:id/orders/:id"),
+            (
+                "/users/123/orders/3f2b8c1e-9a4d-4e6f-8b7a-0c1d2e3f4a5b",
+                "/users/:id/orders/:uuid",
+            ),
+            (
+                "/api/v2/items/B7A3C9D2-1E4F-4A6B-9C8D-7E6F5A4B3C2D/x1?page=2",
+                "/api/v2/items/:uuid/x1",
+            ),
+            ("/health", "/health"),
+            ("/", "/"),
+        ];
+
+        for (path, normalized) in cases.iter() {
+            let v = evaluate("normalize_path", &[Value::String(path.to_string())]).unwrap();
+            assert_eq!(Value::String(normalized.to_string()), v);
+        }
+
+        assert_eq!(Ok(Value::Null), evaluate("normalize_path", &[Value::Null]));
+        assert_eq!(
+            Err(ExpressionError::InvalidArguments),
+            evaluate("normalize_path", &[Value::Int(123)])
+        );
+    }
+
+    #[test]
+    fn test_evaluate_dedup_key() {
+        let key = |arguments: &[Value]| evaluate("dedup_key", arguments).unwrap();
+        let path = |path: &str| evaluate("normalize_path", &[Value::String(path.to_string())]).unwrap();
+        let get = || Value::String("GET".to_string());
+
+        //Two requests to the same route with the same method and status are one key
+        assert_eq!(
+            key(&[path("/users/1?page=2"), get(), Value::Int(5)]),
+            key(&[path("/users/42"), get(), Value::Int(5)])
+        );
+        assert_eq!(
+            Value::String("/users/:id|GET|5".to_string()),
+            key(&[path("/users/42"), get(), Value::Int(5)])
+        );
+
+        let distinct = [
+            key(&[path("/users/42"), get(), Value::Int(4)]),
+            key(&[Value::String("a|b".to_string()), Value::String("c".to_string())]),
+            key(&[Value::String("a".to_string()), Value::String("b|c".to_string())]),
+            key(&[Value::Null, get()]),
+            key(&[Value::String("".to_string()), get()]),
+            key(&[Value::String("\\N".to_string()), get()]),
+        ];
+        for (i, a) in distinct.iter().enumerate() {
+            for b in distinct.iter().skip(i + 1) {
+                assert_ne!(a, b);
+            }
+        }
+        assert_eq!(Value::String("\\N|GET".to_string()), key(&[Value::Null, get()]));
+
+        assert_eq!(Err(ExpressionError::InvalidArguments), evaluate("dedup_key", &[]));
+    }
+
+    #[test]
+    fn test_evaluate_concat() {
+        let arguments = [
+            Value::String("10.0.0.215".to_string()),
+            Value::String(":".to_string()),
+            Value::Int(80),
+            Value::String("/".to_string()),
+            Value::Float(OrderedFloat::from(0.5)),
+            Value::Null,
+            Value::Float(OrderedFloat::from(2.0)),
+        ];
+        let v = evaluate("concat", &arguments).unwrap();
+        assert_eq!(Value::String("10.0.0.215:80/0.52".to_string()), v);
+        let v = evaluate("concat", &[Value::Null]).unwrap();
+        assert_eq!(Value::String("".to_string()), v);
+        assert_eq!(Err(ExpressionError::InvalidArguments), evaluate("concat", &[]));
+
+        let arguments = [
+            Value::String("|".to_string()),
+            Value::String("GET".to_string()),
+            Value::Null,
+            Value::Int(200),
+            Value::Float(OrderedFloat::from(1.25)),
+        ];
+        let v = evaluate("concat_ws", &arguments).unwrap();
+        assert_eq!(Value::String("GET|200|1.25".to_string()), v);
+        let v = evaluate("concat_ws", &[Value::Null, Value::Int(1)]).unwrap();
+        assert_eq!(Value::Null, v);
+        assert_eq!(
+            Err(ExpressionError::InvalidArguments),
+            evaluate("concat_ws", &[Value::Int(1), Value::Int(1)])
+        );
+        assert_eq!(
+            Err(ExpressionError::InvalidArguments),
+            evaluate("concat_ws", &[Value::String("|".to_string())])
+        );
+    }
+
+    #[test]
+    fn test_evaluate_method_class() {
+        let cases = [
+            ("GET", "read"),
+            ("head", "read"),
+            ("Post", "write"),
+            ("DELETE", "write"),
+            ("PROPFIND", "other"),
+        ];
+
+        for (method, class) in cases.iter() {
+            let v = evaluate("method_class", &[Value::String(method.to_string())]).unwrap();
+            assert_eq!(Value::String(class.to_string()), v);
+        }
+
+        let request = common::types::parse_http_request("PUT http://example.com:80/items/1 HTTP/1.1").unwrap();
+        let v = evaluate("method_class", &[Value::HttpRequest(request)]).unwrap();
+        assert_eq!(Value::String("write".to_string()), v);
+
+        let v = evaluate("method_class", &[Value::Null]).unwrap();
+        assert_eq!(Value::Null, v);
+
+        let v = evaluate("method_class", &[Value::Int(1)]);
+        assert_eq!(Err(ExpressionError::InvalidArguments), v);
+    }
+
+    #[test]
+    fn test_evaluate_ua_family() {
+        let cases = [
+            ("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/76.0.3809.100 Safari/537.36", "Chrome"),
+            ("Mozilla/5.0 (Macintosh; Intel Mac OS X 10_14_6) AppleWebKit/605.1.15 (KHTML, like Gecko) Version/12.1.2 Safari/605.1.15", "Safari"),
+            ("Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/76.0.3809.100 Safari/537.36 Edg/76.0.182.42", "Edge"),
+            ("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)", "GoogleBot"),
+            ("curl/7.38.0", "curl"),
+            ("-", "unknown"),
+        ];
+
+        for (user_agent, family) in cases.iter() {
+            let v = evaluate("ua_family", &[Value::String(user_agent.to_string())]).unwrap();
+            assert_eq!(Value::String(family.to_string()), v);
+        }
+
+        let v = evaluate("ua_family", &[Value::Null]).unwrap();
+        assert_eq!(Value::Null, v);
+    }
+
+    #[test]
+    fn test_evaluate_truncate() {
+        let short = Value::String("curl/7.46.0".to_string());
+        let v = evaluate("truncate", &vec![short.clone(), Value::Int(40)]).unwrap();
+        assert_eq!(v, short);
+
+        let long = Value::String("Mozilla/5.0 (Windows NT 5.1) AppleWebKit/537.36".to_string());
+        let v = evaluate("truncate", &vec![long.clone(), Value::Int(11)]).unwrap();
+        assert_eq!(v, Value::String("Mozilla/5.0…".to_string()));
+
+        let v = evaluate("truncate", &vec![long, Value::Int(11), Value::Boolean(false)]).unwrap();
+        assert_eq!(v, Value::String("Mozilla/5.0".to_string()));
+
+        let multibyte = Value::String("日本語のテキスト".to_string());
+        let v = evaluate("truncate", &vec![multibyte, Value::Int(3)]).unwrap();
+        assert_eq!(v, Value::String("日本語…".to_string()));
+
+        let v = evaluate("truncate", &vec![Value::Null, Value::Int(3)]).unwrap();
+        assert_eq!(v, Value::Null);
+    }
+
+    #[test]
+    fn test_evaluate_string_functions() {
+        let s = Value::String("Straße/日本".to_string());
+        assert_eq!(
+            Ok(Value::String("STRASSE/日本".to_string())),
+            evaluate("upper", &[s.clone()])
+        );
+        assert_eq!(
+            Ok(Value::String("straße/日本".to_string())),
+            evaluate("lower", &[s.clone()])
+        );
+        assert_eq!(Ok(Value::Int(9)), evaluate("length", &[s.clone()]));
+
+        let request = common::types::parse_http_request("GET http://example.com/a HTTP/1.1").unwrap();
+        assert_eq!(
+            Ok(Value::String("GET HTTP://EXAMPLE.COM/A HTTP/1.1".to_string())),
+            evaluate("upper", &[Value::HttpRequest(request)])
+        );
+
+        let v = evaluate("substr", &[s.clone(), Value::Int(1), Value::Int(3)]).unwrap();
+        assert_eq!(Value::String("Str".to_string()), v);
+        let v = evaluate("substr", &[s.clone(), Value::Int(8)]).unwrap();
+        assert_eq!(Value::String("日本".to_string()), v);
+        let v = evaluate("substr", &[s.clone(), Value::Int(8), Value::Int(10)]).unwrap();
+        assert_eq!(Value::String("日本".to_string()), v);
+        //A start past the end is an empty string, one before the first character an error
+        let v = evaluate("substr", &[s.clone(), Value::Int(20), Value::Int(2)]).unwrap();
+        assert_eq!(Value::String("".to_string()), v);
+        assert_eq!(
+            Err(ExpressionError::InvalidArguments),
+            evaluate("substr", &[s.clone(), Value::Int(0)])
+        );
+        assert_eq!(
+            Err(ExpressionError::InvalidArguments),
+            evaluate("substr", &[s.clone(), Value::Int(1), Value::Int(-1)])
+        );
+
+        for func_name in ["upper", "lower", "length"].iter() {
+            assert_eq!(Ok(Value::Null), evaluate(func_name, &[Value::Null]));
+            assert_eq!(
+                Err(ExpressionError::InvalidArguments),
+                evaluate(func_name, &[Value::Int(1)])
+            );
+            assert_eq!(
+                Err(ExpressionError::InvalidArguments),
+                evaluate(func_name, &[s.clone(), s.clone()])
+            );
+        }
+        assert_eq!(Ok(Value::Null), evaluate("substr", &[Value::Null, Value::Int(1)]));
+        assert_eq!(Err(ExpressionError::InvalidArguments), evaluate("substr", &[s]));
+    }
 }
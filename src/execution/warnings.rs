@@ -0,0 +1,114 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt;
+
+//A data quality issue that doesn't stop the query, counted while it runs and reported once it ends
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum WarningKind {
+    SkippedLine,
+    NullSentinel,
+    DefaultFallback,
+    Nondeterministic,
+}
+
+impl fmt::Display for WarningKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            WarningKind::SkippedLine => "unparseable lines skipped",
+            WarningKind::NullSentinel => "log with -1 processing times read as null",
+            WarningKind::DefaultFallback => "values replaced by the default of to_int_or or to_float_or",
+            WarningKind::Nondeterministic => "constructs that may give a different result on another run",
+        };
+        write!(f, "{}", description)
+    }
+}
+
+//The count of every kind of warning with the first occurrence as an example
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Warnings {
+    counts: BTreeMap<WarningKind, (u64, String)>,
+}
+
+impl Warnings {
+    pub(crate) fn add<F>(&mut self, kind: WarningKind, detail: F)
+    where
+        F: FnOnce() -> String,
+    {
+        let entry = self.counts.entry(kind).or_insert_with(|| (0, detail()));
+        entry.0 += 1;
+    }
+
+    //Adds the warning only when none of its kind was added yet, for a kind that is expected on every line of some inputs
+    pub(crate) fn add_once<F>(&mut self, kind: WarningKind, detail: F)
+    where
+        F: FnOnce() -> String,
+    {
+        self.counts.entry(kind).or_insert_with(|| (1, detail()));
+    }
+
+    pub(crate) fn count(&self, kind: WarningKind) -> u64 {
+        self.counts.get(&kind).map(|(count, _)| *count).unwrap_or(0)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+}
+
+impl fmt::Display for Warnings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (kind, (count, detail)) in self.counts.iter() {
+            writeln!(f, "Warning: {} {}, first: {}", count, kind, detail)?;
+        }
+
+        Ok(())
+    }
+}
+
+//A query runs on a single thread, the warnings of the streams and the functions they evaluate are
+//collected here rather than passed through every stream and expression
+thread_local! {
+    static COLLECTED: RefCell<Warnings> = RefCell::new(Warnings::default());
+}
+
+pub(crate) fn warn<F>(kind: WarningKind, detail: F)
+where
+    F: FnOnce() -> String,
+{
+    COLLECTED.with(|collected| collected.borrow_mut().add(kind, detail));
+}
+
+pub(crate) fn warn_once<F>(kind: WarningKind, detail: F)
+where
+    F: FnOnce() -> String,
+{
+    COLLECTED.with(|collected| collected.borrow_mut().add_once(kind, detail));
+}
+
+//The warnings collected so far, the collector starts over empty
+pub(crate) fn take() -> Warnings {
+    COLLECTED.with(|collected| std::mem::take(&mut *collected.borrow_mut()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_warnings_summary() {
+        take();
+        warn(WarningKind::SkippedLine, || "line 3".to_string());
+        warn_once(WarningKind::NullSentinel, || "line 3".to_string());
+        warn(WarningKind::SkippedLine, || "line 7".to_string());
+        warn_once(WarningKind::NullSentinel, || "line 7".to_string());
+
+        let warnings = take();
+        assert_eq!(2, warnings.count(WarningKind::SkippedLine));
+        assert_eq!(1, warnings.count(WarningKind::NullSentinel));
+        assert_eq!(
+            "Warning: 2 unparseable lines skipped, first: line 3\nWarning: 1 log with -1 processing times read as null, first: line 3\n",
+            warnings.to_string()
+        );
+        assert!(take().is_empty());
+    }
+}
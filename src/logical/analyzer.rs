@@ -0,0 +1,180 @@
+use super::types::{Aggregate, Node};
+use crate::common::types::DataSource;
+use std::fmt;
+
+//A construct whose result may differ between two runs over the same input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Nondeterminism {
+    UnorderedFirstLast(String),
+    ApproximateAggregate(String),
+}
+
+impl fmt::Display for Nondeterminism {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Nondeterminism::UnorderedFirstLast(name) => {
+                write!(
+                    f,
+                    "{}() without an order by depends on the order the rows arrive in",
+                    name
+                )
+            }
+            Nondeterminism::ApproximateAggregate(name) => {
+                write!(f, "{}() is an estimate and may differ from the exact value", name)
+            }
+        }
+    }
+}
+
+//Walk the plan for the constructs above, first and last count as ordered when the rows reach them
+//straight from a file or through an order by. An order by above the group by only sorts the groups.
+pub(crate) fn find_nondeterminism(node: &Node) -> Vec<Nondeterminism> {
+    let mut found = Vec::new();
+    walk(node, &mut found);
+    found
+}
+
+//Whether the rows the node hands out come in a fixed order
+fn walk(node: &Node, found: &mut Vec<Nondeterminism>) -> bool {
+    match node {
+        Node::GroupBy(_, named_aggregates, source) => {
+            let ordered = walk(source, found);
+            for named_aggregate in named_aggregates.iter() {
+                match named_aggregate.aggregate {
+                    Aggregate::First(_) if !ordered => {
+                        found.push(Nondeterminism::UnorderedFirstLast("first".to_string()))
+                    }
+                    Aggregate::Last(_) if !ordered => {
+                        found.push(Nondeterminism::UnorderedFirstLast("last".to_string()))
+                    }
+                    Aggregate::ApproxCountDistinct(_) => found.push(Nondeterminism::ApproximateAggregate(
+                        "approx_count_distinct".to_string(),
+                    )),
+                    Aggregate::ApproxPercentile(_, _, _) => {
+                        found.push(Nondeterminism::ApproximateAggregate("approx_percentile".to_string()))
+                    }
+                    _ => {}
+                }
+            }
+            //The groups come out of a hash table
+            false
+        }
+        Node::OrderBy(_, _, source) => {
+            walk(source, found);
+            true
+        }
+        //The rows are shuffled by their hash, stable between runs but not an order of the rows
+        Node::HashOrder(_, source) => {
+            walk(source, found);
+            false
+        }
+        Node::Filter(_, source)
+        | Node::Map(_, source)
+        | Node::Limit(_, source)
+        | Node::ChangedBy(_, source)
+        | Node::EnforceSchema(_, _, source)
+        | Node::Distinct(source)
+        | Node::DropColumns(_, source)
+        | Node::Unnest(_, source) => walk(source, found),
+        Node::Tee(detail, summary) => {
+            walk(detail, found);
+            walk(summary, found);
+            false
+        }
+        //Every row of the left side is paired with the right side in turn
+        Node::Join(_, left, right) => {
+            let ordered = walk(left, found);
+            walk(right, found);
+            ordered
+        }
+        //A file is read in the order of its lines and a VALUES list in the order it is written. Stdin is in
+        //whatever order its writer picks, and merged files in whichever order their ties fall.
+        Node::DataSource(data_source, _) => match data_source {
+            DataSource::File(_) | DataSource::Values(_, _) => true,
+            DataSource::Stdin | DataSource::SortedFiles(_, _) => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logical::parser::parse_query;
+    use crate::logical::types::Ordering;
+    use crate::syntax::parser::select_query;
+    use std::path::PathBuf;
+
+    fn plan_from(query_str: &str, data_source: DataSource) -> Node {
+        let (rest, select_stmt) = select_query(query_str).unwrap();
+        assert_eq!("", rest);
+        parse_query(select_stmt, data_source).unwrap()
+    }
+
+    fn plan(query_str: &str) -> Node {
+        plan_from(query_str, DataSource::Stdin)
+    }
+
+    fn analyze(query_str: &str) -> Vec<Nondeterminism> {
+        find_nondeterminism(&plan(query_str))
+    }
+
+    //Sort the rows by timestamp before they are grouped, which the syntax has no way to say
+    fn order_below_group_by(node: Node) -> Node {
+        match node {
+            Node::GroupBy(keys, named_aggregates, source) => Node::GroupBy(
+                keys,
+                named_aggregates,
+                Box::new(Node::OrderBy(
+                    vec!["timestamp".to_string()],
+                    vec![Ordering::Asc],
+                    source,
+                )),
+            ),
+            Node::Map(named_list, source) => Node::Map(named_list, Box::new(order_below_group_by(*source))),
+            Node::OrderBy(column_names, orderings, source) => {
+                Node::OrderBy(column_names, orderings, Box::new(order_below_group_by(*source)))
+            }
+            Node::Filter(formula, source) => Node::Filter(formula, Box::new(order_below_group_by(*source))),
+            Node::DropColumns(column_names, source) => {
+                Node::DropColumns(column_names, Box::new(order_below_group_by(*source)))
+            }
+            node => node,
+        }
+    }
+
+    #[test]
+    fn test_find_nondeterminism() {
+        assert_eq!(
+            vec![Nondeterminism::UnorderedFirstLast("first".to_string())],
+            analyze("select first(request) as r from elb")
+        );
+        assert_eq!(
+            vec![Nondeterminism::ApproximateAggregate(
+                "approx_count_distinct".to_string()
+            )],
+            analyze("select approx_count_distinct(client_and_port) as c from elb")
+        );
+
+        //Sorting the groups doesn't settle which row of a group comes first
+        let query_str =
+            "select backend_and_port, first(request) as r, count(*) as c from elb group by backend_and_port order by c desc";
+        assert_eq!(
+            vec![Nondeterminism::UnorderedFirstLast("first".to_string())],
+            analyze(query_str)
+        );
+        assert!(find_nondeterminism(&order_below_group_by(plan(query_str))).is_empty());
+
+        //The lines of a file come in the same order every run, unlike those of merged files
+        let query_str = "select first(request) as r from elb";
+        let file = DataSource::File(PathBuf::from("access.log"));
+        assert!(find_nondeterminism(&plan_from(query_str, file)).is_empty());
+        let merged = DataSource::SortedFiles(
+            vec![PathBuf::from("a.log"), PathBuf::from("b.log")],
+            "timestamp".to_string(),
+        );
+        assert_eq!(
+            vec![Nondeterminism::UnorderedFirstLast("first".to_string())],
+            find_nondeterminism(&plan_from(query_str, merged))
+        );
+    }
+}
@@ -1,2 +1,4 @@
+pub mod analyzer;
+pub mod optimizer;
 pub mod parser;
 pub mod types;
@@ -0,0 +1,160 @@
+use super::types::{Expression, Formula, LogicInfixOp, Named, Node, Relation};
+
+//Reorder the AND-ed predicates of every filter so the cheap ones run first and short-circuit the
+//expensive ones. The predicates are free of side effects so only the evaluation cost changes.
+pub(crate) fn reorder_filters(node: Node) -> Node {
+    match node {
+        Node::Filter(formula, source) => {
+            Node::Filter(Box::new(reorder_formula(*formula)), Box::new(reorder_filters(*source)))
+        }
+        Node::Map(named_list, source) => Node::Map(named_list, Box::new(reorder_filters(*source))),
+        Node::GroupBy(fields, named_aggregates, source) => {
+            Node::GroupBy(fields, named_aggregates, Box::new(reorder_filters(*source)))
+        }
+        Node::Limit(row_count, source) => Node::Limit(row_count, Box::new(reorder_filters(*source))),
+        Node::OrderBy(column_names, orderings, source) => {
+            Node::OrderBy(column_names, orderings, Box::new(reorder_filters(*source)))
+        }
+        Node::ChangedBy(column_name, source) => Node::ChangedBy(column_name, Box::new(reorder_filters(*source))),
+        Node::HashOrder(column_names, source) => Node::HashOrder(column_names, Box::new(reorder_filters(*source))),
+        Node::EnforceSchema(contract, every_row, source) => {
+            Node::EnforceSchema(contract, every_row, Box::new(reorder_filters(*source)))
+        }
+        Node::Distinct(source) => Node::Distinct(Box::new(reorder_filters(*source))),
+        Node::Tee(detail, summary) => {
+            Node::Tee(Box::new(reorder_filters(*detail)), Box::new(reorder_filters(*summary)))
+        }
+        Node::DropColumns(column_names, source) => Node::DropColumns(column_names, Box::new(reorder_filters(*source))),
+        Node::Unnest(column_name, source) => Node::Unnest(column_name, Box::new(reorder_filters(*source))),
+        Node::Join(join_type, left, right) => Node::Join(
+            join_type,
+            Box::new(reorder_filters(*left)),
+            Box::new(reorder_filters(*right)),
+        ),
+        Node::DataSource(_, _) => node,
+    }
+}
+
+fn reorder_formula(formula: Formula) -> Formula {
+    match formula {
+        Formula::InfixOperator(LogicInfixOp::And, _, _) => {
+            let mut conjuncts = Vec::new();
+            flatten_conjunction(formula, &mut conjuncts);
+
+            let mut conjuncts: Vec<Formula> = conjuncts.into_iter().map(reorder_formula).collect();
+            //The sort is stable so predicates of equal cost keep the order they were written in
+            conjuncts.sort_by_key(formula_cost);
+
+            let mut iter = conjuncts.into_iter();
+            let first = iter.next().unwrap();
+            iter.fold(first, |acc, conjunct| {
+                Formula::InfixOperator(LogicInfixOp::And, Box::new(acc), Box::new(conjunct))
+            })
+        }
+        Formula::InfixOperator(LogicInfixOp::Or, left, right) => Formula::InfixOperator(
+            LogicInfixOp::Or,
+            Box::new(reorder_formula(*left)),
+            Box::new(reorder_formula(*right)),
+        ),
+        Formula::PrefixOperator(op, child) => Formula::PrefixOperator(op, Box::new(reorder_formula(*child))),
+        _ => formula,
+    }
+}
+
+fn flatten_conjunction(formula: Formula, conjuncts: &mut Vec<Formula>) {
+    match formula {
+        Formula::InfixOperator(LogicInfixOp::And, left, right) => {
+            flatten_conjunction(*left, conjuncts);
+            flatten_conjunction(*right, conjuncts);
+        }
+        _ => conjuncts.push(formula),
+    }
+}
+
+//A static estimate: constants and column lookups are cheap, substring matching and function calls
+//(url parsing, string manipulation) are expensive.
+fn formula_cost(formula: &Formula) -> u32 {
+    match formula {
+        Formula::Constant(_) => 0,
+        Formula::InfixOperator(_, left, right) => formula_cost(left) + formula_cost(right),
+        Formula::PrefixOperator(_, child) => formula_cost(child),
+        Formula::Predicate(relation, left, right) => {
+            let relation_cost = match relation {
+                Relation::Contains | Relation::Like | Relation::NotLike | Relation::RegexMatch => 5,
+                _ => 1,
+            };
+            relation_cost + expression_cost(left) + expression_cost(right)
+        }
+        Formula::InList(child, list) => 1 + expression_cost(child) + list.iter().map(expression_cost).sum::<u32>(),
+    }
+}
+
+fn expression_cost(expr: &Expression) -> u32 {
+    match expr {
+        Expression::Constant(_) | Expression::Variable(_) => 0,
+        Expression::Logic(formula) => formula_cost(formula),
+        Expression::Function(_, arguments) => {
+            let arguments_cost: u32 = arguments
+                .iter()
+                .map(|argument| match argument {
+                    Named::Expression(expr, _) => expression_cost(expr),
+                    Named::Star => 0,
+                })
+                .sum();
+            10 + arguments_cost
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::types::{DataSource, Value};
+
+    #[test]
+    fn test_reorder_filters() {
+        let expensive = Formula::Predicate(
+            Relation::Equal,
+            Box::new(Expression::Function(
+                "url_host".to_string(),
+                vec![Named::Expression(Expression::Variable("request".to_string()), None)],
+            )),
+            Box::new(Expression::Constant(Value::String("example.com".to_string()))),
+        );
+        let contains = Formula::Predicate(
+            Relation::Contains,
+            Box::new(Expression::Variable("user_agent".to_string())),
+            Box::new(Expression::Constant(Value::String("curl".to_string()))),
+        );
+        let cheap = Formula::Predicate(
+            Relation::MoreThan,
+            Box::new(Expression::Variable("sent_bytes".to_string())),
+            Box::new(Expression::Constant(Value::Int(100))),
+        );
+
+        let formula = Formula::InfixOperator(
+            LogicInfixOp::And,
+            Box::new(Formula::InfixOperator(
+                LogicInfixOp::And,
+                Box::new(expensive.clone()),
+                Box::new(contains.clone()),
+            )),
+            Box::new(cheap.clone()),
+        );
+        let source = Node::DataSource(DataSource::Stdin, "elb".to_string());
+        let node = Node::Filter(Box::new(formula), Box::new(source.clone()));
+
+        let expected_formula = Formula::InfixOperator(
+            LogicInfixOp::And,
+            Box::new(Formula::InfixOperator(
+                LogicInfixOp::And,
+                Box::new(cheap),
+                Box::new(contains),
+            )),
+            Box::new(expensive),
+        );
+        let expected = Node::Filter(Box::new(expected_formula), Box::new(source));
+
+        assert_eq!(expected, reorder_filters(node));
+    }
+}
@@ -22,6 +22,8 @@ pub enum ParseError {
     UnknownFunction(String),
     #[fail(display = "Having clause but no Group By clause provided")]
     HavingClauseWithoutGroupBy,
+    #[fail(display = "VALUES row {} has {} columns but {} are named", _0, _1, _2)]
+    ValuesColumnCount(usize, usize, usize),
 }
 
 pub type ParseResult<T> = Result<T, ParseError>;
@@ -33,6 +35,29 @@ fn parse_prefix_operator(op: types::LogicPrefixOp, child: &ast::Expression) -> P
     Ok(Box::new(prefix_op))
 }
 
+//IS NULL and IS NOT NULL only look at their left side, the right one is the null they test for
+fn parse_null_test(op: &ast::UnaryOperator, child: &ast::Expression) -> ParseResult<Box<types::Formula>> {
+    let relation = if op == &ast::UnaryOperator::IsNull {
+        types::Relation::IsNull
+    } else {
+        types::Relation::IsNotNull
+    };
+    let child_parsed = parse_value_expression(child)?;
+
+    let null = Box::new(types::Expression::Constant(common::Value::Null));
+    Ok(Box::new(types::Formula::Predicate(relation, child_parsed, null)))
+}
+
+fn parse_in_list(child: &ast::Expression, list: &[ast::Expression]) -> ParseResult<Box<types::Formula>> {
+    let child_parsed = parse_value_expression(child)?;
+    let mut list_parsed = Vec::new();
+    for item in list.iter() {
+        list_parsed.push(*parse_value_expression(item)?);
+    }
+
+    Ok(Box::new(types::Formula::InList(child_parsed, list_parsed)))
+}
+
 fn parse_infix_operator(
     op: types::LogicInfixOp,
     left: &ast::Expression,
@@ -63,9 +88,10 @@ fn parse_logic(expr: &ast::Expression) -> ParseResult<Box<types::Formula>> {
                 let formula = parse_prefix_operator(types::LogicPrefixOp::Not, c)?;
                 Ok(formula)
             } else {
-                unreachable!()
+                parse_null_test(op, c)
             }
         }
+        ast::Expression::InList(child, list) => parse_in_list(child, list),
         ast::Expression::Value(value_expr) => match value_expr {
             ast::Value::Boolean(b) => Ok(Box::new(types::Formula::Constant(*b))),
             _ => Err(ParseError::TypeMismatch),
@@ -99,6 +125,10 @@ fn parse_binary_operator(value_expr: &ast::Expression) -> ParseResult<Box<types:
                 || op == &ast::BinaryOperator::LessThan
                 || op == &ast::BinaryOperator::GreaterEqual
                 || op == &ast::BinaryOperator::LessEqual
+                || op == &ast::BinaryOperator::Contains
+                || op == &ast::BinaryOperator::Like
+                || op == &ast::BinaryOperator::NotLike
+                || op == &ast::BinaryOperator::RegexMatch
             {
                 let formula = parse_condition(value_expr)?;
                 Ok(Box::new(types::Expression::Logic(formula)))
@@ -122,12 +152,12 @@ fn parse_binary_operator(value_expr: &ast::Expression) -> ParseResult<Box<types:
 fn parse_unary_operator(value_expr: &ast::Expression) -> ParseResult<Box<types::Expression>> {
     match value_expr {
         ast::Expression::UnaryOperator(op, expr) => {
-            if op == &ast::UnaryOperator::Not {
-                let formula = parse_prefix_operator(types::LogicPrefixOp::Not, expr)?;
-                Ok(Box::new(types::Expression::Logic(formula)))
+            let formula = if op == &ast::UnaryOperator::Not {
+                parse_prefix_operator(types::LogicPrefixOp::Not, expr)?
             } else {
-                unreachable!();
-            }
+                parse_null_test(op, expr)?
+            };
+            Ok(Box::new(types::Expression::Logic(formula)))
         }
         _ => {
             unreachable!();
@@ -144,6 +174,21 @@ fn parse_value_expression(value_expr: &ast::Expression) -> ParseResult<Box<types
         ast::Expression::Column(column_name) => Ok(Box::new(types::Expression::Variable(column_name.clone()))),
         ast::Expression::BinaryOperator(_, _, _) => parse_binary_operator(value_expr),
         ast::Expression::UnaryOperator(_, _) => parse_unary_operator(value_expr),
+        ast::Expression::InList(child, list) => {
+            let formula = parse_in_list(child, list)?;
+            Ok(Box::new(types::Expression::Logic(formula)))
+        }
+        ast::Expression::FuncCall(func_name, select_exprs, _) if func_name == "prev" => match &select_exprs[..] {
+            [ast::SelectExpression::Expression(expr, _)] => match &**expr {
+                ast::Expression::Column(column_name) => Ok(Box::new(types::Expression::Variable(format!(
+                    "{}{}",
+                    execution::stream::PREVIOUS_PREFIX,
+                    column_name
+                )))),
+                _ => Err(ParseError::InvalidArguments("prev".to_string())),
+            },
+            _ => Err(ParseError::InvalidArguments("prev".to_string())),
+        },
         ast::Expression::FuncCall(func_name, select_exprs, _) => {
             let mut args = Vec::new();
             for select_expr in select_exprs.iter() {
@@ -163,11 +208,162 @@ fn parse_relation(op: &ast::BinaryOperator) -> ParseResult<types::Relation> {
         ast::BinaryOperator::LessEqual => Ok(types::Relation::LessEqual),
         ast::BinaryOperator::LessThan => Ok(types::Relation::LessThan),
         ast::BinaryOperator::MoreThan => Ok(types::Relation::MoreThan),
+        ast::BinaryOperator::Contains => Ok(types::Relation::Contains),
+        ast::BinaryOperator::Like => Ok(types::Relation::Like),
+        ast::BinaryOperator::NotLike => Ok(types::Relation::NotLike),
+        ast::BinaryOperator::RegexMatch => Ok(types::Relation::RegexMatch),
         _ => unreachable!(),
     }
 }
 
+fn is_ordering_comparison(op: &ast::BinaryOperator) -> bool {
+    matches!(
+        op,
+        ast::BinaryOperator::LessThan
+            | ast::BinaryOperator::LessEqual
+            | ast::BinaryOperator::MoreThan
+            | ast::BinaryOperator::GreaterEqual
+    )
+}
+
+//The operands and operators of a chained comparison, 100 < x < 200 parses as (100 < x) < 200
+fn chained_comparison(expr: &ast::Expression) -> (Vec<ast::Expression>, Vec<ast::BinaryOperator>) {
+    match expr {
+        ast::Expression::BinaryOperator(op, left, right) if is_ordering_comparison(op) => {
+            let (mut operands, mut ops) = chained_comparison(left);
+            operands.push(*right.clone());
+            ops.push(op.clone());
+            (operands, ops)
+        }
+        _ => (vec![expr.clone()], Vec::new()),
+    }
+}
+
+//Replace the inner operands of the chained comparisons by a hidden column so each one is evaluated once
+fn bind_chained_operands(
+    expr: &ast::Expression,
+    bindings: &mut Vec<(common::VariableName, ast::Expression)>,
+) -> ast::Expression {
+    match expr {
+        ast::Expression::BinaryOperator(op, left, right)
+            if op == &ast::BinaryOperator::And || op == &ast::BinaryOperator::Or =>
+        {
+            ast::Expression::BinaryOperator(
+                op.clone(),
+                Box::new(bind_chained_operands(left, bindings)),
+                Box::new(bind_chained_operands(right, bindings)),
+            )
+        }
+        ast::Expression::UnaryOperator(op, child) => {
+            ast::Expression::UnaryOperator(op.clone(), Box::new(bind_chained_operands(child, bindings)))
+        }
+        ast::Expression::BinaryOperator(op, left, right) => {
+            let (operands, ops) = chained_comparison(expr);
+            if ops.len() < 2 {
+                return ast::Expression::BinaryOperator(
+                    op.clone(),
+                    Box::new(bind_chained_operands(left, bindings)),
+                    Box::new(bind_chained_operands(right, bindings)),
+                );
+            }
+
+            let operands: Vec<ast::Expression> = operands
+                .iter()
+                .map(|operand| bind_chained_operands(operand, bindings))
+                .collect();
+            let last = operands.len() - 1;
+            let mut operands = operands.into_iter().enumerate().map(|(idx, operand)| match operand {
+                ast::Expression::Column(_) | ast::Expression::Value(_) => operand,
+                _ if idx == 0 || idx == last => operand,
+                _ => {
+                    let name = format!("__chain_{}", bindings.len());
+                    bindings.push((name.clone(), operand));
+                    ast::Expression::Column(name)
+                }
+            });
+            let first = operands.next().unwrap();
+            ops.into_iter().zip(operands).fold(first, |chain, (op, operand)| {
+                ast::Expression::BinaryOperator(op, Box::new(chain), Box::new(operand))
+            })
+        }
+        ast::Expression::InList(child, list) => {
+            ast::Expression::InList(Box::new(bind_chained_operands(child, bindings)), list.clone())
+        }
+        ast::Expression::FuncCall(func_name, select_exprs, within_group_opt) => ast::Expression::FuncCall(
+            func_name.clone(),
+            select_exprs
+                .iter()
+                .map(|select_expr| bind_select_chained_operands(select_expr, bindings))
+                .collect(),
+            within_group_opt.clone(),
+        ),
+        _ => expr.clone(),
+    }
+}
+
+fn bind_select_chained_operands(
+    select_expr: &ast::SelectExpression,
+    bindings: &mut Vec<(common::VariableName, ast::Expression)>,
+) -> ast::SelectExpression {
+    match select_expr {
+        ast::SelectExpression::Star => ast::SelectExpression::Star,
+        ast::SelectExpression::Expression(expr, name_opt) => {
+            ast::SelectExpression::Expression(Box::new(bind_chained_operands(expr, bindings)), name_opt.clone())
+        }
+    }
+}
+
+//A Map adding the bound operands of chained comparisons to the records, outside of the Map of the select list
+fn chain_bindings_map(
+    bindings: &[(common::VariableName, ast::Expression)],
+    source: types::Node,
+) -> ParseResult<types::Node> {
+    let mut named_list = vec![types::Named::Star];
+    for (name, expr) in bindings.iter() {
+        named_list.push(types::Named::Expression(
+            *parse_value_expression(expr)?,
+            Some(name.clone()),
+        ));
+    }
+
+    Ok(types::Node::Map(named_list, Box::new(source)))
+}
+
+//The groups a HAVING clause keeps, the inner operands of its chained comparisons are bound above the groups
+fn having_filter(
+    having_expr: &ast::WhereExpression,
+    bindings: &mut Vec<(common::VariableName, ast::Expression)>,
+    source: types::Node,
+) -> ParseResult<types::Node> {
+    let start = bindings.len();
+    let expr = bind_chained_operands(&having_expr.expr, bindings);
+    if bindings.len() == start {
+        return Ok(types::Node::Filter(parse_logic(&expr)?, Box::new(source)));
+    }
+
+    let root = chain_bindings_map(&bindings[start..], source)?;
+    let root = types::Node::Filter(parse_logic(&expr)?, Box::new(root));
+    let hidden = bindings[start..].iter().map(|(name, _)| name.clone()).collect();
+    Ok(types::Node::DropColumns(hidden, Box::new(root)))
+}
+
 fn parse_condition(condition: &ast::Expression) -> ParseResult<Box<types::Formula>> {
+    let (operands, ops) = chained_comparison(condition);
+    if ops.len() > 1 {
+        let mut formula: Option<Box<types::Formula>> = None;
+        for (idx, op) in ops.iter().enumerate() {
+            let left = parse_value_expression(&operands[idx])?;
+            let right = parse_value_expression(&operands[idx + 1])?;
+            let predicate = Box::new(types::Formula::Predicate(parse_relation(op)?, left, right));
+            formula = Some(match formula {
+                Some(f) => Box::new(types::Formula::InfixOperator(types::LogicInfixOp::And, f, predicate)),
+                None => predicate,
+            });
+        }
+
+        return Ok(formula.unwrap());
+    }
+
     match condition {
         ast::Expression::BinaryOperator(op, left_expr, right_expr) => {
             let left = parse_value_expression(left_expr)?;
@@ -201,6 +397,11 @@ fn parse_expression(select_expr: &ast::SelectExpression) -> ParseResult<Box<type
 fn from_str(value: &str, named: types::Named) -> ParseResult<types::Aggregate> {
     match value {
         "avg" => Ok(types::Aggregate::Avg(named)),
+        "geo_mean" => Ok(types::Aggregate::GeoMean(named)),
+        "entropy" => Ok(types::Aggregate::Entropy(named)),
+        "distinct_ratio" => Ok(types::Aggregate::DistinctRatio(named)),
+        "variance" => Ok(types::Aggregate::Variance(named)),
+        "stddev" => Ok(types::Aggregate::StdDev(named)),
         "count" => Ok(types::Aggregate::Count(named)),
         "first" => Ok(types::Aggregate::First(named)),
         "last" => Ok(types::Aggregate::Last(named)),
@@ -219,10 +420,107 @@ fn parse_ordering(ordering: ast::Ordering) -> ParseResult<types::Ordering> {
     }
 }
 
-fn parse_aggregate(select_expr: &ast::SelectExpression) -> ParseResult<types::NamedAggregate> {
+//The column of how long the backend took on a table that has one, null when the backend never responded
+fn backend_processing_time_column(table_name: &str) -> Option<&'static str> {
+    match table_name {
+        "elb" => Some("backend_processing_time"),
+        "alb" => Some("target_processing_time"),
+        _ => None,
+    }
+}
+
+fn parse_aggregate(select_expr: &ast::SelectExpression, table_name: &str) -> ParseResult<types::NamedAggregate> {
     match select_expr {
         ast::SelectExpression::Expression(expr, name_opt) => match &**expr {
             ast::Expression::FuncCall(func_name, args, within_group_opt) => {
+                if func_name == "count_buckets" {
+                    if args.is_empty() || within_group_opt.is_some() {
+                        return Err(ParseError::InvalidArguments("count_buckets".to_string()));
+                    }
+
+                    let mut formulas = Vec::new();
+                    for arg in args.iter() {
+                        match arg {
+                            ast::SelectExpression::Expression(expr, _) => formulas.push(*parse_logic(expr)?),
+                            ast::SelectExpression::Star => {
+                                return Err(ParseError::InvalidArguments("count_buckets".to_string()));
+                            }
+                        }
+                    }
+
+                    let aggregate = types::Aggregate::CountBuckets(formulas);
+                    return Ok(types::NamedAggregate::new(aggregate, name_opt.clone()));
+                }
+
+                if func_name == "conditional_ratio" {
+                    if within_group_opt.is_some() {
+                        return Err(ParseError::InvalidArguments("conditional_ratio".to_string()));
+                    }
+
+                    let mut formulas = Vec::new();
+                    for arg in args.iter() {
+                        match arg {
+                            ast::SelectExpression::Expression(expr, _) => formulas.push(*parse_logic(expr)?),
+                            ast::SelectExpression::Star => {
+                                return Err(ParseError::InvalidArguments("conditional_ratio".to_string()));
+                            }
+                        }
+                    }
+
+                    if formulas.len() != 2 {
+                        return Err(ParseError::InvalidArguments("conditional_ratio".to_string()));
+                    }
+                    let denominator = formulas.pop().unwrap();
+                    let numerator = formulas.pop().unwrap();
+
+                    let aggregate = types::Aggregate::ConditionalRatio(numerator, denominator);
+                    return Ok(types::NamedAggregate::new(aggregate, name_opt.clone()));
+                }
+
+                //The share of the rows whose backend never responded, their processing time is null. The
+                //column is the one of the table unless given.
+                if func_name == "backend_failure_rate" {
+                    let invalid_arguments = || ParseError::InvalidArguments("backend_failure_rate".to_string());
+                    if within_group_opt.is_some() {
+                        return Err(invalid_arguments());
+                    }
+
+                    let processing_time = match args.as_slice() {
+                        [] => {
+                            let column_name =
+                                backend_processing_time_column(table_name).ok_or_else(invalid_arguments)?;
+                            types::Expression::Variable(column_name.to_string())
+                        }
+                        [ast::SelectExpression::Expression(expr, _)] => *parse_value_expression(expr)?,
+                        _ => return Err(invalid_arguments()),
+                    };
+                    let numerator = types::Formula::Predicate(
+                        types::Relation::IsNull,
+                        Box::new(processing_time),
+                        Box::new(types::Expression::Constant(common::Value::Null)),
+                    );
+                    let aggregate = types::Aggregate::ConditionalRatio(numerator, types::Formula::Constant(true));
+                    return Ok(types::NamedAggregate::new(aggregate, name_opt.clone()));
+                }
+
+                if func_name == "group_duration" {
+                    if args.len() > 1 || within_group_opt.is_some() {
+                        return Err(ParseError::InvalidArguments("group_duration".to_string()));
+                    }
+
+                    //Without an argument the span is measured on the timestamp column
+                    let named = match args.first() {
+                        Some(ast::SelectExpression::Star) => {
+                            return Err(ParseError::InvalidArguments("group_duration".to_string()));
+                        }
+                        Some(arg) => *parse_expression(arg)?,
+                        None => types::Named::Expression(types::Expression::Variable("timestamp".to_string()), None),
+                    };
+
+                    let aggregate = types::Aggregate::GroupDuration(named);
+                    return Ok(types::NamedAggregate::new(aggregate, name_opt.clone()));
+                }
+
                 let named = *parse_expression(&args[0])?;
 
                 let aggregate = if let Some(within_group_clause) = within_group_opt {
@@ -238,6 +536,12 @@ fn parse_aggregate(select_expr: &ast::SelectExpression) -> ParseResult<types::Na
                                             within_group_clause.ordering_term.column_name.clone(),
                                             o,
                                         )
+                                    } else if func_name == "percentile_cont" {
+                                        types::Aggregate::PercentileCont(
+                                            f,
+                                            within_group_clause.ordering_term.column_name.clone(),
+                                            o,
+                                        )
                                     } else if func_name == "approx_percentile" {
                                         types::Aggregate::ApproxPercentile(
                                             f,
@@ -274,6 +578,122 @@ fn parse_aggregate(select_expr: &ast::SelectExpression) -> ParseResult<types::Na
     }
 }
 
+fn formula_variables(formula: &types::Formula, column_names: &mut Vec<common::VariableName>) {
+    match formula {
+        types::Formula::InfixOperator(_, left, right) => {
+            formula_variables(left, column_names);
+            formula_variables(right, column_names);
+        }
+        types::Formula::PrefixOperator(_, child) => formula_variables(child, column_names),
+        types::Formula::Constant(_) => {}
+        types::Formula::Predicate(_, left, right) => {
+            expression_variables(left, column_names);
+            expression_variables(right, column_names);
+        }
+        types::Formula::InList(child, list) => {
+            expression_variables(child, column_names);
+            for item in list.iter() {
+                expression_variables(item, column_names);
+            }
+        }
+    }
+}
+
+fn expression_variables(expr: &types::Expression, column_names: &mut Vec<common::VariableName>) {
+    match expr {
+        types::Expression::Constant(_) => {}
+        types::Expression::Variable(name) => {
+            if !column_names.contains(name) {
+                column_names.push(name.clone());
+            }
+        }
+        types::Expression::Logic(formula) => formula_variables(formula, column_names),
+        types::Expression::Function(_, arguments) => {
+            for argument in arguments.iter() {
+                if let types::Named::Expression(arg, _) = argument {
+                    expression_variables(arg, column_names);
+                }
+            }
+        }
+    }
+}
+
+//The columns a query reads from its source, all those it names but the ones it computes itself or takes
+//from a VALUES list. prev(a) reads a as well.
+pub(crate) fn referenced_columns(node: &types::Node) -> Vec<common::VariableName> {
+    let mut referenced = Vec::new();
+    let mut computed = Vec::new();
+    collect_columns(node, &mut referenced, &mut computed);
+
+    let mut column_names: Vec<common::VariableName> = Vec::new();
+    for name in referenced {
+        let name = match name.strip_prefix(execution::stream::PREVIOUS_PREFIX) {
+            Some(column_name) => column_name.to_string(),
+            None => name,
+        };
+        if !computed.contains(&name) && !column_names.contains(&name) {
+            column_names.push(name);
+        }
+    }
+
+    column_names
+}
+
+fn collect_columns(
+    node: &types::Node,
+    referenced: &mut Vec<common::VariableName>,
+    computed: &mut Vec<common::VariableName>,
+) {
+    match node {
+        types::Node::DataSource(common::DataSource::Values(_, column_names), _) => {
+            computed.extend(column_names.iter().cloned())
+        }
+        types::Node::DataSource(_, _) => {}
+        types::Node::Filter(formula, source) => {
+            formula_variables(formula, referenced);
+            collect_columns(source, referenced, computed);
+        }
+        types::Node::Map(named_list, source) => {
+            for named in named_list.iter() {
+                if let types::Named::Expression(expr, name_opt) = named {
+                    expression_variables(expr, referenced);
+                    match (expr, name_opt) {
+                        //A column kept under its own name is read, not computed
+                        (types::Expression::Variable(column_name), Some(name)) if column_name == name => {}
+                        (_, Some(name)) => computed.push(name.clone()),
+                        (_, None) => {}
+                    }
+                }
+            }
+            collect_columns(source, referenced, computed);
+        }
+        types::Node::GroupBy(fields, named_aggregates, source) => {
+            referenced.extend(fields.iter().cloned());
+            for named_aggregate in named_aggregates.iter() {
+                computed.extend(aggregate_output_names(named_aggregate));
+            }
+            collect_columns(source, referenced, computed);
+        }
+        types::Node::OrderBy(column_names, _, source) | types::Node::HashOrder(column_names, source) => {
+            referenced.extend(column_names.iter().cloned());
+            collect_columns(source, referenced, computed);
+        }
+        types::Node::ChangedBy(column_name, source) => {
+            referenced.push(column_name.clone());
+            collect_columns(source, referenced, computed);
+        }
+        types::Node::Limit(_, source)
+        | types::Node::EnforceSchema(_, _, source)
+        | types::Node::Distinct(source)
+        | types::Node::DropColumns(_, source)
+        | types::Node::Unnest(_, source) => collect_columns(source, referenced, computed),
+        types::Node::Tee(left, right) | types::Node::Join(_, left, right) => {
+            collect_columns(left, referenced, computed);
+            collect_columns(right, referenced, computed);
+        }
+    }
+}
+
 fn check_conflict_naming(named_list: &[types::Named]) -> bool {
     let mut name_set: HashSet<String> = HashSet::new();
     for named in named_list {
@@ -300,114 +720,375 @@ fn check_conflict_naming(named_list: &[types::Named]) -> bool {
     false
 }
 
+//The predicates of an aggregate are evaluated after the projection, keep the columns they read
+fn keep_formula_columns(formulas: &[types::Formula], named_list: &mut Vec<types::Named>) {
+    let mut column_names = Vec::new();
+    for formula in formulas.iter() {
+        formula_variables(formula, &mut column_names);
+    }
+
+    for column_name in column_names {
+        let named = types::Named::Expression(types::Expression::Variable(column_name.clone()), Some(column_name));
+        if !named_list.contains(&named) {
+            named_list.push(named);
+        }
+    }
+}
+
+//The columns an aggregate reads, they have to survive the projection in front of the group by
+fn push_aggregate_inputs(aggregate: types::Aggregate, named_list: &mut Vec<types::Named>) -> ParseResult<()> {
+    match aggregate {
+        types::Aggregate::Avg(named) => {
+            if let types::Named::Star = named {
+                return Err(ParseError::InvalidArguments("avg".to_string()));
+            }
+
+            named_list.push(named);
+        }
+        types::Aggregate::GeoMean(named) => {
+            if let types::Named::Star = named {
+                return Err(ParseError::InvalidArguments("geo_mean".to_string()));
+            }
+
+            named_list.push(named);
+        }
+        types::Aggregate::Entropy(named) => {
+            if let types::Named::Star = named {
+                return Err(ParseError::InvalidArguments("entropy".to_string()));
+            }
+
+            named_list.push(named);
+        }
+        types::Aggregate::DistinctRatio(named) => {
+            if let types::Named::Star = named {
+                return Err(ParseError::InvalidArguments("distinct_ratio".to_string()));
+            }
+
+            named_list.push(named);
+        }
+        types::Aggregate::Variance(named) => {
+            if let types::Named::Star = named {
+                return Err(ParseError::InvalidArguments("variance".to_string()));
+            }
+
+            named_list.push(named);
+        }
+        types::Aggregate::StdDev(named) => {
+            if let types::Named::Star = named {
+                return Err(ParseError::InvalidArguments("stddev".to_string()));
+            }
+
+            named_list.push(named);
+        }
+        types::Aggregate::Count(named) => {
+            named_list.push(named);
+        }
+        types::Aggregate::First(named) => {
+            if let types::Named::Star = named {
+                return Err(ParseError::InvalidArguments("first".to_string()));
+            }
+            named_list.push(named);
+        }
+        types::Aggregate::Last(named) => {
+            if let types::Named::Star = named {
+                return Err(ParseError::InvalidArguments("last".to_string()));
+            }
+            named_list.push(named);
+        }
+        types::Aggregate::Sum(named) => {
+            if let types::Named::Star = named {
+                return Err(ParseError::InvalidArguments("sum".to_string()));
+            }
+            named_list.push(named);
+        }
+        types::Aggregate::Max(named) => {
+            if let types::Named::Star = named {
+                return Err(ParseError::InvalidArguments("max".to_string()));
+            }
+            named_list.push(named);
+        }
+        types::Aggregate::Min(named) => {
+            if let types::Named::Star = named {
+                return Err(ParseError::InvalidArguments("min".to_string()));
+            }
+            named_list.push(named);
+        }
+        types::Aggregate::ApproxCountDistinct(named) => {
+            if let types::Named::Star = named {
+                return Err(ParseError::InvalidArguments("approx_count_distinct".to_string()));
+            }
+            named_list.push(named);
+        }
+        types::Aggregate::PercentileDisc(_, column_name, _) => named_list.push(types::Named::Expression(
+            types::Expression::Variable(column_name.clone()),
+            Some(column_name.clone()),
+        )),
+        types::Aggregate::PercentileCont(_, column_name, _) => named_list.push(types::Named::Expression(
+            types::Expression::Variable(column_name.clone()),
+            Some(column_name.clone()),
+        )),
+        types::Aggregate::ApproxPercentile(_, column_name, _) => named_list.push(types::Named::Expression(
+            types::Expression::Variable(column_name.clone()),
+            Some(column_name.clone()),
+        )),
+        types::Aggregate::CountBuckets(formulas) => keep_formula_columns(&formulas, named_list),
+        types::Aggregate::GroupDuration(named) => {
+            if !named_list.contains(&named) {
+                named_list.push(named);
+            }
+        }
+        types::Aggregate::ConditionalRatio(numerator, denominator) => {
+            keep_formula_columns(&[numerator, denominator], named_list)
+        }
+    }
+
+    Ok(())
+}
+
+//The columns an aggregate adds to the grouped record, count_buckets has one per predicate
+fn aggregate_output_names(named_aggregate: &types::NamedAggregate) -> Vec<common::VariableName> {
+    match &named_aggregate.aggregate {
+        types::Aggregate::CountBuckets(formulas) => {
+            let prefix = named_aggregate
+                .name_opt
+                .clone()
+                .unwrap_or_else(|| "count_buckets".to_string());
+            (0..formulas.len()).map(|i| format!("{}_{}", prefix, i)).collect()
+        }
+        _ => vec![named_aggregate.name_opt.clone().unwrap_or_default()],
+    }
+}
+
+//The data source of a VALUES list, every row has to fill each of the named columns
+pub(crate) fn parse_values(values: &ast::ValuesList) -> ParseResult<common::DataSource> {
+    let mut rows = Vec::with_capacity(values.rows.len());
+    for (idx, row) in values.rows.iter().enumerate() {
+        if row.len() != values.column_names.len() {
+            return Err(ParseError::ValuesColumnCount(
+                idx + 1,
+                row.len(),
+                values.column_names.len(),
+            ));
+        }
+
+        let row = row
+            .iter()
+            .map(|value| match value {
+                ast::Value::Boolean(b) => common::Value::Boolean(*b),
+                ast::Value::Float(f) => common::Value::Float(*f),
+                ast::Value::Integral(i) => common::Value::Int(*i),
+                ast::Value::StringLiteral(s) => common::Value::String(s.clone()),
+            })
+            .collect();
+        rows.push(row);
+    }
+
+    Ok(common::DataSource::Values(rows, values.column_names.clone()))
+}
+
 pub(crate) fn parse_query(query: ast::SelectStatement, data_source: common::DataSource) -> ParseResult<types::Node> {
-    let mut root = types::Node::DataSource(data_source, query.table_name.clone());
+    let data_source = match &query.values_opt {
+        Some(values) => parse_values(values)?,
+        None => data_source,
+    };
+    let mut root = types::Node::DataSource(data_source.clone(), query.table_name.clone());
+    if let Some(join) = &query.join_opt {
+        let right = types::Node::DataSource(parse_values(&join.values)?, join.table_name.clone());
+        let join_type = match join.join_type {
+            ast::JoinType::Cross => execution::types::JoinType::Cross,
+        };
+        root = types::Node::Join(join_type, Box::new(root), Box::new(right));
+    }
     let mut named_aggregates = Vec::new();
     let mut named_list: Vec<types::Named> = Vec::new();
     let mut non_aggregates: Vec<types::Named> = Vec::new();
 
+    //Aggregates in the ORDER BY clause are computed under a hidden name and dropped after the sort
+    let mut order_by_columns = Vec::new();
+    let mut hidden_aggregates = Vec::new();
+    if let Some(order_by_expr) = &query.order_by_expr_opt {
+        for (idx, ordering_term) in order_by_expr.ordering_terms.iter().enumerate() {
+            if let Some(aggregate) = &ordering_term.aggregate_opt {
+                let hidden_name = format!("__order_by_{}", idx);
+                let select_expr = ast::SelectExpression::Expression(aggregate.clone(), Some(hidden_name.clone()));
+                hidden_aggregates.push(parse_aggregate(&select_expr, &query.table_name)?);
+                order_by_columns.push(hidden_name);
+            } else {
+                order_by_columns.push(ordering_term.column_name.clone());
+            }
+        }
+    }
+    let hidden_columns: Vec<common::VariableName> =
+        hidden_aggregates.iter().filter_map(|a| a.name_opt.clone()).collect();
+
+    //An expression over the aliases of the aggregates can only be computed on the grouped records, by a
+    //second Map above the group by listing every selected column in order
+    let aggregate_names: Vec<common::VariableName> = query
+        .select_exprs
+        .iter()
+        .filter_map(|select_expr| parse_aggregate(select_expr, &query.table_name).ok())
+        .filter_map(|named_aggregate| named_aggregate.name_opt)
+        .collect();
+    let mut output_list: Vec<types::Named> = Vec::new();
+    let mut has_post_aggregates = false;
+
+    //The inner operands of chained comparisons are computed once and dropped after their use. Those of the
+    //select list by a Map below the one of the select list, except over the aggregates, and those of the
+    //WHERE clause by the Map of the select list.
+    let mut chain_bindings = Vec::new();
+    let select_exprs: Vec<ast::SelectExpression> = query
+        .select_exprs
+        .iter()
+        .map(|select_expr| {
+            let over_aggregates = match parse_expression(select_expr) {
+                Ok(named) => match *named {
+                    types::Named::Expression(expr, _) => {
+                        let mut column_names = Vec::new();
+                        expression_variables(&expr, &mut column_names);
+                        column_names.iter().any(|name| aggregate_names.contains(name))
+                    }
+                    types::Named::Star => false,
+                },
+                Err(_) => false,
+            };
+            if over_aggregates {
+                select_expr.clone()
+            } else {
+                bind_select_chained_operands(select_expr, &mut chain_bindings)
+            }
+        })
+        .collect();
+    let select_bindings: Vec<(common::VariableName, ast::Expression)> = chain_bindings.clone();
+    let where_expr_opt = query
+        .where_expr_opt
+        .as_ref()
+        .map(|where_expr| bind_chained_operands(&where_expr.expr, &mut chain_bindings));
+    let where_bindings = chain_bindings[select_bindings.len()..].to_vec();
+    let mut hidden_chains: Vec<common::VariableName> = where_bindings.iter().map(|(name, _)| name.clone()).collect();
+
     if !query.select_exprs.is_empty() {
-        for select_expr in query.select_exprs.iter() {
-            if let Ok(named_aggregate) = parse_aggregate(select_expr) {
+        for select_expr in select_exprs.iter() {
+            if let Ok(named_aggregate) = parse_aggregate(select_expr, &query.table_name) {
                 named_aggregates.push(named_aggregate.clone());
+                for name in aggregate_output_names(&named_aggregate) {
+                    output_list.push(types::Named::Expression(
+                        types::Expression::Variable(name.clone()),
+                        Some(name),
+                    ));
+                }
 
-                match named_aggregate.aggregate {
-                    types::Aggregate::Avg(named) => {
-                        if let types::Named::Star = named {
-                            return Err(ParseError::InvalidArguments("avg".to_string()));
-                        }
-
-                        named_list.push(named);
-                    }
-                    types::Aggregate::Count(named) => {
-                        named_list.push(named);
-                    }
-                    types::Aggregate::First(named) => {
-                        if let types::Named::Star = named {
-                            return Err(ParseError::InvalidArguments("first".to_string()));
-                        }
-                        named_list.push(named);
-                    }
-                    types::Aggregate::Last(named) => {
-                        if let types::Named::Star = named {
-                            return Err(ParseError::InvalidArguments("last".to_string()));
-                        }
-                        named_list.push(named);
-                    }
-                    types::Aggregate::Sum(named) => {
-                        if let types::Named::Star = named {
-                            return Err(ParseError::InvalidArguments("sum".to_string()));
-                        }
-                        named_list.push(named);
-                    }
-                    types::Aggregate::Max(named) => {
-                        if let types::Named::Star = named {
-                            return Err(ParseError::InvalidArguments("max".to_string()));
-                        }
-                        named_list.push(named);
-                    }
-                    types::Aggregate::Min(named) => {
-                        if let types::Named::Star = named {
-                            return Err(ParseError::InvalidArguments("min".to_string()));
-                        }
-                        named_list.push(named);
+                push_aggregate_inputs(named_aggregate.aggregate, &mut named_list)?;
+            } else {
+                let named = *parse_expression(select_expr)?;
+                if let types::Named::Expression(expr, name_opt) = &named {
+                    let mut column_names = Vec::new();
+                    expression_variables(expr, &mut column_names);
+                    if column_names.iter().any(|name| aggregate_names.contains(name)) {
+                        has_post_aggregates = true;
+                        output_list.push(named);
+                        continue;
                     }
-                    types::Aggregate::ApproxCountDistinct(named) => {
-                        if let types::Named::Star = named {
-                            return Err(ParseError::InvalidArguments("approx_count_distinct".to_string()));
-                        }
-                        named_list.push(named);
+
+                    match name_opt {
+                        Some(name) => output_list.push(types::Named::Expression(
+                            types::Expression::Variable(name.clone()),
+                            Some(name.clone()),
+                        )),
+                        None => output_list.push(named.clone()),
                     }
-                    types::Aggregate::PercentileDisc(_, column_name, _) => named_list.push(types::Named::Expression(
-                        types::Expression::Variable(column_name.clone()),
-                        Some(column_name.clone()),
-                    )),
-                    types::Aggregate::ApproxPercentile(_, column_name, _) => named_list.push(types::Named::Expression(
-                        types::Expression::Variable(column_name.clone()),
-                        Some(column_name.clone()),
-                    )),
+                } else {
+                    output_list.push(types::Named::Star);
                 }
-            } else {
-                let named = *parse_expression(select_expr)?;
+
                 non_aggregates.push(named.clone());
                 named_list.push(named);
             }
         }
 
+        for named_aggregate in hidden_aggregates {
+            let mut inputs = Vec::new();
+            push_aggregate_inputs(named_aggregate.aggregate.clone(), &mut inputs)?;
+            for named in inputs {
+                if !named_list.contains(&named) {
+                    named_list.push(named);
+                }
+            }
+            named_aggregates.push(named_aggregate);
+        }
+
+        for (name, expr) in where_bindings.iter() {
+            named_list.push(types::Named::Expression(
+                *parse_value_expression(expr)?,
+                Some(name.clone()),
+            ));
+        }
+
         if check_conflict_naming(&named_list) {
             return Err(ParseError::ConflictVariableNaming);
         }
 
+        //Every window of a hop() in the select list is a row of its own, named as the Map names the column
+        let hop_columns: Vec<common::VariableName> = named_list
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, named)| match named {
+                types::Named::Expression(types::Expression::Function(name, _), name_opt) if name == "hop" => {
+                    Some(name_opt.clone().unwrap_or_else(|| format!("{:02}", idx)))
+                }
+                _ => None,
+            })
+            .collect();
+
+        if !select_bindings.is_empty() {
+            root = chain_bindings_map(&select_bindings, root)?;
+            if named_list.contains(&types::Named::Star) {
+                hidden_chains.extend(select_bindings.iter().map(|(name, _)| name.clone()));
+            }
+        }
         root = types::Node::Map(named_list, Box::new(root));
+        for column_name in hop_columns {
+            root = types::Node::Unnest(column_name, Box::new(root));
+        }
     }
 
-    if let Some(where_expr) = query.where_expr_opt {
-        let filter_formula = parse_logic(&where_expr.expr)?;
+    if let Some(where_expr) = where_expr_opt {
+        let filter_formula = parse_logic(&where_expr)?;
         root = types::Node::Filter(filter_formula, Box::new(root));
     }
+    if !hidden_chains.is_empty() {
+        root = types::Node::DropColumns(hidden_chains, Box::new(root));
+    }
 
     if !named_aggregates.is_empty() {
         if let Some(group_by) = query.group_by_exprs_opt {
             let fields = group_by.exprs.clone();
 
-            if !is_match_group_by_fields(&fields, &non_aggregates, &query.table_name) {
+            if !is_match_group_by_fields(&fields, &non_aggregates, &query.table_name, &data_source) {
                 return Err(ParseError::GroupByFieldsMismatch);
             }
 
             root = types::Node::GroupBy(fields, named_aggregates, Box::new(root));
 
-            if let Some(having_expr) = query.having_expr_opt {
-                let filter_formula = parse_logic(&having_expr.expr)?;
-                root = types::Node::Filter(filter_formula, Box::new(root));
+            if let Some(having_expr) = &query.having_expr_opt {
+                root = having_filter(having_expr, &mut chain_bindings, root)?;
             }
         } else {
             let fields = Vec::new();
             root = types::Node::GroupBy(fields, named_aggregates, Box::new(root));
 
-            if let Some(having_expr) = query.having_expr_opt {
-                let filter_formula = parse_logic(&having_expr.expr)?;
-                root = types::Node::Filter(filter_formula, Box::new(root));
+            if let Some(having_expr) = &query.having_expr_opt {
+                root = having_filter(having_expr, &mut chain_bindings, root)?;
+            }
+        }
+
+        if has_post_aggregates {
+            for hidden_column in hidden_columns.iter() {
+                output_list.push(types::Named::Expression(
+                    types::Expression::Variable(hidden_column.clone()),
+                    Some(hidden_column.clone()),
+                ));
             }
+            root = types::Node::Map(output_list, Box::new(root));
         }
     } else {
         //sanity check if there is a group by statement
@@ -420,16 +1101,22 @@ pub(crate) fn parse_query(query: ast::SelectStatement, data_source: common::Data
         }
     }
 
+    if query.distinct {
+        root = types::Node::Distinct(Box::new(root));
+    }
+
     if let Some(order_by_expr) = query.order_by_expr_opt {
-        let mut column_names = Vec::new();
         let mut orderings = Vec::new();
         for ordering_term in order_by_expr.ordering_terms {
-            column_names.push(ordering_term.column_name.clone());
             let ordering = parse_ordering(ordering_term.ordering)?;
             orderings.push(ordering);
         }
 
-        root = types::Node::OrderBy(column_names, orderings, Box::new(root));
+        root = types::Node::OrderBy(order_by_columns, orderings, Box::new(root));
+
+        if !hidden_columns.is_empty() {
+            root = types::Node::DropColumns(hidden_columns, Box::new(root));
+        }
     }
 
     if let Some(limit_expr) = query.limit_expr_opt {
@@ -439,7 +1126,12 @@ pub(crate) fn parse_query(query: ast::SelectStatement, data_source: common::Data
     Ok(root)
 }
 
-fn is_match_group_by_fields(variables: &[common::VariableName], named_list: &[types::Named], table_name: &str) -> bool {
+fn is_match_group_by_fields(
+    variables: &[common::VariableName],
+    named_list: &[types::Named],
+    table_name: &str,
+    data_source: &common::DataSource,
+) -> bool {
     let mut a: Vec<String> = variables.to_vec();
     let mut b: Vec<String> = Vec::new();
 
@@ -460,7 +1152,9 @@ fn is_match_group_by_fields(variables: &[common::VariableName], named_list: &[ty
                 }
             }
             types::Named::Star => {
-                if table_name == "elb" {
+                if let common::DataSource::Values(_, column_names) = data_source {
+                    b.extend(column_names.iter().cloned());
+                } else if table_name == "elb" {
                     for field_name in execution::datasource::ClassicLoadBalancerLogField::field_names().into_iter() {
                         b.push(field_name);
                     }
@@ -477,6 +1171,13 @@ fn is_match_group_by_fields(variables: &[common::VariableName], named_list: &[ty
                     for field_name in execution::datasource::S3Field::field_names().into_iter() {
                         b.push(field_name);
                     }
+                } else if table_name == "nginx" {
+                    for field_name in execution::datasource::NginxLogField::field_names().into_iter() {
+                        b.push(field_name);
+                    }
+                } else if table_name == "w3c" || table_name == "json" {
+                    //The fields are only known once the log is read
+                    return false;
                 } else {
                     unreachable!();
                 }
@@ -587,10 +1288,49 @@ mod test {
         let named = types::Named::Expression(types::Expression::Variable("a".to_string()), Some("a".to_string()));
         let expected = types::NamedAggregate::new(types::Aggregate::Avg(named), None);
 
-        let ans = parse_aggregate(&before).unwrap();
+        let ans = parse_aggregate(&before, "elb").unwrap();
         assert_eq!(expected, ans);
     }
 
+    #[test]
+    fn test_parse_backend_failure_rate() {
+        let failure_rate = |args: Vec<ast::SelectExpression>| {
+            ast::SelectExpression::Expression(
+                Box::new(ast::Expression::FuncCall(
+                    "backend_failure_rate".to_string(),
+                    args,
+                    None,
+                )),
+                None,
+            )
+        };
+        let expected = |column_name: &str| {
+            let numerator = types::Formula::Predicate(
+                types::Relation::IsNull,
+                Box::new(types::Expression::Variable(column_name.to_string())),
+                Box::new(types::Expression::Constant(common::Value::Null)),
+            );
+            let aggregate = types::Aggregate::ConditionalRatio(numerator, types::Formula::Constant(true));
+            Ok(types::NamedAggregate::new(aggregate, None))
+        };
+
+        //The processing time column of the table, or the given one
+        assert_eq!(
+            expected("backend_processing_time"),
+            parse_aggregate(&failure_rate(vec![]), "elb")
+        );
+        assert_eq!(
+            expected("target_processing_time"),
+            parse_aggregate(&failure_rate(vec![]), "alb")
+        );
+        let column = ast::SelectExpression::Expression(Box::new(ast::Expression::Column("t".to_string())), None);
+        assert_eq!(expected("t"), parse_aggregate(&failure_rate(vec![column]), "squid"));
+        assert_eq!(
+            Err(ParseError::InvalidArguments("backend_failure_rate".to_string())),
+            parse_aggregate(&failure_rate(vec![]), "squid")
+        );
+    }
+
     #[test]
     fn test_parse_condition() {
         let before = ast::Expression::BinaryOperator(
@@ -713,6 +1453,149 @@ mod test {
         assert_eq!(expected, ans);
     }
 
+    #[test]
+    fn test_parse_query_with_expression_over_aggregates() {
+        let (_, select_stmt) = crate::syntax::parser::select_query(
+            "select backend_and_port, sum(sent_bytes) as bytes, count(*) as total, bytes / total as per_request from elb group by backend_and_port",
+        )
+        .unwrap();
+        let ans = parse_query(select_stmt, common::DataSource::Stdin).unwrap();
+
+        let variable = |name: &str| {
+            types::Named::Expression(types::Expression::Variable(name.to_string()), Some(name.to_string()))
+        };
+        match ans {
+            types::Node::Map(named_list, source) => {
+                assert_eq!(
+                    vec![
+                        variable("backend_and_port"),
+                        variable("bytes"),
+                        variable("total"),
+                        types::Named::Expression(
+                            types::Expression::Function(
+                                "Divide".to_string(),
+                                vec![
+                                    types::Named::Expression(types::Expression::Variable("bytes".to_string()), None),
+                                    types::Named::Expression(types::Expression::Variable("total".to_string()), None),
+                                ]
+                            ),
+                            Some("per_request".to_string())
+                        ),
+                    ],
+                    named_list
+                );
+                match *source {
+                    types::Node::GroupBy(fields, _, _) => assert_eq!(vec!["backend_and_port".to_string()], fields),
+                    other => panic!("unexpected {:?}", other),
+                }
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_query_with_chained_comparison() {
+        let (_, select_stmt) =
+            crate::syntax::parser::select_query("select a from elb where 100 < a < 200 and 1 < b + 1 < 5").unwrap();
+        let ans = parse_query(select_stmt, common::DataSource::Stdin).unwrap();
+
+        let variable = |name: &str| Box::new(types::Expression::Variable(name.to_string()));
+        let constant = |i: i32| Box::new(types::Expression::Constant(common::Value::Int(i)));
+        let predicate = |rel: types::Relation, l, r| Box::new(types::Formula::Predicate(rel, l, r));
+        let and = |l, r| Box::new(types::Formula::InfixOperator(types::LogicInfixOp::And, l, r));
+        let expected_formula = and(
+            and(
+                predicate(types::Relation::LessThan, constant(100), variable("a")),
+                predicate(types::Relation::LessThan, variable("a"), constant(200)),
+            ),
+            and(
+                predicate(types::Relation::LessThan, constant(1), variable("__chain_0")),
+                predicate(types::Relation::LessThan, variable("__chain_0"), constant(5)),
+            ),
+        );
+
+        match ans {
+            types::Node::DropColumns(hidden, source) => {
+                assert_eq!(vec!["__chain_0".to_string()], hidden);
+                match *source {
+                    types::Node::Filter(formula, source) => {
+                        assert_eq!(expected_formula, formula);
+                        match *source {
+                            types::Node::Map(named_list, _) => {
+                                assert_eq!(2, named_list.len());
+                                assert_eq!(
+                                    types::Named::Expression(
+                                        types::Expression::Function(
+                                            "Plus".to_string(),
+                                            vec![
+                                                types::Named::Expression(*variable("b"), None),
+                                                types::Named::Expression(*constant(1), None),
+                                            ]
+                                        ),
+                                        Some("__chain_0".to_string())
+                                    ),
+                                    named_list[1]
+                                );
+                            }
+                            other => panic!("unexpected {:?}", other),
+                        }
+                    }
+                    other => panic!("unexpected {:?}", other),
+                }
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_query_with_chained_comparison_in_select() {
+        let (_, select_stmt) =
+            crate::syntax::parser::select_query("select 1 < b + 1 < 5 as in_range from elb").unwrap();
+        let ans = parse_query(select_stmt, common::DataSource::Stdin).unwrap();
+
+        let variable = |name: &str| Box::new(types::Expression::Variable(name.to_string()));
+        let constant = |i: i32| Box::new(types::Expression::Constant(common::Value::Int(i)));
+        let predicate = |rel: types::Relation, l, r| Box::new(types::Formula::Predicate(rel, l, r));
+        let expected_formula = Box::new(types::Formula::InfixOperator(
+            types::LogicInfixOp::And,
+            predicate(types::Relation::LessThan, constant(1), variable("__chain_0")),
+            predicate(types::Relation::LessThan, variable("__chain_0"), constant(5)),
+        ));
+
+        //b + 1 is computed once by a Map of its own, below the select list
+        match ans {
+            types::Node::Map(named_list, source) => {
+                assert_eq!(
+                    vec![types::Named::Expression(
+                        types::Expression::Logic(expected_formula),
+                        Some("in_range".to_string())
+                    )],
+                    named_list
+                );
+                match *source {
+                    types::Node::Map(named_list, _) => assert_eq!(
+                        vec![
+                            types::Named::Star,
+                            types::Named::Expression(
+                                types::Expression::Function(
+                                    "Plus".to_string(),
+                                    vec![
+                                        types::Named::Expression(*variable("b"), None),
+                                        types::Named::Expression(*constant(1), None),
+                                    ]
+                                ),
+                                Some("__chain_0".to_string())
+                            ),
+                        ],
+                        named_list
+                    ),
+                    other => panic!("unexpected {:?}", other),
+                }
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
     #[test]
     fn test_parse_query_group_by_without_aggregate() {
         let select_exprs = vec![
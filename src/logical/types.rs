@@ -1,8 +1,13 @@
 use crate::common::types as common;
 use crate::common::types::{DataSource, VariableName};
+use crate::execution::datasource::{is_fifo, NumberFormat, ReaderBuilder, RecordStart};
+use crate::execution::memory::MemoryBudget;
 use crate::execution::types as execution;
 use ordered_float::OrderedFloat;
+use std::path::PathBuf;
 use std::result;
+use std::str::FromStr;
+use std::time::Duration;
 
 pub(crate) type PhysicalResult<T> = result::Result<T, PhysicalPlanError>;
 
@@ -11,6 +16,21 @@ pub enum PhysicalPlanError {
     #[fail(display = "Type Mismatch")]
     #[allow(dead_code)]
     TypeMisMatch,
+    #[fail(display = "Negative limit {} requires the signed limit option", _0)]
+    NegativeLimit(i32),
+    #[fail(display = "The detail and summary queries read different sources")]
+    TeeSourceMismatch,
+    #[fail(display = "Invalid regular expression: {}", _0)]
+    InvalidRegex(String),
+    #[fail(
+        display = "{}() has no partial state to merge, only sum, count and avg can run with --partial",
+        _0
+    )]
+    NoPartialState(String),
+    #[fail(display = "A snapshot keeps the groups of a group by, the query has none")]
+    SnapshotWithoutGroupBy,
+    #[fail(display = "Both sides of the join have a column {}", _0)]
+    JoinColumnClash(String),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -19,8 +39,16 @@ pub(crate) enum Node {
     Filter(Box<Formula>, Box<Node>),
     Map(Vec<Named>, Box<Node>),
     GroupBy(Vec<VariableName>, Vec<NamedAggregate>, Box<Node>),
-    Limit(u32, Box<Node>),
+    Limit(i32, Box<Node>),
     OrderBy(Vec<VariableName>, Vec<Ordering>, Box<Node>),
+    ChangedBy(VariableName, Box<Node>),
+    HashOrder(Vec<VariableName>, Box<Node>),
+    EnforceSchema(Vec<(VariableName, execution::ValueType)>, bool, Box<Node>),
+    Distinct(Box<Node>),
+    Tee(Box<Node>, Box<Node>),
+    DropColumns(Vec<VariableName>, Box<Node>),
+    Unnest(VariableName, Box<Node>),
+    Join(execution::JoinType, Box<Node>, Box<Node>),
 }
 
 impl Node {
@@ -30,7 +58,24 @@ impl Node {
     ) -> PhysicalResult<(Box<execution::Node>, common::Variables)> {
         match self {
             Node::DataSource(data_source, table_name) => {
-                let node = execution::Node::DataSource(data_source.clone(), table_name.clone());
+                let mut reader_builder = ReaderBuilder::new(table_name.clone());
+                reader_builder
+                    .raw_line(physical_plan_creator.raw_line)
+                    .source_column(physical_plan_creator.source_column.clone())
+                    .number_format(physical_plan_creator.number_format)
+                    .skip_bad_lines(physical_plan_creator.skip_bad_lines)
+                    .multiline(physical_plan_creator.multiline.clone())
+                    .follow(physical_plan_creator.window().is_some());
+                if let Some(fields_directive) = &physical_plan_creator.fields_directive {
+                    reader_builder.fields_directive(fields_directive.clone());
+                }
+                reader_builder.json_fields(physical_plan_creator.json_fields.clone());
+                let node = match data_source {
+                    DataSource::SortedFiles(paths, column_name) => {
+                        execution::merge_sorted_files(paths, column_name, &reader_builder)
+                    }
+                    _ => execution::Node::DataSource(data_source.clone(), reader_builder),
+                };
                 let variables = common::empty_variables();
 
                 Ok((Box::new(node), variables))
@@ -66,17 +111,37 @@ impl Node {
                 let mut physical_aggregates = Vec::new();
                 for named_aggregate in named_aggergates.iter() {
                     let (physical_aggregate, aggregate_variables) = named_aggregate.physical(physical_plan_creator)?;
+                    if physical_plan_creator.partial && !physical_aggregate.aggregate.has_partial_state() {
+                        return Err(PhysicalPlanError::NoPartialState(
+                            physical_aggregate.aggregate.name().to_string(),
+                        ));
+                    }
                     variables = common::merge(variables, aggregate_variables);
                     physical_aggregates.push(physical_aggregate);
                 }
                 let (child, child_variables) = source.physical(physical_plan_creator)?;
                 let return_variables = common::merge(variables, child_variables);
 
-                let node = execution::Node::GroupBy(fields.clone(), physical_aggregates, child);
+                physical_plan_creator.grouped = true;
+                let node = execution::Node::GroupBy {
+                    fields: fields.clone(),
+                    named_aggregates: physical_aggregates,
+                    source: child,
+                    partial: physical_plan_creator.partial,
+                    window: physical_plan_creator.window(),
+                    skip_empty: physical_plan_creator.skip_empty_aggregate,
+                    flush_emit: physical_plan_creator.flush_emit,
+                    snapshot: physical_plan_creator.snapshot.clone(),
+                    memory_budget: physical_plan_creator.memory_budget.clone(),
+                };
 
                 Ok((Box::new(node), return_variables))
             }
             Node::Limit(row_count, source) => {
+                if *row_count < 0 && !physical_plan_creator.signed_limit {
+                    return Err(PhysicalPlanError::NegativeLimit(*row_count));
+                }
+
                 let variables = common::empty_variables();
                 let (child, child_variables) = source.physical(physical_plan_creator)?;
                 let return_variables = common::merge(variables, child_variables);
@@ -94,7 +159,90 @@ impl Node {
                     physical_orderings.push(physical_ordering);
                 }
 
-                let node = execution::Node::OrderBy(column_names.clone(), physical_orderings, child);
+                let node = execution::Node::OrderBy {
+                    column_names: column_names.clone(),
+                    orderings: physical_orderings,
+                    source: child,
+                    memory_budget: physical_plan_creator.memory_budget.clone(),
+                };
+                Ok((Box::new(node), return_variables))
+            }
+            Node::ChangedBy(column_name, source) => {
+                let (child, child_variables) = source.physical(physical_plan_creator)?;
+                let node = execution::Node::ChangedBy(column_name.clone(), child);
+                Ok((Box::new(node), child_variables))
+            }
+            Node::HashOrder(column_names, source) => {
+                let (child, child_variables) = source.physical(physical_plan_creator)?;
+                let node = execution::Node::HashOrder(
+                    column_names.clone(),
+                    child,
+                    physical_plan_creator.memory_budget.clone(),
+                );
+                Ok((Box::new(node), child_variables))
+            }
+            Node::EnforceSchema(contract, every_row, source) => {
+                let (child, child_variables) = source.physical(physical_plan_creator)?;
+                let node = execution::Node::EnforceSchema(contract.clone(), *every_row, child);
+                Ok((Box::new(node), child_variables))
+            }
+            Node::Distinct(source) => {
+                let (child, child_variables) = source.physical(physical_plan_creator)?;
+                let node = execution::Node::Distinct {
+                    source: child,
+                    memory_budget: physical_plan_creator.memory_budget.clone(),
+                };
+                Ok((Box::new(node), child_variables))
+            }
+            Node::DropColumns(column_names, source) => {
+                let (child, child_variables) = source.physical(physical_plan_creator)?;
+                let node = execution::Node::DropColumns(column_names.clone(), child);
+                Ok((Box::new(node), child_variables))
+            }
+            Node::Unnest(column_name, source) => {
+                let (child, child_variables) = source.physical(physical_plan_creator)?;
+                let node = execution::Node::Unnest(column_name.clone(), child);
+                Ok((Box::new(node), child_variables))
+            }
+            Node::Tee(detail, summary) => {
+                let (detail, detail_variables) = detail.physical(physical_plan_creator)?;
+                let (summary, summary_variables) = summary.physical(physical_plan_creator)?;
+
+                //Both branches share a single scan, so they have to read the same source
+                let (source, detail) = detail.detach_source();
+                let (summary_source, summary) = summary.detach_source();
+                if source != summary_source {
+                    return Err(PhysicalPlanError::TeeSourceMismatch);
+                }
+
+                let return_variables = common::merge(detail_variables, summary_variables);
+                let node = execution::Node::Tee(
+                    Box::new(source),
+                    Box::new(detail),
+                    Box::new(summary),
+                    physical_plan_creator.memory_budget.clone(),
+                );
+                Ok((Box::new(node), return_variables))
+            }
+            Node::Join(join_type, left, right) => {
+                let (left, left_variables) = left.physical(physical_plan_creator)?;
+                let (right, right_variables) = right.physical(physical_plan_creator)?;
+
+                //A table without a fixed set of columns is checked as its records are joined
+                if let (Some(left_names), Some(right_names)) = (left.output_names(), right.output_names()) {
+                    if let Some(column_name) = right_names.into_iter().find(|name| left_names.contains(name)) {
+                        return Err(PhysicalPlanError::JoinColumnClash(column_name));
+                    }
+                }
+
+                let return_variables = common::merge(left_variables, right_variables);
+                let node = execution::Node::Join {
+                    join_type: *join_type,
+                    left,
+                    right,
+                    row_limit: physical_plan_creator.join_row_limit,
+                    memory_budget: physical_plan_creator.memory_budget.clone(),
+                };
                 Ok((Box::new(node), return_variables))
             }
         }
@@ -182,6 +330,7 @@ pub(crate) enum Formula {
     PrefixOperator(LogicPrefixOp, Box<Formula>),
     Constant(bool),
     Predicate(Relation, Box<Expression>, Box<Expression>),
+    InList(Box<Expression>, Vec<Expression>),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -226,7 +375,7 @@ impl Formula {
             Formula::Predicate(relation, left_expr, right_expr) => {
                 let (left, left_variables) = left_expr.physical(physical_plan_creator)?;
                 let (right, right_variables) = right_expr.physical(physical_plan_creator)?;
-                let physical_relation = relation.physical()?;
+                let physical_relation = relation.physical(right_expr)?;
 
                 let return_variables = common::merge(left_variables, right_variables);
                 Ok((
@@ -234,14 +383,53 @@ impl Formula {
                     return_variables,
                 ))
             }
+            Formula::InList(child_expr, list) => {
+                let (child, mut return_variables) = child_expr.physical(physical_plan_creator)?;
+                let mut physical_list = Vec::new();
+                for item in list.iter() {
+                    let (physical_item, item_variables) = item.physical(physical_plan_creator)?;
+                    physical_list.push(*physical_item);
+                    return_variables = common::merge(return_variables, item_variables);
+                }
+
+                Ok((
+                    Box::new(execution::Formula::InList(child, physical_list)),
+                    return_variables,
+                ))
+            }
         }
     }
 }
 
+//The seed of a run not given one, fixed so that two runs over the same rows give the same estimates
+pub(crate) const DEFAULT_SEED: u64 = 0;
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub(crate) struct PhysicalPlanCreator {
     counter: u32,
     data_source: DataSource,
+    raw_line: bool,
+    partial: bool,
+    signed_limit: bool,
+    nan_policy: execution::NanPolicy,
+    number_format: NumberFormat,
+    flush_interval: Option<Duration>,
+    source_column: Option<VariableName>,
+    skip_empty_aggregate: bool,
+    fields_directive: Option<String>,
+    flush_emit: execution::FlushEmit,
+    skip_bad_lines: bool,
+    multiline: Option<RecordStart>,
+    join_row_limit: u64,
+    snapshot: Option<PathBuf>,
+    //Shared by every blocking operator of the plan
+    memory_budget: MemoryBudget,
+    //Whether a group by took the snapshot
+    grouped: bool,
+    //Seeds every randomized operator of the plan
+    seed: u64,
+    //The columns the query reads from a json log
+    json_fields: Vec<VariableName>,
 }
 
 impl PhysicalPlanCreator {
@@ -249,6 +437,127 @@ impl PhysicalPlanCreator {
         PhysicalPlanCreator {
             counter: 0,
             data_source,
+            raw_line: false,
+            partial: false,
+            signed_limit: false,
+            nan_policy: execution::NanPolicy::default(),
+            number_format: NumberFormat::default(),
+            flush_interval: None,
+            source_column: None,
+            skip_empty_aggregate: false,
+            fields_directive: None,
+            flush_emit: execution::FlushEmit::default(),
+            skip_bad_lines: false,
+            multiline: None,
+            join_row_limit: execution::DEFAULT_JOIN_ROW_LIMIT,
+            snapshot: None,
+            memory_budget: MemoryBudget::default(),
+            grouped: false,
+            seed: DEFAULT_SEED,
+            json_fields: Vec::new(),
+        }
+    }
+
+    pub(crate) fn with_seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed.unwrap_or(DEFAULT_SEED);
+        self
+    }
+
+    pub(crate) fn with_source_column(mut self, source_column: Option<VariableName>) -> Self {
+        self.source_column = source_column;
+        self
+    }
+
+    pub(crate) fn with_raw_line(mut self, raw_line: bool) -> Self {
+        self.raw_line = raw_line;
+        self
+    }
+
+    pub(crate) fn with_partial(mut self, partial: bool) -> Self {
+        self.partial = partial;
+        self
+    }
+
+    pub(crate) fn with_signed_limit(mut self, signed_limit: bool) -> Self {
+        self.signed_limit = signed_limit;
+        self
+    }
+
+    pub(crate) fn with_nan_policy(mut self, nan_policy: execution::NanPolicy) -> Self {
+        self.nan_policy = nan_policy;
+        self
+    }
+
+    pub(crate) fn with_number_format(mut self, number_format: NumberFormat) -> Self {
+        self.number_format = number_format;
+        self
+    }
+
+    pub(crate) fn with_fields_directive(mut self, fields_directive: Option<String>) -> Self {
+        self.fields_directive = fields_directive;
+        self
+    }
+
+    pub(crate) fn with_json_fields(mut self, json_fields: Vec<VariableName>) -> Self {
+        self.json_fields = json_fields;
+        self
+    }
+
+    pub(crate) fn with_skip_empty_aggregate(mut self, skip_empty_aggregate: bool) -> Self {
+        self.skip_empty_aggregate = skip_empty_aggregate;
+        self
+    }
+
+    pub(crate) fn with_skip_bad_lines(mut self, skip_bad_lines: bool) -> Self {
+        self.skip_bad_lines = skip_bad_lines;
+        self
+    }
+
+    pub(crate) fn with_multiline(mut self, multiline: Option<RecordStart>) -> Self {
+        self.multiline = multiline;
+        self
+    }
+
+    pub(crate) fn with_join_row_limit(mut self, join_row_limit: u64) -> Self {
+        self.join_row_limit = join_row_limit;
+        self
+    }
+
+    pub(crate) fn with_snapshot(mut self, snapshot: Option<PathBuf>) -> Self {
+        self.snapshot = snapshot;
+        self
+    }
+
+    pub(crate) fn with_max_memory(mut self, max_memory: Option<u64>) -> Self {
+        self.memory_budget = max_memory.map(MemoryBudget::new).unwrap_or_default();
+        self
+    }
+
+    pub(crate) fn with_flush_emit(mut self, flush_emit: execution::FlushEmit) -> Self {
+        self.flush_emit = flush_emit;
+        self
+    }
+
+    pub(crate) fn with_flush_interval(mut self, flush_interval: Option<Duration>) -> Self {
+        self.flush_interval = flush_interval;
+        self
+    }
+
+    //Once the plan is created, a snapshot no group by took would be silently ignored
+    pub(crate) fn check_snapshot(&self) -> PhysicalResult<()> {
+        if self.snapshot.is_some() && !self.grouped {
+            return Err(PhysicalPlanError::SnapshotWithoutGroupBy);
+        }
+
+        Ok(())
+    }
+
+    //A file has an end to aggregate up to, only a stream read from stdin or a named pipe is flushed on an interval
+    fn window(&self) -> Option<Duration> {
+        match self.data_source {
+            DataSource::Stdin => self.flush_interval,
+            DataSource::File(ref path) if is_fifo(path) => self.flush_interval,
+            DataSource::File(_) | DataSource::Values(_, _) | DataSource::SortedFiles(_, _) => None,
         }
     }
 
@@ -293,7 +602,16 @@ pub(crate) enum Aggregate {
     Sum(Named),
     ApproxCountDistinct(Named),
     PercentileDisc(OrderedFloat<f32>, VariableName, Ordering),
+    PercentileCont(OrderedFloat<f32>, VariableName, Ordering),
     ApproxPercentile(OrderedFloat<f32>, VariableName, Ordering),
+    CountBuckets(Vec<Formula>),
+    ConditionalRatio(Formula, Formula),
+    GroupDuration(Named),
+    GeoMean(Named),
+    Entropy(Named),
+    DistinctRatio(Named),
+    Variance(Named),
+    StdDev(Named),
 }
 
 impl Aggregate {
@@ -314,10 +632,27 @@ impl Aggregate {
                     Named::Star => execution::Named::Star,
                 };
 
-                let avg_aggregate = execution::AvgAggregate::new();
+                let avg_aggregate = execution::AvgAggregate::new().with_nan_policy(physical_plan_creator.nan_policy);
                 let aggregate = execution::Aggregate::Avg(avg_aggregate, physical_named);
                 Ok((aggregate, variables))
             }
+            Aggregate::GeoMean(named) => {
+                let mut variables = common::empty_variables();
+
+                let physical_named = match named {
+                    Named::Expression(expr, name) => {
+                        let (physical_expr, expr_variables) = expr.physical(physical_plan_creator)?;
+                        variables = common::merge(variables, expr_variables);
+                        execution::Named::Expression(*physical_expr, name.clone())
+                    }
+                    Named::Star => execution::Named::Star,
+                };
+
+                let geo_mean_aggregate =
+                    execution::GeoMeanAggregate::new().with_nan_policy(physical_plan_creator.nan_policy);
+                let aggregate = execution::Aggregate::GeoMean(geo_mean_aggregate, physical_named);
+                Ok((aggregate, variables))
+            }
             Aggregate::Count(named) => {
                 let mut variables = common::empty_variables();
 
@@ -346,7 +681,7 @@ impl Aggregate {
                     Named::Star => execution::Named::Star,
                 };
 
-                let sum_aggregate = execution::SumAggregate::new();
+                let sum_aggregate = execution::SumAggregate::new().with_nan_policy(physical_plan_creator.nan_policy);
                 let aggregate = execution::Aggregate::Sum(sum_aggregate, physical_named);
                 Ok((aggregate, variables))
             }
@@ -394,7 +729,7 @@ impl Aggregate {
                     Named::Star => execution::Named::Star,
                 };
 
-                let min_aggregate = execution::MinAggregate::new();
+                let min_aggregate = execution::MinAggregate::new().with_nan_policy(physical_plan_creator.nan_policy);
                 let aggregate = execution::Aggregate::Min(min_aggregate, physical_named);
                 Ok((aggregate, variables))
             }
@@ -410,7 +745,7 @@ impl Aggregate {
                     Named::Star => execution::Named::Star,
                 };
 
-                let max_aggregate = execution::MaxAggregate::new();
+                let max_aggregate = execution::MaxAggregate::new().with_nan_policy(physical_plan_creator.nan_policy);
                 let aggregate = execution::Aggregate::Max(max_aggregate, physical_named);
                 Ok((aggregate, variables))
             }
@@ -426,7 +761,8 @@ impl Aggregate {
                     Named::Star => execution::Named::Star,
                 };
 
-                let approx_count_distinct_aggregate = execution::ApproxCountDistinctAggregate::new();
+                let approx_count_distinct_aggregate =
+                    execution::ApproxCountDistinctAggregate::new(physical_plan_creator.seed);
                 let aggregate =
                     execution::Aggregate::ApproxCountDistinct(approx_count_distinct_aggregate, physical_named);
                 Ok((aggregate, variables))
@@ -439,6 +775,14 @@ impl Aggregate {
                 let aggregate = execution::Aggregate::PercentileDisc(percentile_disc_aggregate, column_name.clone());
                 Ok((aggregate, variables))
             }
+            Aggregate::PercentileCont(percentile, column_name, ordering) => {
+                let variables = common::empty_variables();
+                let physical_ordering = ordering.physical()?;
+
+                let percentile_cont_aggregate = execution::PercentileContAggregate::new(*percentile, physical_ordering);
+                let aggregate = execution::Aggregate::PercentileCont(percentile_cont_aggregate, column_name.clone());
+                Ok((aggregate, variables))
+            }
             Aggregate::ApproxPercentile(percentile, column_name, ordering) => {
                 let variables = common::empty_variables();
                 let physical_ordering = ordering.physical()?;
@@ -449,6 +793,112 @@ impl Aggregate {
                     execution::Aggregate::ApproxPercentile(approx_percentile_aggregate, column_name.clone());
                 Ok((aggregate, variables))
             }
+            Aggregate::CountBuckets(formulas) => {
+                let mut variables = common::empty_variables();
+                let mut physical_formulas = Vec::new();
+                for formula in formulas.iter() {
+                    let (physical_formula, formula_variables) = formula.physical(physical_plan_creator)?;
+                    variables = common::merge(variables, formula_variables);
+                    physical_formulas.push(*physical_formula);
+                }
+
+                let count_buckets_aggregate = execution::CountBucketsAggregate::new(physical_formulas.len());
+                let aggregate = execution::Aggregate::CountBuckets(count_buckets_aggregate, physical_formulas);
+                Ok((aggregate, variables))
+            }
+            Aggregate::ConditionalRatio(numerator, denominator) => {
+                let (physical_numerator, numerator_variables) = numerator.physical(physical_plan_creator)?;
+                let (physical_denominator, denominator_variables) = denominator.physical(physical_plan_creator)?;
+                let variables = common::merge(numerator_variables, denominator_variables);
+
+                let aggregate = execution::Aggregate::ConditionalRatio(
+                    execution::ConditionalRatioAggregate::new(),
+                    *physical_numerator,
+                    *physical_denominator,
+                );
+                Ok((aggregate, variables))
+            }
+            Aggregate::GroupDuration(named) => {
+                let mut variables = common::empty_variables();
+
+                let physical_named = match named {
+                    Named::Expression(expr, name) => {
+                        let (physical_expr, expr_variables) = expr.physical(physical_plan_creator)?;
+                        variables = common::merge(variables, expr_variables);
+                        execution::Named::Expression(*physical_expr, name.clone())
+                    }
+                    Named::Star => execution::Named::Star,
+                };
+
+                let aggregate =
+                    execution::Aggregate::GroupDuration(execution::GroupDurationAggregate::new(), physical_named);
+                Ok((aggregate, variables))
+            }
+            Aggregate::Entropy(named) => {
+                let mut variables = common::empty_variables();
+
+                let physical_named = match named {
+                    Named::Expression(expr, name) => {
+                        let (physical_expr, expr_variables) = expr.physical(physical_plan_creator)?;
+                        variables = common::merge(variables, expr_variables);
+                        execution::Named::Expression(*physical_expr, name.clone())
+                    }
+                    Named::Star => execution::Named::Star,
+                };
+
+                let aggregate = execution::Aggregate::Entropy(execution::EntropyAggregate::new(), physical_named);
+                Ok((aggregate, variables))
+            }
+            Aggregate::DistinctRatio(named) => {
+                let mut variables = common::empty_variables();
+
+                let physical_named = match named {
+                    Named::Expression(expr, name) => {
+                        let (physical_expr, expr_variables) = expr.physical(physical_plan_creator)?;
+                        variables = common::merge(variables, expr_variables);
+                        execution::Named::Expression(*physical_expr, name.clone())
+                    }
+                    Named::Star => execution::Named::Star,
+                };
+
+                let aggregate =
+                    execution::Aggregate::DistinctRatio(execution::DistinctRatioAggregate::new(), physical_named);
+                Ok((aggregate, variables))
+            }
+            Aggregate::Variance(named) => {
+                let mut variables = common::empty_variables();
+
+                let physical_named = match named {
+                    Named::Expression(expr, name) => {
+                        let (physical_expr, expr_variables) = expr.physical(physical_plan_creator)?;
+                        variables = common::merge(variables, expr_variables);
+                        execution::Named::Expression(*physical_expr, name.clone())
+                    }
+                    Named::Star => execution::Named::Star,
+                };
+
+                let variance_aggregate =
+                    execution::VarianceAggregate::new().with_nan_policy(physical_plan_creator.nan_policy);
+                let aggregate = execution::Aggregate::Variance(variance_aggregate, physical_named);
+                Ok((aggregate, variables))
+            }
+            Aggregate::StdDev(named) => {
+                let mut variables = common::empty_variables();
+
+                let physical_named = match named {
+                    Named::Expression(expr, name) => {
+                        let (physical_expr, expr_variables) = expr.physical(physical_plan_creator)?;
+                        variables = common::merge(variables, expr_variables);
+                        execution::Named::Expression(*physical_expr, name.clone())
+                    }
+                    Named::Star => execution::Named::Star,
+                };
+
+                let std_dev_aggregate =
+                    execution::StdDevAggregate::new().with_nan_policy(physical_plan_creator.nan_policy);
+                let aggregate = execution::Aggregate::StdDev(std_dev_aggregate, physical_named);
+                Ok((aggregate, variables))
+            }
         }
     }
 }
@@ -461,10 +911,17 @@ pub(crate) enum Relation {
     LessThan,
     GreaterEqual,
     LessEqual,
+    Contains,
+    Like,
+    NotLike,
+    RegexMatch,
+    IsNull,
+    IsNotNull,
 }
 
 impl Relation {
-    pub(crate) fn physical(&self) -> PhysicalResult<execution::Relation> {
+    //The pattern of a regex match is compiled once here from the string constant on its right
+    pub(crate) fn physical(&self, right_expr: &Expression) -> PhysicalResult<execution::Relation> {
         match self {
             Relation::Equal => Ok(execution::Relation::Equal),
             Relation::NotEqual => Ok(execution::Relation::NotEqual),
@@ -472,6 +929,19 @@ impl Relation {
             Relation::LessThan => Ok(execution::Relation::LessThan),
             Relation::GreaterEqual => Ok(execution::Relation::GreaterEqual),
             Relation::LessEqual => Ok(execution::Relation::LessEqual),
+            Relation::Contains => Ok(execution::Relation::Contains),
+            Relation::Like => Ok(execution::Relation::Like),
+            Relation::NotLike => Ok(execution::Relation::NotLike),
+            Relation::RegexMatch => match right_expr {
+                Expression::Constant(common::Value::String(pattern)) => execution::RegexPattern::from_str(pattern)
+                    .map(execution::Relation::RegexMatch)
+                    .map_err(PhysicalPlanError::InvalidRegex),
+                _ => Err(PhysicalPlanError::InvalidRegex(
+                    "the pattern has to be a string constant".to_string(),
+                )),
+            },
+            Relation::IsNull => Ok(execution::Relation::IsNull),
+            Relation::IsNotNull => Ok(execution::Relation::IsNotNull),
         }
     }
 }
@@ -498,10 +968,20 @@ mod test {
     #[test]
     fn test_relation_gen_physical() {
         let rel = Relation::Equal;
-        let ans = rel.physical().unwrap();
+        let ans = rel.physical(&Expression::Constant(common::Value::Int(1))).unwrap();
         let expected = execution::Relation::Equal;
 
         assert_eq!(expected, ans);
+
+        let rel = Relation::RegexMatch;
+        let ans = rel
+            .physical(&Expression::Constant(common::Value::String("bot|crawler".to_string())))
+            .unwrap();
+        let expected = execution::Relation::RegexMatch(execution::RegexPattern::from_str("bot|crawler").unwrap());
+        assert_eq!(expected, ans);
+
+        let ans = rel.physical(&Expression::Constant(common::Value::String("(bot".to_string())));
+        assert!(matches!(ans, Err(PhysicalPlanError::InvalidRegex(_))));
     }
 
     #[test]
@@ -570,7 +1050,10 @@ mod test {
                 execution::Named::Expression(execution::Expression::Variable("a".to_string()), Some("a".to_string())),
                 execution::Named::Expression(execution::Expression::Variable("b".to_string()), Some("b".to_string())),
             ],
-            Box::new(execution::Node::DataSource(DataSource::Stdin, "elb".to_string())),
+            Box::new(execution::Node::DataSource(
+                DataSource::Stdin,
+                ReaderBuilder::new("elb".to_string()),
+            )),
         );
 
         let expected_filter = execution::Node::Filter(Box::new(expected_source), Box::new(expected_filtered_formula));
@@ -582,6 +1065,54 @@ mod test {
         assert_eq!(expected_variables, variables);
     }
 
+    #[test]
+    fn test_negative_limit_gen_physical() {
+        let limit = Node::Limit(-2, Box::new(Node::DataSource(DataSource::Stdin, "elb".to_string())));
+
+        let mut physical_plan_creator = PhysicalPlanCreator::new(DataSource::Stdin);
+        let result = limit.physical(&mut physical_plan_creator);
+        assert_eq!(Err(PhysicalPlanError::NegativeLimit(-2)), result);
+
+        let mut physical_plan_creator = PhysicalPlanCreator::new(DataSource::Stdin).with_signed_limit(true);
+        let (physical_limit, _) = limit.physical(&mut physical_plan_creator).unwrap();
+        let expected = execution::Node::Limit(
+            -2,
+            Box::new(execution::Node::DataSource(
+                DataSource::Stdin,
+                ReaderBuilder::new("elb".to_string()),
+            )),
+        );
+        assert_eq!(expected, *physical_limit);
+    }
+
+    #[test]
+    fn test_tee_gen_physical() {
+        let detail = Node::Limit(1, Box::new(Node::DataSource(DataSource::Stdin, "elb".to_string())));
+        let summary = Node::DataSource(DataSource::Stdin, "elb".to_string());
+        let tee = Node::Tee(Box::new(detail), Box::new(summary));
+
+        let mut physical_plan_creator = PhysicalPlanCreator::new(DataSource::Stdin);
+        let (physical_tee, _) = tee.physical(&mut physical_plan_creator).unwrap();
+        let expected = execution::Node::Tee(
+            Box::new(execution::Node::DataSource(
+                DataSource::Stdin,
+                ReaderBuilder::new("elb".to_string()),
+            )),
+            Box::new(execution::Node::Limit(1, Box::new(execution::Node::TeeInput))),
+            Box::new(execution::Node::TeeInput),
+            MemoryBudget::default(),
+        );
+        assert_eq!(expected, *physical_tee);
+
+        let mismatched = Node::Tee(
+            Box::new(Node::DataSource(DataSource::Stdin, "elb".to_string())),
+            Box::new(Node::DataSource(DataSource::Stdin, "alb".to_string())),
+        );
+        let mut physical_plan_creator = PhysicalPlanCreator::new(DataSource::Stdin);
+        let result = mismatched.physical(&mut physical_plan_creator);
+        assert_eq!(Err(PhysicalPlanError::TeeSourceMismatch), result);
+    }
+
     #[test]
     fn test_group_by_gen_physical() {
         let filtered_formula = Formula::Predicate(
@@ -635,13 +1166,16 @@ mod test {
                 execution::Named::Expression(execution::Expression::Variable("a".to_string()), Some("a".to_string())),
                 execution::Named::Expression(execution::Expression::Variable("b".to_string()), Some("b".to_string())),
             ],
-            Box::new(execution::Node::DataSource(DataSource::Stdin, "elb".to_string())),
+            Box::new(execution::Node::DataSource(
+                DataSource::Stdin,
+                ReaderBuilder::new("elb".to_string()),
+            )),
         );
 
         let expected_filter = execution::Node::Filter(Box::new(expected_source), Box::new(expected_filtered_formula));
-        let expected_group_by = execution::Node::GroupBy(
-            vec!["b".to_string()],
-            vec![
+        let expected_group_by = execution::Node::GroupBy {
+            fields: vec!["b".to_string()],
+            named_aggregates: vec![
                 execution::NamedAggregate::new(
                     execution::Aggregate::Avg(
                         execution::AvgAggregate::new(),
@@ -663,8 +1197,14 @@ mod test {
                     None,
                 ),
             ],
-            Box::new(expected_filter),
-        );
+            source: Box::new(expected_filter),
+            partial: false,
+            window: None,
+            skip_empty: false,
+            flush_emit: execution::FlushEmit::All,
+            snapshot: None,
+            memory_budget: MemoryBudget::default(),
+        };
 
         let mut expected_variables = common::Variables::default();
         expected_variables.insert("const_000000000".to_string(), common::Value::Int(1));
@@ -5,7 +5,10 @@ extern crate nom;
 extern crate prettytable;
 #[macro_use]
 extern crate lazy_static;
+extern crate flate2;
 extern crate pdatastructs;
+#[cfg(feature = "publicsuffix")]
+extern crate publicsuffix;
 
 mod app;
 mod common;
@@ -17,7 +20,7 @@ use clap::load_yaml;
 use clap::App;
 use prettytable::{Cell, Row, Table};
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 fn main() {
@@ -26,31 +29,306 @@ fn main() {
 
     match app_m.subcommand() {
         ("query", Some(sub_m)) => {
-            if let Some(query_str) = sub_m.value_of("query") {
-                let lower_case_query_str = query_str.to_ascii_lowercase();
-                let output_mode = if let Some(output_format) = sub_m.value_of("output") {
-                    match app::OutputMode::from_str(output_format) {
+            let query_str = match sub_m.value_of("query_file") {
+                Some(path) => match app::read_query_file(path) {
+                    Ok(query_str) => Some(query_str),
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    }
+                },
+                None => sub_m.value_of("query").map(String::from),
+            };
+
+            if let Some(query_str) = query_str {
+                let lower_case_query_str = syntax::parser::lowercase_query(&query_str);
+                let output_mode = match sub_m.value_of("output") {
+                    Some("prometheus") => {
+                        let value_column = sub_m.value_of("metric_value").map(|s| s.to_ascii_lowercase());
+                        let metric = match sub_m.value_of("metric_name") {
+                            Some(name) => app::PrometheusMetric::new(name, value_column),
+                            None => Err("the prometheus output needs a --metric-name".to_string()),
+                        };
+                        match metric {
+                            Ok(metric) => app::OutputMode::Prometheus(metric),
+                            Err(e) => {
+                                eprintln!("{}", e);
+                                std::process::exit(1);
+                            }
+                        }
+                    }
+                    Some(output_format) => match app::OutputMode::from_str(output_format) {
                         Ok(output_mode) => output_mode,
                         Err(e) => {
                             eprintln!("{}", e);
                             std::process::exit(1);
                         }
+                    },
+                    None => app::OutputMode::Table,
+                };
+
+                let nan_policy = if let Some(policy) = sub_m.value_of("nan_policy") {
+                    match execution::types::NanPolicy::from_str(policy) {
+                        Ok(nan_policy) => nan_policy,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            std::process::exit(1);
+                        }
+                    }
+                } else {
+                    execution::types::NanPolicy::default()
+                };
+
+                let number_format = if let Some(format) = sub_m.value_of("number_format") {
+                    match execution::datasource::NumberFormat::from_str(format) {
+                        Ok(number_format) => number_format,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            std::process::exit(1);
+                        }
+                    }
+                } else {
+                    execution::datasource::NumberFormat::default()
+                };
+
+                let multiline = if let Some(pattern) = sub_m.value_of("multiline") {
+                    match execution::datasource::RecordStart::from_str(pattern) {
+                        Ok(record_start) => Some(record_start),
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            std::process::exit(1);
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                let cross_join_limit = if let Some(rows) = sub_m.value_of("cross_join_limit") {
+                    match rows.parse::<u64>() {
+                        Ok(rows) if rows > 0 => Some(rows),
+                        _ => {
+                            eprintln!("cross join limit must be a positive number of rows");
+                            std::process::exit(1);
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                let max_memory = if let Some(size) = sub_m.value_of("max_memory") {
+                    match execution::memory::parse_size(size) {
+                        Ok(size) => Some(size),
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            std::process::exit(1);
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                let expect_schema = if let Some(contract) = sub_m.value_of("expect_schema") {
+                    match app::parse_schema_contract(contract) {
+                        Ok(contract) => Some(contract),
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            std::process::exit(1);
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                let flush_interval = if let Some(seconds) = sub_m.value_of("flush_interval") {
+                    match seconds.parse::<u64>() {
+                        Ok(seconds) if seconds > 0 => Some(std::time::Duration::from_secs(seconds)),
+                        _ => {
+                            eprintln!("flush interval must be a positive number of seconds");
+                            std::process::exit(1);
+                        }
+                    }
+                } else {
+                    None
+                };
+
+                let flush_emit = if let Some(mode) = sub_m.value_of("flush_emit") {
+                    match execution::types::FlushEmit::from_str(mode) {
+                        Ok(flush_emit) => flush_emit,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            std::process::exit(1);
+                        }
                     }
                 } else {
-                    app::OutputMode::Table
+                    execution::types::FlushEmit::default()
                 };
 
-                let result = if let Some(filename) = sub_m.value_of("file_to_select") {
-                    let path = Path::new(filename);
-                    let data_source = common::types::DataSource::File(path.to_path_buf());
-                    app::run(&*lower_case_query_str, data_source, false, output_mode)
+                let seed = if let Some(seed) = sub_m.value_of("seed") {
+                    match seed.parse::<u64>() {
+                        Ok(seed) => Some(seed),
+                        Err(_) => {
+                            eprintln!("seed must be a non-negative integer");
+                            std::process::exit(1);
+                        }
+                    }
                 } else {
-                    let data_source = common::types::DataSource::Stdin;
-                    app::run(&*lower_case_query_str, data_source, false, output_mode)
+                    None
+                };
+
+                let mut record_layout = app::RecordLayout::default();
+                if let Some(separator) = sub_m.value_of("record_separator") {
+                    record_layout.separator = app::unescape_separator(separator);
+                }
+                if let Some(trailing_newline) = sub_m.value_of("trailing_newline") {
+                    match trailing_newline.parse::<bool>() {
+                        Ok(trailing_newline) => record_layout.trailing_newline = trailing_newline,
+                        Err(_) => {
+                            eprintln!("trailing newline must be true or false");
+                            std::process::exit(1);
+                        }
+                    }
+                }
+
+                let options = app::QueryOptions {
+                    raw_line: sub_m.is_present("raw_line"),
+                    partial: sub_m.is_present("partial"),
+                    skip_empty_aggregate: sub_m.is_present("skip_empty_aggregate"),
+                    strict_deterministic: sub_m.is_present("strict_deterministic"),
+                    skip_bad_lines: sub_m.is_present("skip_bad_lines"),
+                    warnings_as_errors: sub_m.is_present("warnings_as_errors"),
+                    multiline,
+                    cross_join_limit,
+                    max_memory,
+                    expect_schema,
+                    check_every_row: sub_m.is_present("check_every_row"),
+                    snapshot: sub_m.value_of("snapshot").map(PathBuf::from),
+                    seed,
+                    fields_directive: sub_m.value_of("fields_directive").map(|s| s.to_string()),
+                    signed_limit: sub_m.is_present("signed_limit"),
+                    nan_policy,
+                    changed_by: sub_m.value_of("changed_by").map(|s| s.to_ascii_lowercase()),
+                    number_format,
+                    summary: sub_m.value_of("summary").map(syntax::parser::lowercase_query),
+                    flush_interval,
+                    flush_emit,
+                    quiet: sub_m.is_present("quiet"),
+                    partition_by: sub_m.value_of("partition_by").map(|s| s.to_ascii_lowercase()),
+                    partition_template: sub_m.value_of("out").map(|s| s.to_string()),
+                    stringify: sub_m.is_present("stringify"),
+                    input_format: sub_m.value_of("input_format").map(|s| s.to_ascii_lowercase()),
+                    record_layout,
+                    no_header: sub_m.is_present("no_header"),
+                    //Without a home the query runs uncached
+                    cache_dir: if sub_m.is_present("cache") {
+                        app::ResultCache::user_cache_dir()
+                    } else {
+                        None
+                    },
+                    source_column: match sub_m.value_of("source_column") {
+                        Some(column_name) => Some(column_name.to_ascii_lowercase()),
+                        None if sub_m.is_present("with_source") => Some("__source".to_string()),
+                        None => None,
+                    },
+                    columns: sub_m.value_of("columns").map(|s| {
+                        s.split(',')
+                            .map(|column_name| column_name.trim().to_ascii_lowercase())
+                            .collect()
+                    }),
+                    order_by_hash: sub_m.value_of("order_by_hash").map(|s| {
+                        s.split(',')
+                            .map(|column_name| column_name.trim().to_ascii_lowercase())
+                            .collect()
+                    }),
+                    ..app::QueryOptions::default()
+                };
+                //With --query-file the first positional argument is already a file rather than the query
+                let first_file = sub_m.value_of("query").filter(|_| sub_m.is_present("query_file"));
+                let filenames: Vec<&str> = first_file
+                    .into_iter()
+                    .chain(sub_m.values_of("file_to_select").into_iter().flatten())
+                    .collect();
+                if filenames.is_empty() && sub_m.value_of("query_file") == Some("-") {
+                    eprintln!("the query is read from stdin, the log has to be given as a file");
+                    std::process::exit(1);
+                }
+                let data_source = match (sub_m.value_of("sorted_by"), filenames.as_slice()) {
+                    (Some(column_name), [_, ..]) => common::types::DataSource::SortedFiles(
+                        filenames.iter().map(PathBuf::from).collect(),
+                        column_name.to_ascii_lowercase(),
+                    ),
+                    (Some(_), []) => {
+                        eprintln!("--sorted-by merges files, it can't apply to stdin");
+                        std::process::exit(1);
+                    }
+                    (None, [filename]) => common::types::DataSource::File(Path::new(filename).to_path_buf()),
+                    (None, []) => common::types::DataSource::Stdin,
+                    (None, _) => {
+                        eprintln!("several files are read together only as a merge of sorted files, see --sorted-by");
+                        std::process::exit(1);
+                    }
                 };
 
+                //As grep has it, 0 when a row matched, 1 when none did and 2 when the query failed
+                if sub_m.is_present("exists") {
+                    match app::exists(&*lower_case_query_str, data_source, &options) {
+                        Ok(found) => {
+                            println!("{}", found);
+                            std::process::exit(if found { 0 } else { 1 });
+                        }
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            std::process::exit(2);
+                        }
+                    }
+                }
+
+                let result = app::run(&*lower_case_query_str, data_source, false, output_mode, &options);
+
+                //As with --exists, a failed query exits with 2
                 if let Err(e) = result {
-                    println!("{}", e);
+                    eprintln!("{}", e);
+                    std::process::exit(2);
+                }
+            } else {
+                println!("{}", sub_m.usage());
+            }
+        }
+        ("merge", Some(sub_m)) => {
+            let output_mode = if let Some(output_format) = sub_m.value_of("output") {
+                match app::OutputMode::from_str(output_format) {
+                    Ok(output_mode) => output_mode,
+                    Err(e) => {
+                        eprintln!("{}", e);
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                app::OutputMode::Table
+            };
+
+            if let Some(filenames) = sub_m.values_of("files_to_merge") {
+                let paths: Vec<PathBuf> = filenames.map(PathBuf::from).collect();
+                if let Err(e) = app::merge(&paths, output_mode) {
+                    eprintln!("{}", e);
+                }
+            } else {
+                println!("{}", sub_m.usage());
+            }
+        }
+        ("bench", Some(sub_m)) => {
+            if let Some(filename) = sub_m.value_of("file_to_bench") {
+                let iterations = match sub_m.value_of("iterations").map(str::parse::<usize>) {
+                    None => 1,
+                    Some(Ok(iterations)) if iterations > 0 => iterations,
+                    Some(_) => {
+                        eprintln!("iterations must be a positive number");
+                        std::process::exit(1);
+                    }
+                };
+
+                match app::bench(Path::new(filename), sub_m.value_of("table"), iterations) {
+                    Ok(report) => print!("{}", report),
+                    Err(e) => eprintln!("{}", e),
                 }
             } else {
                 println!("{}", sub_m.usage());
@@ -58,9 +336,40 @@ fn main() {
         }
         ("explain", Some(sub_m)) => {
             if let Some(query_str) = sub_m.value_of("query") {
-                let lower_case_query_str = query_str.to_ascii_lowercase();
-                let data_source = common::types::DataSource::Stdin;
-                let result = app::run(&*lower_case_query_str, data_source, true, app::OutputMode::Table);
+                let lower_case_query_str = syntax::parser::lowercase_query(query_str);
+                let explain_format = if let Some(format) = sub_m.value_of("explain_format") {
+                    match app::ExplainFormat::from_str(format) {
+                        Ok(explain_format) => explain_format,
+                        Err(e) => {
+                            eprintln!("{}", e);
+                            std::process::exit(1);
+                        }
+                    }
+                } else {
+                    app::ExplainFormat::Text
+                };
+                let options = app::QueryOptions {
+                    explain_format,
+                    explain_cost: sub_m.is_present("explain_cost"),
+                    ..app::QueryOptions::default()
+                };
+
+                if options.explain_cost && sub_m.value_of("file_to_read").is_some_and(|f| f.ends_with(".gz")) {
+                    eprintln!("the cost can only be estimated from an uncompressed log file");
+                    std::process::exit(1);
+                }
+
+                let data_source = match sub_m.value_of("file_to_read") {
+                    Some(filename) => common::types::DataSource::File(PathBuf::from(filename)),
+                    None => common::types::DataSource::Stdin,
+                };
+                let result = app::run(
+                    &*lower_case_query_str,
+                    data_source,
+                    true,
+                    app::OutputMode::Table,
+                    &options,
+                );
 
                 if let Err(e) = result {
                     println!("{}", e);
@@ -111,6 +420,23 @@ fn main() {
                         ]));
                     }
                     table.printstd();
+                } else if type_str == "nginx" {
+                    let schema = execution::datasource::NginxLogField::schema();
+                    let mut table = Table::new();
+                    for (field, datatype) in schema.iter() {
+                        table.add_row(Row::new(vec![
+                            Cell::new(&*field.to_string()),
+                            Cell::new(&*datatype.to_string()),
+                        ]));
+                    }
+                    table.printstd();
+                } else if type_str == "w3c" {
+                    println!(
+                        "The fields are named by the \"{}\" line of the log, every value is a string",
+                        execution::datasource::DEFAULT_FIELDS_DIRECTIVE
+                    );
+                } else if type_str == "json" {
+                    println!("A JSON object per line, each key is a field and a nested object is flattened");
                 } else {
                     eprintln!("Unknown log format");
                 }
@@ -120,6 +446,9 @@ fn main() {
                 println!("* alb");
                 println!("* squid");
                 println!("* s3");
+                println!("* nginx");
+                println!("* json");
+                println!("* w3c");
             }
         }
         _ => {
@@ -12,6 +12,10 @@ pub(crate) struct SelectStatement {
     pub(crate) having_expr_opt: Option<WhereExpression>,
     pub(crate) order_by_expr_opt: Option<OrderByExpression>,
     pub(crate) limit_expr_opt: Option<LimitExpression>,
+    //Set when the query reads the inline rows of a VALUES list, table_name then holds its alias
+    pub(crate) values_opt: Option<ValuesList>,
+    pub(crate) join_opt: Option<JoinClause>,
+    pub(crate) distinct: bool,
 }
 
 impl SelectStatement {
@@ -32,8 +36,26 @@ impl SelectStatement {
             having_expr_opt,
             order_by_expr_opt,
             limit_expr_opt,
+            values_opt: None,
+            join_opt: None,
+            distinct: false,
         }
     }
+
+    pub(crate) fn with_values(mut self, values_opt: Option<ValuesList>) -> Self {
+        self.values_opt = values_opt;
+        self
+    }
+
+    pub(crate) fn with_join(mut self, join_opt: Option<JoinClause>) -> Self {
+        self.join_opt = join_opt;
+        self
+    }
+
+    pub(crate) fn with_distinct(mut self, distinct: bool) -> Self {
+        self.distinct = distinct;
+        self
+    }
 }
 
 impl fmt::Display for SelectStatement {
@@ -62,6 +84,7 @@ pub(crate) enum Expression {
     BinaryOperator(BinaryOperator, Box<Expression>, Box<Expression>),
     UnaryOperator(UnaryOperator, Box<Expression>),
     FuncCall(FuncName, Vec<SelectExpression>, Option<WithinGroupClause>),
+    InList(Box<Expression>, Vec<Expression>),
 }
 
 pub(crate) type FuncName = String;
@@ -79,6 +102,10 @@ pub(crate) enum BinaryOperator {
     LessThan,
     GreaterEqual,
     LessEqual,
+    Contains,
+    Like,
+    NotLike,
+    RegexMatch,
     And,
     Or,
 }
@@ -98,6 +125,10 @@ impl FromStr for BinaryOperator {
             "<" => Ok(BinaryOperator::LessThan),
             ">=" => Ok(BinaryOperator::GreaterEqual),
             "<=" => Ok(BinaryOperator::LessEqual),
+            "contains" => Ok(BinaryOperator::Contains),
+            "like" => Ok(BinaryOperator::Like),
+            "not like" => Ok(BinaryOperator::NotLike),
+            "~" => Ok(BinaryOperator::RegexMatch),
             "and" => Ok(BinaryOperator::And),
             "or" => Ok(BinaryOperator::Or),
             _ => Err("unknown binary operator".to_string()),
@@ -114,6 +145,8 @@ impl fmt::Display for BinaryOperator {
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub(crate) enum UnaryOperator {
     Not,
+    IsNull,
+    IsNotNull,
 }
 
 impl fmt::Display for UnaryOperator {
@@ -154,15 +187,50 @@ impl GroupByExpression {
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub(crate) struct LimitExpression {
-    pub(crate) row_count: u32,
+    pub(crate) row_count: i32,
 }
 
 impl LimitExpression {
-    pub(crate) fn new(row_count: u32) -> Self {
+    pub(crate) fn new(row_count: i32) -> Self {
         LimitExpression { row_count }
     }
 }
 
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub(crate) struct ValuesList {
+    pub(crate) rows: Vec<Vec<Value>>,
+    pub(crate) column_names: Vec<ColumnName>,
+}
+
+impl ValuesList {
+    pub(crate) fn new(rows: Vec<Vec<Value>>, column_names: Vec<ColumnName>) -> Self {
+        ValuesList { rows, column_names }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub(crate) enum JoinType {
+    Cross,
+}
+
+//The table joined to the one of the FROM clause, a VALUES list under its alias
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub(crate) struct JoinClause {
+    pub(crate) join_type: JoinType,
+    pub(crate) table_name: String,
+    pub(crate) values: ValuesList,
+}
+
+impl JoinClause {
+    pub(crate) fn new(join_type: JoinType, table_name: &str, values: ValuesList) -> Self {
+        JoinClause {
+            join_type,
+            table_name: table_name.to_string(),
+            values,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub(crate) enum Ordering {
     Asc,
@@ -185,6 +253,8 @@ impl FromStr for Ordering {
 pub(crate) struct OrderingTerm {
     pub(crate) column_name: String,
     pub(crate) ordering: Ordering,
+    //Set when ordering by an aggregate call, column_name then holds its source text
+    pub(crate) aggregate_opt: Option<Box<Expression>>,
 }
 
 impl OrderingTerm {
@@ -192,6 +262,15 @@ impl OrderingTerm {
         OrderingTerm {
             column_name: column_name.to_string(),
             ordering: Ordering::from_str(ordering).unwrap(),
+            aggregate_opt: None,
+        }
+    }
+
+    pub(crate) fn with_aggregate(text: &str, aggregate: Expression, ordering: &str) -> Self {
+        OrderingTerm {
+            column_name: text.to_string(),
+            ordering: Ordering::from_str(ordering).unwrap(),
+            aggregate_opt: Some(Box::new(aggregate)),
         }
     }
 }
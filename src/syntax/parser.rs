@@ -4,11 +4,11 @@ use std::str::FromStr;
 
 use nom::{
     branch::alt,
-    bytes::complete::{escaped, tag},
-    character::complete::{char, digit1, none_of, one_of, space0, space1},
-    combinator::{cut, map, map_res, not, opt},
+    bytes::complete::{escaped, is_not, tag},
+    character::complete::{anychar, char, digit1, none_of, space0, space1},
+    combinator::{cut, map, map_res, not, opt, recognize, verify},
     error::{context, VerboseError},
-    multi::separated_list,
+    multi::{many0, separated_list},
     number::complete,
     sequence::{delimited, pair, preceded, terminated, tuple},
     AsChar, IResult, InputTakeAtPosition,
@@ -24,17 +24,77 @@ lazy_static! {
     };
 }
 
+//Keywords and identifiers are case insensitive, the query is lowercased but for its string literals,
+//double quoted with backslash escapes or single quoted with doubled quotes
+pub(crate) fn lowercase_query(query: &str) -> String {
+    let mut lowercased = String::with_capacity(query.len());
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for c in query.chars() {
+        match quote {
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None => {
+                lowercased.push(c.to_ascii_lowercase());
+                continue;
+            }
+        }
+        lowercased.push(c);
+    }
+
+    lowercased
+}
+
 fn string_literal_interior<'a>(i: &'a str) -> IResult<&'a str, &'a str, VerboseError<&'a str>> {
-    escaped(none_of("\""), '\\', one_of("\"n\\"))(i)
+    //A backslash escapes the next character, kept as it is for LIKE and regular expressions, \% or \d
+    escaped(none_of("\"\\"), '\\', anychar)(i)
+}
+
+//Only the escaped quote is turned into a quote, the other escapes are kept for LIKE
+fn unescape_quotes(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('"') => unescaped.push('"'),
+            escaped => {
+                unescaped.push('\\');
+                unescaped.extend(escaped);
+            }
+        }
+    }
+
+    unescaped
+}
+
+//A single quoted string as in SQL, a quote within it is doubled, 'it''s'
+fn single_quoted_interior(i: &str) -> IResult<&str, String, VerboseError<&str>> {
+    map(many0(alt((is_not("'"), map(tag("''"), |_| "'")))), |parts| {
+        parts.concat()
+    })(i)
 }
 
 fn string_literal<'a>(i: &'a str) -> IResult<&'a str, ast::Value, VerboseError<&'a str>> {
     context(
         "string",
-        map(
-            preceded(char('\"'), cut(terminated(string_literal_interior, char('\"')))),
-            |s| ast::Value::StringLiteral(s.to_string()),
-        ),
+        alt((
+            map(
+                preceded(char('\"'), cut(terminated(string_literal_interior, char('\"')))),
+                |s| ast::Value::StringLiteral(unescape_quotes(s)),
+            ),
+            map(
+                preceded(char('\''), cut(terminated(single_quoted_interior, char('\'')))),
+                ast::Value::StringLiteral,
+            ),
+        )),
     )(i)
 }
 
@@ -83,10 +143,23 @@ where
     }
 }
 
+//A double quoted alias can hold any character but the quote itself, e.g. spaces or keywords
+fn alias(i: &str) -> IResult<&str, &str, VerboseError<&str>> {
+    alt((delimited(char('"'), is_not("\""), char('"')), identifier))(i)
+}
+
 fn column_name<'a>(i: &'a str) -> IResult<&'a str, &'a str, VerboseError<&'a str>> {
     terminated(identifier, not(char('(')))(i)
 }
 
+//A keyword ends where the word does, so "likes" or "inbound" isn't read as "like" or "in" and the rest
+fn keyword<'a>(word: &'static str) -> impl Fn(&'a str) -> IResult<&'a str, &'a str, VerboseError<&'a str>> {
+    terminated(
+        tag(word),
+        not(verify(anychar, |c: &char| c.is_alphanumeric() || *c == '_')),
+    )
+}
+
 fn boolean<'a>(i: &'a str) -> IResult<&'a str, ast::Value, VerboseError<&'a str>> {
     alt((
         map(tag("true"), |_| ast::Value::Boolean(true)),
@@ -110,8 +183,50 @@ fn integral<'a>(i: &'a str) -> IResult<&'a str, ast::Value, VerboseError<&'a str
     ))(i)
 }
 
+//A number with a unit suffix, durations become float seconds and sizes integral bytes:
+//1.5s, 250ms, 2m, 10kb, 10mb, 1gb and the binary 10kib, 10mib, 1gib
+fn unit_literal(i: &str) -> IResult<&str, ast::Value, VerboseError<&str>> {
+    let (rest, number) = recognize(complete::float)(i)?;
+    let (rest, unit) = nom::bytes::complete::take_while1(|c: char| c.is_alphanumeric() || c == '_')(rest)?;
+
+    let unknown_unit = || {
+        nom::Err::Failure(VerboseError {
+            errors: vec![(i, nom::error::VerboseErrorKind::Context("unknown unit of the literal"))],
+        })
+    };
+    let number = number.parse::<f64>().map_err(|_| unknown_unit())?;
+
+    let seconds = match unit {
+        "ms" => Some(0.001),
+        "s" => Some(1.0),
+        "m" => Some(60.0),
+        _ => None,
+    };
+    if let Some(seconds) = seconds {
+        return Ok((rest, ast::Value::Float(OrderedFloat::from((number * seconds) as f32))));
+    }
+
+    let bytes = match unit {
+        "kb" => 1e3,
+        "mb" => 1e6,
+        "gb" => 1e9,
+        "kib" => 1024.0,
+        "mib" => 1024.0 * 1024.0,
+        "gib" => 1024.0 * 1024.0 * 1024.0,
+        _ => {
+            return Err(unknown_unit());
+        }
+    };
+    let bytes = (number * bytes).round();
+    if bytes > f64::from(i32::MAX) || bytes < f64::from(i32::MIN) {
+        return Err(unknown_unit());
+    }
+
+    Ok((rest, ast::Value::Integral(bytes as i32)))
+}
+
 fn value<'a>(i: &'a str) -> IResult<&'a str, ast::Value, VerboseError<&'a str>> {
-    alt((integral, float, boolean, string_literal))(i)
+    alt((unit_literal, integral, float, boolean, string_literal))(i)
 }
 
 fn parens<'a>(i: &'a str) -> IResult<&'a str, ast::Expression, VerboseError<&'a str>> {
@@ -135,21 +250,30 @@ fn within_group_clause<'a>(i: &'a str) -> IResult<&'a str, ast::WithinGroupClaus
     )(i)
 }
 
+//The functions taking their arguments as a bracketed list, count_buckets([a < 1, a < 2])
+const BRACKETED_ARGUMENT_FUNCTIONS: &[&str] = &["count_buckets"];
+
 fn func_call<'a>(i: &'a str) -> IResult<&'a str, ast::Expression, VerboseError<&'a str>> {
-    map(
-        tuple((
-            identifier,
-            delimited(tag("("), opt(select_expression_list), tag(")")),
-            opt(within_group_clause),
-        )),
-        |(func_name, select_expr_list_opt, within_group_opt)| {
-            if let Some(select_expr_list) = select_expr_list_opt {
-                ast::Expression::FuncCall(func_name.to_string(), select_expr_list, within_group_opt)
-            } else {
-                ast::Expression::FuncCall(func_name.to_string(), vec![], within_group_opt)
-            }
-        },
-    )(i)
+    let (i, func_name) = identifier::<VerboseError<&'a str>>(i)?;
+    let (i, select_expr_list_opt) = if BRACKETED_ARGUMENT_FUNCTIONS.contains(&func_name) {
+        delimited(
+            tag("("),
+            opt(alt((
+                delimited(pair(tag("["), space0), select_expression_list, pair(tag("]"), space0)),
+                select_expression_list,
+            ))),
+            tag(")"),
+        )(i)?
+    } else {
+        delimited(tag("("), opt(select_expression_list), tag(")"))(i)?
+    };
+    let (i, within_group_opt) = opt(within_group_clause)(i)?;
+
+    let select_expr_list = select_expr_list_opt.unwrap_or_default();
+    Ok((
+        i,
+        ast::Expression::FuncCall(func_name.to_string(), select_expr_list, within_group_opt),
+    ))
 }
 
 fn factor<'a>(i: &'a str) -> IResult<&'a str, ast::Expression, VerboseError<&'a str>> {
@@ -165,17 +289,68 @@ fn factor<'a>(i: &'a str) -> IResult<&'a str, ast::Expression, VerboseError<&'a
     )(i)
 }
 
+fn null_test<'a>(i: &'a str) -> IResult<&'a str, ast::UnaryOperator, VerboseError<&'a str>> {
+    delimited(
+        pair(keyword("is"), space1),
+        alt((
+            map(tuple((tag("not"), space1, tag("null"))), |_| {
+                ast::UnaryOperator::IsNotNull
+            }),
+            map(tag("null"), |_| ast::UnaryOperator::IsNull),
+        )),
+        space0,
+    )(i)
+}
+
+fn in_list(i: &str) -> IResult<&str, Vec<ast::Expression>, VerboseError<&str>> {
+    delimited(
+        tuple((keyword("in"), space0, char('('))),
+        separated_list(char(','), expression),
+        pair(preceded(space0, char(')')), space0),
+    )(i)
+}
+
+//A test written after the expression it applies to, binding as tightly as a comparison
+enum PostfixTest {
+    Null(ast::UnaryOperator),
+    In(Vec<ast::Expression>),
+}
+
+impl PostfixTest {
+    fn keyword(&self) -> &'static str {
+        match self {
+            PostfixTest::Null(_) => "is",
+            PostfixTest::In(_) => "in",
+        }
+    }
+
+    fn apply(self, expr: ast::Expression) -> ast::Expression {
+        match self {
+            PostfixTest::Null(op) => ast::Expression::UnaryOperator(op, Box::new(expr)),
+            PostfixTest::In(list) => ast::Expression::InList(Box::new(expr), list),
+        }
+    }
+}
+
+fn postfix_test(i: &str) -> IResult<&str, PostfixTest, VerboseError<&str>> {
+    alt((map(null_test, PostfixTest::Null), map(in_list, PostfixTest::In)))(i)
+}
+
+//The not applies to the factor after it together with its test, "not a is null" negates "a is null"
 fn expression_term_opt_not<'a>(i: &'a str) -> IResult<&'a str, ast::Expression, VerboseError<&'a str>> {
-    map(
-        pair(opt(tuple((space1, tag("not"), space1))), factor),
-        |(opt_not, factor)| {
-            if opt_not.is_some() {
+    alt((
+        map(
+            preceded(tuple((space1, tag("not"), space1)), pair(factor, opt(postfix_test))),
+            |(factor, postfix_test_opt)| {
+                let factor = match postfix_test_opt {
+                    Some(postfix_test) => postfix_test.apply(factor),
+                    None => factor,
+                };
                 ast::Expression::UnaryOperator(ast::UnaryOperator::Not, Box::new(factor))
-            } else {
-                factor
-            }
-        },
-    )(i)
+            },
+        ),
+        factor,
+    ))(i)
 }
 
 fn expression<'a>(i: &'a str) -> IResult<&'a str, ast::Expression, VerboseError<&'a str>> {
@@ -188,6 +363,12 @@ fn expression<'a>(i: &'a str) -> IResult<&'a str, ast::Expression, VerboseError<
     precedence_table.insert("<=".to_string(), (4, true));
     precedence_table.insert(">".to_string(), (4, true));
     precedence_table.insert(">=".to_string(), (4, true));
+    precedence_table.insert("contains".to_string(), (4, true));
+    precedence_table.insert("like".to_string(), (4, true));
+    precedence_table.insert("not like".to_string(), (4, true));
+    precedence_table.insert("~".to_string(), (4, true));
+    precedence_table.insert("is".to_string(), (4, true));
+    precedence_table.insert("in".to_string(), (4, true));
     precedence_table.insert("=".to_string(), (3, true));
     precedence_table.insert("!=".to_string(), (3, true));
     precedence_table.insert("and".to_string(), (2, true));
@@ -203,10 +384,7 @@ fn select_expression<'a>(i: &'a str) -> IResult<&'a str, ast::SelectExpression,
         alt((
             map(char('*'), |_| ast::SelectExpression::Star),
             map(
-                pair(
-                    expression,
-                    opt(preceded(tuple((space0, tag("as"), space1)), identifier)),
-                ),
+                pair(expression, opt(preceded(tuple((space0, tag("as"), space1)), alias))),
                 |(e, name_opt)| ast::SelectExpression::Expression(Box::new(e), name_opt.map(|s| s.to_string())),
             ),
         )),
@@ -249,16 +427,26 @@ fn group_by_expression<'a>(i: &'a str) -> IResult<&'a str, ast::GroupByExpressio
 }
 
 fn limit_expression<'a>(i: &'a str) -> IResult<&'a str, ast::LimitExpression, VerboseError<&'a str>> {
-    map(preceded(tuple((tag("limit"), space1)), digit1), |s: &str| {
-        let v = s.parse::<u32>().unwrap();
-        ast::LimitExpression::new(v)
-    })(i)
+    map_res(
+        preceded(tuple((tag("limit"), space1)), recognize(pair(opt(char('-')), digit1))),
+        |s: &str| s.parse::<i32>().map(ast::LimitExpression::new),
+    )(i)
+}
+
+fn ordering_key<'a>(i: &'a str) -> IResult<&'a str, (&'a str, Option<ast::Expression>), VerboseError<&'a str>> {
+    alt((map(column_name, |name| (name, None)), |i: &'a str| {
+        let (rest, expr) = func_call(i)?;
+        Ok((rest, (&i[..i.len() - rest.len()], Some(expr))))
+    }))(i)
 }
 
 fn ordering_term<'a>(i: &'a str) -> IResult<&'a str, ast::OrderingTerm, VerboseError<&'a str>> {
     map(
-        pair(column_name, preceded(space1, alt((tag("asc"), tag("desc"))))),
-        |(column_name, ordering)| ast::OrderingTerm::new(column_name, ordering),
+        pair(ordering_key, preceded(space1, alt((tag("asc"), tag("desc"))))),
+        |((text, aggregate_opt), ordering)| match aggregate_opt {
+            Some(aggregate) => ast::OrderingTerm::with_aggregate(text, aggregate, ordering),
+            None => ast::OrderingTerm::new(text, ordering),
+        },
     )(i)
 }
 
@@ -272,8 +460,58 @@ fn order_by_clause<'a>(i: &'a str) -> IResult<&'a str, ast::OrderByExpression, V
     )(i)
 }
 
-fn from_clause<'a>(i: &'a str) -> IResult<&'a str, &'a str, VerboseError<&'a str>> {
-    terminated(preceded(tuple((tag("from"), space1)), identifier), space0)(i)
+fn values_row(i: &str) -> IResult<&str, Vec<ast::Value>, VerboseError<&str>> {
+    delimited(
+        char('('),
+        separated_list(char(','), delimited(space0, value, space0)),
+        char(')'),
+    )(i)
+}
+
+//An inline table, (values (1, "a"), (2, "b")) as t(id, name)
+fn values_list(i: &str) -> IResult<&str, (&str, ast::ValuesList), VerboseError<&str>> {
+    map(
+        tuple((
+            delimited(
+                tuple((char('('), space0, tag("values"), space0)),
+                separated_list(char(','), delimited(space0, values_row, space0)),
+                char(')'),
+            ),
+            preceded(tuple((space1, tag("as"), space1)), identifier),
+            delimited(
+                char('('),
+                separated_list(char(','), delimited(space0, identifier, space0)),
+                char(')'),
+            ),
+        )),
+        |(rows, alias, column_names)| {
+            let column_names = column_names.into_iter().map(|name| name.to_string()).collect();
+            (alias, ast::ValuesList::new(rows, column_names))
+        },
+    )(i)
+}
+
+fn from_clause<'a>(i: &'a str) -> IResult<&'a str, (&'a str, Option<ast::ValuesList>), VerboseError<&'a str>> {
+    terminated(
+        preceded(
+            tuple((tag("from"), space1)),
+            alt((
+                map(values_list, |(alias, values)| (alias, Some(values))),
+                map(identifier, |table_name| (table_name, None)),
+            )),
+        ),
+        space0,
+    )(i)
+}
+
+fn join_clause(i: &str) -> IResult<&str, ast::JoinClause, VerboseError<&str>> {
+    map(
+        terminated(
+            preceded(tuple((tag("cross"), space1, tag("join"), space1)), values_list),
+            space0,
+        ),
+        |(alias, values)| ast::JoinClause::new(ast::JoinType::Cross, alias, values),
+    )(i)
 }
 
 fn parse_expression_atom<'a>(i: &'a str) -> IResult<&'a str, ast::Expression, VerboseError<&'a str>> {
@@ -292,6 +530,10 @@ fn parse_expression_op<'a>(i: &'a str) -> IResult<&'a str, &'a str, VerboseError
         tag("<"),
         tag(">="),
         tag("<="),
+        tag("contains"),
+        keyword("like"),
+        map(tuple((tag("not"), space1, keyword("like"))), |_| "not like"),
+        tag("~"),
         tag("and"),
         tag("or"),
     ))(i)
@@ -303,7 +545,22 @@ fn parse_expression_at_precedence<'a>(
     precedence_table: &HashMap<String, (u32, bool)>,
 ) -> IResult<&'a str, ast::Expression, VerboseError<&'a str>> {
     let (mut i1, mut expr) = parse_expression_atom(i0)?;
-    while let Ok((i2, op)) = parse_expression_op(i1) {
+    loop {
+        if let Ok((i2, postfix_test)) = postfix_test(i1) {
+            let (test_precedence, _) = *precedence_table.get(postfix_test.keyword()).unwrap();
+            if test_precedence < current_precedence {
+                break;
+            }
+
+            expr = postfix_test.apply(expr);
+            i1 = i2;
+            continue;
+        }
+
+        let (i2, op) = match parse_expression_op(i1) {
+            Ok(parsed) => parsed,
+            Err(_) => break,
+        };
         let (op_precedence, op_left_associative) = *precedence_table.get(op).unwrap();
 
         if op_precedence < current_precedence {
@@ -330,8 +587,10 @@ pub(crate) fn select_query<'a>(i: &'a str) -> IResult<&'a str, ast::SelectStatem
         preceded(
             tag("select"),
             tuple((
+                opt(terminated(preceded(space1, tag("distinct")), space1)),
                 select_expression_list,
                 from_clause,
+                opt(join_clause),
                 opt(where_expression),
                 opt(group_by_expression),
                 opt(having_expression),
@@ -339,7 +598,17 @@ pub(crate) fn select_query<'a>(i: &'a str) -> IResult<&'a str, ast::SelectStatem
                 opt(limit_expression),
             )),
         ),
-        |(select_exprs, table_name, where_expr, group_by_expr, having_expr, order_by_expr, limit_expr)| {
+        |(
+            distinct_opt,
+            select_exprs,
+            (table_name, values),
+            join,
+            where_expr,
+            group_by_expr,
+            having_expr,
+            order_by_expr,
+            limit_expr,
+        )| {
             ast::SelectStatement::new(
                 select_exprs,
                 table_name,
@@ -349,6 +618,9 @@ pub(crate) fn select_query<'a>(i: &'a str) -> IResult<&'a str, ast::SelectStatem
                 order_by_expr,
                 limit_expr,
             )
+            .with_values(values)
+            .with_join(join)
+            .with_distinct(distinct_opt.is_some())
         },
     )(i)
 }
@@ -357,6 +629,18 @@ pub(crate) fn select_query<'a>(i: &'a str) -> IResult<&'a str, ast::SelectStatem
 mod test {
     use super::*;
 
+    #[test]
+    fn test_lowercase_query() {
+        assert_eq!(
+            r#"select a from elb where b ~ "^/API/\"X\"" and c = 'It''s'"#,
+            lowercase_query(r#"SELECT A FROM ELB WHERE B ~ "^/API/\"X\"" AND C = 'It''s'"#)
+        );
+        assert_eq!(
+            r#"select "a\\" as b from elb"#,
+            lowercase_query(r#"SELECT "a\\" AS B FROM ELB"#)
+        );
+    }
+
     #[test]
     fn test_identifier() {
         assert_eq!(
@@ -422,6 +706,18 @@ mod test {
             string_literal("\"10.0\n5,3|\""),
             Ok(("", ast::Value::StringLiteral("10.0\n5,3|".to_string())))
         );
+        assert_eq!(
+            string_literal(r#""say \"hi\", 100\%""#),
+            Ok(("", ast::Value::StringLiteral(r#"say "hi", 100\%"#.to_string())))
+        );
+        assert_eq!(
+            string_literal("'it''s, ok'"),
+            Ok(("", ast::Value::StringLiteral("it's, ok".to_string())))
+        );
+        assert_eq!(
+            string_literal("''"),
+            Ok(("", ast::Value::StringLiteral("".to_string())))
+        );
     }
 
     #[test]
@@ -430,6 +726,18 @@ mod test {
         assert_eq!(integral("-123"), Ok(("", ast::Value::Integral(-123))));
     }
 
+    #[test]
+    fn test_unit_literal() {
+        assert_eq!(value("1.5s"), Ok(("", ast::Value::Float(OrderedFloat::from(1.5)))));
+        assert_eq!(value("250ms"), Ok(("", ast::Value::Float(OrderedFloat::from(0.25)))));
+        assert_eq!(value("2m "), Ok((" ", ast::Value::Float(OrderedFloat::from(120.0)))));
+        assert_eq!(value("10mb"), Ok(("", ast::Value::Integral(10_000_000))));
+        assert_eq!(value("1.5kib"), Ok(("", ast::Value::Integral(1536))));
+        assert!(value("10xb").is_err());
+        assert!(value("3gib").is_err());
+        assert_eq!(value("10 "), Ok((" ", ast::Value::Integral(10))));
+    }
+
     #[test]
     fn test_float() {
         assert_eq!(float("123.0"), Ok(("", ast::Value::Float(OrderedFloat::from(123.0)))));
@@ -525,6 +833,72 @@ mod test {
         );
         assert_eq!(expression("true and not true"), Ok(("", ans)));
 
+        let ans = ast::Expression::BinaryOperator(
+            ast::BinaryOperator::Or,
+            Box::new(ast::Expression::UnaryOperator(
+                ast::UnaryOperator::IsNull,
+                Box::new(ast::Expression::Column("a".to_string())),
+            )),
+            Box::new(ast::Expression::UnaryOperator(
+                ast::UnaryOperator::Not,
+                Box::new(ast::Expression::UnaryOperator(
+                    ast::UnaryOperator::IsNotNull,
+                    Box::new(ast::Expression::Column("b".to_string())),
+                )),
+            )),
+        );
+        assert_eq!(expression("a is null or not b is not null"), Ok(("", ans)));
+
+        //The tests apply to the whole arithmetic before them, not to its last factor
+        let sum = || {
+            Box::new(ast::Expression::BinaryOperator(
+                ast::BinaryOperator::Plus,
+                Box::new(ast::Expression::Column("a".to_string())),
+                Box::new(ast::Expression::Column("b".to_string())),
+            ))
+        };
+        let ans = ast::Expression::UnaryOperator(ast::UnaryOperator::IsNull, sum());
+        assert_eq!(expression("a + b is null"), Ok(("", ans)));
+        let ans = ast::Expression::InList(sum(), vec![ast::Expression::Value(ast::Value::Integral(3))]);
+        assert_eq!(expression("a + b in (3)"), Ok(("", ans)));
+        let ans = ast::Expression::BinaryOperator(
+            ast::BinaryOperator::And,
+            Box::new(ast::Expression::UnaryOperator(ast::UnaryOperator::IsNotNull, sum())),
+            Box::new(ast::Expression::BinaryOperator(
+                ast::BinaryOperator::Equal,
+                Box::new(ast::Expression::Column("c".to_string())),
+                Box::new(ast::Expression::Value(ast::Value::Integral(1))),
+            )),
+        );
+        assert_eq!(expression("a + b is not null and c = 1"), Ok(("", ans)));
+
+        let ans = ast::Expression::BinaryOperator(
+            ast::BinaryOperator::And,
+            Box::new(ast::Expression::InList(
+                Box::new(ast::Expression::Column("a".to_string())),
+                vec![
+                    ast::Expression::Value(ast::Value::Integral(400)),
+                    ast::Expression::Value(ast::Value::Integral(403)),
+                ],
+            )),
+            Box::new(ast::Expression::InList(
+                Box::new(ast::Expression::Column("b".to_string())),
+                vec![],
+            )),
+        );
+        assert_eq!(expression("a in (400, 403) and b in ()"), Ok(("", ans)));
+
+        //The commas and quotes within the strings don't split the list
+        let ans = ast::Expression::InList(
+            Box::new(ast::Expression::Column("a".to_string())),
+            vec![
+                ast::Expression::Value(ast::Value::StringLiteral("a,b".to_string())),
+                ast::Expression::Value(ast::Value::StringLiteral("c'd".to_string())),
+                ast::Expression::Value(ast::Value::StringLiteral("e\",f".to_string())),
+            ],
+        );
+        assert_eq!(expression(r#"a in ('a,b', 'c''d', "e\",f")"#), Ok(("", ans)));
+
         let ans = ast::Expression::BinaryOperator(
             ast::BinaryOperator::And,
             Box::new(ast::Expression::BinaryOperator(
@@ -539,6 +913,41 @@ mod test {
             )),
         );
         assert_eq!(expression("a = 1 and b = 2"), Ok(("", ans)));
+
+        let ans = ast::Expression::BinaryOperator(
+            ast::BinaryOperator::Or,
+            Box::new(ast::Expression::BinaryOperator(
+                ast::BinaryOperator::Like,
+                Box::new(ast::Expression::Column("request".to_string())),
+                Box::new(ast::Expression::Value(ast::Value::StringLiteral("GET %".to_string()))),
+            )),
+            Box::new(ast::Expression::BinaryOperator(
+                ast::BinaryOperator::NotLike,
+                Box::new(ast::Expression::Column("path".to_string())),
+                Box::new(ast::Expression::Value(ast::Value::StringLiteral(
+                    "%100\\%_".to_string(),
+                ))),
+            )),
+        );
+        assert_eq!(
+            expression(r#"request like "GET %" or path not like "%100\%_""#),
+            Ok(("", ans))
+        );
+
+        let ans = ast::Expression::BinaryOperator(
+            ast::BinaryOperator::RegexMatch,
+            Box::new(ast::Expression::Column("user_agent".to_string())),
+            Box::new(ast::Expression::Value(ast::Value::StringLiteral(
+                "bot|crawler".to_string(),
+            ))),
+        );
+        assert_eq!(expression(r#"user_agent ~ "bot|crawler""#), Ok(("", ans)));
+
+        //A word that only starts with a keyword isn't the keyword
+        let ans = ast::Expression::Column("a".to_string());
+        assert_eq!(expression("a likes"), Ok(("likes", ans.clone())));
+        assert_eq!(expression("a inbound (1)"), Ok(("inbound (1)", ans.clone())));
+        assert_eq!(expression("a island"), Ok(("island", ans)));
     }
 
     #[test]
@@ -563,6 +972,28 @@ mod test {
             ast::SelectExpression::Expression(Box::new(ast::Expression::Column("c".to_string())), None),
         ];
         assert_eq!(select_expression_list("a, b, c"), Ok(("", ans)));
+
+        let ans = vec![
+            ast::SelectExpression::Expression(
+                Box::new(ast::Expression::FuncCall(
+                    "avg".to_string(),
+                    vec![ast::SelectExpression::Expression(
+                        Box::new(ast::Expression::Column("x".to_string())),
+                        None,
+                    )],
+                    None,
+                )),
+                Some("avg latency (ms)".to_string()),
+            ),
+            ast::SelectExpression::Expression(
+                Box::new(ast::Expression::Column("b".to_string())),
+                Some("from".to_string()),
+            ),
+        ];
+        assert_eq!(
+            select_expression_list(r#"avg(x) as "avg latency (ms)", b as "from""#),
+            Ok(("", ans))
+        );
     }
 
     #[test]
@@ -663,6 +1094,13 @@ mod test {
         );
 
         assert_eq!(func_call("percentile_disc(1.0)"), Ok(("", ans)));
+
+        //Only the functions taking a list accept it in brackets
+        let column =
+            |name: &str| ast::SelectExpression::Expression(Box::new(ast::Expression::Column(name.to_string())), None);
+        let ans = ast::Expression::FuncCall("count_buckets".to_string(), vec![column("a"), column("b")], None);
+        assert_eq!(func_call("count_buckets([a, b])"), Ok(("", ans)));
+        assert!(func_call("foo([a, b])").is_err());
     }
 
     #[test]
@@ -696,6 +1134,55 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_select_statement_from_values() {
+        let select_exprs = vec![ast::SelectExpression::Star];
+        let values = ast::ValuesList::new(
+            vec![
+                vec![ast::Value::Integral(1), ast::Value::StringLiteral("a".to_string())],
+                vec![ast::Value::Integral(2), ast::Value::StringLiteral("b".to_string())],
+            ],
+            vec!["id".to_string(), "name".to_string()],
+        );
+        let ans = ast::SelectStatement::new(select_exprs, "t", None, None, None, None, None).with_values(Some(values));
+        assert_eq!(
+            select_query(r#"select * from (values (1, "a"), (2,"b")) as t(id, name)"#),
+            Ok(("", ans))
+        );
+
+        let values = ast::ValuesList::new(
+            vec![
+                vec![ast::Value::Integral(1), ast::Value::StringLiteral("a, b".to_string())],
+                vec![ast::Value::Integral(2), ast::Value::StringLiteral("c'd)".to_string())],
+            ],
+            vec!["id".to_string(), "name".to_string()],
+        );
+        let ans = ast::SelectStatement::new(vec![ast::SelectExpression::Star], "t", None, None, None, None, None)
+            .with_values(Some(values));
+        assert_eq!(
+            select_query(r#"select * from (values (1, 'a, b'), (2, 'c''d)')) as t(id, name)"#),
+            Ok(("", ans))
+        );
+    }
+
+    #[test]
+    fn test_select_statement_with_distinct() {
+        let select_exprs = vec![ast::SelectExpression::Expression(
+            Box::new(ast::Expression::Column("a".to_string())),
+            None,
+        )];
+        let ans = ast::SelectStatement::new(select_exprs, "elb", None, None, None, None, None).with_distinct(true);
+        assert_eq!(select_query("select distinct a from elb"), Ok(("", ans)));
+
+        //A column whose name starts with distinct is just a column
+        let select_exprs = vec![ast::SelectExpression::Expression(
+            Box::new(ast::Expression::Column("distinct_a".to_string())),
+            None,
+        )];
+        let ans = ast::SelectStatement::new(select_exprs, "elb", None, None, None, None, None);
+        assert_eq!(select_query("select distinct_a from elb"), Ok(("", ans)));
+    }
+
     #[test]
     fn test_select_statement_with_limit() {
         let select_exprs = vec![
@@ -708,6 +1195,17 @@ mod test {
         let ans = ast::SelectStatement::new(select_exprs, "elb", None, None, None, None, Some(limit_expr));
 
         assert_eq!(select_query("select a, b, c from elb limit 1"), Ok(("", ans)));
+
+        let select_exprs = vec![ast::SelectExpression::Star];
+        let limit_expr = ast::LimitExpression::new(-5);
+        let ans = ast::SelectStatement::new(select_exprs, "elb", None, None, None, None, Some(limit_expr));
+
+        assert_eq!(select_query("select * from elb limit -5"), Ok(("", ans)));
+
+        //A limit that doesn't fit is left unparsed rather than panicking
+        assert!(limit_expression("limit 99999999999").is_err());
+        let (rest, _) = select_query("select * from elb limit 99999999999").unwrap();
+        assert_eq!("limit 99999999999", rest);
     }
 
     #[test]
@@ -724,6 +1222,33 @@ mod test {
         assert_eq!(select_query("select a, b, c from elb order by a asc"), Ok(("", ans)));
     }
 
+    #[test]
+    fn test_select_statement_with_order_by_aggregate() {
+        let select_exprs = vec![ast::SelectExpression::Expression(
+            Box::new(ast::Expression::Column("a".to_string())),
+            None,
+        )];
+
+        let count = ast::Expression::FuncCall("count".to_string(), vec![ast::SelectExpression::Star], None);
+        let order_by_clause =
+            ast::OrderByExpression::new(vec![ast::OrderingTerm::with_aggregate("count(*)", count, "desc")]);
+        let group_by_expr = ast::GroupByExpression::new(vec!["a".to_string()]);
+        let ans = ast::SelectStatement::new(
+            select_exprs,
+            "elb",
+            None,
+            Some(group_by_expr),
+            None,
+            Some(order_by_clause),
+            None,
+        );
+
+        assert_eq!(
+            select_query("select a from elb group by a order by count(*) desc"),
+            Ok(("", ans))
+        );
+    }
+
     #[test]
     fn test_select_statement_with_within_group() {
         let select_exprs = vec![